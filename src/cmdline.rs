@@ -0,0 +1,88 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Command line
+//!
+//! The kernel command line, `bootargs` in the `/chosen` node of the device tree (the
+//! firmware fills it in from `cmdline.txt`). Kernels built with the `DYSEOS_CMDLINE`
+//! environment variable set use that when the device tree has none.
+//!
+//! Options are separated by whitespace, either `key=value` or a bare flag. Known ones:
+//!
+//!   - `console=ttyAMA0` prints on the PL011 UART (the default), `console=ttyS0` or
+//!     `console=serial0` on the mini UART, optionally with a baud rate as in
+//!     `console=ttyS0,115200`, see [crate::drivers::console::init()].
+//!   - `panic=N` reboots `N` seconds after a panic, see [crate::drivers::power].
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.kernel.org/doc/html/latest/admin-guide/kernel-parameters.html>
+//!   - <https://www.raspberrypi.com/documentation/computers/configuration.html#the-kernel-command-line>
+//!
+
+use crate::fdt;
+
+/// Command line built into the kernel, for booting without one
+const BUILT_IN: Option<&str> = option_env!("DYSEOS_CMDLINE");
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The whole command line, empty if there is none
+///
+/// Comes from the device tree, so it's only complete after [crate::fdt::init()].
+pub fn get() -> &'static str {
+    fdt::get()
+        .and_then(|fdt| fdt.property("/chosen", "bootargs"))
+        .and_then(fdt::as_str)
+        .filter(|bootargs| !bootargs.trim().is_empty())
+        .or(BUILT_IN)
+        .unwrap_or("")
+}
+
+/// The value of the last `key=value` option
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::cmdline;
+///
+/// // Booted with "console=ttyS0 quiet"
+/// assert_eq!(cmdline::value("console"), Some("ttyS0"));
+/// assert_eq!(cmdline::value("quiet"), None);
+/// ```
+pub fn value(key: &str) -> Option<&'static str> {
+    get()
+        .split_whitespace()
+        .rev()
+        .filter_map(|option| option.split_once('='))
+        .find(|&(name, _)| name == key)
+        .map(|(_, value)| value)
+}
+
+/// The values of every `key=value` option, in order
+///
+/// For options that can be given more than once, like `console`.
+pub fn values(key: &str) -> impl Iterator<Item = &'static str> + '_ {
+    get()
+        .split_whitespace()
+        .filter_map(|option| option.split_once('='))
+        .filter(move |&(name, _)| name == key)
+        .map(|(_, value)| value)
+}
+
+/// Whether the flag `name` was given, without a value
+pub fn flag(name: &str) -> bool {
+    get().split_whitespace().any(|option| option == name)
+}
@@ -22,37 +22,45 @@ compile_error!("Unsupported target");
 
 use aarch64_cpu::asm;
 
+pub mod context;
+pub mod exception;
+pub mod mmu;
+pub mod percpu;
+pub mod smp;
+pub mod timer;
+pub mod uaccess;
+
 /// # Start code
 ///
 /// If on the boot core starts the kernel, if not parks it.
-/// Also initializes the bss section by calling [_init_mem()]. This code is
-/// linked to the beggining of the .text section by the linker script.
+/// Also initializes the bss section by calling [_init_mem()], builds the kernel translation
+/// tables ([mmu]) and drops from EL2 to EL1 with [_el2_to_el1()]. This code is linked to the
+/// beggining of the .text section by the linker script.
+///
+/// Everything up to the `eret` runs at the physical load address with the MMU off, so it only
+/// uses pc relative addresses (adrp). The stack and entry handed to EL1 are loaded from the
+/// literal pool (`ldr =`), those are the kernel's virtual addresses.
+///
+/// Each core owns a [CORE_STACK_SIZE] slice of the boot core stack region, the boot core takes
+/// the top one.
+///
+/// The firmware (and qemu with `-dtb`) passes the physical address of the device tree in x0,
+/// it's kept in x19 and stored to [boot_dtb()] once the bss is zeroed.
 ///
 /// ### TODO:
 /// - Make board independant using features
 /// - Learn about processors to see if it can do more
+/// - Support booting from EL1 (or EL3)
 ///
-/// ### Disassembly
-/// ```
-/// 0000000000080000 <_start>:
-///    80000: d53800a0     	mrs	x0, MPIDR_EL1
-///    80004: 92400400     	and	x0, x0, #0x3
-///    80008: aa1f03e1     	mov	x1, xzr
-///    8000c: eb01001f     	cmp	x0, x1
-///    80010: 54000201     	b.ne	0x80050 <_park>
-///    80014: d503201f     	nop
-///    80018: 10ffff40     	adr	x0, 0x80000 <_start>
-///    8001c: 9100001f     	mov	sp, x0
-///    80020: d503201f     	nop
-///    80024: 100001be     	adr	x30, 0x80058 <_kernel_init>
-///    80028: 14000003     	b	0x80034 <_init_mem>
-///    8002c: d503205f     	wfe
-///    80030: 17ffffff     	b	0x8002c <_start+0x2c>
-/// ```
 #[link_section = ".text._start"]
 #[no_mangle]
 unsafe fn _start() -> ! {
     core::arch::asm!(
+        "mov    x19, x0",
+        // only boot from EL2, that is where qemu and the armstub leave us
+        "mrs    x0, CurrentEL",
+        "cmp    x0, #{EL2}",
+        "b.ne   _park",
         // check if this is the boot core (ID = 0)
         "mrs    x0, mpidr_el1",
         "and    x0, x0, #0x3",
@@ -63,16 +71,104 @@ unsafe fn _start() -> ! {
         "adrp	x0, _ebcstack",
         "add	x0, x0, #:lo12:_ebcstack",
         "mov	sp, x0",
-        // set x30 to kernel init
-        "adrp	x30, _kernel_init",
-        "add	x30, x30, #:lo12:_kernel_init",
-        // // begin init bss
-        "b      _init_mem"
+        // begin init bss
+        "bl     _init_mem",
+        "adrp   x0, {DTB}",
+        "str    x19, [x0, #:lo12:{DTB}]",
+        "bl     _init_mmu",
+        // drop to EL1 on the same stack and enter the kernel, both in the upper half
+        "ldr    x0, =_ebcstack",
+        "ldr    x1, =_kernel_init",
+        "b      _el2_to_el1",
+        EL2 = const 0b10 << 2,
+        DTB = sym BOOT_DTB,
     );
 
     _park();
 }
 
+/// Physical address of the device tree from the firmware, written by [_start()]
+static BOOT_DTB: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Physical address of the device tree the firmware booted the kernel with
+///
+/// None if it didn't pass one, qemu only does with `-dtb`.
+pub fn boot_dtb() -> Option<usize> {
+    match BOOT_DTB.load(core::sync::atomic::Ordering::Relaxed) {
+        0 => None,
+        dtb => Some(dtb),
+    }
+}
+
+/// Size of each core's boot stack
+pub const CORE_STACK_SIZE: usize = 1 << CORE_STACK_SHIFT;
+const CORE_STACK_SHIFT: usize = 17;
+
+/// # Secondary core start code
+///
+/// Secondary cores are released here by [smp::start_secondary_cores()]. Same as [_start()]
+/// without the bss and table init, each core gets its own stack below the boot core's and
+/// enters `_secondary_init` at EL1.
+#[link_section = ".text._start_secondary"]
+#[no_mangle]
+unsafe fn _start_secondary() -> ! {
+    core::arch::asm!(
+        "mrs    x0, CurrentEL",
+        "cmp    x0, #{EL2}",
+        "b.ne   _park",
+        // stack top = _ebcstack - core_id * CORE_STACK_SIZE, physical until the eret
+        "mrs    x1, mpidr_el1",
+        "and    x1, x1, #0x3",
+        "adrp	x0, _ebcstack",
+        "add	x0, x0, #:lo12:_ebcstack",
+        "sub    x0, x0, x1, lsl #{SHIFT}",
+        "mov	sp, x0",
+        "ldr    x0, =_ebcstack",
+        "sub    x0, x0, x1, lsl #{SHIFT}",
+        "ldr    x1, =_secondary_init",
+        "b      _el2_to_el1",
+        EL2 = const 0b10 << 2,
+        SHIFT = const CORE_STACK_SHIFT,
+    );
+
+    _park();
+}
+
+/// Drop from EL2 to EL1
+///
+/// Mostly copied from Andre Richter's tutorials (09_privilege_level). Gives EL1 access to the
+/// physical timer and counter, makes EL1 aarch64, turns on the EL1 MMU and then `eret`s to
+/// `entry` with all exceptions masked and `sp` set to `stack_top`. Both are virtual addresses.
+#[no_mangle]
+unsafe extern "C" fn _el2_to_el1(stack_top: u64, entry: u64) -> ! {
+    use aarch64_cpu::registers::*;
+    use tock_registers::interfaces::Writeable;
+
+    // Enable timer counter registers for EL1.
+    CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+
+    // No offset for reading the counters.
+    CNTVOFF_EL2.set(0);
+
+    // Set EL1 execution state to AArch64.
+    HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
+
+    // Fake an exception return, start in EL1 with SP_EL1 and everything masked.
+    SPSR_EL2.write(
+        SPSR_EL2::D::Masked
+            + SPSR_EL2::A::Masked
+            + SPSR_EL2::I::Masked
+            + SPSR_EL2::F::Masked
+            + SPSR_EL2::M::EL1h,
+    );
+    ELR_EL2.set(entry);
+    SP_EL1.set(stack_top);
+
+    mmu::enable_el1();
+
+    asm::eret()
+}
+
 /// Initialize the bss section of memory
 ///
 /// Copied directly from <https://docs.rust-embedded.org/embedonomicon/main.html#life-before-main>
@@ -0,0 +1,214 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Aarch64 Context switching
+//!
+//! Saves the callee-saved registers of one task and restores another's.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/ARM-software/abi-aa/blob/main/aapcs64/aapcs64.rst#general-purpose-registers>
+//!   - <https://elixir.bootlin.com/linux/latest/source/arch/arm64/kernel/entry.S> (cpu_switch_to)
+//!
+
+use aarch64_cpu::registers::CPACR_EL1;
+use tock_registers::interfaces::Writeable;
+
+/// FP/SIMD register file
+///
+/// Only saved when the `fpsimd` feature is enabled. The kernel is built softfloat so whatever
+/// lives in these registers at a switch belongs to the task being switched out, that means all
+/// 32 registers get saved (not just the callee-saved d8-d15).
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct FpState {
+    q: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+/// Saved state of a suspended task
+///
+/// Everything the AAPCS64 says a callee has to preserve. The caller saved registers are already
+/// on the task's stack by the time [_switch_context()] gets called.
+///
+/// ### Dev note
+///
+/// The layout is used by the assembly below, don't reorder the feilds.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    /// x19 - x28
+    callee: [u64; 10],
+    /// x29
+    fp: u64,
+    /// x30, where [_switch_context()] returns to
+    lr: u64,
+    sp: u64,
+    _reserved: u64,
+    fpsimd: FpState,
+}
+
+impl FpState {
+    /// Zeroed FP/SIMD state, all exceptions masked and round to nearest
+    pub const fn new() -> FpState {
+        FpState { q: [0; 32], fpcr: 0, fpsr: 0 }
+    }
+}
+
+impl Default for FpState {
+    fn default() -> FpState {
+        FpState::new()
+    }
+}
+
+impl Context {
+    /// An empty context, switching to this is UB
+    pub const fn new() -> Context {
+        Context {
+            callee: [0; 10],
+            fp: 0,
+            lr: 0,
+            sp: 0,
+            _reserved: 0,
+            fpsimd: FpState::new(),
+        }
+    }
+
+    /// Builds the context of a task that has never run
+    ///
+    /// The first switch to this context lands in `_task_trampoline` on top of `stack_top`, which
+    /// calls `entry(arg)`. `entry` should never return.
+    pub fn new_task(entry: extern "C" fn(usize) -> !, arg: usize, stack_top: usize) -> Context {
+        extern "C" {
+            fn _task_trampoline();
+        }
+
+        let mut ctx = Context::new();
+        ctx.callee[0] = entry as usize as u64;
+        ctx.callee[1] = arg as u64;
+        ctx.lr = _task_trampoline as *const () as usize as u64;
+        ctx.sp = (stack_top & !0xF) as u64;
+        ctx
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+/// Allow EL0 and EL1 to use the FP/SIMD registers without trapping
+///
+/// Required before the first switch when the `fpsimd` feature is enabled.
+pub fn enable_fpsimd() {
+    CPACR_EL1.write(CPACR_EL1::FPEN::TrapNothing);
+    aarch64_cpu::asm::barrier::isb(aarch64_cpu::asm::barrier::SY);
+}
+
+extern "C" {
+    /// Switch from one task to another
+    ///
+    /// Saves the callee-saved registers, sp and (with `fpsimd`) the FP/SIMD registers into
+    /// `prev`, then loads `next` and returns into it. Returns to the caller when something
+    /// switches back to `prev`.
+    ///
+    /// ## Safety
+    ///
+    /// `prev` must be valid for writes and `next` must have been filled by a previous switch
+    /// or [Context::new_task()]. Both must stay alive until the switch completes.
+    pub fn _switch_context(prev: *mut Context, next: *const Context);
+}
+
+core::arch::global_asm!(
+    ".section .text._switch_context",
+    ".global _switch_context",
+    ".type _switch_context, %function",
+    "_switch_context:",
+    "    stp    x19, x20, [x0, #0]",
+    "    stp    x21, x22, [x0, #16]",
+    "    stp    x23, x24, [x0, #32]",
+    "    stp    x25, x26, [x0, #48]",
+    "    stp    x27, x28, [x0, #64]",
+    "    stp    x29, x30, [x0, #80]",
+    "    mov    x9, sp",
+    "    str    x9, [x0, #96]",
+    ".if {FPSIMD}",
+    "    .arch_extension fp",
+    "    .arch_extension simd",
+    "    add    x9, x0, #{FP}",
+    "    stp    q0, q1, [x9, #0]",
+    "    stp    q2, q3, [x9, #32]",
+    "    stp    q4, q5, [x9, #64]",
+    "    stp    q6, q7, [x9, #96]",
+    "    stp    q8, q9, [x9, #128]",
+    "    stp    q10, q11, [x9, #160]",
+    "    stp    q12, q13, [x9, #192]",
+    "    stp    q14, q15, [x9, #224]",
+    "    stp    q16, q17, [x9, #256]",
+    "    stp    q18, q19, [x9, #288]",
+    "    stp    q20, q21, [x9, #320]",
+    "    stp    q22, q23, [x9, #352]",
+    "    stp    q24, q25, [x9, #384]",
+    "    stp    q26, q27, [x9, #416]",
+    "    stp    q28, q29, [x9, #448]",
+    "    stp    q30, q31, [x9, #480]",
+    "    mrs    x10, fpcr",
+    "    mrs    x11, fpsr",
+    "    stp    x10, x11, [x9, #512]",
+    "    add    x9, x1, #{FP}",
+    "    ldp    q0, q1, [x9, #0]",
+    "    ldp    q2, q3, [x9, #32]",
+    "    ldp    q4, q5, [x9, #64]",
+    "    ldp    q6, q7, [x9, #96]",
+    "    ldp    q8, q9, [x9, #128]",
+    "    ldp    q10, q11, [x9, #160]",
+    "    ldp    q12, q13, [x9, #192]",
+    "    ldp    q14, q15, [x9, #224]",
+    "    ldp    q16, q17, [x9, #256]",
+    "    ldp    q18, q19, [x9, #288]",
+    "    ldp    q20, q21, [x9, #320]",
+    "    ldp    q22, q23, [x9, #352]",
+    "    ldp    q24, q25, [x9, #384]",
+    "    ldp    q26, q27, [x9, #416]",
+    "    ldp    q28, q29, [x9, #448]",
+    "    ldp    q30, q31, [x9, #480]",
+    "    ldp    x10, x11, [x9, #512]",
+    "    msr    fpcr, x10",
+    "    msr    fpsr, x11",
+    ".endif",
+    "    ldp    x19, x20, [x1, #0]",
+    "    ldp    x21, x22, [x1, #16]",
+    "    ldp    x23, x24, [x1, #32]",
+    "    ldp    x25, x26, [x1, #48]",
+    "    ldp    x27, x28, [x1, #64]",
+    "    ldp    x29, x30, [x1, #80]",
+    "    ldr    x9, [x1, #96]",
+    "    mov    sp, x9",
+    "    ret",
+    ".size _switch_context, . - _switch_context",
+    "",
+    // First switch into a new task: x19 = entry, x20 = arg
+    ".section .text._task_trampoline",
+    ".global _task_trampoline",
+    ".type _task_trampoline, %function",
+    "_task_trampoline:",
+    "    mov    x0, x20",
+    "    mov    x29, xzr",
+    "    mov    x30, xzr",
+    "    br     x19",
+    ".size _task_trampoline, . - _task_trampoline",
+    FPSIMD = const cfg!(feature = "fpsimd") as u8,
+    FP = const core::mem::offset_of!(Context, fpsimd),
+);
@@ -0,0 +1,409 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Aarch64 Exceptions
+//!
+//! EL1 vector table, trap frames and interrupt masking. Every vector saves the
+//! interrupted state in a [TrapFrame] on the current stack and calls into Rust.
+//! IRQs get dispatched through [crate::drivers::interrupt] and give the scheduler
+//! a chance to switch tasks before the frame is restored.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! Copied (mostly) from Andre Richter's Rust RaspberryPi tutorials
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/rust-embedded/rust-raspberrypi-OS-tutorials/tree/master/11_exceptions_part1_groundwork>
+//!   - <https://developer.arm.com/documentation/102412/latest/>
+//!
+
+use aarch64_cpu::{asm::barrier, registers::*};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use tock_registers::interfaces::{Readable, Writeable};
+
+/// State of the interrupted code
+///
+/// ### Dev note
+///
+/// The layout is used by the vector assembly, don't reorder the feilds.
+#[repr(C)]
+pub struct TrapFrame {
+    /// x0 - x30
+    pub gpr: [u64; 31],
+    /// Stack pointer of EL0
+    pub sp_el0: u64,
+    /// Return address
+    pub elr: u64,
+    /// Saved program status
+    pub spsr: u64,
+    /// Exception syndrome
+    pub esr: u64,
+    /// Fault address
+    pub far: u64,
+}
+
+/// Prints the exception class and the registers
+impl core::fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(f, "ESR_EL1: {:#010x} (EC {:#04x})", self.esr, self.esr >> 26)?;
+        writeln!(f, "FAR_EL1: {:#018x}", self.far)?;
+        writeln!(f, "ELR_EL1: {:#018x}", self.elr)?;
+        writeln!(f, "SPSR_EL1: {:#010x}", self.spsr)?;
+        writeln!(f, "SP_EL0: {:#018x}", self.sp_el0)?;
+
+        for (i, reg) in self.gpr.iter().enumerate() {
+            write!(f, "x{:<2}: {:#018x}", i, reg)?;
+            match i % 2 {
+                0 => write!(f, "   ")?,
+                _ => writeln!(f)?,
+            }
+        }
+
+        writeln!(f)
+    }
+}
+
+/// Size of [TrapFrame], used by the assembly
+const FRAME_SIZE: usize = core::mem::size_of::<TrapFrame>();
+
+crate::percpu! {
+    /// Interrupt nesting depth of the core
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Masks IRQs on the calling core until dropped
+///
+/// Restores the previous mask, so guards can nest.
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::cpu::exception::IrqGuard;
+///
+/// {
+///     let _irq = IrqGuard::new();
+///     // no interrupts on this core in here
+/// }
+/// ```
+pub struct IrqGuard {
+    daif: u64,
+}
+
+impl IrqGuard {
+    /// Save the mask and disable IRQs
+    #[inline]
+    pub fn new() -> IrqGuard {
+        let daif = DAIF.get();
+        local_irq_disable();
+        IrqGuard { daif }
+    }
+}
+
+impl Default for IrqGuard {
+    fn default() -> IrqGuard {
+        IrqGuard::new()
+    }
+}
+
+impl Drop for IrqGuard {
+    #[inline]
+    fn drop(&mut self) {
+        DAIF.set(self.daif);
+    }
+}
+
+/// Unmask IRQs on the calling core
+#[inline]
+pub fn local_irq_enable() {
+    unsafe { core::arch::asm!("msr daifclr, #2", options(nostack, preserves_flags)) };
+}
+
+/// Mask IRQs on the calling core
+#[inline]
+pub fn local_irq_disable() {
+    unsafe { core::arch::asm!("msr daifset, #2", options(nostack, preserves_flags)) };
+}
+
+/// True when IRQs are masked on the calling core
+#[inline]
+pub fn irqs_masked() -> bool {
+    DAIF.is_set(DAIF::I)
+}
+
+/// True when the calling core is handling an interrupt
+#[inline]
+pub fn in_irq() -> bool {
+    IRQ_DEPTH.local().load(Ordering::Relaxed) > 0
+}
+
+/// Point VBAR_EL1 at the vector table
+///
+/// Each core has its own VBAR, every core has to call this.
+pub fn init() {
+    extern "C" {
+        static _vectors: u8;
+    }
+
+    VBAR_EL1.set(unsafe { &_vectors as *const u8 as u64 });
+    barrier::isb(barrier::SY);
+}
+
+/// Drop to EL0 at `entry` with `stack_top` as its stack and `arg` in x0
+///
+/// Every other register starts zeroed so nothing leaks from the kernel. Exceptions from EL0
+/// come back in on the current kernel stack, whatever is on it now is never returned to.
+///
+/// ## Safety
+///
+/// `entry` and `stack_top` have to be memory EL0 can run and use.
+pub unsafe fn enter_el0(entry: usize, stack_top: usize, arg: usize) -> ! {
+    core::arch::asm!(
+        // ELR and SPSR can't be clobbered by an IRQ before the eret
+        "msr    daifset, #2",
+        "msr    elr_el1, {entry}",
+        "msr    sp_el0, {stack}",
+        // EL0t, nothing masked
+        "msr    spsr_el1, xzr",
+        "mov    x0, {arg}",
+        "mov    x1, xzr",
+        "mov    x2, xzr",
+        "mov    x3, xzr",
+        "mov    x4, xzr",
+        "mov    x5, xzr",
+        "mov    x6, xzr",
+        "mov    x7, xzr",
+        "mov    x8, xzr",
+        "mov    x9, xzr",
+        "mov    x10, xzr",
+        "mov    x11, xzr",
+        "mov    x12, xzr",
+        "mov    x13, xzr",
+        "mov    x14, xzr",
+        "mov    x15, xzr",
+        "mov    x16, xzr",
+        "mov    x17, xzr",
+        "mov    x18, xzr",
+        "mov    x19, xzr",
+        "mov    x20, xzr",
+        "mov    x21, xzr",
+        "mov    x22, xzr",
+        "mov    x23, xzr",
+        "mov    x24, xzr",
+        "mov    x25, xzr",
+        "mov    x26, xzr",
+        "mov    x27, xzr",
+        "mov    x28, xzr",
+        "mov    x29, xzr",
+        "mov    x30, xzr",
+        "eret",
+        entry = in(reg) entry,
+        stack = in(reg) stack_top,
+        arg = in(reg) arg,
+        options(noreturn),
+    );
+}
+
+//--------------------------------------------------------------------------------------------------
+// Handlers, called from the vector table
+//--------------------------------------------------------------------------------------------------
+
+/// Anything without a proper handler
+#[no_mangle]
+extern "C" fn _unhandled_exception(frame: &mut TrapFrame) {
+    panic!("Unhandled exception\n{}", frame);
+}
+
+/// Synchronous exception from the kernel itself
+#[no_mangle]
+extern "C" fn _current_el_sync(frame: &mut TrapFrame) {
+    // User copies fault pages in like the program would, and fail on bad pointers
+    if frame.esr >> 26 == EC_DABT_CURRENT && (frame.far as usize) < crate::memory::vm::USER_END {
+        if user_fault(frame, false) == Some(true) {
+            return;
+        }
+
+        if let Some(fixup) = super::uaccess::fixup(frame.elr as usize) {
+            frame.elr = fixup as u64;
+            return;
+        }
+    }
+
+    panic!("Kernel synchronous exception\n{}", frame);
+}
+
+/// Exception class of an SVC from aarch64
+const EC_SVC64: u64 = 0x15;
+
+/// Exception class of an instruction abort from a lower EL
+const EC_IABT_LOWER: u64 = 0x20;
+
+/// Exception class of a data abort from a lower EL
+const EC_DABT_LOWER: u64 = 0x24;
+
+/// Exception class of a data abort from the current EL
+const EC_DABT_CURRENT: u64 = 0x25;
+
+/// Write not read bit of a data abort's ISS
+const ESR_WNR: u64 = 1 << 6;
+
+/// Let the running task's address space resolve an abort on a user address
+///
+/// None if the task has no address space.
+fn user_fault(frame: &TrapFrame, exec: bool) -> Option<bool> {
+    let write = !exec && frame.esr & ESR_WNR != 0;
+    crate::task::with_space(|space| space.handle_fault(frame.far as usize, write, exec))
+}
+
+/// Exit code of a task killed by a fault in EL0 (-SIGSEGV)
+const USER_FAULT: i32 = -11;
+
+/// Synchronous exception from EL0
+///
+/// System calls run with IRQs enabled, they are just the task running in the kernel. Aborts
+/// on pages of the task's regions that aren't mapped yet are resolved and retried, any
+/// other exception is a fault of the user program and kills the task.
+#[no_mangle]
+extern "C" fn _lower_el_sync(frame: &mut TrapFrame) {
+    match frame.esr >> 26 {
+        EC_SVC64 => {
+            local_irq_enable();
+            crate::syscall::dispatch(frame);
+            local_irq_disable();
+        }
+        EC_IABT_LOWER | EC_DABT_LOWER if user_fault(frame, frame.esr >> 26 == EC_IABT_LOWER) == Some(true) => {}
+        _ => {
+            crate::println!("Task {} killed by a user exception\n{}", crate::task::current(), frame);
+            crate::task::exit(USER_FAULT);
+        }
+    }
+}
+
+/// IRQ from any EL
+///
+/// Runs the registered handlers, then lets the scheduler preempt the interrupted task.
+#[no_mangle]
+extern "C" fn _handle_irq(_frame: &mut TrapFrame) {
+    let depth = IRQ_DEPTH.local();
+
+    depth.fetch_add(1, Ordering::Relaxed);
+    crate::drivers::interrupt::dispatch();
+    depth.fetch_sub(1, Ordering::Relaxed);
+
+    crate::task::preempt();
+}
+
+//--------------------------------------------------------------------------------------------------
+// Vector table
+//--------------------------------------------------------------------------------------------------
+
+core::arch::global_asm!(
+    // Save the interrupted state in a TrapFrame and call the handler with it
+    ".macro CALL_WITH_FRAME handler",
+    "    sub    sp, sp, #{FRAME}",
+    "    stp    x0, x1, [sp, #16 * 0]",
+    "    stp    x2, x3, [sp, #16 * 1]",
+    "    stp    x4, x5, [sp, #16 * 2]",
+    "    stp    x6, x7, [sp, #16 * 3]",
+    "    stp    x8, x9, [sp, #16 * 4]",
+    "    stp    x10, x11, [sp, #16 * 5]",
+    "    stp    x12, x13, [sp, #16 * 6]",
+    "    stp    x14, x15, [sp, #16 * 7]",
+    "    stp    x16, x17, [sp, #16 * 8]",
+    "    stp    x18, x19, [sp, #16 * 9]",
+    "    stp    x20, x21, [sp, #16 * 10]",
+    "    stp    x22, x23, [sp, #16 * 11]",
+    "    stp    x24, x25, [sp, #16 * 12]",
+    "    stp    x26, x27, [sp, #16 * 13]",
+    "    stp    x28, x29, [sp, #16 * 14]",
+    "    mrs    x0, sp_el0",
+    "    stp    x30, x0, [sp, #16 * 15]",
+    "    mrs    x1, elr_el1",
+    "    mrs    x2, spsr_el1",
+    "    stp    x1, x2, [sp, #16 * 16]",
+    "    mrs    x3, esr_el1",
+    "    mrs    x4, far_el1",
+    "    stp    x3, x4, [sp, #16 * 17]",
+    "    mov    x0, sp",
+    "    bl     \\handler",
+    "    b      _exception_return",
+    ".endm",
+    "",
+    ".section .text._vectors",
+    ".balign 0x800",
+    ".global _vectors",
+    "_vectors:",
+    // Current EL with SP_EL0, never used
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    // Current EL with SP_ELx
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _current_el_sync",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _handle_irq",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    // Lower EL, aarch64
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _lower_el_sync",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _handle_irq",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    // Lower EL, aarch32
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    ".balign 0x80",
+    "    CALL_WITH_FRAME _unhandled_exception",
+    "",
+    // Restore a TrapFrame and return to whatever it interrupted
+    ".balign 0x80",
+    ".global _exception_return",
+    "_exception_return:",
+    "    ldp    x1, x2, [sp, #16 * 16]",
+    "    msr    elr_el1, x1",
+    "    msr    spsr_el1, x2",
+    "    ldr    x0, [sp, #16 * 15 + 8]",
+    "    msr    sp_el0, x0",
+    "    ldp    x0, x1, [sp, #16 * 0]",
+    "    ldp    x2, x3, [sp, #16 * 1]",
+    "    ldp    x4, x5, [sp, #16 * 2]",
+    "    ldp    x6, x7, [sp, #16 * 3]",
+    "    ldp    x8, x9, [sp, #16 * 4]",
+    "    ldp    x10, x11, [sp, #16 * 5]",
+    "    ldp    x12, x13, [sp, #16 * 6]",
+    "    ldp    x14, x15, [sp, #16 * 7]",
+    "    ldp    x16, x17, [sp, #16 * 8]",
+    "    ldp    x18, x19, [sp, #16 * 9]",
+    "    ldp    x20, x21, [sp, #16 * 10]",
+    "    ldp    x22, x23, [sp, #16 * 11]",
+    "    ldp    x24, x25, [sp, #16 * 12]",
+    "    ldp    x26, x27, [sp, #16 * 13]",
+    "    ldp    x28, x29, [sp, #16 * 14]",
+    "    ldr    x30, [sp, #16 * 15]",
+    "    add    sp, sp, #{FRAME}",
+    "    eret",
+    FRAME = const FRAME_SIZE,
+);
@@ -0,0 +1,326 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Aarch64 MMU
+//!
+//! Translation tables, the translation control registers and TLB maintenance. Both
+//! halves use the 64KiB granule with 42 bit addresses, so a walk is two levels: 8192
+//! entries of 512MiB at L2 and 8192 pages of 64KiB at L3.
+//!
+//!   - TTBR1 maps the kernel: all of RAM and the peripherals at [crate::memory::KERNEL_BASE].
+//!   - TTBR0 maps the running process ([crate::memory::vm::AddressSpace]) and is switched
+//!     with the task, tagged with the process' ASID. Kernel tasks get an empty table.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://developer.arm.com/documentation/101811/latest/> (AArch64 memory management)
+//!   - <https://developer.arm.com/documentation/ddi0487/latest/> (D8, the VMSAv8-64 translation system)
+//!   - <https://github.com/rust-embedded/rust-raspberrypi-OS-tutorials/tree/master/10_virtual_mem_part1_identity_mapping>
+//!
+
+use crate::memory::{KERNEL_BASE, PAGE_SIZE};
+use aarch64_cpu::{asm::barrier, registers::*};
+use core::ptr::addr_of_mut;
+use tock_registers::interfaces::{ReadWriteable, Writeable};
+
+/// Entries in a translation table
+pub const ENTRIES: usize = 8192;
+
+/// Bits of virtual address translated, for both halves
+pub const VA_BITS: usize = 42;
+
+/// Address bits below an L2 entry (512MiB)
+pub const L2_SHIFT: usize = 29;
+
+/// Address bits below an L3 entry (a page)
+pub const L3_SHIFT: usize = 16;
+
+/// Start of the peripherals, mapped as device memory
+const MMIO_START: usize = 0x3F00_0000;
+
+/// End of the local peripherals (the BCM2836 interrupt controller)
+const MMIO_END: usize = 0x6000_0000;
+
+/// A translation table, one 64KiB page
+#[repr(C, align(65536))]
+pub struct PageTable(pub [u64; ENTRIES]);
+
+impl PageTable {
+    /// A table with nothing mapped
+    pub const fn empty() -> PageTable {
+        PageTable([0; ENTRIES])
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Descriptors
+//--------------------------------------------------------------------------------------------------
+
+/// Entry is valid
+const VALID: u64 = 1 << 0;
+/// L2: points at a table instead of a block, L3: a page
+const TABLE_OR_PAGE: u64 = 1 << 1;
+/// MAIR index 0, normal write-back memory
+const ATTR_NORMAL: u64 = 0 << 2;
+/// MAIR index 1, device nGnRE
+const ATTR_DEVICE: u64 = 1 << 2;
+/// EL0 can access
+const AP_EL0: u64 = 1 << 6;
+/// Read only (for every EL that can access it)
+const AP_RO: u64 = 1 << 7;
+/// Inner shareable
+const SH_INNER: u64 = 0b11 << 8;
+/// Access flag, set so the first access doesn't fault
+const AF: u64 = 1 << 10;
+/// Not global, TLB entries are tagged with the ASID
+const NG: u64 = 1 << 11;
+/// EL1 can't execute
+const PXN: u64 = 1 << 53;
+/// EL0 can't execute
+const UXN: u64 = 1 << 54;
+/// Output address bits of a table or page descriptor
+const ADDR_MASK: u64 = 0x0000_FFFF_FFFF_0000;
+
+/// MAIR_EL1: attr0 normal write-back read/write allocate, attr1 device nGnRE
+const MAIR: u64 = 0x04_FF;
+
+/// Descriptor of a table at physical address `pa`
+pub const fn table_descriptor(pa: usize) -> u64 {
+    (pa as u64 & ADDR_MASK) | TABLE_OR_PAGE | VALID
+}
+
+/// Descriptor of a user page at physical address `pa`
+pub const fn user_page_descriptor(pa: usize, write: bool, exec: bool) -> u64 {
+    let mut desc = (pa as u64 & ADDR_MASK) | TABLE_OR_PAGE | VALID;
+    desc |= ATTR_NORMAL | AP_EL0 | SH_INNER | AF | NG | PXN;
+
+    if !write {
+        desc |= AP_RO;
+    }
+    if !exec {
+        desc |= UXN;
+    }
+
+    desc
+}
+
+/// True if the descriptor maps something
+pub const fn is_valid(desc: u64) -> bool {
+    desc & VALID != 0
+}
+
+/// Physical address a table or page descriptor points at
+pub const fn descriptor_address(desc: u64) -> usize {
+    (desc & ADDR_MASK) as usize
+}
+
+/// Kernel block (L2) or page (L3) descriptor
+const fn kernel_descriptor(pa: usize, page: bool) -> u64 {
+    let attr = match pa >= MMIO_START {
+        true => ATTR_DEVICE | PXN,
+        false => ATTR_NORMAL,
+    };
+    let kind = match page {
+        true => TABLE_OR_PAGE,
+        false => 0,
+    };
+
+    (pa as u64) | kind | VALID | attr | SH_INNER | AF | UXN
+}
+
+//--------------------------------------------------------------------------------------------------
+// Kernel tables
+//--------------------------------------------------------------------------------------------------
+
+/// TTBR1 root, the kernel half
+static mut KERNEL_L2: PageTable = PageTable::empty();
+
+/// Pages of the 512MiB block the peripherals start in, it's part RAM and part device
+static mut KERNEL_L3: PageTable = PageTable::empty();
+
+/// TTBR0 of tasks without an address space, nothing mapped
+static mut EMPTY_L2: PageTable = PageTable::empty();
+
+/// Build the kernel translation tables
+///
+/// Runs on the boot core from `_start`, in EL2 with the MMU off, right after the bss is
+/// cleared. Nothing here can use an absolute address: the code runs at its physical address
+/// and only pc relative addressing (adrp) is correct until the MMU is on.
+#[no_mangle]
+unsafe extern "C" fn _init_mmu() {
+    let l2 = &mut *addr_of_mut!(KERNEL_L2);
+    let l3 = &mut *addr_of_mut!(KERNEL_L3);
+    let mixed = MMIO_START >> L2_SHIFT;
+
+    for (i, entry) in l3.0.iter_mut().enumerate() {
+        *entry = kernel_descriptor((mixed << L2_SHIFT) + (i << L3_SHIFT), true);
+    }
+
+    for (i, entry) in l2.0.iter_mut().enumerate().take(MMIO_END >> L2_SHIFT) {
+        *entry = match i == mixed {
+            true => table_descriptor(l3 as *const PageTable as usize),
+            false => kernel_descriptor(i << L2_SHIFT, false),
+        };
+    }
+}
+
+/// Turn the MMU on for EL1
+///
+/// Called by every core from `_el2_to_el1`, still at its physical address with the MMU off.
+/// Translation starts with the `eret` to EL1, which has to go to a kernel virtual address.
+///
+/// ## Safety
+///
+/// The tables have to be built ([_init_mmu()]) before the first core gets here.
+pub unsafe fn enable_el1() {
+    MAIR_EL1.set(MAIR);
+
+    TCR_EL1.write(
+        TCR_EL1::TBI0::Used
+            + TCR_EL1::IPS::Bits_40
+            + TCR_EL1::TG1::KiB_64
+            + TCR_EL1::SH1::Inner
+            + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::EPD1::EnableTTBR1Walks
+            + TCR_EL1::A1::TTBR0
+            + TCR_EL1::T1SZ.val((64 - VA_BITS) as u64)
+            + TCR_EL1::TG0::KiB_64
+            + TCR_EL1::SH0::Inner
+            + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::EPD0::EnableTTBR0Walks
+            + TCR_EL1::AS::ASID8Bits
+            + TCR_EL1::T0SZ.val((64 - VA_BITS) as u64),
+    );
+
+    TTBR1_EL1.set(addr_of_mut!(KERNEL_L2) as u64);
+    TTBR0_EL1.set(addr_of_mut!(EMPTY_L2) as u64);
+
+    barrier::isb(barrier::SY);
+    core::arch::asm!("tlbi vmalle1", "dsb nsh", options(nostack, preserves_flags));
+    barrier::isb(barrier::SY);
+
+    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// TTBR0 value of tasks without an address space
+pub fn empty_ttbr0() -> u64 {
+    crate::memory::virt_to_phys(addr_of_mut!(EMPTY_L2) as usize) as u64
+}
+
+/// TTBR0 value of a table at physical address `root` tagged with `asid`
+pub fn ttbr0(root: usize, asid: u16) -> u64 {
+    (asid as u64) << 48 | root as u64
+}
+
+/// Switch the user half of the address space on the calling core
+///
+/// Entries of different ASIDs can sit in the TLB together, so switching doesn't flush.
+pub fn set_ttbr0(ttbr0: u64) {
+    TTBR0_EL1.set(ttbr0);
+    barrier::isb(barrier::SY);
+}
+
+/// Make table updates visible to the table walkers
+///
+/// Needed after turning an invalid entry valid, before anything can use it.
+pub fn sync_tables() {
+    barrier::dsb(barrier::ISHST);
+    barrier::isb(barrier::SY);
+}
+
+/// Drop the TLB entry of the user page at `va`, on every core
+pub fn flush_page(asid: u16, va: usize) {
+    let arg = (asid as u64) << 48 | ((va as u64 >> 12) & 0xFFF_FFFF_FFFF);
+
+    unsafe {
+        core::arch::asm!(
+            "dsb    ishst",
+            "tlbi   vae1is, {arg}",
+            "dsb    ish",
+            "isb",
+            arg = in(reg) arg,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+/// Drop every TLB entry tagged with `asid`, on every core
+pub fn flush_asid(asid: u16) {
+    let arg = (asid as u64) << 48;
+
+    unsafe {
+        core::arch::asm!(
+            "dsb    ishst",
+            "tlbi   aside1is, {arg}",
+            "dsb    ish",
+            "isb",
+            arg = in(reg) arg,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+/// Make code written through the kernel mapping at `kva` visible to instruction fetches
+pub fn sync_icache(kva: usize, len: usize) {
+    /// Smallest cache line on the Cortex-A53
+    const LINE: usize = 64;
+
+    let mut line = kva & !(LINE - 1);
+    while line < kva + len {
+        unsafe { core::arch::asm!("dc cvau, {}", in(reg) line, options(nostack, preserves_flags)) };
+        line += LINE;
+    }
+
+    unsafe { core::arch::asm!("dsb ish", "ic ialluis", "dsb ish", "isb", options(nostack, preserves_flags)) };
+}
+
+/// Write the cached lines of `len` bytes at `kva` back to memory
+///
+/// For buffers a device is about to read, like a mailbox message or a DMA source.
+pub fn clean_dcache(kva: usize, len: usize) {
+    for_each_line(kva, len, |line| unsafe {
+        core::arch::asm!("dc cvac, {}", in(reg) line, options(nostack, preserves_flags))
+    });
+}
+
+/// Drop the cached lines of `len` bytes at `kva`, so reads see what a device wrote
+///
+/// Dirty lines are written back first, a line shared with other data loses nothing.
+pub fn invalidate_dcache(kva: usize, len: usize) {
+    for_each_line(kva, len, |line| unsafe {
+        core::arch::asm!("dc civac, {}", in(reg) line, options(nostack, preserves_flags))
+    });
+}
+
+/// Run `f` on every data cache line of `len` bytes at `kva`, then wait for them all
+fn for_each_line(kva: usize, len: usize, f: impl Fn(usize)) {
+    /// Smallest cache line on the Cortex-A53
+    const LINE: usize = 64;
+
+    let mut line = kva & !(LINE - 1);
+    while line < kva + len {
+        f(line);
+        line += LINE;
+    }
+
+    unsafe { core::arch::asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// Static check the layout constants agree
+const _: () = assert!(PAGE_SIZE == 1 << L3_SHIFT && KERNEL_BASE == !((1 << VA_BITS) - 1));
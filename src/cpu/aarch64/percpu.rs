@@ -0,0 +1,229 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Aarch64 Per-core data
+//!
+//! Variables declared with [crate::percpu!] are linked into the `.percpu` section. That
+//! section is only a template: at boot every core copies it into its own slot of
+//! `.percpu_area` and keeps the distance from the template to its copy in TPIDR_EL1.
+//! Accessing a per-core variable adds that offset to the variable's address, so each core
+//! only ever sees its own copy.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.kernel.org/doc/html/latest/core-api/this_cpu_ops.html>
+//!   - <https://developer.arm.com/documentation/ddi0601/latest/AArch64-Registers/TPIDR-EL1--EL1-Software-Thread-ID-Register>
+//!   - <https://sourceware.org/binutils/docs/ld/Output-Section-Data.html>
+//!
+
+use super::{exception::IrqGuard, smp::NUM_CORES};
+use aarch64_cpu::registers::TPIDR_EL1;
+use core::cell::UnsafeCell;
+use tock_registers::interfaces::{Readable, Writeable};
+
+extern "C" {
+    static _spercpu: u8;
+    static _epercpu: u8;
+    static _spercpu_area: u8;
+}
+
+/// Distance between two core's copies
+fn stride() -> usize {
+    // The linker script rounds the template up to a cache line, so this is too.
+    unsafe { &_epercpu as *const u8 as usize - &_spercpu as *const u8 as usize }
+}
+
+/// What to add to a template address to get `core`'s copy
+fn offset(core: usize) -> usize {
+    unsafe {
+        (&_spercpu_area as *const u8 as usize + core * stride())
+            .wrapping_sub(&_spercpu as *const u8 as usize)
+    }
+}
+
+/// A variable with one copy per core
+///
+/// Only [crate::percpu!] can make these. The copy of the calling core is reached with
+/// [PerCpu::borrow()] which masks IRQs while the borrow lives: the tick can't preempt the
+/// task (and move it to another core's copy) and an interrupt handler on this core can't
+/// alias it. Sync values, like atomics, can skip the guard with [PerCpu::local()].
+///
+pub struct PerCpu<T> {
+    borrowed: UnsafeCell<bool>,
+    value: UnsafeCell<T>,
+}
+
+// Every core only touches its own copy, except through `for_core` which needs `T: Sync`.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+/// TypeState per-core borrow
+///
+/// Mutable access to the calling core's copy of a [PerCpu]. IRQs stay masked until the guard
+/// is dropped.
+///
+pub struct PerCpuGuard<'a, T: 'a> {
+    borrowed: &'a mut bool,
+    value: &'a mut T,
+    _irq: IrqGuard,
+}
+
+impl<T> PerCpu<T> {
+    /// Used by [crate::percpu!]
+    ///
+    /// ## Safety
+    ///
+    /// The value must live in the `.percpu` section, or the per-core offsets point at
+    /// something else.
+    #[doc(hidden)]
+    pub const unsafe fn new(value: T) -> PerCpu<T> {
+        PerCpu {
+            borrowed: UnsafeCell::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Address of the calling core's copy of `field`
+    fn local_ptr<F>(field: &UnsafeCell<F>) -> *mut F {
+        (field.get() as usize).wrapping_add(TPIDR_EL1.get() as usize) as *mut F
+    }
+
+    /// Borrow the calling core's copy
+    ///
+    /// Returns None if the copy is already borrowed on this core.
+    pub fn try_borrow(&self) -> Option<PerCpuGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        let borrowed = unsafe { &mut *Self::local_ptr(&self.borrowed) };
+
+        if *borrowed {
+            return None;
+        }
+        *borrowed = true;
+
+        Some(PerCpuGuard {
+            borrowed,
+            value: unsafe { &mut *Self::local_ptr(&self.value) },
+            _irq: irq,
+        })
+    }
+
+    /// Borrow the calling core's copy
+    ///
+    /// ## Panics
+    ///
+    /// If the copy is already borrowed on this core, like [core::cell::RefCell::borrow_mut()].
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::percpu;
+    ///
+    /// percpu! {
+    ///     /// Bytes logged by this core
+    ///     static LOGGED: usize = 0;
+    /// }
+    ///
+    /// *LOGGED.borrow() += 12;
+    /// ```
+    pub fn borrow(&self) -> PerCpuGuard<'_, T> {
+        match self.try_borrow() {
+            Some(guard) => guard,
+            None => panic!("Per-core value already borrowed"),
+        }
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// Shared reference to the calling core's copy, without masking IRQs
+    ///
+    /// The reference stays valid if the task later runs on another core, it just isn't that
+    /// core's copy anymore.
+    pub fn local(&self) -> &T {
+        unsafe { &*Self::local_ptr(&self.value) }
+    }
+
+    /// Shared reference to the copy of `core`
+    pub fn for_core(&self, core: usize) -> &T {
+        assert!(core < NUM_CORES);
+        unsafe { &*((self.value.get() as usize).wrapping_add(offset(core)) as *const T) }
+    }
+}
+
+impl<T> core::ops::Deref for PerCpuGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> core::ops::DerefMut for PerCpuGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T> Drop for PerCpuGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        *self.borrowed = false;
+    }
+}
+
+/// Set up the calling core's copy of the per-core variables
+///
+/// Copies the `.percpu` template into this core's slot and points TPIDR_EL1 at it. Has to be
+/// the first thing every core does in Rust, nothing per-core works before it.
+pub fn init() {
+    let core = super::smp::core_id();
+
+    unsafe {
+        let template = &_spercpu as *const u8;
+        let copy = template.wrapping_add(offset(core)) as *mut u8;
+        core::ptr::copy_nonoverlapping(template, copy, stride());
+    }
+
+    TPIDR_EL1.set(offset(core) as u64);
+}
+
+/// Declare per-core variables
+///
+/// Each `static` becomes a [crate::cpu::percpu::PerCpu] in the `.percpu` section, every core
+/// starts with a copy of the initial value.
+///
+/// ## Examples
+///
+/// ```
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use dyseos::percpu;
+///
+/// percpu! {
+///     /// Interrupts handled by this core
+///     static IRQ_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// }
+///
+/// IRQ_COUNT.local().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::cpu::percpu::PerCpu<$ty> = {
+                let value: $ty = $init;
+                // Safety: the link_section above
+                unsafe { $crate::cpu::percpu::PerCpu::new(value) }
+            };
+        )+
+    };
+}
@@ -0,0 +1,64 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Aarch64 Multi-core
+//!
+//! Core ids and secondary core bring up. The armstub (and qemu) leave cores 1-3
+//! spinning on a table of release addresses, writing an address and sending an
+//! event starts the core there.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/raspberrypi/tools/blob/master/armstubs/armstub8.S>
+//!   - <https://www.kernel.org/doc/Documentation/arm64/booting.txt>
+//!
+
+use crate::memory::{phys_to_virt, virt_to_phys};
+use aarch64_cpu::{asm, asm::barrier, registers::MPIDR_EL1};
+use tock_registers::interfaces::Readable;
+
+/// Number of cores on the BCM2837
+pub const NUM_CORES: usize = 4;
+
+/// Release address of core 0, the others follow 8 bytes apart
+const SPIN_TABLE: usize = 0xD8;
+
+/// Id of the calling core
+#[inline]
+pub fn core_id() -> usize {
+    (MPIDR_EL1.get() & 0x3) as usize
+}
+
+/// Release cores 1-3 into `_start_secondary`
+///
+/// Should only be called once by the boot core, after the scheduler is up.
+pub fn start_secondary_cores() {
+    extern "C" {
+        fn _start_secondary();
+    }
+
+    // The cores are still running from physical addresses with their caches off
+    let entry = virt_to_phys(_start_secondary as *const () as usize) as u64;
+
+    for core in 1..NUM_CORES {
+        let release = phys_to_virt(SPIN_TABLE + core * 8);
+
+        unsafe {
+            core::ptr::write_volatile(release as *mut u64, entry);
+            core::arch::asm!("dc civac, {}", in(reg) release, options(nostack, preserves_flags));
+        }
+    }
+
+    barrier::dsb(barrier::SY);
+    asm::sev();
+}
@@ -0,0 +1,101 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Aarch64 Generic timer
+//!
+//! The EL1 physical timer, used for the scheduler tick. Each core has its own
+//! comparator, the counter is shared.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://developer.arm.com/documentation/102379/latest/>
+//!   - <https://github.com/rust-embedded/rust-raspberrypi-OS-tutorials/tree/master/07_timestamps>
+//!
+
+use aarch64_cpu::{asm::barrier, registers::*};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use tock_registers::interfaces::{Readable, Writeable};
+
+const NANOSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Counter ticks between two timer interrupts, shared by all cores
+static TICK_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+/// Counter frequency in Hz
+#[inline]
+pub fn frequency() -> u64 {
+    CNTFRQ_EL0.get()
+}
+
+/// Current value of the physical counter
+///
+/// The isb keeps the read from being executed early.
+#[inline]
+pub fn counter() -> u64 {
+    barrier::isb(barrier::SY);
+    CNTPCT_EL0.get()
+}
+
+/// Convert counter ticks to a duration
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let freq = frequency();
+    let secs = ticks / freq;
+    let nanos = ((ticks % freq) * NANOSEC_PER_SEC) / freq;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Convert a duration to counter ticks, saturates at u64::MAX
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * frequency() as u128) / NANOSEC_PER_SEC as u128;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Time since the counter started (usually power on)
+pub fn uptime() -> Duration {
+    ticks_to_duration(counter())
+}
+
+/// Busy wait for `duration`
+pub fn spin_for(duration: Duration) {
+    let end = counter().saturating_add(duration_to_ticks(duration));
+    while counter() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Start a periodic interrupt on the calling core
+///
+/// The interrupt still has to be routed with [crate::drivers::interrupt::enable()].
+pub fn start_tick(hz: u64) {
+    let interval = frequency() / hz;
+    TICK_INTERVAL.store(interval, Ordering::Relaxed);
+
+    CNTP_CVAL_EL0.set(counter() + interval);
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+}
+
+/// Schedule the next tick, called from the timer interrupt
+///
+/// Skips ticks that were missed rather than firing them back to back.
+pub fn rearm() {
+    let interval = TICK_INTERVAL.load(Ordering::Relaxed);
+    let now = counter();
+    let mut next = CNTP_CVAL_EL0.get() + interval;
+
+    if next <= now {
+        next = now + interval;
+    }
+
+    CNTP_CVAL_EL0.set(next);
+}
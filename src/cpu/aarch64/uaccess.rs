@@ -0,0 +1,141 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS User memory access
+//!
+//! The only code that touches user memory. System calls copy through [copy_from_user()]
+//! and [copy_to_user()], whose user side loads and stores are unprivileged (`ldtrb` and
+//! `sttrb`), so they get the permissions EL0 would.
+//!
+//! A copy can still fault. Pages of the task's regions that aren't mapped yet are faulted in
+//! and the copy carries on. Anything else finds the faulting instruction in the exception
+//! table ([fixup()]), the handler resumes there and the copy returns an error, so the system
+//! call unwinds like any other failure instead of leaking whatever it held.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.kernel.org/doc/html/latest/arch/x86/exception-tables.html>
+//!   - <https://developer.arm.com/documentation/ddi0602/latest/Base-Instructions/LDTRB--Load-register-byte--unprivileged-->
+//!
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for user memory access
+///
+/// The copy faulted on an address the task can't access.
+pub struct UserFault;
+
+/// Allows printing the error
+impl core::fmt::Display for UserFault {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("Bad user address")
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Copy `dst.len()` bytes from the user address `src` into `dst`
+///
+/// The caller checks that the range is in the user half.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserFault> {
+    match unsafe { _copy_from_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(UserFault),
+    }
+}
+
+/// Copy `src` to the user address `dst`
+///
+/// The caller checks that the range is in the user half.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserFault> {
+    match unsafe { _copy_to_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(UserFault),
+    }
+}
+
+/// Where to resume after a fault at `pc`, if it's a user access
+///
+/// The exception table: every instruction that may fault on a user address and the code
+/// that makes its routine fail.
+pub fn fixup(pc: usize) -> Option<usize> {
+    extern "C" {
+        static _copy_from_user_load: u8;
+        static _copy_from_user_fault: u8;
+        static _copy_to_user_store: u8;
+        static _copy_to_user_fault: u8;
+    }
+
+    let table = unsafe {
+        [
+            (&_copy_from_user_load as *const u8 as usize, &_copy_from_user_fault as *const u8 as usize),
+            (&_copy_to_user_store as *const u8 as usize, &_copy_to_user_fault as *const u8 as usize),
+        ]
+    };
+
+    table.iter().find(|&&(insn, _)| insn == pc).map(|&(_, fixup)| fixup)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+extern "C" {
+    /// Copy `len` bytes, loading from user memory, returns the number not copied
+    fn _copy_from_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    /// Copy `len` bytes, storing to user memory, returns the number not copied
+    fn _copy_to_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+// A byte at a time, x2 counts what's left and is the return value either way
+core::arch::global_asm!(
+    ".section .text._copy_from_user",
+    ".global _copy_from_user",
+    ".type _copy_from_user, %function",
+    "_copy_from_user:",
+    "    cbz    x2, _copy_from_user_fault",
+    "_copy_from_user_load:",
+    "    ldtrb  w3, [x1]",
+    "    strb   w3, [x0], #1",
+    "    add    x1, x1, #1",
+    "    subs   x2, x2, #1",
+    "    b.ne   _copy_from_user_load",
+    "_copy_from_user_fault:",
+    "    mov    x0, x2",
+    "    ret",
+    ".size _copy_from_user, . - _copy_from_user",
+    "",
+    ".section .text._copy_to_user",
+    ".global _copy_to_user",
+    ".type _copy_to_user, %function",
+    "_copy_to_user:",
+    "    cbz    x2, _copy_to_user_fault",
+    "1:",
+    "    ldrb   w3, [x1], #1",
+    "_copy_to_user_store:",
+    "    sttrb  w3, [x0]",
+    "    add    x0, x0, #1",
+    "    subs   x2, x2, #1",
+    "    b.ne   1b",
+    "_copy_to_user_fault:",
+    "    mov    x0, x2",
+    "    ret",
+    ".size _copy_to_user, . - _copy_to_user",
+    "",
+    ".global _copy_from_user_load",
+    ".global _copy_from_user_fault",
+    ".global _copy_to_user_store",
+    ".global _copy_to_user_fault",
+);
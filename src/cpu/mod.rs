@@ -31,4 +31,10 @@
 pub mod aarch64;
 
 #[cfg(target_arch = "aarch64")]
-pub use self::aarch64::{_park, _spin_n};
\ No newline at end of file
+pub use self::aarch64::{_park, _spin_n, boot_dtb};
+
+#[cfg(target_arch = "aarch64")]
+pub use self::aarch64::context::{Context, _switch_context, enable_fpsimd};
+
+#[cfg(target_arch = "aarch64")]
+pub use self::aarch64::{exception, mmu, percpu, smp, timer, uaccess};
\ No newline at end of file
@@ -0,0 +1,163 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Audio
+//!
+//! Sound through the headphone jack. The jack's left and right are the two PWM channels on
+//! GPIO40 and GPIO45, filtered on the board, so a sample is a pulse width: [play()] turns
+//! each one into a duty of the PWM period and has DMA feed the PWM FIFO, paced by its DREQ.
+//!
+//! The PWM clock runs at 100MHz, at 44.1kHz that's a range of 2267 ticks, about 11 bits.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 9)
+//!   - <https://github.com/raspberrypi/linux/blob/rpi-6.6.y/arch/arm/boot/dts/overlays/audremap-overlay.dts>
+//!
+
+use super::dma::{Channel, DmaBuffer, DmaError, Dreq, Transfer};
+use super::gpio::Pin;
+use super::pwm::{self, FifoOutput, PwmError};
+use core::time::Duration;
+
+/// Frequency of the PWM clock while playing
+const PWM_CLOCK: u32 = 100_000_000;
+
+/// Fewest ticks in a period, 8 bits
+const MIN_RANGE: u32 = 256;
+
+/// Bytes of one sample in the FIFO, a word per channel
+const FRAME_BYTES: usize = 2 * 4;
+
+/// Time on top of how long the samples take to play before giving up on the DMA
+const PLAY_MARGIN: Duration = Duration::from_millis(100);
+
+/// Words the PWM FIFO holds, each one is a period of one channel
+const FIFO_WORDS: usize = 8;
+
+/// Time on top of how long the FIFO takes to empty after the last sample
+const DRAIN_MARGIN: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for audio
+pub enum AudioError {
+    /// The headphone pins are in use
+    PinsTaken,
+    /// The sample rate is 0, or too fast for 8 bits
+    BadRate,
+    /// The PWM channels or clock
+    Pwm(PwmError),
+    /// The DMA buffer or transfer
+    Dma(DmaError),
+}
+
+/// Allows printing the error
+impl core::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            AudioError::PinsTaken => f.write_str("Headphone pins are in use"),
+            AudioError::BadRate => f.write_str("Unsupported sample rate"),
+            AudioError::Pwm(err) => write!(f, "Audio: {}", err),
+            AudioError::Dma(err) => write!(f, "Audio: {}", err),
+        }
+    }
+}
+
+impl From<PwmError> for AudioError {
+    fn from(err: PwmError) -> AudioError {
+        AudioError::Pwm(err)
+    }
+}
+
+impl From<DmaError> for AudioError {
+    fn from(err: DmaError) -> AudioError {
+        AudioError::Dma(err)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Play mono `samples` at `rate` Hz on both sides of the headphone jack, returns when
+/// they're done
+///
+/// The samples are copied to a DMA buffer first, 8 bytes each. Fails with
+/// [AudioError::Pwm] of [PwmError::Busy] while PWM is in use.
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::drivers::audio;
+///
+/// // A second of 440Hz square wave
+/// let samples: Vec<i16> = (0..44_100).map(|i| if i / 50 % 2 == 0 { 8000 } else { -8000 }).collect();
+/// audio::play(&samples, 44_100).unwrap();
+/// ```
+pub fn play(samples: &[i16], rate: u32) -> Result<(), AudioError> {
+    if rate == 0 || PWM_CLOCK / rate < MIN_RANGE {
+        return Err(AudioError::BadRate);
+    }
+    if samples.is_empty() {
+        return Ok(());
+    }
+
+    let range = PWM_CLOCK / rate;
+    let mut buffer = DmaBuffer::new(samples.len() * FRAME_BYTES)?;
+    for (frame, &sample) in buffer.chunks_exact_mut(FRAME_BYTES).zip(samples) {
+        let duty = to_duty(sample, range).to_le_bytes();
+        frame[..4].copy_from_slice(&duty);
+        frame[4..].copy_from_slice(&duty);
+    }
+
+    let (left, right) = match (Pin::<40, _>::take(), Pin::<45, _>::take()) {
+        (Some(left), Some(right)) => (left.into_alt0(), right.into_alt0()),
+        (left, right) => {
+            left.into_iter().for_each(Pin::release);
+            right.into_iter().for_each(Pin::release);
+            return Err(AudioError::PinsTaken);
+        }
+    };
+
+    let result = (|| {
+        // Both channels first, the clock is theirs to change then
+        let output = FifoOutput::start(range)?;
+        pwm::set_clock(PWM_CLOCK)?;
+
+        let mut transfer = Transfer::new();
+        transfer.to_peripheral(&buffer, 0, buffer.len(), FifoOutput::FIFO, Dreq::Pwm)?;
+        Channel::take()?.run(&mut transfer, periods(samples.len(), range) + PLAY_MARGIN)?;
+
+        output.drain(periods(FIFO_WORDS, range) + DRAIN_MARGIN);
+        Ok(())
+    })();
+
+    left.release();
+    right.release();
+    result
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// How long `count` periods of `range` ticks of the PWM clock take
+fn periods(count: usize, range: u32) -> Duration {
+    let period = Duration::from_nanos(range as u64 * 1_000_000_000 / PWM_CLOCK as u64);
+    period.saturating_mul(u32::try_from(count).unwrap_or(u32::MAX))
+}
+
+/// The duty for `sample` in a period of `range` ticks, silence is half of it
+fn to_duty(sample: i16, range: u32) -> u32 {
+    (((sample as i32 + 0x8000) as u64 * range as u64) >> 16) as u32
+}
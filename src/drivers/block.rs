@@ -0,0 +1,207 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Block devices
+//!
+//! Storage that is read and written in fixed size blocks, like the SD card. Filesystems
+//! such as [crate::vfs::fat] sit on a [BlockDevice] and don't care which driver is below.
+//!
+//! Disks usually carry an MBR partition table, [partitions()] reads it and hands back each
+//! partition as a [Partition], a block device of its own.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://wiki.osdev.org/MBR_(x86)>
+//!   - <https://en.wikipedia.org/wiki/Partition_type>
+//!
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+/// Block size nearly every disk uses
+pub const SECTOR_SIZE: usize = 512;
+
+/// Offset of the partition table in the MBR
+const MBR_TABLE: usize = 446;
+
+/// Size of a partition table entry
+const MBR_ENTRY: usize = 16;
+
+/// The MBR ends with 0x55 0xAA
+const MBR_SIGNATURE: usize = 510;
+
+/// Partition type of a GPT disk's protective MBR
+const TYPE_GPT: u8 = 0xEE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for block devices
+pub enum BlockError {
+    /// The blocks are past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    BadBuffer,
+    /// The device can't be written
+    ReadOnly,
+    /// There is no medium, like an empty card slot
+    NoMedia,
+    /// Another driver has the device's pins
+    Busy,
+    /// The device didn't answer in time
+    Timeout,
+    /// The device reported an error
+    Io,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            BlockError::OutOfRange => f.write_str("Block out of range"),
+            BlockError::BadBuffer => f.write_str("Buffer is not a multiple of the block size"),
+            BlockError::ReadOnly => f.write_str("Device is read-only"),
+            BlockError::NoMedia => f.write_str("No medium present"),
+            BlockError::Busy => f.write_str("Device pins are in use"),
+            BlockError::Timeout => f.write_str("Device timed out"),
+            BlockError::Io => f.write_str("Device error"),
+        }
+    }
+}
+
+/// Storage addressed in blocks
+///
+/// Buffers are always a whole number of blocks, starting at block `lba`.
+pub trait BlockDevice: Send + Sync {
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    /// Number of blocks on the device
+    fn num_blocks(&self) -> u64;
+
+    /// Read `buf.len() / block_size()` blocks starting at `lba`
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / block_size()` blocks starting at `lba`
+    fn write_blocks(&self, _lba: u64, _buf: &[u8]) -> Result<(), BlockError> {
+        Err(BlockError::ReadOnly)
+    }
+}
+
+/// A range of blocks of another device
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+    kind: u8,
+}
+
+impl Partition {
+    /// The blocks `start..start + count` of `device`
+    ///
+    /// Fails with [BlockError::OutOfRange] if they aren't all on the device.
+    pub fn new(device: Arc<dyn BlockDevice>, start: u64, count: u64, kind: u8) -> Result<Partition, BlockError> {
+        match start.checked_add(count) {
+            Some(end) if end <= device.num_blocks() => Ok(Partition { device, start, count, kind }),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// First block on the whole device
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// MBR partition type, like 0x0C for FAT32 with LBA
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// The blocks of the partition, checked against its size
+    fn translate(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        let blocks = check_buffer(len, self.block_size())?;
+
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.count => Ok(self.start + lba),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.device.read_blocks(self.translate(lba, buf.len())?, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.device.write_blocks(self.translate(lba, buf.len())?, buf)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Number of blocks in a buffer of `len` bytes
+///
+/// For drivers checking the buffers they're given, fails with [BlockError::BadBuffer] unless
+/// `len` is a multiple of `block_size`.
+pub fn check_buffer(len: usize, block_size: usize) -> Result<u64, BlockError> {
+    match len.is_multiple_of(block_size) {
+        true => Ok((len / block_size) as u64),
+        false => Err(BlockError::BadBuffer),
+    }
+}
+
+/// The partitions in the MBR of `device`
+///
+/// Empty entries are skipped. A disk without an MBR, or with a GPT one, has no partitions
+/// as far as this is concerned, the caller can still use the whole device.
+pub fn partitions(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let mut mbr = vec![0; device.block_size().max(SECTOR_SIZE)];
+    device.read_blocks(0, &mut mbr[..device.block_size()])?;
+
+    if mbr[MBR_SIGNATURE..MBR_SIGNATURE + 2] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+
+    let entries: Vec<&[u8]> = mbr[MBR_TABLE..MBR_SIGNATURE].chunks(MBR_ENTRY).collect();
+
+    // A FAT boot sector has the same signature, its "partition table" is boot code. Real
+    // entries have a status byte of 0x00 or 0x80
+    if entries.iter().any(|entry| entry[0] & 0x7F != 0) {
+        return Ok(Vec::new());
+    }
+
+    if entries.iter().any(|entry| entry[4] == TYPE_GPT) {
+        return Ok(Vec::new());
+    }
+
+    Ok(entries
+        .iter()
+        .filter(|entry| entry[4] != 0)
+        .filter_map(|entry| {
+            let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+            let count = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+
+            Partition::new(device.clone(), start, count, entry[4]).ok()
+        })
+        .collect())
+}
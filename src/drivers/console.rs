@@ -13,11 +13,20 @@
 //!
 //! complete disaster atm.
 //!
+//! Output goes to a UART, the PL011 or the [mini UART](super::mini_uart) as the command line
+//! says (see [init()]), to a screen console once one is registered (see
+//! [crate::drivers::fbcon]), or to both, picked with [set_output()]. Input only comes from
+//! the UART.
 //!
 //! Author: Mitchell Scott <scott.mitchell913@gmail.com>
 //!
 
-use crate::sync::mutex::{Mutex, MutexGuard};
+use super::gpio::{Pin, Pull};
+use super::mini_uart::MiniUart;
+use crate::sync::once::OnceCell;
+use crate::sync::ticket::{TicketLock, TicketLockGuard};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 //--------------------------------------------------------------------------------------------------
 // Public Console Traits
@@ -32,8 +41,18 @@ pub trait Count {
     }
 }
 
+/// Reads chars from the Console
+pub trait Read {
+    /// Next received byte, if there is one
+    ///
+    /// Default trait implementation never receives anything
+    fn read_byte(&mut self) -> Option<u8> {
+        None
+    }
+}
+
 /// Full console trait
-pub trait Console: core::fmt::Write + Count {}
+pub trait Console: core::fmt::Write + Count + Read {}
 
 //--------------------------------------------------------------------------------------------------
 // (private) Global instances
@@ -62,12 +81,16 @@ impl<const T: usize> SysConsole<T> {
     /// Send a character.
     fn write_char(&mut self, c: char) {
         unsafe {
-            core::ptr::write_volatile(T as *mut u8, c as u8);
+            crate::drivers::mmio::write32(T, c as u32);
         }
 
         self.chars_written += 1;
     }
 
+    /// Flag register, bit 4 is set while the receive FIFO is empty
+    const FR: usize = T + 0x18;
+    const FR_RXFE: u32 = 1 << 4;
+
 }
 
 /// Implementing `core::fmt::Write` enables usage of the `format_args!` macros, which in turn are
@@ -98,12 +121,86 @@ impl<const T: usize> Count for SysConsole<T> {
     }
 }
 
+/// Implements Read
+impl<const T: usize> Read for SysConsole<T> {
+    /// Pops the receive FIFO of the UART
+    fn read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            match crate::drivers::mmio::read32(Self::FR) & Self::FR_RXFE {
+                0 => Some(crate::drivers::mmio::read32(T) as u8),
+                _ => None,
+            }
+        }
+    }
+}
+
 /// Implements Console
 impl<const T: usize> Console for SysConsole<T> {}
 
-/// A static qemu console implementation wrapped in a mutex for safety
-static SYS_CONSOLE_LOCK: Mutex<SysConsole<0x3F20_1000>> = Mutex::new(SysConsole::new());
+/// The UART behind the console
+enum Serial {
+    Pl011(SysConsole<0x3F20_1000>),
+    Mini(MiniUart<0x3F21_5000>),
+}
+
+impl Serial {
+    fn get(&mut self) -> &mut dyn Console {
+        match self {
+            Serial::Pl011(uart) => uart,
+            Serial::Mini(uart) => uart,
+        }
+    }
+}
 
+/// Implements `core::fmt::Write` by handing the string to the UART in use
+impl core::fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.get().write_str(s)
+    }
+}
+
+/// Implements Count
+impl Count for Serial {
+    fn chars_written(&self) -> usize {
+        match self {
+            Serial::Pl011(uart) => uart.chars_written(),
+            Serial::Mini(uart) => uart.chars_written(),
+        }
+    }
+}
+
+/// Implements Read
+impl Read for Serial {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.get().read_byte()
+    }
+}
+
+/// Implements Console
+impl Console for Serial {}
+
+/// A static qemu console implementation wrapped in a ticket lock for safety
+///
+/// A [TicketLock] hands the console out in arrival order, lines printed by different cores
+/// don't get interleaved and no core can starve the others. The PL011 until [init()] says
+/// otherwise.
+static SYS_CONSOLE_LOCK: TicketLock<Serial> = TicketLock::new(Serial::Pl011(SysConsole::new()));
+
+/// Speed of the mini UART when the command line doesn't say, the PL011 keeps whatever the
+/// firmware set
+const BAUD: u32 = 115_200;
+
+/// `console=` names of the mini UART, the firmware calls the primary UART `serial0`
+const MINI_UART_NAMES: [&str; 2] = ["ttyS0", "serial0"];
+
+/// `console=` names of either UART
+const SERIAL_NAMES: [&str; 4] = ["ttyS0", "serial0", "ttyAMA0", "serial1"];
+
+/// The console on the screen, if there is one
+static SCREEN_CONSOLE_LOCK: OnceCell<TicketLock<Box<dyn Console + Send>>> = OnceCell::new();
+
+/// Where output goes, an [Output]
+static OUTPUT: AtomicU8 = AtomicU8::new(Output::Serial as u8);
 
 //--------------------------------------------------------------------------------------------------
 // Private api
@@ -111,27 +208,38 @@ static SYS_CONSOLE_LOCK: Mutex<SysConsole<0x3F20_1000>> = Mutex::new(SysConsole:
 
 /// Return a reference to the qemu console.
 ///
-/// The [crate::sync::mutex::Mutex] is supposed to make this thread safe but isn't ready yet. 
-/// Until [crate::sync::mutex] is ready this is not thread safe. Ok to use in Qemu though.
-///
-/// ## Panics
-/// 
-///  panic!() is not available because it depends on this. Instead just park the core. Could try
-/// waiting for the lock to be available but without poison in [crate::sync::mutex::Mutex] it 
-/// might spin forever. Basically this a disaster and probably will be for a bit.
-/// 
-///  This function parks when the [crate::sync::mutex::Mutex] returns an error.
-/// Should probably not park here because currently the mutex does not wait very
-/// long. Once futex_wait is available the park should never hit. Until then this will
-/// probably park alot.
+/// The [crate::sync::ticket::TicketLock] spins (in `wfe`) until every core that asked
+/// earlier is done printing. IRQs stay masked while the guard lives, so printing from an
+/// interrupt handler can't deadlock with the task it interrupted.
 ///
 /// ## Examples
 ///
 /// see [crate::drivers::console::_print()]
-fn console<'a>() -> MutexGuard<'a, impl Console> {
-    match SYS_CONSOLE_LOCK.lock() {
-        Ok(guard) => guard,
-        Err(_) => crate::cpu::_park(),
+fn console<'a>() -> TicketLockGuard<'a, impl Console> {
+    SYS_CONSOLE_LOCK.lock()
+}
+
+/// The baud rate at the start of `console=` options like `115200n8`
+fn baud(options: &str) -> Option<u32> {
+    let digits = options.find(|c: char| !c.is_ascii_digit()).unwrap_or(options.len());
+    options[..digits].parse().ok().filter(|&baud| baud > 0)
+}
+
+/// Run `f` on every console output goes to
+///
+/// The UART is locked first and stays locked, so both consoles get lines in the same order.
+/// Output meant only for the screen goes to the UART while there is no screen.
+fn each_output(mut f: impl FnMut(&mut dyn Console)) {
+    let mut serial = console();
+    let screen = SCREEN_CONSOLE_LOCK.get();
+    let output = output();
+
+    if output != Output::Screen || screen.is_none() {
+        f(&mut *serial);
+    }
+
+    if let (Output::Screen | Output::Both, Some(screen)) = (output, screen) {
+        f(&mut **screen.lock());
     }
 }
 
@@ -139,13 +247,116 @@ fn console<'a>() -> MutexGuard<'a, impl Console> {
 // Public Code
 //--------------------------------------------------------------------------------------------------
 
+/// Where [_print()] and [_write_bytes()] send output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Output {
+    /// The UART
+    Serial,
+    /// The screen console
+    Screen,
+    /// Both of them
+    Both,
+}
+
+/// Send output to `output` from now on
+pub fn set_output(output: Output) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+/// Where output goes
+pub fn output() -> Output {
+    match OUTPUT.load(Ordering::Relaxed) {
+        0 => Output::Serial,
+        1 => Output::Screen,
+        _ => Output::Both,
+    }
+}
+
+/// Pick the UART and connect it to its pins, GPIO14 (TX) and GPIO15 (RX)
+///
+/// The last serial `console=` on the [command line](crate::cmdline) decides, others such as
+/// `console=tty1` are skipped. `ttyS0` or `serial0` (the firmware's name) picks the mini
+/// UART (ALT5), at the baud rate after a comma if there is one. Anything else keeps the
+/// PL011 (ALT0) at whatever the firmware set. Qemu doesn't need the pins, a real board
+/// prints nothing without them. The pins stay claimed.
+///
+/// Needs the heap and the device tree, the mini UART asks the firmware for its clock.
+pub fn init() {
+    let (Some(tx), Some(rx)) = (Pin::<14, _>::take(), Pin::<15, _>::take()) else {
+        return;
+    };
+
+    let serial = crate::cmdline::values("console")
+        .map(|console| console.split_once(',').unwrap_or((console, "")))
+        .filter(|(name, _)| SERIAL_NAMES.contains(name))
+        .last();
+
+    // The lines idle high, a pull-up keeps an unconnected RX from reading noise
+    match serial {
+        Some((name, options)) if MINI_UART_NAMES.contains(&name) => {
+            let mut uart = MiniUart::new();
+            uart.init(baud(options).unwrap_or(BAUD));
+
+            tx.into_alt5().set_pull(Pull::None);
+            rx.into_alt5().set_pull(Pull::Up);
+            *SYS_CONSOLE_LOCK.lock() = Serial::Mini(uart);
+        }
+        _ => {
+            tx.into_alt0().set_pull(Pull::None);
+            rx.into_alt0().set_pull(Pull::Up);
+        }
+    }
+}
+
+/// Make `screen` the screen console
+///
+/// Only the first one registered is used, output only goes there once [set_output()] asks
+/// for it.
+pub fn register_screen(screen: Box<dyn Console + Send>) {
+    let _ = SCREEN_CONSOLE_LOCK.set(TicketLock::new(screen));
+}
+
 /// Base print implementation
 ///
 /// Uses console() to init a backend that provides the classic rust print frontend. Users should
 /// call [crate::print] or [crate::println] not this.
 pub fn _print(args: core::fmt::Arguments) {
     // (&mut *console()).write_fmt(args).unwrap();
-    core::fmt::Write::write_fmt(&mut *console(), args).unwrap();
+    each_output(|console| console.write_fmt(args).unwrap());
+}
+
+/// Write raw bytes, invalid UTF-8 shows up as U+FFFD
+///
+/// For output that doesn't come from Rust, like the write syscall.
+pub fn _write_bytes(bytes: &[u8]) {
+    each_output(|console| {
+        for chunk in bytes.utf8_chunks() {
+            console.write_str(chunk.valid()).unwrap();
+
+            if !chunk.invalid().is_empty() {
+                console.write_char(char::REPLACEMENT_CHARACTER).unwrap();
+            }
+        }
+    });
+}
+
+/// Read whatever input is waiting, up to `buf.len()` bytes
+///
+/// Never blocks, returns the number of bytes read.
+pub fn _read(buf: &mut [u8]) -> usize {
+    let mut console = console();
+    let mut count = 0;
+
+    while count < buf.len() {
+        match console.read_byte() {
+            Some(byte) => buf[count] = byte,
+            None => break,
+        }
+        count += 1;
+    }
+
+    count
 }
 
 /// # Print macro
@@ -167,7 +378,7 @@ macro_rules! print {
 macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ({
-        $crate::drivers::console::_print(format_args!($($arg)*));
-        $crate::print!("\n");
+        // One call so the line and its newline are printed under the same lock
+        $crate::drivers::console::_print(format_args!("{}\n", format_args!($($arg)*)));
     })
 }
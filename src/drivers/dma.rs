@@ -0,0 +1,586 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS DMA
+//!
+//! The BCM2835 DMA engine. A transfer is a chain of control blocks, each one copying a run
+//! of bytes between memory and memory or memory and a peripheral register. Peripheral ends
+//! are paced by the peripheral's DREQ line, so the engine only moves data when the device
+//! is ready for it.
+//!
+//! Memory ends are [DmaBuffer]s: whole page frames, so no other data shares their cache
+//! lines, cleaned before the engine reads them and invalidated around its writes. Control
+//! blocks are cleaned the same way before the channel starts, the engine reads memory past
+//! the ARM's caches.
+//!
+//! Only the full channels (0-6) are used, and only those the firmware leaves free
+//! (`brcm,dma-channel-mask` in the device tree). [Channel::run()] sleeps until the completion
+//! interrupt, or polls when it can't sleep, and gives up after a timeout.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 4)
+//!   - <https://elinux.org/BCM2835_datasheet_errata>
+//!   - <https://github.com/torvalds/linux/blob/master/drivers/dma/bcm2835-dma.c>
+//!
+
+use super::interrupt::{self, Irq};
+use super::mmio;
+use crate::cpu::{mmu, timer};
+use crate::memory::frame::{self, FrameRange};
+use crate::memory::{virt_to_phys, PAGE_SIZE};
+use crate::sync::futex::{futex_wait, futex_wake, FutexError};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+/// Where channel 0 is, the others follow every 0x100
+const DMA_BASE: usize = 0x3F00_7000;
+
+/// Channels with the full register set, 7-14 are lite channels and 15 is elsewhere
+const FULL_CHANNELS: usize = 7;
+
+/// Channels the firmware leaves free if the device tree doesn't say (what the Pi 3's does)
+const DEFAULT_CHANNEL_MASK: u32 = 0x7F35;
+
+/// Interrupt of channel 0, the others follow
+const FIRST_IRQ: usize = 16;
+
+/// Runs of one control block in 1D mode are shorter, TXFR_LEN has 30 bits
+const MAX_LEN: usize = 1 << 30;
+
+/// The engine sees RAM here, past the VideoCore's L2 cache
+const BUS_RAM: usize = 0xC000_0000;
+/// And the peripherals at 0x3F00_0000 here
+const BUS_PERIPHERALS: usize = 0x7E00_0000;
+const PERIPHERALS: usize = 0x3F00_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for DMA
+pub enum DmaError {
+    /// Every channel is taken, or [init()] didn't run
+    NoChannel,
+    /// No frames for a buffer
+    OutOfMemory,
+    /// Offset and length don't fit the buffer
+    OutOfRange,
+    /// The transfer has no control blocks
+    Empty,
+    /// The engine stopped with an error, the channel's DEBUG register
+    Bus(u32),
+    /// The transfer didn't finish in time, the channel was reset
+    Timeout,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for DmaError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            DmaError::NoChannel => f.write_str("No free DMA channel"),
+            DmaError::OutOfMemory => f.write_str("Out of memory for a DMA buffer"),
+            DmaError::OutOfRange => f.write_str("Transfer doesn't fit its buffer"),
+            DmaError::Empty => f.write_str("Transfer is empty"),
+            DmaError::Bus(debug) => write!(f, "DMA error, debug {:#x}", debug),
+            DmaError::Timeout => f.write_str("DMA transfer timed out"),
+        }
+    }
+}
+
+/// Peripherals that pace transfers, the DREQ (PERMAP) numbers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Dreq {
+    /// Display serial interface
+    Dsi = 1,
+    /// PCM transmit
+    PcmTx = 2,
+    /// PCM receive
+    PcmRx = 3,
+    /// Secondary memory interface
+    Smi = 4,
+    /// PWM
+    Pwm = 5,
+    /// SPI transmit
+    SpiTx = 6,
+    /// SPI receive
+    SpiRx = 7,
+    /// BSC slave transmit
+    BscSlaveTx = 8,
+    /// BSC slave receive
+    BscSlaveRx = 9,
+    /// SD card controller
+    Emmc = 11,
+    /// PL011 transmit
+    UartTx = 12,
+    /// SD host controller
+    SdHost = 13,
+    /// PL011 receive
+    UartRx = 14,
+}
+
+/// Memory for the engine to read or write
+///
+/// Always whole frames, zeroed when allocated. Dereferences to its first `len` bytes.
+pub struct DmaBuffer {
+    frames: Option<FrameRange>,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// A zeroed buffer of `len` bytes
+    pub fn new(len: usize) -> Result<DmaBuffer, DmaError> {
+        let count = len.max(1).div_ceil(PAGE_SIZE);
+        let frames = frame::alloc(count).ok_or(DmaError::OutOfMemory)?;
+
+        Ok(DmaBuffer {
+            frames: Some(frames),
+            len,
+        })
+    }
+
+    /// Size in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer holds no bytes
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Kernel virtual address of byte `offset`, if `len` bytes from there fit
+    fn range(&self, offset: usize, len: usize) -> Result<usize, DmaError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len && len < MAX_LEN => Ok(self.as_ptr() as usize + offset),
+            _ => Err(DmaError::OutOfRange),
+        }
+    }
+
+    fn frames(&self) -> &FrameRange {
+        self.frames.as_ref().unwrap()
+    }
+}
+
+impl core::ops::Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.frames().as_ptr(), self.len) }
+    }
+}
+
+impl core::ops::DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.frames().as_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Some(frames) = self.frames.take() {
+            frame::free(frames);
+        }
+    }
+}
+
+/// A control block, as the engine reads it
+#[derive(Clone, Copy, Default)]
+#[repr(C, align(32))]
+struct ControlBlock {
+    ti: u32,
+    source: u32,
+    dest: u32,
+    len: u32,
+    stride: u32,
+    next: u32,
+    _reserved: [u32; 2],
+}
+
+/// Bits of a control block's transfer information
+struct Ti;
+
+impl Ti {
+    const INTEN: u32 = 1 << 0;
+    /// Wait for the AXI write response, so the data landed when the next block starts
+    const WAIT_RESP: u32 = 1 << 3;
+    const DEST_INC: u32 = 1 << 4;
+    const DEST_DREQ: u32 = 1 << 6;
+    const SRC_INC: u32 = 1 << 8;
+    const SRC_DREQ: u32 = 1 << 10;
+    const PERMAP_SHIFT: u32 = 16;
+}
+
+/// A chain of control blocks
+///
+/// The buffers it reads and writes are borrowed until the transfer is dropped, so nothing
+/// can touch them while the engine might.
+///
+/// ## Examples
+///
+/// ```
+/// use core::time::Duration;
+/// use dyseos::drivers::dma::{Channel, DmaBuffer, Transfer};
+///
+/// let mut src = DmaBuffer::new(4096).unwrap();
+/// let mut dst = DmaBuffer::new(4096).unwrap();
+/// src.fill(0xAA);
+///
+/// let mut transfer = Transfer::new();
+/// transfer.copy(&src, 0, &mut dst, 0, 4096).unwrap();
+/// Channel::take().unwrap().run(&mut transfer, Duration::from_millis(10)).unwrap();
+///
+/// assert!(dst.iter().all(|&byte| byte == 0xAA));
+/// ```
+pub struct Transfer<'a> {
+    blocks: Vec<ControlBlock>,
+    /// Memory the engine reads, cleaned before it starts
+    reads: Vec<(usize, usize)>,
+    /// Memory the engine writes, invalidated before and after
+    writes: Vec<(usize, usize)>,
+    buffers: PhantomData<&'a mut DmaBuffer>,
+}
+
+impl<'a> Transfer<'a> {
+    /// A transfer without control blocks
+    pub fn new() -> Transfer<'a> {
+        Transfer {
+            blocks: Vec::new(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            buffers: PhantomData,
+        }
+    }
+
+    /// Copy `len` bytes from `src` at `src_offset` to `dst` at `dst_offset`
+    pub fn copy(
+        &mut self,
+        src: &'a DmaBuffer,
+        src_offset: usize,
+        dst: &'a mut DmaBuffer,
+        dst_offset: usize,
+        len: usize,
+    ) -> Result<&mut Transfer<'a>, DmaError> {
+        let source = src.range(src_offset, len)?;
+        let dest = dst.range(dst_offset, len)?;
+
+        self.reads.push((source, len));
+        self.writes.push((dest, len));
+        self.push(Ti::SRC_INC | Ti::DEST_INC, bus_ram(source), bus_ram(dest), len)
+    }
+
+    /// Write `len` bytes of `src` from `offset` into the peripheral register at physical
+    /// address `register`, as fast as `dreq` asks for them
+    pub fn to_peripheral(
+        &mut self,
+        src: &'a DmaBuffer,
+        offset: usize,
+        len: usize,
+        register: usize,
+        dreq: Dreq,
+    ) -> Result<&mut Transfer<'a>, DmaError> {
+        let source = src.range(offset, len)?;
+        let ti = Ti::SRC_INC | Ti::DEST_DREQ | (dreq as u32) << Ti::PERMAP_SHIFT;
+
+        self.reads.push((source, len));
+        self.push(ti, bus_ram(source), bus_peripheral(register), len)
+    }
+
+    /// Read `len` bytes from the peripheral register at physical address `register` into
+    /// `dst` from `offset`, as fast as `dreq` offers them
+    pub fn from_peripheral(
+        &mut self,
+        register: usize,
+        dreq: Dreq,
+        dst: &'a mut DmaBuffer,
+        offset: usize,
+        len: usize,
+    ) -> Result<&mut Transfer<'a>, DmaError> {
+        let dest = dst.range(offset, len)?;
+        let ti = Ti::DEST_INC | Ti::SRC_DREQ | (dreq as u32) << Ti::PERMAP_SHIFT;
+
+        self.writes.push((dest, len));
+        self.push(ti, bus_peripheral(register), bus_ram(dest), len)
+    }
+
+    /// Number of control blocks
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Whether there are no control blocks
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn push(&mut self, ti: u32, source: u32, dest: u32, len: usize) -> Result<&mut Transfer<'a>, DmaError> {
+        self.blocks.push(ControlBlock {
+            ti: ti | Ti::WAIT_RESP,
+            source,
+            dest,
+            len: len as u32,
+            ..Default::default()
+        });
+
+        Ok(self)
+    }
+
+    /// Chain the blocks, interrupt at the end, and make them visible to the engine
+    ///
+    /// Returns the bus address of the first block.
+    fn link(&mut self) -> Result<u32, DmaError> {
+        let addresses: Vec<u32> = self.blocks.iter().map(|block| bus_ram(block as *const _ as usize)).collect();
+        let first = *addresses.first().ok_or(DmaError::Empty)?;
+
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            block.ti &= !Ti::INTEN;
+            block.next = addresses.get(i + 1).copied().unwrap_or(0);
+        }
+        if let Some(last) = self.blocks.last_mut() {
+            last.ti |= Ti::INTEN;
+        }
+
+        let size = self.blocks.len() * core::mem::size_of::<ControlBlock>();
+        mmu::clean_dcache(self.blocks.as_ptr() as usize, size);
+        Ok(first)
+    }
+}
+
+impl Default for Transfer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A DMA channel, given back when dropped
+pub struct Channel {
+    index: usize,
+}
+
+impl Channel {
+    /// Claim a free channel
+    pub fn take() -> Result<Channel, DmaError> {
+        let mut free = FREE.load(Ordering::Relaxed);
+
+        loop {
+            if free == 0 {
+                return Err(DmaError::NoChannel);
+            }
+
+            let index = free.trailing_zeros() as usize;
+            match FREE.compare_exchange(free, free & !(1 << index), Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Ok(Channel { index }),
+                Err(now) => free = now,
+            }
+        }
+    }
+
+    /// The channel number
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Run `transfer` and wait for it to finish
+    ///
+    /// A transfer paced by a peripheral that stops asking for data never ends, after
+    /// `timeout` the channel is reset and the call fails with [DmaError::Timeout].
+    pub fn run(&mut self, transfer: &mut Transfer, timeout: Duration) -> Result<(), DmaError> {
+        let first = transfer.link()?;
+
+        for &(kva, len) in &transfer.reads {
+            mmu::clean_dcache(kva, len);
+        }
+        // Dirty lines written back later would land on top of what the engine wrote
+        for &(kva, len) in &transfer.writes {
+            mmu::invalidate_dcache(kva, len);
+        }
+
+        let done = &DONE[self.index];
+        done.store(0, Ordering::Relaxed);
+        ENGINE.start(self.index, first);
+        let deadline = timer::uptime() + timeout;
+
+        let result = loop {
+            if let Some(debug) = ENGINE.error(self.index) {
+                break Err(DmaError::Bus(debug));
+            }
+            if done.load(Ordering::Acquire) != 0 || ENGINE.finished(self.index) {
+                break Ok(());
+            }
+
+            let now = timer::uptime();
+            if now >= deadline {
+                break Err(DmaError::Timeout);
+            }
+
+            // Polling when sleeping isn't possible, the status register tells as well
+            if let Err(FutexError::NotAllowed) = futex_wait(done, 0, Some(deadline - now)) {
+                core::hint::spin_loop();
+            }
+        };
+
+        // Also stops a transfer that timed out
+        ENGINE.reset(self.index);
+
+        // Lines the cpu speculatively loaded during the transfer are stale
+        for &(kva, len) in &transfer.writes {
+            mmu::invalidate_dcache(kva, len);
+        }
+
+        result
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        FREE.fetch_or(1 << self.index, Ordering::Release);
+    }
+}
+
+/// BCM2835 DMA registers
+struct Engine<const T: usize>;
+
+impl<const T: usize> Engine<T> {
+    const CS: usize = 0x00;
+    const CS_ACTIVE: u32 = 1 << 0;
+    const CS_END: u32 = 1 << 1;
+    const CS_INT: u32 = 1 << 2;
+    const CS_ERROR: u32 = 1 << 8;
+    const CS_PRIORITY: u32 = 8 << 16;
+    const CS_PANIC_PRIORITY: u32 = 15 << 20;
+    const CS_WAIT_FOR_WRITES: u32 = 1 << 28;
+    const CS_RESET: u32 = 1 << 31;
+    const CONBLK_AD: usize = 0x04;
+    /// Error flags, write 1 to clear
+    const DEBUG: usize = 0x20;
+    const DEBUG_ERRORS: u32 = 0b111;
+
+    /// Interrupt status of every channel, one bit each
+    const INT_STATUS: usize = T + 0xFE0;
+    /// Enable bit of every channel
+    const ENABLE: usize = T + 0xFF0;
+
+    fn register(channel: usize, offset: usize) -> usize {
+        T + channel * 0x100 + offset
+    }
+
+    fn enable(&self, channel: usize) {
+        unsafe { mmio::modify32(Self::ENABLE, 1 << channel, 1 << channel) };
+    }
+
+    fn start(&self, channel: usize, first: u32) {
+        unsafe {
+            mmio::write32(Self::register(channel, Self::CONBLK_AD), first);
+            mmio::write32(
+                Self::register(channel, Self::CS),
+                Self::CS_ACTIVE | Self::CS_PRIORITY | Self::CS_PANIC_PRIORITY | Self::CS_WAIT_FOR_WRITES,
+            );
+        }
+    }
+
+    fn status(&self, channel: usize) -> u32 {
+        unsafe { mmio::read32(Self::register(channel, Self::CS)) }
+    }
+
+    /// The DEBUG register, if the channel stopped with an error
+    fn error(&self, channel: usize) -> Option<u32> {
+        match self.status(channel) & Self::CS_ERROR {
+            0 => None,
+            _ => Some(unsafe { mmio::read32(Self::register(channel, Self::DEBUG)) }),
+        }
+    }
+
+    /// Whether the last control block is done
+    fn finished(&self, channel: usize) -> bool {
+        self.status(channel) & (Self::CS_ACTIVE | Self::CS_END) == Self::CS_END
+    }
+
+    /// Stop the channel and clear its flags
+    fn reset(&self, channel: usize) {
+        unsafe {
+            mmio::write32(Self::register(channel, Self::CS), Self::CS_RESET);
+            mmio::write32(Self::register(channel, Self::CS), Self::CS_END | Self::CS_INT);
+            mmio::write32(Self::register(channel, Self::DEBUG), Self::DEBUG_ERRORS);
+        }
+    }
+
+    /// Acknowledge the interrupts of the full channels, returns which fired
+    fn take_interrupts(&self) -> u32 {
+        let pending = unsafe { mmio::read32(Self::INT_STATUS) } & ((1 << FULL_CHANNELS) - 1);
+
+        for channel in (0..FULL_CHANNELS).filter(|channel| pending & (1 << channel) != 0) {
+            unsafe { mmio::write32(Self::register(channel, Self::CS), Self::CS_INT) };
+        }
+
+        pending
+    }
+}
+
+static ENGINE: Engine<DMA_BASE> = Engine;
+
+/// Channels nobody has taken, set by [init()]
+static FREE: AtomicU32 = AtomicU32::new(0);
+
+/// Set by the interrupt when a channel's transfer is done, futexes [Channel::run()] sleeps on
+static DONE: [AtomicU32; FULL_CHANNELS] = [const { AtomicU32::new(0) }; FULL_CHANNELS];
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Find the channels the firmware left free, reset and enable them
+///
+/// Called once by the boot core, after [crate::fdt::init()].
+pub fn init() {
+    let mask = crate::fdt::get()
+        .and_then(|fdt| fdt.property("/soc/dma", "brcm,dma-channel-mask"))
+        .and_then(crate::fdt::as_u64)
+        .map_or(DEFAULT_CHANNEL_MASK, |mask| mask as u32);
+    let mask = mask & ((1 << FULL_CHANNELS) - 1);
+
+    for channel in (0..FULL_CHANNELS).filter(|channel| mask & (1 << channel) != 0) {
+        ENGINE.enable(channel);
+        ENGINE.reset(channel);
+
+        let irq = Irq::peripheral(FIRST_IRQ + channel);
+        if interrupt::register(irq, handle_interrupt).is_ok() {
+            interrupt::enable(irq);
+        }
+    }
+
+    FREE.store(mask, Ordering::Release);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// Wake whoever waits on the channels that finished
+fn handle_interrupt() {
+    let pending = ENGINE.take_interrupts();
+
+    for channel in (0..FULL_CHANNELS).filter(|channel| pending & (1 << channel) != 0) {
+        DONE[channel].store(1, Ordering::Release);
+        futex_wake(&DONE[channel], usize::MAX);
+    }
+}
+
+/// Bus address of RAM at kernel virtual address `kva`
+fn bus_ram(kva: usize) -> u32 {
+    (virt_to_phys(kva) | BUS_RAM) as u32
+}
+
+/// Bus address of the peripheral register at physical address `pa`
+fn bus_peripheral(pa: usize) -> u32 {
+    (pa - PERIPHERALS + BUS_PERIPHERALS) as u32
+}
@@ -0,0 +1,657 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS EMMC
+//!
+//! Driver for the SD card slot, the Arasan SDHCI controller at 0x3F30_0000 (the
+//! datasheet calls it EMMC). [init()] identifies the card, switches it to a 4 bit bus at
+//! high speed when the card can do it and makes it available as a [BlockDevice], also in
+//! [crate::vfs::devfs] as `mmcblk0` with its partitions as `mmcblk0p1` and on.
+//!
+//! The firmware leaves the slot's pins (GPIO48-53) on the other SD controller, SDHOST, so
+//! they're switched to this one first, with the pull-ups the command and data lines need.
+//! Everything is polled, transfers move through the data port one word at a time. QEMU
+//! connects `-drive if=sd` to this controller.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 5)
+//!   - <https://www.sdcard.org/downloads/pls/> (Physical Layer and Host Controller Simplified Specifications)
+//!   - <https://github.com/LdB-ECM/Raspberry-Pi/blob/master/SD_FAT32/SDCard.c>
+//!   - <https://elixir.bootlin.com/linux/v6.6/source/drivers/mmc/host/sdhci.c>
+//!
+
+use super::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use super::gpio::{Alt3, Pin, Pull};
+use super::mmio;
+use super::mailbox::{self, ClockId};
+use crate::cpu::timer;
+use crate::sync::mutex::Mutex;
+use crate::sync::once::OnceCell;
+use crate::vfs::devfs;
+use alloc::sync::Arc;
+use core::time::Duration;
+
+/// Where the controller is
+const EMMC_BASE: usize = 0x3F30_0000;
+
+/// Clock going into the controller's divider, if the firmware won't say
+///
+/// What the firmware sets on the Pi 3. If it's really lower the card just runs slower
+/// than asked, never faster.
+const BASE_CLOCK: u32 = 250_000_000;
+
+/// Card clock during identification
+const CLOCK_IDENTIFY: u32 = 400_000;
+/// Card clock in default speed mode
+const CLOCK_NORMAL: u32 = 25_000_000;
+/// Card clock in high speed mode
+const CLOCK_HIGH: u32 = 50_000_000;
+
+/// How long a command or a block can take
+const TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the card can stay busy powering up
+const POWER_UP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most blocks moved by one command
+const MAX_BLOCKS: usize = 256;
+
+/// Linux's major number for SD cards
+const MMC_MAJOR: u64 = 179;
+
+/// Names in devfs of the first four partitions
+const PARTITION_NAMES: [&str; 4] = ["mmcblk0p1", "mmcblk0p2", "mmcblk0p3", "mmcblk0p4"];
+
+/// CMDTM: transfer mode bits
+const TM_BLKCNT_EN: u32 = 1 << 1;
+const TM_AUTO_CMD12: u32 = 1 << 2;
+const TM_DAT_DIR_READ: u32 = 1 << 4;
+const TM_MULTI_BLOCK: u32 = 1 << 5;
+
+/// CMDTM: command bits
+const RESPONSE_NONE: u32 = 0;
+const RESPONSE_136: u32 = 1 << 16;
+const RESPONSE_48: u32 = 2 << 16;
+const RESPONSE_48_BUSY: u32 = 3 << 16;
+const CRC_CHECK: u32 = 1 << 19;
+const INDEX_CHECK: u32 = 1 << 20;
+const IS_DATA: u32 = 1 << 21;
+
+/// Normal response, R1 (and R6, R7)
+const R1: u32 = RESPONSE_48 | CRC_CHECK | INDEX_CHECK;
+/// R1 and the card signals busy on DAT0 afterwards
+const R1B: u32 = RESPONSE_48_BUSY | CRC_CHECK | INDEX_CHECK;
+/// CID and CSD register, R2
+const R2: u32 = RESPONSE_136 | CRC_CHECK;
+/// OCR register, R3, no CRC or index
+const R3: u32 = RESPONSE_48;
+
+/// The CMDTM value of a command
+const fn command(index: u32, flags: u32) -> u32 {
+    (index << 24) | flags
+}
+
+const GO_IDLE_STATE: u32 = command(0, RESPONSE_NONE);
+const ALL_SEND_CID: u32 = command(2, R2);
+const SEND_RELATIVE_ADDR: u32 = command(3, R1);
+const SWITCH_FUNC: u32 = command(6, R1 | IS_DATA | TM_DAT_DIR_READ);
+const SELECT_CARD: u32 = command(7, R1B);
+const SEND_IF_COND: u32 = command(8, R1);
+const SEND_CSD: u32 = command(9, R2);
+const SET_BLOCKLEN: u32 = command(16, R1);
+const READ_SINGLE_BLOCK: u32 = command(17, R1 | IS_DATA | TM_DAT_DIR_READ);
+const READ_MULTIPLE_BLOCK: u32 =
+    command(18, R1 | IS_DATA | TM_DAT_DIR_READ | TM_MULTI_BLOCK | TM_BLKCNT_EN | TM_AUTO_CMD12);
+const WRITE_BLOCK: u32 = command(24, R1 | IS_DATA);
+const WRITE_MULTIPLE_BLOCK: u32 = command(25, R1 | IS_DATA | TM_MULTI_BLOCK | TM_BLKCNT_EN | TM_AUTO_CMD12);
+const APP_CMD: u32 = command(55, R1);
+/// Application commands, sent after [APP_CMD]
+const SET_BUS_WIDTH: u32 = command(6, R1);
+const SD_SEND_OP_COND: u32 = command(41, R3);
+
+/// CMD8 argument: 2.7-3.6V and a check pattern the card echoes
+const IF_COND_ARG: u32 = 0x1AA;
+/// ACMD41 argument: 3.2-3.4V, plus HCS for cards that answered CMD8
+const OCR_VOLTAGE: u32 = 0x00FF_8000;
+const OCR_HCS: u32 = 1 << 30;
+/// OCR bits: power up is done, card is SDHC/SDXC
+const OCR_READY: u32 = 1 << 31;
+const OCR_CCS: u32 = 1 << 30;
+/// CMD6 argument: switch function group 1 to high speed
+const SWITCH_HIGH_SPEED: u32 = 0x80FF_FFF1;
+
+/// The controller
+struct Controller<const T: usize> {
+    /// Clock going into the divider, in Hz
+    base_clock: u32,
+}
+
+impl<const T: usize> Controller<T> {
+    const BLKSIZECNT: usize = T + 0x04;
+    const ARG1: usize = T + 0x08;
+    const CMDTM: usize = T + 0x0C;
+    const RESP0: usize = T + 0x10;
+    const DATA: usize = T + 0x20;
+    const STATUS: usize = T + 0x24;
+    const CONTROL0: usize = T + 0x28;
+    const CONTROL1: usize = T + 0x2C;
+    const INTERRUPT: usize = T + 0x30;
+    const IRPT_MASK: usize = T + 0x34;
+    const IRPT_EN: usize = T + 0x38;
+
+    const STATUS_CMD_INHIBIT: u32 = 1 << 0;
+    const STATUS_DAT_INHIBIT: u32 = 1 << 1;
+
+    const CONTROL0_4BIT: u32 = 1 << 1;
+    const CONTROL0_HIGH_SPEED: u32 = 1 << 2;
+    /// Bus power on at 3.3V, the power control register of a standard SDHCI
+    const CONTROL0_POWER_33V: u32 = 0xF << 8;
+
+    const CONTROL1_CLK_INTLEN: u32 = 1 << 0;
+    const CONTROL1_CLK_STABLE: u32 = 1 << 1;
+    const CONTROL1_CLK_EN: u32 = 1 << 2;
+    const CONTROL1_CLK_FREQ: u32 = 0xFFC0;
+    /// Data timeout of TMCLK * 2^27
+    const CONTROL1_DATA_TOUNIT: u32 = 0xE << 16;
+    const CONTROL1_SRST_HC: u32 = 1 << 24;
+    const CONTROL1_SRST_CMD: u32 = 1 << 25;
+    const CONTROL1_SRST_DATA: u32 = 1 << 26;
+
+    const INT_CMD_DONE: u32 = 1 << 0;
+    const INT_DATA_DONE: u32 = 1 << 1;
+    const INT_WRITE_RDY: u32 = 1 << 4;
+    const INT_READ_RDY: u32 = 1 << 5;
+    const INT_ERROR: u32 = 1 << 15;
+    const INT_CMD_TIMEOUT: u32 = 1 << 16;
+    const INT_DATA_TIMEOUT: u32 = 1 << 20;
+    const INT_ERRORS: u32 = 0xFFFF_0000 | Self::INT_ERROR;
+
+    /// Reset the controller and start the card clock at identification speed
+    fn reset(&self) -> Result<(), BlockError> {
+        unsafe {
+            mmio::write32(Self::CONTROL1, Self::CONTROL1_SRST_HC);
+            self.wait_clear(Self::CONTROL1, Self::CONTROL1_SRST_HC)?;
+
+            mmio::write32(Self::CONTROL0, Self::CONTROL0_POWER_33V);
+            mmio::write32(Self::CONTROL1, Self::CONTROL1_CLK_INTLEN | Self::CONTROL1_DATA_TOUNIT);
+
+            // Polled, the status bits latch without reaching the interrupt controller
+            mmio::write32(Self::IRPT_EN, 0);
+            mmio::write32(Self::IRPT_MASK, u32::MAX);
+            mmio::write32(Self::INTERRUPT, u32::MAX);
+        }
+
+        self.set_clock(CLOCK_IDENTIFY)
+    }
+
+    /// Run the card clock at `hz` or the closest speed below it
+    fn set_clock(&self, hz: u32) -> Result<(), BlockError> {
+        self.wait_clear(Self::STATUS, Self::STATUS_CMD_INHIBIT | Self::STATUS_DAT_INHIBIT)?;
+
+        // 10 bit divided clock mode, the card gets base_clock / (2 * divisor)
+        let divisor = self.base_clock.div_ceil(2 * hz).min(0x3FF);
+        let bits = ((divisor & 0xFF) << 8) | ((divisor >> 8) << 6);
+
+        unsafe {
+            mmio::modify32(Self::CONTROL1, Self::CONTROL1_CLK_EN, 0);
+            mmio::modify32(Self::CONTROL1, Self::CONTROL1_CLK_FREQ, bits);
+        }
+
+        self.wait_set(Self::CONTROL1, Self::CONTROL1_CLK_STABLE)?;
+        unsafe { mmio::modify32(Self::CONTROL1, Self::CONTROL1_CLK_EN, Self::CONTROL1_CLK_EN) };
+
+        Ok(())
+    }
+
+    /// Send a command, returns the first word of the response
+    fn command(&self, cmd: u32, arg: u32) -> Result<u32, BlockError> {
+        let busy = match cmd & (IS_DATA | RESPONSE_48_BUSY) {
+            0 | RESPONSE_48 | RESPONSE_136 => Self::STATUS_CMD_INHIBIT,
+            _ => Self::STATUS_CMD_INHIBIT | Self::STATUS_DAT_INHIBIT,
+        };
+        self.wait_clear(Self::STATUS, busy)?;
+
+        unsafe {
+            mmio::write32(Self::INTERRUPT, u32::MAX);
+            mmio::write32(Self::ARG1, arg);
+            mmio::write32(Self::CMDTM, cmd);
+        }
+
+        self.wait_interrupt(Self::INT_CMD_DONE)?;
+
+        // The card holds DAT0 low while it's busy
+        if cmd & RESPONSE_48_BUSY == RESPONSE_48_BUSY {
+            self.wait_clear(Self::STATUS, Self::STATUS_DAT_INHIBIT)?;
+        }
+
+        Ok(unsafe { mmio::read32(Self::RESP0) })
+    }
+
+    /// Send an application specific command
+    fn app_command(&self, rca: u32, cmd: u32, arg: u32) -> Result<u32, BlockError> {
+        self.command(APP_CMD, rca << 16)?;
+        self.command(cmd, arg)
+    }
+
+    /// The whole 136 bit response of the last command, without its CRC
+    fn response_136(&self) -> u128 {
+        (0..4).fold(0, |response, i| response | (unsafe { mmio::read32(Self::RESP0 + i * 4) } as u128) << (i * 32))
+    }
+
+    /// Run a command that reads `buf.len() / block_size` blocks into `buf`
+    fn read(&self, cmd: u32, arg: u32, block_size: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.set_blocks(block_size, buf.len() / block_size);
+        self.command(cmd, arg)?;
+
+        for block in buf.chunks_mut(block_size) {
+            self.wait_interrupt(Self::INT_READ_RDY)?;
+
+            for word in block.chunks_mut(4) {
+                word.copy_from_slice(&unsafe { mmio::read32(Self::DATA) }.to_le_bytes());
+            }
+        }
+
+        self.wait_interrupt(Self::INT_DATA_DONE)
+    }
+
+    /// Run a command that writes `buf.len() / block_size` blocks from `buf`
+    fn write(&self, cmd: u32, arg: u32, block_size: usize, buf: &[u8]) -> Result<(), BlockError> {
+        self.set_blocks(block_size, buf.len() / block_size);
+        self.command(cmd, arg)?;
+
+        for block in buf.chunks(block_size) {
+            self.wait_interrupt(Self::INT_WRITE_RDY)?;
+
+            for word in block.chunks(4) {
+                unsafe { mmio::write32(Self::DATA, u32::from_le_bytes(word.try_into().unwrap())) };
+            }
+        }
+
+        self.wait_interrupt(Self::INT_DATA_DONE)
+    }
+
+    fn set_blocks(&self, size: usize, count: usize) {
+        unsafe { mmio::write32(Self::BLKSIZECNT, ((count as u32) << 16) | size as u32) };
+    }
+
+    fn set_bus(&self, wide: bool, high_speed: bool) {
+        let value = (wide as u32 * Self::CONTROL0_4BIT) | (high_speed as u32 * Self::CONTROL0_HIGH_SPEED);
+        unsafe { mmio::modify32(Self::CONTROL0, Self::CONTROL0_4BIT | Self::CONTROL0_HIGH_SPEED, value) };
+    }
+
+    /// Wait for the interrupt bits in `mask`, failing on any error bit
+    ///
+    /// A failed command leaves the command and data lines in a state only a reset gets
+    /// them out of.
+    fn wait_interrupt(&self, mask: u32) -> Result<(), BlockError> {
+        let end = timer::uptime() + TIMEOUT;
+
+        loop {
+            let status = unsafe { mmio::read32(Self::INTERRUPT) };
+
+            if status & Self::INT_ERRORS != 0 {
+                unsafe { mmio::write32(Self::INTERRUPT, status) };
+                self.reset_lines();
+
+                return Err(match status & (Self::INT_CMD_TIMEOUT | Self::INT_DATA_TIMEOUT) {
+                    0 => BlockError::Io,
+                    _ => BlockError::Timeout,
+                });
+            }
+
+            if status & mask == mask {
+                unsafe { mmio::write32(Self::INTERRUPT, mask) };
+                return Ok(());
+            }
+
+            if timer::uptime() > end {
+                self.reset_lines();
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn reset_lines(&self) {
+        let lines = Self::CONTROL1_SRST_CMD | Self::CONTROL1_SRST_DATA;
+
+        unsafe { mmio::modify32(Self::CONTROL1, lines, lines) };
+        let _ = self.wait_clear(Self::CONTROL1, lines);
+    }
+
+    fn wait_clear(&self, reg: usize, mask: u32) -> Result<(), BlockError> {
+        self.wait_for(|| unsafe { mmio::read32(reg) } & mask == 0)
+    }
+
+    fn wait_set(&self, reg: usize, mask: u32) -> Result<(), BlockError> {
+        self.wait_for(|| unsafe { mmio::read32(reg) } & mask == mask)
+    }
+
+    fn wait_for(&self, done: impl Fn() -> bool) -> Result<(), BlockError> {
+        let end = timer::uptime() + TIMEOUT;
+
+        while !done() {
+            if timer::uptime() > end {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+
+        Ok(())
+    }
+}
+
+/// An identified SD card
+///
+/// One transfer at a time, the controller has a single set of registers.
+pub struct SdCard<const T: usize> {
+    controller: Controller<T>,
+    /// Relative card address, picked by the card
+    rca: u32,
+    /// SDHC and SDXC cards are addressed in blocks, older ones in bytes
+    block_addressed: bool,
+    blocks: u64,
+    lock: Mutex<()>,
+    pins: Option<SdPins>,
+}
+
+impl<const T: usize> SdCard<T> {
+    /// Reset the controller and bring the card in the slot up
+    ///
+    /// Fails with [BlockError::NoMedia] if no card answers, and with [BlockError::Busy] if
+    /// the slot's pins are taken.
+    pub fn new() -> Result<SdCard<T>, BlockError> {
+        let pins = SdPins::take().ok_or(BlockError::Busy)?;
+
+        match SdCard::bring_up() {
+            Ok(mut card) => {
+                card.pins = Some(pins);
+                Ok(card)
+            }
+            Err(err) => {
+                pins.release();
+                Err(err)
+            }
+        }
+    }
+
+    /// Identify and configure the card, with the pins already on the controller
+    fn bring_up() -> Result<SdCard<T>, BlockError> {
+        let base_clock = mailbox::get_clock_rate(ClockId::Emmc).unwrap_or(BASE_CLOCK);
+        let controller = Controller::<T> { base_clock };
+        controller.reset()?;
+
+        controller.command(GO_IDLE_STATE, 0)?;
+
+        // Version 2 cards echo the pattern back, older ones don't know the command
+        let version_2 = match controller.command(SEND_IF_COND, IF_COND_ARG) {
+            Ok(echo) if echo & 0xFFF == IF_COND_ARG => true,
+            Ok(_) => return Err(BlockError::Io),
+            Err(BlockError::Timeout) => false,
+            Err(err) => return Err(err),
+        };
+
+        let ocr_arg = OCR_VOLTAGE | if version_2 { OCR_HCS } else { 0 };
+        let end = timer::uptime() + POWER_UP_TIMEOUT;
+        let ocr = loop {
+            let ocr = match controller.app_command(0, SD_SEND_OP_COND, ocr_arg) {
+                Ok(ocr) => ocr,
+                Err(BlockError::Timeout) => return Err(BlockError::NoMedia),
+                Err(err) => return Err(err),
+            };
+
+            if ocr & OCR_READY != 0 {
+                break ocr;
+            }
+            if timer::uptime() > end {
+                return Err(BlockError::Timeout);
+            }
+            timer::spin_for(Duration::from_millis(10));
+        };
+
+        controller.command(ALL_SEND_CID, 0)?;
+        let rca = controller.command(SEND_RELATIVE_ADDR, 0)? >> 16;
+
+        controller.command(SEND_CSD, rca << 16)?;
+        let blocks = csd_blocks(controller.response_136()).ok_or(BlockError::Io)?;
+
+        controller.command(SELECT_CARD, rca << 16)?;
+
+        let card = SdCard {
+            controller,
+            rca,
+            block_addressed: ocr & OCR_CCS != 0,
+            blocks,
+            lock: Mutex::new(()),
+            pins: None,
+        };
+        card.configure(version_2)?;
+
+        Ok(card)
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.blocks * SECTOR_SIZE as u64
+    }
+
+    /// Switch to a 4 bit bus, to high speed if the card supports it, and fix the block size
+    fn configure(&self, version_2: bool) -> Result<(), BlockError> {
+        let controller = &self.controller;
+
+        // Every SD memory card supports 4 bits
+        controller.app_command(self.rca, SET_BUS_WIDTH, 2)?;
+        controller.set_bus(true, false);
+
+        // CMD6 came with version 1.1, the status says if group 1 switched to function 1
+        let mut status = [0; 64];
+        let high_speed = version_2
+            && controller.read(SWITCH_FUNC, SWITCH_HIGH_SPEED, status.len(), &mut status).is_ok()
+            && status[16] & 0xF == 1;
+
+        match high_speed {
+            true => {
+                controller.set_bus(true, true);
+                controller.set_clock(CLOCK_HIGH)?;
+            }
+            false => controller.set_clock(CLOCK_NORMAL)?,
+        }
+
+        if !self.block_addressed {
+            controller.command(SET_BLOCKLEN, SECTOR_SIZE as u32)?;
+        }
+
+        Ok(())
+    }
+
+    /// Argument of a read or write command for block `lba`
+    fn address(&self, lba: u64) -> u32 {
+        match self.block_addressed {
+            true => lba as u32,
+            false => (lba * SECTOR_SIZE as u64) as u32,
+        }
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), BlockError> {
+        let count = block::check_buffer(len, SECTOR_SIZE)?;
+
+        match lba.checked_add(count) {
+            Some(end) if end <= self.blocks => Ok(()),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl<const T: usize> BlockDevice for SdCard<T> {
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(lba, buf.len())?;
+        let _guard = self.lock.lock().map_err(|_| BlockError::Io)?;
+
+        for (i, chunk) in buf.chunks_mut(MAX_BLOCKS * SECTOR_SIZE).enumerate() {
+            let cmd = match chunk.len() {
+                SECTOR_SIZE => READ_SINGLE_BLOCK,
+                _ => READ_MULTIPLE_BLOCK,
+            };
+            let arg = self.address(lba + (i * MAX_BLOCKS) as u64);
+
+            self.controller.read(cmd, arg, SECTOR_SIZE, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(lba, buf.len())?;
+        let _guard = self.lock.lock().map_err(|_| BlockError::Io)?;
+
+        for (i, chunk) in buf.chunks(MAX_BLOCKS * SECTOR_SIZE).enumerate() {
+            let cmd = match chunk.len() {
+                SECTOR_SIZE => WRITE_BLOCK,
+                _ => WRITE_MULTIPLE_BLOCK,
+            };
+            let arg = self.address(lba + (i * MAX_BLOCKS) as u64);
+
+            self.controller.write(cmd, arg, SECTOR_SIZE, chunk)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<const T: usize> Drop for SdCard<T> {
+    fn drop(&mut self) {
+        if let Some(pins) = self.pins.take() {
+            pins.release();
+        }
+    }
+}
+
+/// The card found by [init()]
+static CARD: OnceCell<Arc<dyn BlockDevice>> = OnceCell::new();
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Bring up the card in the slot and add it and its partitions to devfs
+///
+/// Call once, after [crate::vfs::init()]. An empty slot is [BlockError::NoMedia].
+pub fn init() -> Result<(), BlockError> {
+    let card: Arc<dyn BlockDevice> = Arc::new(SdCard::<EMMC_BASE>::new()?);
+
+    let _ = devfs::register_block("mmcblk0", card.clone(), MMC_MAJOR << 8);
+
+    let partitions = block::partitions(&card)?;
+    for ((partition, name), minor) in partitions.into_iter().zip(PARTITION_NAMES).zip(1..) {
+        let _ = devfs::register_block(name, Arc::new(partition), (MMC_MAJOR << 8) | minor);
+    }
+
+    let _ = CARD.set(card);
+    Ok(())
+}
+
+/// The card [init()] found, if it found one
+pub fn card() -> Option<Arc<dyn BlockDevice>> {
+    CARD.get().cloned()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// The slot's clock, command and data lines
+struct SdPins {
+    clk: Pin<48, Alt3>,
+    cmd: Pin<49, Alt3>,
+    dat0: Pin<50, Alt3>,
+    dat1: Pin<51, Alt3>,
+    dat2: Pin<52, Alt3>,
+    dat3: Pin<53, Alt3>,
+}
+
+impl SdPins {
+    /// Claim the pins and switch them to the EMMC controller, all or nothing
+    ///
+    /// The card drives the command and data lines open drain while it's identified, they
+    /// need the pull-ups. The clock only ever comes from the controller.
+    fn take() -> Option<SdPins> {
+        let pins = (
+            Pin::<48, _>::take(),
+            Pin::<49, _>::take(),
+            Pin::<50, _>::take(),
+            Pin::<51, _>::take(),
+            Pin::<52, _>::take(),
+            Pin::<53, _>::take(),
+        );
+
+        match pins {
+            (Some(clk), Some(cmd), Some(dat0), Some(dat1), Some(dat2), Some(dat3)) => {
+                clk.set_pull(Pull::None);
+                cmd.set_pull(Pull::Up);
+                dat0.set_pull(Pull::Up);
+                dat1.set_pull(Pull::Up);
+                dat2.set_pull(Pull::Up);
+                dat3.set_pull(Pull::Up);
+
+                Some(SdPins {
+                    clk: clk.into_mode(),
+                    cmd: cmd.into_mode(),
+                    dat0: dat0.into_mode(),
+                    dat1: dat1.into_mode(),
+                    dat2: dat2.into_mode(),
+                    dat3: dat3.into_mode(),
+                })
+            }
+            (clk, cmd, dat0, dat1, dat2, dat3) => {
+                clk.into_iter().for_each(Pin::release);
+                cmd.into_iter().for_each(Pin::release);
+                dat0.into_iter().for_each(Pin::release);
+                dat1.into_iter().for_each(Pin::release);
+                dat2.into_iter().for_each(Pin::release);
+                dat3.into_iter().for_each(Pin::release);
+                None
+            }
+        }
+    }
+
+    fn release(self) {
+        self.clk.release();
+        self.cmd.release();
+        self.dat0.release();
+        self.dat1.release();
+        self.dat2.release();
+        self.dat3.release();
+    }
+}
+
+/// Number of 512 byte blocks from a CSD register, as the controller hands it over
+///
+/// The controller drops the CRC byte, so bit `n` of the CSD is bit `n - 8` here.
+fn csd_blocks(csd: u128) -> Option<u64> {
+    let bits = |high: u32, low: u32| ((csd >> (low - 8)) & ((1 << (high - low + 1)) - 1)) as u64;
+
+    match bits(127, 126) {
+        // Version 2 (SDHC and SDXC): (C_SIZE + 1) * 512KiB
+        1 => Some((bits(69, 48) + 1) * 1024),
+        // Version 1: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) blocks of 2^READ_BL_LEN bytes
+        0 => {
+            let bytes = (bits(73, 62) + 1) << (bits(49, 47) + 2) << bits(83, 80);
+            Some(bytes / SECTOR_SIZE as u64)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,442 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Framebuffer Console
+//!
+//! A text [Console] drawn on the [Framebuffer]. The font is an 8x16 PSF1 font built into
+//! the kernel, its glyphs are indexed by Latin-1 code point and glyph 0 stands in for
+//! anything else.
+//!
+//! The usual ANSI escape sequences work, enough for colours and simple full screen output:
+//!
+//!   - `ESC [ n m`: 0 reset, 1 bold, 7 reverse, 22 and 27 undo them, 30-37 and 90-97
+//!     foreground, 40-47 and 100-107 background, 39 and 49 default colours.
+//!   - `ESC [ row ; col H` (or `f`) moves the cursor, `ESC [ n A/B/C/D` moves it by `n`.
+//!   - `ESC [ n J` and `ESC [ n K` clear the screen and the line, from the cursor on for 0
+//!     and everything for 2.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.win.tue.nl/~aeb/linux/kbd/font-formats-1.html>
+//!   - <https://en.wikipedia.org/wiki/ANSI_escape_code>
+//!   - <https://dejavu-fonts.github.io/> (the glyphs are DejaVu Sans Mono, rendered at 14px)
+//!
+
+use super::console::{self, Console, Count, Output, Read};
+use super::framebuffer::{Framebuffer, FramebufferError, Rgb, DEFAULT_HEIGHT, DEFAULT_WIDTH};
+use alloc::boxed::Box;
+
+/// The built in font
+static FONT: &[u8] = include_bytes!("font.psf");
+
+/// The standard VGA colours, then their bright versions
+const PALETTE: [Rgb; 16] = [
+    Rgb(0x00, 0x00, 0x00),
+    Rgb(0xAA, 0x00, 0x00),
+    Rgb(0x00, 0xAA, 0x00),
+    Rgb(0xAA, 0x55, 0x00),
+    Rgb(0x00, 0x00, 0xAA),
+    Rgb(0xAA, 0x00, 0xAA),
+    Rgb(0x00, 0xAA, 0xAA),
+    Rgb(0xAA, 0xAA, 0xAA),
+    Rgb(0x55, 0x55, 0x55),
+    Rgb(0xFF, 0x55, 0x55),
+    Rgb(0x55, 0xFF, 0x55),
+    Rgb(0xFF, 0xFF, 0x55),
+    Rgb(0x55, 0x55, 0xFF),
+    Rgb(0xFF, 0x55, 0xFF),
+    Rgb(0x55, 0xFF, 0xFF),
+    Rgb(0xFF, 0xFF, 0xFF),
+];
+
+/// Palette index of the default colours, light grey on black
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+/// Tab stops are every this many columns
+const TAB: usize = 8;
+
+/// Parameters kept of an escape sequence, the rest are dropped
+const MAX_PARAMS: usize = 4;
+
+/// PSF1 header
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER: usize = 4;
+/// Set in the mode byte if the font has 512 glyphs
+const PSF1_MODE_512: u8 = 0x01;
+
+/// Glyphs are always this wide
+const GLYPH_WIDTH: usize = 8;
+
+/// A PSF1 font
+struct Font {
+    glyphs: &'static [u8],
+    /// Bytes per glyph, one per line
+    height: usize,
+    count: usize,
+}
+
+impl Font {
+    /// Parse a PSF1 font, None if it isn't one
+    fn psf1(bytes: &'static [u8]) -> Option<Font> {
+        if bytes.len() < PSF1_HEADER || bytes[..2] != PSF1_MAGIC {
+            return None;
+        }
+
+        let count = match bytes[2] & PSF1_MODE_512 {
+            0 => 256,
+            _ => 512,
+        };
+        let height = bytes[3] as usize;
+        let glyphs = bytes.get(PSF1_HEADER..PSF1_HEADER + count * height)?;
+
+        match height {
+            0 => None,
+            _ => Some(Font { glyphs, height, count }),
+        }
+    }
+
+    /// The glyph for `c`
+    fn glyph(&self, c: char) -> &[u8] {
+        let index = match c as usize {
+            index if index < self.count => index,
+            _ => 0,
+        };
+
+        &self.glyphs[index * self.height..(index + 1) * self.height]
+    }
+}
+
+/// Where the console is in an escape sequence
+#[derive(Clone, Copy)]
+enum State {
+    Normal,
+    /// After ESC
+    Escape,
+    /// After ESC [
+    Csi {
+        params: [u16; MAX_PARAMS],
+        count: usize,
+        /// A private sequence (`ESC [ ?`), parsed but ignored
+        private: bool,
+    },
+}
+
+/// A text console on a framebuffer
+///
+/// Lines wrap at the right edge and the screen scrolls at the bottom. Drawing is flushed
+/// at the end of every write, so output shows up in whole `print!`s.
+pub struct FbConsole {
+    fb: Framebuffer,
+    font: Font,
+    columns: usize,
+    rows: usize,
+    /// The cursor, `column` is `columns` after writing the last column of a line
+    column: usize,
+    row: usize,
+    /// Palette indexes
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    reverse: bool,
+    state: State,
+    /// Rows drawn on since the last flush
+    dirty: Option<(usize, usize)>,
+    chars_written: usize,
+}
+
+impl FbConsole {
+    /// A console taking up all of `fb`, starts out cleared
+    ///
+    /// Fails with [FramebufferError::TooSmall] if not even one character fits.
+    pub fn new(fb: Framebuffer) -> Result<FbConsole, FramebufferError> {
+        let font = Font::psf1(FONT).expect("built in font is broken");
+
+        let (columns, rows) = (fb.width() / GLYPH_WIDTH, fb.height() / font.height);
+        if columns == 0 || rows == 0 {
+            return Err(FramebufferError::TooSmall(fb.width(), fb.height()));
+        }
+
+        let mut console = FbConsole {
+            columns,
+            rows,
+            fb,
+            font,
+            column: 0,
+            row: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            reverse: false,
+            state: State::Normal,
+            dirty: None,
+            chars_written: 0,
+        };
+
+        console.clear_screen();
+        console.flush();
+        Ok(console)
+    }
+
+    /// Size in characters, columns then rows
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Handle one character of output
+    fn put(&mut self, c: char) {
+        match (self.state, c) {
+            (State::Normal, '\x1b') => self.state = State::Escape,
+            (State::Normal, _) => self.control(c),
+            (State::Escape, '[') => {
+                self.state = State::Csi {
+                    params: [0; MAX_PARAMS],
+                    count: 0,
+                    private: false,
+                }
+            }
+            // Anything else after ESC isn't supported, drop both
+            (State::Escape, _) => self.state = State::Normal,
+            (State::Csi { params, count, private }, c) => self.csi(params, count, private, c),
+        }
+    }
+
+    /// A character outside of escape sequences
+    fn control(&mut self, c: char) {
+        match c {
+            // A new line starts at the left, like the UART's carriage return
+            '\n' => {
+                self.column = 0;
+                self.newline();
+            }
+            '\r' => self.column = 0,
+            '\t' => self.column = ((self.column / TAB + 1) * TAB).min(self.columns - 1),
+            '\x08' => self.column = self.column.saturating_sub(1),
+            c if c.is_control() => {}
+            c => {
+                if self.column >= self.columns {
+                    self.column = 0;
+                    self.newline();
+                }
+
+                self.draw(c);
+                self.column += 1;
+            }
+        }
+    }
+
+    /// The next character of a control sequence
+    fn csi(&mut self, mut params: [u16; MAX_PARAMS], mut count: usize, mut private: bool, c: char) {
+        match c {
+            '0'..='9' => {
+                if let Some(param) = params.get_mut(count) {
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+            }
+            ';' => count += 1,
+            '?' | '<' | '=' | '>' => private = true,
+            // Final byte
+            '\x40'..='\x7E' => {
+                self.state = State::Normal;
+
+                if !private {
+                    self.command(c, &params[..(count + 1).min(MAX_PARAMS)]);
+                }
+                return;
+            }
+            _ => {
+                self.state = State::Normal;
+                return;
+            }
+        }
+
+        self.state = State::Csi { params, count, private };
+    }
+
+    /// Run a finished control sequence
+    fn command(&mut self, c: char, params: &[u16]) {
+        // Omitted and 0 mean the same to the cursor movements
+        let n = (params[0] as usize).max(1);
+
+        match c {
+            'm' => params.iter().for_each(|&param| self.sgr(param)),
+            'A' => self.row = self.row.saturating_sub(n),
+            'B' => self.row = (self.row + n).min(self.rows - 1),
+            'C' => self.column = (self.column + n).min(self.columns - 1),
+            'D' => self.column = self.column.min(self.columns - 1).saturating_sub(n),
+            'H' | 'f' => {
+                let column = params.get(1).copied().unwrap_or(0) as usize;
+                self.row = n.min(self.rows) - 1;
+                self.column = column.clamp(1, self.columns) - 1;
+            }
+            'J' => match params[0] {
+                0 => {
+                    self.clear_line(self.column);
+                    self.clear_rows(self.row + 1, self.rows);
+                }
+                2 | 3 => self.clear_screen(),
+                _ => {}
+            },
+            'K' => match params[0] {
+                0 => self.clear_line(self.column),
+                2 => self.clear_line(0),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// One select graphic rendition parameter
+    fn sgr(&mut self, param: u16) {
+        match param {
+            0 => {
+                self.fg = DEFAULT_FG;
+                self.bg = DEFAULT_BG;
+                self.bold = false;
+                self.reverse = false;
+            }
+            1 => self.bold = true,
+            7 => self.reverse = true,
+            22 => self.bold = false,
+            27 => self.reverse = false,
+            30..=37 => self.fg = (param - 30) as u8,
+            39 => self.fg = DEFAULT_FG,
+            40..=47 => self.bg = (param - 40) as u8,
+            49 => self.bg = DEFAULT_BG,
+            90..=97 => self.fg = (param - 90) as u8 + 8,
+            100..=107 => self.bg = (param - 100) as u8 + 8,
+            _ => {}
+        }
+    }
+
+    /// The colours to draw with, foreground then background
+    fn colours(&self) -> (Rgb, Rgb) {
+        // Bold brightens the dark colours, like the Linux console
+        let fg = match self.bold && self.fg < 8 {
+            true => self.fg + 8,
+            false => self.fg,
+        };
+        let (fg, bg) = (PALETTE[fg as usize], PALETTE[self.bg as usize]);
+
+        match self.reverse {
+            true => (bg, fg),
+            false => (fg, bg),
+        }
+    }
+
+    /// Draw `c` at the cursor
+    fn draw(&mut self, c: char) {
+        let (fg, bg) = self.colours();
+        let glyph = self.font.glyph(c);
+
+        self.fb.draw_bitmap(self.column * GLYPH_WIDTH, self.row * self.font.height, glyph, fg, bg);
+        self.touch(self.row, self.row + 1);
+    }
+
+    /// Move to the next row, scrolling at the bottom
+    fn newline(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        self.fb.scroll_up(self.font.height, PALETTE[self.bg as usize]);
+        self.touch(0, self.rows);
+    }
+
+    /// Clear the current row from `column` on
+    fn clear_line(&mut self, column: usize) {
+        let x = column * GLYPH_WIDTH;
+        let y = self.row * self.font.height;
+
+        self.fb.fill_rect(x, y, usize::MAX, self.font.height, PALETTE[self.bg as usize]);
+        self.touch(self.row, self.row + 1);
+    }
+
+    /// Clear rows `start..end`
+    fn clear_rows(&mut self, start: usize, end: usize) {
+        let height = end.saturating_sub(start) * self.font.height;
+
+        self.fb.fill_rect(0, start * self.font.height, usize::MAX, height, PALETTE[self.bg as usize]);
+        self.touch(start, end);
+    }
+
+    /// Clear everything and move the cursor top left
+    fn clear_screen(&mut self) {
+        // The pixels past the last full row too
+        let height = self.fb.height();
+        self.fb.fill_rect(0, 0, usize::MAX, height, PALETTE[self.bg as usize]);
+        self.touch(0, self.rows);
+
+        self.column = 0;
+        self.row = 0;
+    }
+
+    /// Rows `start..end` need flushing
+    fn touch(&mut self, start: usize, end: usize) {
+        self.dirty = match self.dirty {
+            Some((first, last)) => Some((first.min(start), last.max(end))),
+            None => Some((start, end)),
+        };
+    }
+
+    /// Make what was drawn visible
+    fn flush(&mut self) {
+        if let Some((start, end)) = self.dirty.take() {
+            let height = self.font.height;
+            self.fb.flush(start * height, (end - start) * height);
+        }
+    }
+}
+
+/// Implementing `core::fmt::Write` gives the console `write_fmt()`, see [console::_print()].
+impl core::fmt::Write for FbConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            self.put(c);
+        }
+        self.chars_written += s.len();
+        self.flush();
+
+        Ok(())
+    }
+}
+
+/// Implements Count
+impl Count for FbConsole {
+    /// Returns the number of bytes written
+    fn chars_written(&self) -> usize {
+        self.chars_written
+    }
+}
+
+/// Implements Read, there's no keyboard
+impl Read for FbConsole {}
+
+/// Implements Console
+impl Console for FbConsole {}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Put a console on the screen and send kernel output to it as well as the UART
+///
+/// Needs the heap.
+pub fn init() -> Result<(), FramebufferError> {
+    let fb = Framebuffer::new(DEFAULT_WIDTH, DEFAULT_HEIGHT)?;
+
+    console::register_screen(Box::new(FbConsole::new(fb)?));
+    console::set_output(Output::Both);
+    Ok(())
+}
@@ -0,0 +1,241 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Framebuffer
+//!
+//! A linear framebuffer from the VideoCore, asked for through the [mailbox](super::mailbox).
+//! Always 32 bits per pixel, the firmware picks whether that's RGB or BGR and [Rgb] hides
+//! the difference.
+//!
+//! The buffer sits in the VideoCore's share of RAM, which the kernel maps cacheable. The
+//! display reads memory directly, so drawing only shows up after a [Framebuffer::flush()].
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/raspberrypi/firmware/wiki/Mailbox-framebuffer-interface>
+//!   - <https://github.com/bztsrc/raspi3-tutorial/tree/master/09_framebuffer>
+//!
+
+use super::mailbox::{Message, MailboxError, Tag};
+use crate::cpu::mmu;
+use crate::memory::phys_to_virt;
+
+/// Width asked for when the caller doesn't care
+pub const DEFAULT_WIDTH: usize = 1024;
+/// Height asked for when the caller doesn't care
+pub const DEFAULT_HEIGHT: usize = 768;
+
+/// The only depth supported
+const DEPTH: u32 = 32;
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Pixel orders the firmware knows
+const ORDER_BGR: u32 = 0;
+const ORDER_RGB: u32 = 1;
+
+/// Alignment asked for the buffer
+const ALIGNMENT: u32 = 4096;
+
+/// Masks the bus alias off a VideoCore address
+const BUS_MASK: u32 = 0x3FFF_FFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for the framebuffer
+pub enum FramebufferError {
+    /// Talking to the firmware failed
+    Mailbox(MailboxError),
+    /// The firmware didn't hand out a buffer, no display or no memory
+    NoBuffer,
+    /// The firmware picked a depth other than 32 bits per pixel
+    Unsupported(u32),
+    /// The mode the firmware picked is too small to use, width and height
+    TooSmall(usize, usize),
+}
+
+/// Allows printing the error
+impl core::fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            FramebufferError::Mailbox(err) => write!(f, "{}", err),
+            FramebufferError::NoBuffer => f.write_str("Firmware didn't allocate a framebuffer"),
+            FramebufferError::Unsupported(depth) => write!(f, "Unsupported depth of {} bits", depth),
+            FramebufferError::TooSmall(width, height) => write!(f, "Mode of {}x{} is too small", width, height),
+        }
+    }
+}
+
+impl From<MailboxError> for FramebufferError {
+    fn from(err: MailboxError) -> FramebufferError {
+        FramebufferError::Mailbox(err)
+    }
+}
+
+/// A colour
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    /// Black
+    pub const BLACK: Rgb = Rgb(0, 0, 0);
+    /// White
+    pub const WHITE: Rgb = Rgb(0xFF, 0xFF, 0xFF);
+}
+
+/// A framebuffer handed out by the firmware
+///
+/// Coordinates are in pixels with (0, 0) top left. Drawing outside the buffer is clipped.
+pub struct Framebuffer {
+    /// Kernel virtual address of the first pixel
+    base: usize,
+    width: usize,
+    height: usize,
+    /// Bytes from one line to the next
+    pitch: usize,
+    /// Red in the low byte
+    rgb: bool,
+}
+
+impl Framebuffer {
+    /// Ask the firmware for a `width` by `height` framebuffer
+    pub fn new(width: usize, height: usize) -> Result<Framebuffer, FramebufferError> {
+        let size = [width as u32, height as u32];
+
+        let mut message = Message::new();
+        let physical = message.tag(Tag::SET_PHYSICAL_SIZE, &size, 2)?;
+        message.tag(Tag::SET_VIRTUAL_SIZE, &size, 2)?;
+        message.tag(Tag::SET_VIRTUAL_OFFSET, &[0, 0], 2)?;
+        let depth = message.tag(Tag::SET_DEPTH, &[DEPTH], 1)?;
+        let order = message.tag(Tag::SET_PIXEL_ORDER, &[ORDER_RGB], 1)?;
+        let buffer = message.tag(Tag::ALLOCATE_BUFFER, &[ALIGNMENT], 2)?;
+        let pitch = message.tag(Tag::GET_PITCH, &[], 1)?;
+        message.send()?;
+
+        match message.response(depth)? {
+            [DEPTH] => {}
+            [depth] => return Err(FramebufferError::Unsupported(*depth)),
+            _ => return Err(FramebufferError::Unsupported(0)),
+        }
+
+        let (Some(&[width, height]), Some(&[address, size]), Some(&[pitch]), Some(&[order])) = (
+            message.response(physical).ok(),
+            message.response(buffer).ok(),
+            message.response(pitch).ok(),
+            message.response(order).ok(),
+        ) else {
+            return Err(FramebufferError::NoBuffer);
+        };
+
+        if address == 0 || size == 0 || (pitch as usize) < width as usize * BYTES_PER_PIXEL {
+            return Err(FramebufferError::NoBuffer);
+        }
+
+        Ok(Framebuffer {
+            base: phys_to_virt((address & BUS_MASK) as usize),
+            width: width as usize,
+            height: height as usize,
+            pitch: pitch as usize,
+            rgb: order != ORDER_BGR,
+        })
+    }
+
+    /// Width in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bytes from one line to the next
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    /// Colour the pixel at (`x`, `y`)
+    pub fn put_pixel(&mut self, x: usize, y: usize, colour: Rgb) {
+        if x < self.width && y < self.height {
+            let pixel = self.pixel(colour);
+            unsafe { self.line(y).add(x).write_volatile(pixel) };
+        }
+    }
+
+    /// Fill a `width` by `height` rectangle with its top left corner at (`x`, `y`)
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, colour: Rgb) {
+        let pixel = self.pixel(colour);
+        let right = x.saturating_add(width).min(self.width);
+        let bottom = y.saturating_add(height).min(self.height);
+
+        for y in y..bottom {
+            let line = self.line(y);
+            for x in x..right {
+                unsafe { line.add(x).write_volatile(pixel) };
+            }
+        }
+    }
+
+    /// Paint a 1 bit deep bitmap, `rows` holds one byte per line with the leftmost pixel
+    /// in the high bit
+    ///
+    /// Glyphs of a font are drawn with this.
+    pub fn draw_bitmap(&mut self, x: usize, y: usize, rows: &[u8], fg: Rgb, bg: Rgb) {
+        let (fg, bg) = (self.pixel(fg), self.pixel(bg));
+        let right = (x + 8).min(self.width);
+
+        for (y, bits) in (y..self.height).zip(rows) {
+            let line = self.line(y);
+            for (x, bit) in (x..right).zip((0..8).rev()) {
+                let pixel = if bits & (1 << bit) != 0 { fg } else { bg };
+                unsafe { line.add(x).write_volatile(pixel) };
+            }
+        }
+    }
+
+    /// Move the picture up by `lines` and fill the lines freed at the bottom
+    pub fn scroll_up(&mut self, lines: usize, fill: Rgb) {
+        let lines = lines.min(self.height);
+        let moved = (self.height - lines) * self.pitch;
+
+        unsafe {
+            let base = self.base as *mut u8;
+            core::ptr::copy(base.add(lines * self.pitch), base, moved);
+        }
+
+        self.fill_rect(0, self.height - lines, self.width, lines, fill);
+    }
+
+    /// Make lines `y..y + height` visible, they can still sit in the cache
+    pub fn flush(&self, y: usize, height: usize) {
+        let bottom = y.saturating_add(height).min(self.height);
+
+        if y < bottom {
+            mmu::clean_dcache(self.base + y * self.pitch, (bottom - y) * self.pitch);
+        }
+    }
+
+    /// First pixel of line `y`
+    fn line(&self, y: usize) -> *mut u32 {
+        (self.base + y * self.pitch) as *mut u32
+    }
+
+    /// The colour as it goes in memory
+    fn pixel(&self, Rgb(r, g, b): Rgb) -> u32 {
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+
+        match self.rgb {
+            true => (b << 16) | (g << 8) | r,
+            false => (r << 16) | (g << 8) | b,
+        }
+    }
+}
@@ -0,0 +1,476 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS GPIO
+//!
+//! The 54 GPIO pins of the BCM2837, with their mode in the type: a [Pin] starts out as
+//! an [Input] and changes mode by being consumed, so a pin configured for the UART can't
+//! be driven as an output by accident.
+//!
+//! A pin is claimed once with [Pin::take()] and stays claimed, in whatever mode it was
+//! left in, until it's handed back with [Pin::release()].
+//!
+//! Inputs can interrupt on edges or levels, see [Pin::listen()]. The handlers run from
+//! the IRQ of the GPIO banks with IRQs masked, the same rules as [interrupt::register()].
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 6)
+//!   - <https://elinux.org/BCM2835_datasheet_errata>
+//!
+
+use super::interrupt::{self, Irq, IrqError};
+use super::mmio;
+use crate::cpu::_spin_n;
+use crate::sync::once::Once;
+use crate::sync::ticket::TicketLock;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Number of pins
+pub const NUM_PINS: usize = 54;
+
+/// Interrupts of the three GPIO banks (pins 0-27, 28-45 and 46-53)
+const GPIO_IRQS: [Irq; 3] = [Irq::peripheral(49), Irq::peripheral(50), Irq::peripheral(51)];
+
+/// Cycles the pull control signals have to be held, from the datasheet
+const PULL_SETUP_CYCLES: usize = 150;
+
+/// Pull resistor of a pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Pull {
+    /// Floating
+    None = 0,
+    /// Pulled to ground
+    Down = 1,
+    /// Pulled to 3.3V
+    Up = 2,
+}
+
+/// What makes an input interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Low to high, synchronised to the system clock
+    Rising,
+    /// High to low, synchronised to the system clock
+    Falling,
+    /// Either edge
+    Both,
+    /// As long as the pin is high
+    High,
+    /// As long as the pin is low
+    Low,
+}
+
+/// A pin mode
+///
+/// Sealed, the modes are the ones in this module.
+pub trait Mode: private::Sealed {
+    /// Value of the pin's function select field
+    const FSEL: u32;
+}
+
+/// Mode of a pin read by software
+pub struct Input;
+/// Mode of a pin driven by software
+pub struct Output;
+/// Alternate function 0, the PL011 UART on GPIO14/15
+pub struct Alt0;
+/// Alternate function 1
+pub struct Alt1;
+/// Alternate function 2
+pub struct Alt2;
+/// Alternate function 3
+pub struct Alt3;
+/// Alternate function 4
+pub struct Alt4;
+/// Alternate function 5, the mini UART on GPIO14/15
+pub struct Alt5;
+
+impl Mode for Input {
+    const FSEL: u32 = 0b000;
+}
+impl Mode for Output {
+    const FSEL: u32 = 0b001;
+}
+impl Mode for Alt0 {
+    const FSEL: u32 = 0b100;
+}
+impl Mode for Alt1 {
+    const FSEL: u32 = 0b101;
+}
+impl Mode for Alt2 {
+    const FSEL: u32 = 0b110;
+}
+impl Mode for Alt3 {
+    const FSEL: u32 = 0b111;
+}
+impl Mode for Alt4 {
+    const FSEL: u32 = 0b011;
+}
+impl Mode for Alt5 {
+    const FSEL: u32 = 0b010;
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for super::Input {}
+    impl Sealed for super::Output {}
+    impl Sealed for super::Alt0 {}
+    impl Sealed for super::Alt1 {}
+    impl Sealed for super::Alt2 {}
+    impl Sealed for super::Alt3 {}
+    impl Sealed for super::Alt4 {}
+    impl Sealed for super::Alt5 {}
+}
+
+/// GPIO pin `N` in mode `M`
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::drivers::gpio::{Pin, Pull, Trigger};
+///
+/// let mut led = Pin::<21, _>::take().unwrap().into_output();
+/// led.set_high();
+///
+/// let button = Pin::<20, _>::take().unwrap();
+/// button.set_pull(Pull::Up);
+/// button.listen(Trigger::Falling, || println!("pressed")).unwrap();
+/// ```
+pub struct Pin<const N: usize, M: Mode> {
+    mode: PhantomData<M>,
+}
+
+impl<const N: usize> Pin<N, Input> {
+    /// Claim pin `N`, None if it's already claimed
+    ///
+    /// The pin is switched to [Input], its pull is left alone.
+    pub fn take() -> Option<Pin<N, Input>> {
+        const { assert!(N < NUM_PINS, "no such GPIO pin") };
+
+        match TAKEN.fetch_or(1 << N, Ordering::AcqRel) & (1 << N) {
+            0 => Some(Pin::<N, Input>::new()),
+            _ => None,
+        }
+    }
+
+    /// Whether the pin reads high
+    pub fn is_high(&self) -> bool {
+        GPIO.level(N)
+    }
+
+    /// Whether the pin reads low
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Call `handler` whenever `trigger` happens on the pin
+    ///
+    /// One handler per pin, fails if the pin already has one.
+    pub fn listen(&self, trigger: Trigger, handler: fn()) -> Result<(), IrqError> {
+        init_irqs();
+
+        HANDLERS[N]
+            .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .map_err(|_| IrqError)?;
+
+        GPIO.clear_event(N);
+        GPIO.set_trigger(N, Some(trigger));
+        Ok(())
+    }
+
+    /// Stop interrupting, and drop the handler
+    pub fn unlisten(&self) {
+        GPIO.set_trigger(N, None);
+        GPIO.clear_event(N);
+        HANDLERS[N].store(0, Ordering::Release);
+    }
+}
+
+impl<const N: usize> Pin<N, Output> {
+    /// Drive the pin high
+    pub fn set_high(&mut self) {
+        GPIO.set(N, true);
+    }
+
+    /// Drive the pin low
+    pub fn set_low(&mut self) {
+        GPIO.set(N, false);
+    }
+
+    /// Drive the pin high or low
+    pub fn set(&mut self, high: bool) {
+        GPIO.set(N, high);
+    }
+
+    /// Drive the pin to the other level
+    pub fn toggle(&mut self) {
+        GPIO.set(N, !self.is_set_high());
+    }
+
+    /// Whether the pin is driven high
+    pub fn is_set_high(&self) -> bool {
+        GPIO.level(N)
+    }
+}
+
+impl<const N: usize, M: Mode> Pin<N, M> {
+    fn new() -> Pin<N, M> {
+        GPIO.select(N, M::FSEL);
+        Pin { mode: PhantomData }
+    }
+
+    /// The pin number
+    pub const fn number(&self) -> usize {
+        N
+    }
+
+    /// Set the pull resistor
+    pub fn set_pull(&self, pull: Pull) {
+        GPIO.set_pull(N, pull);
+    }
+
+    /// Switch to [Input]
+    pub fn into_input(self) -> Pin<N, Input> {
+        self.into_mode()
+    }
+
+    /// Switch to [Output], the pin keeps the level it was last set to
+    pub fn into_output(self) -> Pin<N, Output> {
+        self.into_mode()
+    }
+
+    /// Switch to alternate function 0
+    pub fn into_alt0(self) -> Pin<N, Alt0> {
+        self.into_mode()
+    }
+
+    /// Switch to alternate function 5
+    pub fn into_alt5(self) -> Pin<N, Alt5> {
+        self.into_mode()
+    }
+
+    /// Switch to any mode
+    pub fn into_mode<M2: Mode>(self) -> Pin<N, M2> {
+        // Interrupts only belong to inputs
+        GPIO.set_trigger(N, None);
+        HANDLERS[N].store(0, Ordering::Release);
+
+        Pin::<N, M2>::new()
+    }
+
+    /// Hand the pin back, as an input with the pull resistor off
+    pub fn release(self) {
+        let pin = self.into_input();
+        pin.set_pull(Pull::None);
+
+        TAKEN.fetch_and(!(1 << N), Ordering::AcqRel);
+    }
+}
+
+/// Pins work with drivers written against `embedded-hal`
+impl<const N: usize, M: Mode> embedded_hal::digital::ErrorType for Pin<N, M> {
+    type Error = core::convert::Infallible;
+}
+
+impl<const N: usize> embedded_hal::digital::InputPin for Pin<N, Input> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_low(self))
+    }
+}
+
+impl<const N: usize> embedded_hal::digital::OutputPin for Pin<N, Output> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Pin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Pin::set_high(self);
+        Ok(())
+    }
+}
+
+impl<const N: usize> embedded_hal::digital::StatefulOutputPin for Pin<N, Output> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_set_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!Pin::is_set_high(self))
+    }
+}
+
+/// BCM2837 GPIO registers
+///
+/// Every register has one bit per pin over two words, except function select with 3 bits
+/// per pin over six.
+struct Gpio<const T: usize> {
+    /// Function select and the pull sequence are read-modify-write or multi step
+    lock: TicketLock<()>,
+}
+
+impl<const T: usize> Gpio<T> {
+    const GPFSEL0: usize = T;
+    const GPSET0: usize = T + 0x1C;
+    const GPCLR0: usize = T + 0x28;
+    const GPLEV0: usize = T + 0x34;
+    const GPEDS0: usize = T + 0x40;
+    const GPREN0: usize = T + 0x4C;
+    const GPFEN0: usize = T + 0x58;
+    const GPHEN0: usize = T + 0x64;
+    const GPLEN0: usize = T + 0x70;
+    const GPAREN0: usize = T + 0x7C;
+    const GPAFEN0: usize = T + 0x88;
+    const GPPUD: usize = T + 0x94;
+    const GPPUDCLK0: usize = T + 0x98;
+
+    const fn new() -> Gpio<T> {
+        Gpio { lock: TicketLock::new(()) }
+    }
+
+    /// Register and bit of `pin` in a one bit per pin register
+    fn bit(base: usize, pin: usize) -> (usize, u32) {
+        (base + (pin / 32) * 4, 1 << (pin % 32))
+    }
+
+    fn select(&self, pin: usize, fsel: u32) {
+        let reg = Self::GPFSEL0 + (pin / 10) * 4;
+        let shift = (pin % 10) * 3;
+
+        let _guard = self.lock.lock();
+        unsafe { mmio::modify32(reg, 0b111 << shift, fsel << shift) };
+    }
+
+    /// Set and clear are write-1 registers, no lock needed
+    fn set(&self, pin: usize, high: bool) {
+        let base = match high {
+            true => Self::GPSET0,
+            false => Self::GPCLR0,
+        };
+        let (reg, bit) = Self::bit(base, pin);
+
+        unsafe { mmio::write32(reg, bit) };
+    }
+
+    fn level(&self, pin: usize) -> bool {
+        let (reg, bit) = Self::bit(Self::GPLEV0, pin);
+        unsafe { mmio::read32(reg) & bit != 0 }
+    }
+
+    /// The BCM2837 sequence: set the control signal, clock it into the pin, then remove both
+    fn set_pull(&self, pin: usize, pull: Pull) {
+        let (reg, bit) = Self::bit(Self::GPPUDCLK0, pin);
+
+        let _guard = self.lock.lock();
+        unsafe {
+            mmio::write32(Self::GPPUD, pull as u32);
+            _spin_n(PULL_SETUP_CYCLES);
+            mmio::write32(reg, bit);
+            _spin_n(PULL_SETUP_CYCLES);
+            mmio::write32(Self::GPPUD, 0);
+            mmio::write32(reg, 0);
+        }
+    }
+
+    /// Enable `trigger` for `pin` and disable the others, None disables all of them
+    fn set_trigger(&self, pin: usize, trigger: Option<Trigger>) {
+        let detects = [
+            (Self::GPAREN0, matches!(trigger, Some(Trigger::Rising | Trigger::Both))),
+            (Self::GPAFEN0, matches!(trigger, Some(Trigger::Falling | Trigger::Both))),
+            (Self::GPHEN0, trigger == Some(Trigger::High)),
+            (Self::GPLEN0, trigger == Some(Trigger::Low)),
+            // The synchronous edge detects stay off, the asynchronous ones catch short pulses
+            (Self::GPREN0, false),
+            (Self::GPFEN0, false),
+        ];
+
+        let _guard = self.lock.lock();
+        for (base, enabled) in detects {
+            let (reg, bit) = Self::bit(base, pin);
+            unsafe { mmio::modify32(reg, bit, if enabled { bit } else { 0 }) };
+        }
+    }
+
+    fn clear_event(&self, pin: usize) {
+        let (reg, bit) = Self::bit(Self::GPEDS0, pin);
+        unsafe { mmio::write32(reg, bit) };
+    }
+
+    /// Take the pending events of both words, clearing them
+    fn take_events(&self) -> u64 {
+        let mut events = 0;
+
+        for word in 0..2 {
+            let reg = Self::GPEDS0 + word * 4;
+            let pending = unsafe { mmio::read32(reg) };
+
+            unsafe { mmio::write32(reg, pending) };
+            events |= (pending as u64) << (word * 32);
+        }
+
+        events
+    }
+}
+
+static GPIO: Gpio<0x3F20_0000> = Gpio::new();
+
+/// Claimed pins, one bit each
+static TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupt handlers of the pins, 0 means none, like [interrupt]'s own table
+static HANDLERS: [AtomicUsize; NUM_PINS] = [const { AtomicUsize::new(0) }; NUM_PINS];
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// Install [dispatch()] on the bank interrupts, the first time a pin listens
+///
+/// Nothing else handles those interrupts, so registering can't fail.
+fn init_irqs() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        for irq in GPIO_IRQS {
+            if interrupt::register(irq, dispatch).is_ok() {
+                interrupt::enable(irq);
+            }
+        }
+    });
+}
+
+/// Run the handlers of every pin with a pending event
+fn dispatch() {
+    let mut events = GPIO.take_events();
+
+    while events != 0 {
+        let pin = events.trailing_zeros() as usize;
+        events &= events - 1;
+
+        match HANDLERS.get(pin).map(|handler| handler.load(Ordering::Acquire)) {
+            Some(0) | None => {}
+            Some(handler) => {
+                let handler: fn() = unsafe { core::mem::transmute(handler) };
+                handler();
+            }
+        }
+    }
+}
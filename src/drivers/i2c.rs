@@ -0,0 +1,497 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS I2C
+//!
+//! The BCM2835 BSC1 master on the header's GPIO2 (SDA) and GPIO3 (SCL), as an
+//! [embedded_hal::i2c::I2c] with 7 bit addresses.
+//!
+//! Like [SPI](super::spi) it polls, or with [Config::interrupts] sleeps until the
+//! controller needs its FIFO serviced or is done.
+//!
+//! The controller only knows whole transfers that end in a stop. Consecutive operations of
+//! the same kind go out as one transfer, and a write of up to 16 bytes followed by reads
+//! gets its repeated start by queuing the read while the write is still going, the usual
+//! register read. Anything else is separate transfers with a stop between them.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 3)
+//!   - <https://docs.rs/embedded-hal/1.0.0/embedded_hal/i2c/index.html>
+//!
+
+use super::gpio::{Alt0, Pin};
+use super::interrupt::{self, Irq};
+use super::mailbox::{self, ClockId};
+use super::mmio;
+use crate::cpu::timer;
+use crate::sync::futex::{futex_wait, futex_wake, FutexError};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource, Operation, SevenBitAddress};
+
+/// Core clock if the firmware won't say, the Pi 3's default
+const CORE_CLOCK: u32 = 250_000_000;
+
+/// Bytes the FIFO holds
+const FIFO_LEN: usize = 16;
+
+/// Longest a transfer may go without the controller making progress
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Peripheral interrupt of the BSC controllers
+const IRQ: Irq = Irq::peripheral(53);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for the I2C driver
+pub enum I2cError {
+    /// Another driver holds the I2C pins
+    PinsTaken,
+    /// The address or a byte wasn't acknowledged
+    NoAcknowledge,
+    /// The device held SCL low for too long
+    ClockStretchTimeout,
+    /// The controller stopped moving data
+    Timeout,
+    /// Over 65535 bytes in one direction without a change of direction
+    TooLong,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for I2cError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            I2cError::PinsTaken => f.write_str("I2C pins are in use"),
+            I2cError::NoAcknowledge => f.write_str("I2C device didn't acknowledge"),
+            I2cError::ClockStretchTimeout => f.write_str("I2C device stretched the clock for too long"),
+            I2cError::Timeout => f.write_str("I2C transfer timed out"),
+            I2cError::TooLong => f.write_str("I2C transfer is too long"),
+        }
+    }
+}
+
+impl embedded_hal::i2c::Error for I2cError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            // The controller doesn't say which byte it was
+            I2cError::NoAcknowledge => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            I2cError::ClockStretchTimeout => ErrorKind::Bus,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// How to set up the bus
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Clock in Hz, rounded down to what the divider can make
+    pub frequency: u32,
+    /// Sleep on the interrupt instead of polling
+    pub interrupts: bool,
+}
+
+impl Default for Config {
+    /// Standard mode (100kHz), polling
+    fn default() -> Config {
+        Config {
+            frequency: 100_000,
+            interrupts: false,
+        }
+    }
+}
+
+/// The BSC1 master
+///
+/// Holds its pins until dropped, so there is only ever one. The Pi has pull-ups on both
+/// lines, the pins' own are left alone.
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::drivers::i2c::{Config, I2c};
+/// use embedded_hal::i2c::I2c as _;
+///
+/// let mut i2c = I2c::new(Config::default()).unwrap();
+/// let mut id = [0];
+/// i2c.write_read(0x76, &[0xD0], &mut id).unwrap();
+/// ```
+pub struct I2c {
+    pins: Option<(Pin<2, Alt0>, Pin<3, Alt0>)>,
+    interrupts: bool,
+}
+
+impl I2c {
+    /// Claim the pins and set up the controller
+    ///
+    /// Asks the firmware for the core clock, so it needs the heap. Falls back to polling if
+    /// the interrupt has another handler.
+    pub fn new(config: Config) -> Result<I2c, I2cError> {
+        let pins = match (Pin::<2, _>::take(), Pin::<3, _>::take()) {
+            (Some(sda), Some(scl)) => (sda.into_alt0(), scl.into_alt0()),
+            (sda, scl) => {
+                sda.into_iter().for_each(Pin::release);
+                scl.into_iter().for_each(Pin::release);
+                return Err(I2cError::PinsTaken);
+            }
+        };
+        let clock = mailbox::get_clock_rate(ClockId::Core).unwrap_or(CORE_CLOCK);
+
+        let interrupts = config.interrupts && interrupt::register(IRQ, handle_interrupt).is_ok();
+        if interrupts {
+            interrupt::enable(IRQ);
+        }
+
+        BSC1.setup(divider(clock, config.frequency));
+
+        Ok(I2c {
+            pins: Some(pins),
+            interrupts,
+        })
+    }
+
+    /// One or two transfers: `write`'s bytes, then with a repeated start `read`'s
+    ///
+    /// The repeated start needs all of `write` in the FIFO before the read is queued.
+    fn run(&mut self, address: u8, write: Option<&[Operation]>, read: Option<&mut [Operation]>) -> Result<(), I2cError> {
+        let write_len = write.map_or(Ok(0), total)?;
+        let read_len = read.as_deref().map_or(Ok(0), total)?;
+
+        BSC1.set_address(address);
+
+        let result = (|| {
+            if let Some(write) = write {
+                let bytes = write.iter().flat_map(|op| match op {
+                    Operation::Write(bytes) => bytes.iter(),
+                    Operation::Read(_) => Default::default(),
+                });
+
+                BSC1.start(write_len, false);
+                // With a read queued, only until the controller has sent the address
+                let until = match read {
+                    Some(_) => Bsc1::S_TA | Bsc1::S_DONE,
+                    None => Bsc1::S_DONE,
+                };
+                self.pump(bytes.copied(), core::iter::empty(), until)?;
+            }
+
+            if let Some(read) = read {
+                let bytes = read.iter_mut().flat_map(|op| match op {
+                    Operation::Read(bytes) => bytes.iter_mut(),
+                    Operation::Write(_) => Default::default(),
+                });
+
+                BSC1.start(read_len, true);
+                self.pump(core::iter::empty(), bytes, Bsc1::S_DONE)?;
+            }
+
+            Ok(())
+        })();
+
+        BSC1.finish();
+        result
+    }
+
+    /// Move bytes through the FIFO until `tx` is sent, `rx` is filled and a status bit in
+    /// `until` is set
+    ///
+    /// TA raises no interrupt, waiting for it always polls.
+    fn pump<'a>(
+        &mut self,
+        tx: impl Iterator<Item = u8>,
+        rx: impl Iterator<Item = &'a mut u8>,
+        until: u32,
+    ) -> Result<(), I2cError> {
+        let mut tx = tx.peekable();
+        let mut rx = rx.peekable();
+        let mut deadline = timer::uptime() + TIMEOUT;
+
+        loop {
+            let mut progress = false;
+
+            while tx.peek().is_some() && BSC1.can_write() {
+                BSC1.write(tx.next().unwrap_or(0));
+                progress = true;
+            }
+            while BSC1.can_read() {
+                let byte = BSC1.read();
+                if let Some(slot) = rx.next() {
+                    *slot = byte;
+                }
+                progress = true;
+            }
+
+            let status = BSC1.status();
+            BSC1.check(status)?;
+
+            if status & until != 0 && tx.peek().is_none() && rx.peek().is_none() && !BSC1.can_read() {
+                return Ok(());
+            }
+
+            let now = timer::uptime();
+            if progress {
+                deadline = now + TIMEOUT;
+            } else if now >= deadline {
+                return Err(I2cError::Timeout);
+            }
+
+            if self.interrupts && until & Bsc1::S_TA == 0 {
+                wait_for_interrupt(tx.peek().is_some(), deadline - now);
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Drop for I2c {
+    fn drop(&mut self) {
+        if self.interrupts {
+            interrupt::unregister(IRQ);
+        }
+
+        if let Some((sda, scl)) = self.pins.take() {
+            sda.release();
+            scl.release();
+        }
+    }
+}
+
+impl embedded_hal::i2c::ErrorType for I2c {
+    type Error = I2cError;
+}
+
+impl embedded_hal::i2c::I2c<SevenBitAddress> for I2c {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), I2cError> {
+        let mut operations = operations;
+
+        while let Some(first) = operations.first() {
+            let reading = matches!(first, Operation::Read(_));
+            let group = same_kind(operations);
+            let (current, rest) = core::mem::take(&mut operations).split_at_mut(group);
+
+            if reading {
+                self.run(address, None, Some(current))?;
+                operations = rest;
+                continue;
+            }
+
+            // A short write and the reads after it, joined by a repeated start
+            let reads = same_kind(rest);
+            if reads > 0 && total(current)? <= FIFO_LEN {
+                let (next, rest) = rest.split_at_mut(reads);
+                self.run(address, Some(current), Some(next))?;
+                operations = rest;
+            } else {
+                self.run(address, Some(current), None)?;
+                operations = rest;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// BCM2835 BSC registers
+struct Controller<const T: usize>;
+
+impl<const T: usize> Controller<T> {
+    const C: usize = T;
+    const C_READ: u32 = 1 << 0;
+    const C_CLEAR: u32 = 0b11 << 4;
+    const C_ST: u32 = 1 << 7;
+    /// Interrupt on DONE
+    const C_INTD: u32 = 1 << 8;
+    /// Interrupt on TXW
+    const C_INTT: u32 = 1 << 9;
+    /// Interrupt on RXR
+    const C_INTR: u32 = 1 << 10;
+    const C_I2CEN: u32 = 1 << 15;
+    const S: usize = T + 0x04;
+    /// Transfer active
+    const S_TA: u32 = 1 << 0;
+    const S_DONE: u32 = 1 << 1;
+    /// FIFO is less than a quarter full while writing
+    const S_TXW: u32 = 1 << 2;
+    /// FIFO is three quarters full while reading
+    const S_RXR: u32 = 1 << 3;
+    /// FIFO has room
+    const S_TXD: u32 = 1 << 4;
+    /// FIFO has data
+    const S_RXD: u32 = 1 << 5;
+    /// No acknowledge
+    const S_ERR: u32 = 1 << 8;
+    /// Clock stretch timeout
+    const S_CLKT: u32 = 1 << 9;
+    const DLEN: usize = T + 0x08;
+    const A: usize = T + 0x0C;
+    const FIFO: usize = T + 0x10;
+    const DIV: usize = T + 0x14;
+
+    fn setup(&self, divider: u32) {
+        unsafe {
+            mmio::write32(Self::C, Self::C_I2CEN | Self::C_CLEAR);
+            mmio::write32(Self::S, Self::S_CLKT | Self::S_ERR | Self::S_DONE);
+            mmio::write32(Self::DIV, divider);
+        }
+    }
+
+    fn set_address(&self, address: u8) {
+        unsafe { mmio::write32(Self::A, address as u32 & 0x7F) };
+    }
+
+    /// Start a transfer of `len` bytes
+    ///
+    /// Written while a write is still active this queues a repeated start for after it. A
+    /// DONE left from an earlier transfer is cleared, it would end this one's wait early.
+    fn start(&self, len: usize, read: bool) {
+        let read = if read { Self::C_READ } else { 0 };
+
+        unsafe {
+            mmio::write32(Self::S, Self::S_DONE);
+            mmio::write32(Self::DLEN, len as u32);
+            mmio::write32(Self::C, Self::C_I2CEN | Self::C_ST | read);
+        }
+    }
+
+    /// Drop anything left in the FIFO and clear the status, ready for the next transfer
+    fn finish(&self) {
+        unsafe {
+            mmio::write32(Self::C, Self::C_I2CEN | Self::C_CLEAR);
+            mmio::write32(Self::S, Self::S_CLKT | Self::S_ERR | Self::S_DONE);
+        }
+    }
+
+    fn status(&self) -> u32 {
+        unsafe { mmio::read32(Self::S) }
+    }
+
+    /// The error `status` reports, if any
+    fn check(&self, status: u32) -> Result<(), I2cError> {
+        match status {
+            s if s & Self::S_ERR != 0 => Err(I2cError::NoAcknowledge),
+            s if s & Self::S_CLKT != 0 => Err(I2cError::ClockStretchTimeout),
+            _ => Ok(()),
+        }
+    }
+
+    fn can_write(&self) -> bool {
+        self.status() & Self::S_TXD != 0
+    }
+
+    fn can_read(&self) -> bool {
+        self.status() & Self::S_RXD != 0
+    }
+
+    fn write(&self, byte: u8) {
+        unsafe { mmio::write32(Self::FIFO, byte as u32) };
+    }
+
+    fn read(&self) -> u8 {
+        unsafe { mmio::read32(Self::FIFO) as u8 }
+    }
+
+    /// Interrupt when the transfer is done, the FIFO fills up while reading, and if
+    /// `writing` when it runs low
+    fn enable_interrupts(&self, writing: bool) {
+        let mask = Self::C_INTD | Self::C_INTR | Self::C_INTT;
+        let enable = match writing {
+            true => mask,
+            false => Self::C_INTD | Self::C_INTR,
+        };
+
+        unsafe { mmio::modify32(Self::C, mask, enable) };
+    }
+
+    /// The interrupt is level triggered on DONE, TXW and RXR, masking it is the only way to
+    /// quiet it before the FIFO is serviced
+    fn disable_interrupts(&self) -> bool {
+        let mask = Self::C_INTD | Self::C_INTR | Self::C_INTT;
+
+        unsafe {
+            mmio::modify32(Self::C, mask, 0);
+            self.status() & (Self::S_DONE | Self::S_TXW | Self::S_RXR) != 0
+        }
+    }
+}
+
+/// BSC1, the one on the header
+type Bsc1 = Controller<0x3F80_4000>;
+
+static BSC1: Bsc1 = Controller;
+
+/// Set by the interrupt, the futex a transfer sleeps on
+static WAKE: AtomicU32 = AtomicU32::new(0);
+
+/// Operations at the start of `operations` of the same kind as the first
+fn same_kind(operations: &[Operation]) -> usize {
+    let reading = |op: &Operation| matches!(op, Operation::Read(_));
+
+    match operations.first() {
+        Some(first) => operations.iter().take_while(|op| reading(op) == reading(first)).count(),
+        None => 0,
+    }
+}
+
+/// Bytes `operations` move, a transfer's length register is 16 bits
+fn total(operations: &[Operation]) -> Result<usize, I2cError> {
+    let len = operations
+        .iter()
+        .map(|op| match op {
+            Operation::Read(bytes) => bytes.len(),
+            Operation::Write(bytes) => bytes.len(),
+        })
+        .sum();
+
+    match len {
+        0..=0xFFFF => Ok(len),
+        _ => Err(I2cError::TooLong),
+    }
+}
+
+/// Divider for the fastest clock not above `frequency`
+///
+/// It's rounded down to even by the hardware, 0 divides by 32768.
+fn divider(clock: u32, frequency: u32) -> u32 {
+    let divider = clock.div_ceil(frequency.max(1)).next_multiple_of(2);
+
+    match divider {
+        0..=2 => 2,
+        32768.. => 0,
+        divider => divider,
+    }
+}
+
+/// Sleep until the controller wants attention, or at most `timeout`
+///
+/// Spins once if the caller can't sleep.
+fn wait_for_interrupt(writing: bool, timeout: Duration) {
+    WAKE.store(0, Ordering::Relaxed);
+    BSC1.enable_interrupts(writing);
+
+    if let Err(FutexError::NotAllowed) = futex_wait(&WAKE, 0, Some(timeout)) {
+        core::hint::spin_loop();
+    }
+
+    BSC1.disable_interrupts();
+}
+
+fn handle_interrupt() {
+    if BSC1.disable_interrupts() {
+        WAKE.store(1, Ordering::Release);
+        futex_wake(&WAKE, usize::MAX);
+    }
+}
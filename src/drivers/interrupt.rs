@@ -0,0 +1,223 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Interrupt controllers
+//!
+//! The BCM2837 has two: the ARM local controller (0x4000_0000) with the per-core
+//! timer and mailbox interrupts, and the BCM2835 peripheral controller (0x3F00_B200)
+//! that all the GPU side devices go through. Peripheral interrupts show up on the
+//! local controller as a single GPU source, which is routed to core 0.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2836/bcm2836-peripherals.pdf>
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 7)
+//!
+
+use super::mmio;
+use crate::cpu::smp::core_id;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of local interrupt sources
+const LOCAL_IRQS: usize = 16;
+
+/// Number of peripheral interrupt sources (pending 1 and 2)
+const PERIPHERAL_IRQS: usize = 64;
+
+/// Total number of interrupt numbers
+pub const NUM_IRQS: usize = LOCAL_IRQS + PERIPHERAL_IRQS;
+
+/// Local source that means "look at the peripheral controller"
+const LOCAL_GPU: usize = 8;
+
+#[derive(Debug)]
+/// An error type for interrupt registration
+pub struct IrqError;
+
+/// Allows printing the error
+impl core::fmt::Display for IrqError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("Interrupt already has a handler")
+    }
+}
+
+/// Interrupt number
+///
+/// Local sources are 0-15, peripheral interrupt `n` is `16 + n`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Irq(usize);
+
+impl Irq {
+    /// Secure physical timer
+    pub const CNTPS: Irq = Irq(0);
+    /// Non-secure physical timer, the scheduler tick
+    pub const CNTPNS: Irq = Irq(1);
+    /// Hypervisor timer
+    pub const CNTHP: Irq = Irq(2);
+    /// Virtual timer
+    pub const CNTV: Irq = Irq(3);
+
+    /// Peripheral interrupt `n` (0-63) of the BCM2835 controller
+    pub const fn peripheral(n: usize) -> Irq {
+        Irq(LOCAL_IRQS + n)
+    }
+
+    /// The interrupt number
+    pub const fn number(&self) -> usize {
+        self.0
+    }
+
+    const fn is_local(&self) -> bool {
+        self.0 < LOCAL_IRQS
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Controllers
+//--------------------------------------------------------------------------------------------------
+
+/// BCM2836 ARM local interrupt controller
+struct LocalController<const T: usize>;
+
+impl<const T: usize> LocalController<T> {
+    const TIMER_CNTL: usize = T + 0x40;
+    const MAILBOX_CNTL: usize = T + 0x50;
+    const IRQ_SOURCE: usize = T + 0x60;
+
+    /// Route a timer or mailbox interrupt to the calling core
+    fn set_enabled(&self, irq: usize, enabled: bool) {
+        let core = core_id();
+        let (reg, bit) = match irq {
+            0..=3 => (Self::TIMER_CNTL + core * 4, irq),
+            4..=7 => (Self::MAILBOX_CNTL + core * 4, irq - 4),
+            _ => return,
+        };
+
+        unsafe { mmio::modify32(reg, 1 << bit, (enabled as u32) << bit) };
+    }
+
+    /// Pending sources of the calling core, bits 12+ are reserved
+    fn pending(&self) -> u32 {
+        unsafe { mmio::read32(Self::IRQ_SOURCE + core_id() * 4) & 0xFFF }
+    }
+}
+
+/// BCM2835 peripheral interrupt controller
+struct PeripheralController<const T: usize>;
+
+impl<const T: usize> PeripheralController<T> {
+    const PENDING_1: usize = T + 0x04;
+    const PENDING_2: usize = T + 0x08;
+    const ENABLE_1: usize = T + 0x10;
+    const DISABLE_1: usize = T + 0x1C;
+
+    /// Enable and disable registers are write-1-to-set, no read-modify-write needed
+    fn set_enabled(&self, n: usize, enabled: bool) {
+        let base = match enabled {
+            true => Self::ENABLE_1,
+            false => Self::DISABLE_1,
+        };
+
+        unsafe { mmio::write32(base + (n / 32) * 4, 1 << (n % 32)) };
+    }
+
+    fn pending(&self) -> u64 {
+        unsafe { mmio::read32(Self::PENDING_1) as u64 | (mmio::read32(Self::PENDING_2) as u64) << 32 }
+    }
+}
+
+static LOCAL_CONTROLLER: LocalController<0x4000_0000> = LocalController;
+static PERIPHERAL_CONTROLLER: PeripheralController<0x3F00_B200> = PeripheralController;
+
+/// Registered handlers, 0 means none
+///
+/// Function pointers stored as usize so the table can be read from IRQ context without a lock.
+static HANDLERS: [AtomicUsize; NUM_IRQS] = [const { AtomicUsize::new(0) }; NUM_IRQS];
+
+/// Call the handler of an interrupt, or mask it if there is none
+fn handle(irq: Irq) {
+    match HANDLERS[irq.0].load(Ordering::Acquire) {
+        0 => disable(irq),
+        handler => {
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Install the handler of an interrupt
+///
+/// Handlers run with IRQs masked and must not block. Only one handler per interrupt.
+pub fn register(irq: Irq, handler: fn()) -> Result<(), IrqError> {
+    match HANDLERS[irq.0].compare_exchange(
+        0,
+        handler as usize,
+        Ordering::AcqRel,
+        Ordering::Relaxed,
+    ) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(IrqError),
+    }
+}
+
+/// Remove the handler of an interrupt and mask it
+pub fn unregister(irq: Irq) {
+    disable(irq);
+    HANDLERS[irq.0].store(0, Ordering::Release);
+}
+
+/// Unmask an interrupt
+///
+/// Local interrupts are only unmasked for the calling core.
+pub fn enable(irq: Irq) {
+    match irq.is_local() {
+        true => LOCAL_CONTROLLER.set_enabled(irq.0, true),
+        false => PERIPHERAL_CONTROLLER.set_enabled(irq.0 - LOCAL_IRQS, true),
+    }
+}
+
+/// Mask an interrupt
+pub fn disable(irq: Irq) {
+    match irq.is_local() {
+        true => LOCAL_CONTROLLER.set_enabled(irq.0, false),
+        false => PERIPHERAL_CONTROLLER.set_enabled(irq.0 - LOCAL_IRQS, false),
+    }
+}
+
+/// Run the handlers of every pending interrupt on the calling core
+///
+/// Called by the IRQ vector, see [crate::cpu::exception].
+pub fn dispatch() {
+    let mut local = LOCAL_CONTROLLER.pending();
+
+    while local != 0 {
+        let source = local.trailing_zeros() as usize;
+        local &= local - 1;
+
+        if source != LOCAL_GPU {
+            handle(Irq(source));
+            continue;
+        }
+
+        let mut peripheral = PERIPHERAL_CONTROLLER.pending();
+        while peripheral != 0 {
+            let n = peripheral.trailing_zeros() as usize;
+            peripheral &= peripheral - 1;
+            handle(Irq::peripheral(n));
+        }
+    }
+}
@@ -0,0 +1,447 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Mailbox
+//!
+//! The property interface of the VideoCore firmware, through the mailbox at 0x3F00_B880.
+//! Board information, memory split, clocks, power domains and the framebuffer are all
+//! behind it.
+//!
+//! A [Message] holds any number of tags. [Message::tag()] adds one and returns the
+//! [TagSlot] its response is read from after [Message::send()]. The functions at the
+//! bottom wrap the common single tag requests.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface>
+//!   - <https://github.com/raspberrypi/firmware/wiki/Accessing-mailboxes>
+//!   - <https://jsandler18.github.io/extra/prop-channel.html>
+//!
+
+use super::mmio;
+use crate::cpu::{mmu, timer};
+use crate::memory::virt_to_phys;
+use crate::sync::mutex::Mutex;
+use alloc::boxed::Box;
+use core::time::Duration;
+
+/// Words in a message buffer
+const BUFFER_WORDS: usize = 256;
+
+/// Channel of the property interface, ARM to VideoCore
+const CHANNEL_PROPERTY: u32 = 8;
+
+/// Request code of a message
+const REQUEST: u32 = 0;
+/// Response codes of a message
+const RESPONSE_OK: u32 = 0x8000_0000;
+const RESPONSE_ERROR: u32 = 0x8000_0001;
+/// Set in a tag's code once the firmware answered it, the rest is the response length
+const TAG_RESPONSE: u32 = 1 << 31;
+
+/// The VideoCore sees ARM physical memory here, uncached
+const BUS_ALIAS: u32 = 0xC000_0000;
+
+/// How long the firmware gets to answer
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for the mailbox
+pub enum MailboxError {
+    /// The message has no room for another tag
+    Full,
+    /// The firmware didn't answer
+    Timeout,
+    /// The firmware couldn't parse the message
+    BadMessage,
+    /// The firmware didn't answer this tag, it probably doesn't know it
+    Unanswered(Tag),
+    /// The response is shorter than expected
+    ShortResponse(Tag),
+    /// The mailbox couldn't be locked
+    Lock,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MailboxError::Full => f.write_str("Mailbox message is full"),
+            MailboxError::Timeout => f.write_str("Firmware didn't answer"),
+            MailboxError::BadMessage => f.write_str("Firmware rejected the message"),
+            MailboxError::Unanswered(tag) => write!(f, "Firmware didn't answer tag {:#010x}", tag.0),
+            MailboxError::ShortResponse(tag) => write!(f, "Short response to tag {:#010x}", tag.0),
+            MailboxError::Lock => f.write_str("Failed to lock the mailbox"),
+        }
+    }
+}
+
+/// A property tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tag(u32);
+
+impl Tag {
+    /// Firmware revision
+    pub const GET_FIRMWARE_REVISION: Tag = Tag(0x0000_0001);
+    /// Board model
+    pub const GET_BOARD_MODEL: Tag = Tag(0x0001_0001);
+    /// Board revision, the code in `/proc/cpuinfo`
+    pub const GET_BOARD_REVISION: Tag = Tag(0x0001_0002);
+    /// MAC address of the ethernet
+    pub const GET_BOARD_MAC_ADDRESS: Tag = Tag(0x0001_0003);
+    /// Board serial number
+    pub const GET_BOARD_SERIAL: Tag = Tag(0x0001_0004);
+    /// Base and size of the ARM's memory
+    pub const GET_ARM_MEMORY: Tag = Tag(0x0001_0005);
+    /// Base and size of the VideoCore's memory
+    pub const GET_VC_MEMORY: Tag = Tag(0x0001_0006);
+    /// Whether a device is powered
+    pub const GET_POWER_STATE: Tag = Tag(0x0002_0001);
+    /// Power a device on or off
+    pub const SET_POWER_STATE: Tag = Tag(0x0002_8001);
+    /// Rate a clock runs at
+    pub const GET_CLOCK_RATE: Tag = Tag(0x0003_0002);
+    /// Fastest rate a clock can run at
+    pub const GET_MAX_CLOCK_RATE: Tag = Tag(0x0003_0004);
+    /// Change the rate of a clock
+    pub const SET_CLOCK_RATE: Tag = Tag(0x0003_8002);
+    /// SoC temperature in thousandths of a degree
+    pub const GET_TEMPERATURE: Tag = Tag(0x0003_0006);
+    /// Allocate the framebuffer
+    pub const ALLOCATE_BUFFER: Tag = Tag(0x0004_0001);
+    /// Release the framebuffer
+    pub const RELEASE_BUFFER: Tag = Tag(0x0004_8001);
+    /// Size of the display
+    pub const SET_PHYSICAL_SIZE: Tag = Tag(0x0004_8003);
+    /// Size of the framebuffer, can be larger than the display
+    pub const SET_VIRTUAL_SIZE: Tag = Tag(0x0004_8004);
+    /// Bits per pixel
+    pub const SET_DEPTH: Tag = Tag(0x0004_8005);
+    /// RGB or BGR
+    pub const SET_PIXEL_ORDER: Tag = Tag(0x0004_8006);
+    /// Bytes per line of the framebuffer
+    pub const GET_PITCH: Tag = Tag(0x0004_0008);
+    /// Which part of the framebuffer is shown
+    pub const SET_VIRTUAL_OFFSET: Tag = Tag(0x0004_8009);
+
+    /// A tag without a constant here
+    pub const fn new(id: u32) -> Tag {
+        Tag(id)
+    }
+
+    /// The tag's id
+    pub const fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Clocks of the board
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ClockId {
+    /// The SD card controller
+    Emmc = 1,
+    /// The PL011 UART
+    Uart = 2,
+    /// The ARM cores
+    Arm = 3,
+    /// The VideoCore, and the APB peripherals behind it
+    Core = 4,
+    /// 3D block
+    V3d = 5,
+    /// Video encoder
+    H264 = 6,
+    /// Image sensor pipeline
+    Isp = 7,
+    /// SDRAM
+    Sdram = 8,
+    /// Display pixel clock
+    Pixel = 9,
+    /// PWM
+    Pwm = 10,
+}
+
+/// Devices with their own power domain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PowerDevice {
+    /// SD card
+    SdCard = 0,
+    /// PL011 UART
+    Uart0 = 1,
+    /// Mini UART
+    Uart1 = 2,
+    /// USB controller
+    UsbHcd = 3,
+    /// I2C buses
+    I2c0 = 4,
+    /// I2C bus 1
+    I2c1 = 5,
+    /// I2C bus 2
+    I2c2 = 6,
+    /// SPI
+    Spi = 7,
+    /// Camera transmitter
+    Ccp2tx = 8,
+}
+
+/// Message buffer, the mailbox only takes 16 byte aligned addresses
+///
+/// Aligned to the 64 byte cache lines (its size follows), so no other heap object shares a
+/// line that `Mailbox::call` invalidates while the firmware writes the response.
+#[repr(C, align(64))]
+struct Buffer([u32; BUFFER_WORDS]);
+
+/// A property message
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::drivers::mailbox::{Message, Tag};
+///
+/// let mut message = Message::new();
+/// let model = message.tag(Tag::GET_BOARD_MODEL, &[], 1).unwrap();
+/// let revision = message.tag(Tag::GET_BOARD_REVISION, &[], 1).unwrap();
+/// message.send().unwrap();
+///
+/// let model = message.response(model).unwrap()[0];
+/// ```
+pub struct Message {
+    buffer: Box<Buffer>,
+    /// Words in use, the end tag goes here
+    len: usize,
+}
+
+/// Where a tag sits in a [Message]
+#[derive(Clone, Copy, Debug)]
+pub struct TagSlot {
+    tag: Tag,
+    offset: usize,
+    words: usize,
+}
+
+impl Message {
+    /// An empty message
+    pub fn new() -> Message {
+        Message {
+            buffer: Box::new(Buffer([0; BUFFER_WORDS])),
+            len: 2,
+        }
+    }
+
+    /// Add `tag` with the `request` values and room for `response_words` of response
+    pub fn tag(&mut self, tag: Tag, request: &[u32], response_words: usize) -> Result<TagSlot, MailboxError> {
+        let words = request.len().max(response_words);
+
+        // Header, values and the end tag
+        if self.len + 3 + words + 1 > BUFFER_WORDS {
+            return Err(MailboxError::Full);
+        }
+
+        let slot = TagSlot { tag, offset: self.len, words };
+        let buffer = &mut self.buffer.0;
+
+        buffer[slot.offset] = tag.0;
+        buffer[slot.offset + 1] = (words * 4) as u32;
+        buffer[slot.offset + 2] = REQUEST;
+        buffer[slot.offset + 3..slot.offset + 3 + words].fill(0);
+        buffer[slot.offset + 3..slot.offset + 3 + request.len()].copy_from_slice(request);
+
+        self.len += 3 + words;
+        Ok(slot)
+    }
+
+    /// Hand the message to the firmware and wait for its answer
+    pub fn send(&mut self) -> Result<(), MailboxError> {
+        let buffer = &mut self.buffer.0;
+        buffer[self.len] = 0;
+        buffer[0] = ((self.len + 1) * 4) as u32;
+        buffer[1] = REQUEST;
+
+        let _guard = LOCK.lock().map_err(|_| MailboxError::Lock)?;
+        MAILBOX.call(CHANNEL_PROPERTY, &mut self.buffer)?;
+
+        match self.buffer.0[1] {
+            RESPONSE_OK => Ok(()),
+            RESPONSE_ERROR => Err(MailboxError::BadMessage),
+            // Never touched, the firmware didn't see the message
+            _ => Err(MailboxError::Timeout),
+        }
+    }
+
+    /// The response values of a tag, after [Message::send()]
+    pub fn response(&self, slot: TagSlot) -> Result<&[u32], MailboxError> {
+        let code = self.buffer.0[slot.offset + 2];
+        if code & TAG_RESPONSE == 0 {
+            return Err(MailboxError::Unanswered(slot.tag));
+        }
+
+        // The firmware says how long the full response is, it can be more than fits
+        let words = ((code & !TAG_RESPONSE) as usize / 4).min(slot.words);
+        Ok(&self.buffer.0[slot.offset + 3..slot.offset + 3 + words])
+    }
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Message::new()
+    }
+}
+
+/// The mailbox registers
+struct Mailbox<const T: usize>;
+
+impl<const T: usize> Mailbox<T> {
+    /// Mailbox 0, VideoCore to ARM
+    const READ: usize = T;
+    const STATUS_0: usize = T + 0x18;
+    /// Mailbox 1, ARM to VideoCore
+    const WRITE: usize = T + 0x20;
+    const STATUS_1: usize = T + 0x38;
+
+    const STATUS_FULL: u32 = 1 << 31;
+    const STATUS_EMPTY: u32 = 1 << 30;
+
+    /// Send the buffer on `channel` and wait for the firmware to hand it back
+    fn call(&self, channel: u32, buffer: &mut Buffer) -> Result<(), MailboxError> {
+        let kva = buffer as *mut Buffer as usize;
+        let size = core::mem::size_of::<Buffer>();
+        let message = (virt_to_phys(kva) as u32 | BUS_ALIAS) | channel;
+
+        // The firmware reads and writes memory directly, past the ARM's caches
+        mmu::clean_dcache(kva, size);
+
+        self.wait_while(Self::STATUS_1, Self::STATUS_FULL)?;
+        unsafe { mmio::write32(Self::WRITE, message) };
+
+        loop {
+            self.wait_while(Self::STATUS_0, Self::STATUS_EMPTY)?;
+
+            // Answers to other channels aren't ours to take, but nobody else listens
+            if unsafe { mmio::read32(Self::READ) } == message {
+                break;
+            }
+        }
+
+        mmu::invalidate_dcache(kva, size);
+        Ok(())
+    }
+
+    fn wait_while(&self, status: usize, bit: u32) -> Result<(), MailboxError> {
+        let end = timer::uptime() + TIMEOUT;
+
+        while unsafe { mmio::read32(status) } & bit != 0 {
+            if timer::uptime() > end {
+                return Err(MailboxError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+
+        Ok(())
+    }
+}
+
+static MAILBOX: Mailbox<0x3F00_B880> = Mailbox;
+
+/// One message in flight at a time
+static LOCK: Mutex<()> = Mutex::new(());
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Board revision code
+pub fn get_board_revision() -> Result<u32, MailboxError> {
+    Ok(query::<1>(Tag::GET_BOARD_REVISION, &[])?[0])
+}
+
+/// Board serial number
+pub fn get_board_serial() -> Result<u64, MailboxError> {
+    let [low, high] = query::<2>(Tag::GET_BOARD_SERIAL, &[])?;
+    Ok(((high as u64) << 32) | low as u64)
+}
+
+/// MAC address of the ethernet, in network byte order
+pub fn get_mac_address() -> Result<[u8; 6], MailboxError> {
+    let [low, high] = query::<2>(Tag::GET_BOARD_MAC_ADDRESS, &[])?;
+
+    let mut mac = [0; 6];
+    mac[..4].copy_from_slice(&low.to_le_bytes());
+    mac[4..].copy_from_slice(&high.to_le_bytes()[..2]);
+    Ok(mac)
+}
+
+/// Base and size of the memory the ARM cores own
+pub fn get_arm_memory() -> Result<(usize, usize), MailboxError> {
+    let [base, size] = query::<2>(Tag::GET_ARM_MEMORY, &[])?;
+    Ok((base as usize, size as usize))
+}
+
+/// Base and size of the memory the VideoCore keeps
+pub fn get_vc_memory() -> Result<(usize, usize), MailboxError> {
+    let [base, size] = query::<2>(Tag::GET_VC_MEMORY, &[])?;
+    Ok((base as usize, size as usize))
+}
+
+/// Rate of a clock in Hz
+pub fn get_clock_rate(clock: ClockId) -> Result<u32, MailboxError> {
+    Ok(query::<2>(Tag::GET_CLOCK_RATE, &[clock as u32])?[1])
+}
+
+/// Fastest rate a clock can run at, in Hz
+pub fn get_max_clock_rate(clock: ClockId) -> Result<u32, MailboxError> {
+    Ok(query::<2>(Tag::GET_MAX_CLOCK_RATE, &[clock as u32])?[1])
+}
+
+/// Ask for a clock rate, returns the rate the firmware picked
+pub fn set_clock_rate(clock: ClockId, hz: u32) -> Result<u32, MailboxError> {
+    // The last word asks the firmware not to change the turbo setting
+    Ok(query::<2>(Tag::SET_CLOCK_RATE, &[clock as u32, hz, 0])?[1])
+}
+
+/// Whether a device is powered on
+pub fn get_power_state(device: PowerDevice) -> Result<bool, MailboxError> {
+    let [_, state] = query::<2>(Tag::GET_POWER_STATE, &[device as u32])?;
+    Ok(state & 1 != 0)
+}
+
+/// Power a device on or off, returns the state it ended up in
+///
+/// With `wait` the firmware only answers once the device's power is stable.
+pub fn set_power_state(device: PowerDevice, on: bool, wait: bool) -> Result<bool, MailboxError> {
+    let state = on as u32 | (wait as u32) << 1;
+    let [_, state] = query::<2>(Tag::SET_POWER_STATE, &[device as u32, state])?;
+
+    // Bit 1 of the answer means the device doesn't exist
+    match state & 2 {
+        0 => Ok(state & 1 != 0),
+        _ => Err(MailboxError::Unanswered(Tag::SET_POWER_STATE)),
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// Send a message with just `tag` and return the first `N` words of its response
+fn query<const N: usize>(tag: Tag, request: &[u32]) -> Result<[u32; N], MailboxError> {
+    let mut message = Message::new();
+    let slot = message.tag(tag, request, N)?;
+    message.send()?;
+
+    message
+        .response(slot)?
+        .get(..N)
+        .and_then(|values| values.try_into().ok())
+        .ok_or(MailboxError::ShortResponse(tag))
+}
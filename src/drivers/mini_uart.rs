@@ -0,0 +1,150 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Mini UART
+//!
+//! UART1 of the BCM2837's auxiliary peripherals. On the Pi 3 the PL011 drives the
+//! Bluetooth chip and the serial pins of the GPIO header get this one, on GPIO14/15 in
+//! ALT5.
+//!
+//! Its baud rate is divided from the core clock, which the firmware is free to change.
+//! Setting `core_freq=250` (or `enable_uart=1`) in `config.txt` pins it.
+//!
+//! Under qemu it's the second serial port: `-serial null -serial stdio`.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 2)
+//!   - <https://www.raspberrypi.com/documentation/computers/configuration.html#mini-uart-and-cpu-core-frequency>
+//!
+
+use super::console::{Console, Count, Read};
+use super::mailbox::{self, ClockId};
+use super::mmio;
+
+/// Core clock if the firmware won't say, the Pi 3's default
+const CORE_CLOCK: u32 = 250_000_000;
+
+/// A mini UART
+pub struct MiniUart<const T: usize> {
+    chars_written: usize,
+}
+
+impl<const T: usize> MiniUart<T> {
+    /// Enables of the auxiliary peripherals, bit 0 is the mini UART
+    const AUX_ENABLES: usize = T + 0x04;
+    const AUX_ENABLES_UART: u32 = 1 << 0;
+
+    /// Data, and the baud divisor's low byte while LCR's DLAB bit is set
+    const MU_IO: usize = T + 0x40;
+    const MU_IER: usize = T + 0x44;
+    /// Writing clears the FIFOs
+    const MU_IIR: usize = T + 0x48;
+    const MU_IIR_CLEAR_FIFOS: u32 = 0b11 << 1;
+    const MU_LCR: usize = T + 0x4C;
+    const MU_LCR_8BIT: u32 = 0b11;
+    const MU_MCR: usize = T + 0x50;
+    const MU_LSR: usize = T + 0x54;
+    const MU_LSR_DATA_READY: u32 = 1 << 0;
+    const MU_LSR_TX_EMPTY: u32 = 1 << 5;
+    const MU_CNTL: usize = T + 0x60;
+    const MU_CNTL_RX_EN: u32 = 1 << 0;
+    const MU_CNTL_TX_EN: u32 = 1 << 1;
+    const MU_BAUD: usize = T + 0x68;
+
+    /// A mini UART that still needs [MiniUart::init()]
+    pub const fn new() -> MiniUart<T> {
+        MiniUart { chars_written: 0 }
+    }
+
+    /// Turn the UART on at `baud`, 8N1, no flow control or interrupts
+    ///
+    /// Asks the firmware for the core clock, so it needs the heap.
+    pub fn init(&mut self, baud: u32) {
+        let clock = mailbox::get_clock_rate(ClockId::Core).unwrap_or(CORE_CLOCK);
+
+        unsafe {
+            mmio::modify32(Self::AUX_ENABLES, Self::AUX_ENABLES_UART, Self::AUX_ENABLES_UART);
+            mmio::write32(Self::MU_CNTL, 0);
+            mmio::write32(Self::MU_IER, 0);
+            mmio::write32(Self::MU_LCR, Self::MU_LCR_8BIT);
+            mmio::write32(Self::MU_MCR, 0);
+            mmio::write32(Self::MU_IIR, Self::MU_IIR_CLEAR_FIFOS);
+            mmio::write32(Self::MU_BAUD, Self::divisor(clock, baud));
+            mmio::write32(Self::MU_CNTL, Self::MU_CNTL_RX_EN | Self::MU_CNTL_TX_EN);
+        }
+    }
+
+    /// The baud rate is `clock / (8 * (divisor + 1))`
+    fn divisor(clock: u32, baud: u32) -> u32 {
+        (clock / (8 * baud.max(1))).saturating_sub(1).min(0xFFFF)
+    }
+
+    /// Send a byte, once there's room in the FIFO
+    fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while mmio::read32(Self::MU_LSR) & Self::MU_LSR_TX_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+
+            mmio::write32(Self::MU_IO, byte as u32);
+        }
+    }
+}
+
+impl<const T: usize> Default for MiniUart<T> {
+    fn default() -> MiniUart<T> {
+        MiniUart::new()
+    }
+}
+
+/// Implementing `core::fmt::Write` gives the UART `write_fmt()`, like the PL011's console.
+impl<const T: usize> core::fmt::Write for MiniUart<T> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            // Convert newline to carrige return + newline.
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+
+            self.write_byte(byte);
+        }
+        self.chars_written += s.chars().count();
+
+        Ok(())
+    }
+}
+
+/// Implements Count
+impl<const T: usize> Count for MiniUart<T> {
+    /// Returns the number of chars written
+    fn chars_written(&self) -> usize {
+        self.chars_written
+    }
+}
+
+/// Implements Read
+impl<const T: usize> Read for MiniUart<T> {
+    /// Pops the receive FIFO
+    fn read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            match mmio::read32(Self::MU_LSR) & Self::MU_LSR_DATA_READY {
+                0 => None,
+                _ => Some(mmio::read32(Self::MU_IO) as u8),
+            }
+        }
+    }
+}
+
+/// Implements Console
+impl<const T: usize> Console for MiniUart<T> {}
@@ -0,0 +1,55 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Memory mapped IO
+//!
+//! Volatile register access for the device drivers. Drivers keep their base address
+//! in a const generic (like [crate::drivers::console]) and go through these so every
+//! register access looks the same.
+//!
+//! Addresses are the physical ones from the datasheets, the helpers translate them to
+//! the kernel's mapping of the peripherals ([crate::memory::phys_to_virt()]).
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use crate::memory::phys_to_virt;
+
+/// Read a 32 bit register
+///
+/// ## Safety
+///
+/// `addr` must be a valid, aligned device register.
+#[inline]
+pub unsafe fn read32(addr: usize) -> u32 {
+    core::ptr::read_volatile(phys_to_virt(addr) as *const u32)
+}
+
+/// Write a 32 bit register
+///
+/// ## Safety
+///
+/// `addr` must be a valid, aligned device register.
+#[inline]
+pub unsafe fn write32(addr: usize, value: u32) {
+    core::ptr::write_volatile(phys_to_virt(addr) as *mut u32, value)
+}
+
+/// Read-modify-write the bits in `mask` of a 32 bit register
+///
+/// ## Safety
+///
+/// `addr` must be a valid, aligned device register. Not atomic, the caller has to make sure
+/// nothing else writes the register at the same time.
+#[inline]
+pub unsafe fn modify32(addr: usize, mask: u32, value: u32) {
+    write32(addr, (read32(addr) & !mask) | (value & mask))
+}
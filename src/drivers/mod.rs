@@ -16,4 +16,21 @@
 //! Author: Mitchell Scott <scott.mitchell913@gmail.com>
 //!
 
+pub mod audio;
+pub mod block;
+pub mod dma;
 pub mod console;
+pub mod emmc;
+pub mod fbcon;
+pub mod framebuffer;
+pub mod gpio;
+pub mod i2c;
+pub mod interrupt;
+pub mod mailbox;
+pub mod mini_uart;
+pub mod mmio;
+pub mod power;
+pub mod pwm;
+pub mod rng;
+pub mod spi;
+pub mod systimer;
@@ -0,0 +1,185 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Power management
+//!
+//! The PM block's watchdog, the only way the ARM can reset the board. [reboot()] and
+//! [halt()] both let it expire almost immediately: the firmware reads why from the
+//! partition field of PM_RSTS, and for partition 63 it stays halted instead of booting
+//! again (that's what Linux does, the Pi can't cut its own power).
+//!
+//! The watchdog can also guard the kernel: once started with [start_watchdog()] the board
+//! resets unless [pet()] is called more often than the timeout.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/torvalds/linux/blob/master/drivers/watchdog/bcm2835_wdt.c>
+//!   - <https://github.com/qemu/qemu/blob/master/hw/misc/bcm2835_powermgt.c>
+//!
+
+use super::mmio;
+use crate::cpu::{_park, timer};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+/// The watchdog counts down at this rate
+const WDOG_FREQUENCY: u64 = 1 << 16;
+
+/// The watchdog counter is 20 bits
+const MAX_TICKS: u32 = 0x000F_FFFF;
+
+/// Ticks given before a reset, long enough for the write to land
+const RESET_TICKS: u32 = 10;
+
+/// Partition that tells the firmware to halt instead of booting
+const HALT_PARTITION: u32 = 63;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for the watchdog
+pub enum WatchdogError {
+    /// The counter is 20 bits, just under 16 seconds
+    TooLong,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for WatchdogError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            WatchdogError::TooLong => f.write_str("Watchdog timeout is longer than 16 seconds"),
+        }
+    }
+}
+
+/// BCM2835 power management registers
+struct PowerManager<const T: usize>;
+
+impl<const T: usize> PowerManager<T> {
+    /// Reset control
+    const PM_RSTC: usize = T + 0x1C;
+    const PM_RSTC_WRCFG_MASK: u32 = 0x30;
+    const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x20;
+    /// Reset status, the firmware reads the boot partition from the even bits of the low 12
+    const PM_RSTS: usize = T + 0x20;
+    const PM_RSTS_PARTITION_MASK: u32 = 0x555;
+    /// Watchdog counter
+    const PM_WDOG: usize = T + 0x24;
+
+    /// Every write needs this in the top byte
+    const PASSWORD: u32 = 0x5A00_0000;
+
+    /// Reload the watchdog with `ticks` and make it reset the board when it runs out
+    fn start(&self, ticks: u32) {
+        unsafe {
+            let rstc = mmio::read32(Self::PM_RSTC) & !Self::PM_RSTC_WRCFG_MASK;
+
+            mmio::write32(Self::PM_WDOG, Self::PASSWORD | (ticks & MAX_TICKS));
+            mmio::write32(Self::PM_RSTC, Self::PASSWORD | rstc | Self::PM_RSTC_WRCFG_FULL_RESET);
+        }
+    }
+
+    fn stop(&self) {
+        unsafe {
+            let rstc = mmio::read32(Self::PM_RSTC) & !Self::PM_RSTC_WRCFG_MASK;
+            mmio::write32(Self::PM_RSTC, Self::PASSWORD | rstc);
+        }
+    }
+
+    fn remaining(&self) -> u32 {
+        unsafe { mmio::read32(Self::PM_WDOG) & MAX_TICKS }
+    }
+
+    /// Boot from `partition` next time, its bits are spread over every other bit
+    fn set_partition(&self, partition: u32) {
+        let spread = (0..6).fold(0, |bits, i| bits | ((partition >> i) & 1) << (i * 2));
+
+        unsafe {
+            let rsts = mmio::read32(Self::PM_RSTS) & !Self::PM_RSTS_PARTITION_MASK;
+            mmio::write32(Self::PM_RSTS, Self::PASSWORD | rsts | spread);
+        }
+    }
+}
+
+static POWER_MANAGER: PowerManager<0x3F10_0000> = PowerManager;
+
+/// Timeout of the running watchdog in ticks, what [pet()] reloads
+static TIMEOUT: AtomicU32 = AtomicU32::new(0);
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Reset the board, it boots again
+pub fn reboot() -> ! {
+    POWER_MANAGER.set_partition(0);
+    POWER_MANAGER.start(RESET_TICKS);
+    _park();
+}
+
+/// Reset the board into the firmware's halt state, only power cycling brings it back
+pub fn halt() -> ! {
+    POWER_MANAGER.set_partition(HALT_PARTITION);
+    POWER_MANAGER.start(RESET_TICKS);
+    _park();
+}
+
+/// Reboot `delay` from now
+///
+/// Delays the watchdog can count are left to it, so a core that's stuck still reboots.
+/// Longer ones spin first.
+pub fn reboot_after(delay: Duration) -> ! {
+    if start_watchdog(delay).is_err() {
+        timer::spin_for(delay);
+    }
+
+    reboot();
+}
+
+/// Reset the board unless [pet()] is called at least every `timeout`
+///
+/// Replaces the timeout of a running watchdog.
+pub fn start_watchdog(timeout: Duration) -> Result<(), WatchdogError> {
+    let ticks = timeout.as_micros() * WDOG_FREQUENCY as u128 / 1_000_000;
+
+    if ticks > MAX_TICKS as u128 {
+        return Err(WatchdogError::TooLong);
+    }
+
+    let ticks = (ticks as u32).max(RESET_TICKS);
+    TIMEOUT.store(ticks, Ordering::Relaxed);
+    POWER_MANAGER.start(ticks);
+    Ok(())
+}
+
+/// Restart the watchdog's countdown, does nothing if it isn't running
+pub fn pet() {
+    match TIMEOUT.load(Ordering::Relaxed) {
+        0 => {}
+        ticks => POWER_MANAGER.start(ticks),
+    }
+}
+
+/// Stop the watchdog
+pub fn stop_watchdog() {
+    TIMEOUT.store(0, Ordering::Relaxed);
+    POWER_MANAGER.stop();
+}
+
+/// Time until the watchdog resets the board, None if it isn't running
+pub fn time_left() -> Option<Duration> {
+    match TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        _ => Some(Duration::from_micros(
+            POWER_MANAGER.remaining() as u64 * 1_000_000 / WDOG_FREQUENCY,
+        )),
+    }
+}
@@ -0,0 +1,497 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS PWM
+//!
+//! The BCM2835 PWM block: two channels sharing one clock from the clock manager
+//! ([set_clock()]). A [Pwm] drives one channel on one of its pins in mark-space mode, a high
+//! pulse of `duty` clock ticks every `range` ticks.
+//!
+//! The channels can also be fed samples through their FIFO, that's how
+//! [audio](super::audio) plays through the headphone jack.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapters 6.3 and 9)
+//!   - <https://elinux.org/BCM2835_datasheet_errata> (clock manager)
+//!   - <https://github.com/torvalds/linux/blob/master/drivers/pwm/pwm-bcm2835.c>
+//!
+
+use super::gpio::{Alt0, Alt5, Pin};
+use super::mmio;
+use crate::cpu::timer;
+use crate::sync::ticket::TicketLock;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use core::time::Duration;
+
+/// Physical address of the PWM block
+const PWM_BASE: usize = 0x3F20_C000;
+
+/// PLLD runs at this rate on the Pi 3
+const PLLD_FREQUENCY: u32 = 500_000_000;
+
+/// The crystal
+const OSCILLATOR_FREQUENCY: u32 = 19_200_000;
+
+/// How long the clock manager may stay busy
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for PWM
+pub enum PwmError {
+    /// The channel is already in use
+    Busy,
+    /// Neither clock source divides down to the frequency
+    ClockOutOfRange,
+    /// A range of 0
+    ZeroRange,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for PwmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            PwmError::Busy => f.write_str("PWM channel is in use"),
+            PwmError::ClockOutOfRange => f.write_str("PWM clock can't run at that frequency"),
+            PwmError::ZeroRange => f.write_str("PWM range can't be 0"),
+        }
+    }
+}
+
+impl embedded_hal::pwm::Error for PwmError {
+    fn kind(&self) -> embedded_hal::pwm::ErrorKind {
+        embedded_hal::pwm::ErrorKind::Other
+    }
+}
+
+/// A PWM channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Channel {
+    /// PWM0, the left side of the headphone jack
+    One = 0,
+    /// PWM1, the right side of the headphone jack
+    Two = 1,
+}
+
+/// A pin that can carry a PWM channel
+///
+/// Sealed, the pins are GPIO12, 18 and 40 for [Channel::One], GPIO13, 19, 41 and 45 for
+/// [Channel::Two], each in the alternate function that connects it.
+pub trait PwmPin: private::Sealed {
+    /// The channel on the pin
+    const CHANNEL: Channel;
+}
+
+impl PwmPin for Pin<12, Alt0> {
+    const CHANNEL: Channel = Channel::One;
+}
+impl PwmPin for Pin<18, Alt5> {
+    const CHANNEL: Channel = Channel::One;
+}
+impl PwmPin for Pin<40, Alt0> {
+    const CHANNEL: Channel = Channel::One;
+}
+impl PwmPin for Pin<13, Alt0> {
+    const CHANNEL: Channel = Channel::Two;
+}
+impl PwmPin for Pin<19, Alt5> {
+    const CHANNEL: Channel = Channel::Two;
+}
+impl PwmPin for Pin<41, Alt0> {
+    const CHANNEL: Channel = Channel::Two;
+}
+impl PwmPin for Pin<45, Alt0> {
+    const CHANNEL: Channel = Channel::Two;
+}
+
+mod private {
+    use super::{Alt0, Alt5, Pin};
+
+    pub trait Sealed {}
+
+    impl Sealed for Pin<12, Alt0> {}
+    impl Sealed for Pin<18, Alt5> {}
+    impl Sealed for Pin<40, Alt0> {}
+    impl Sealed for Pin<13, Alt0> {}
+    impl Sealed for Pin<19, Alt5> {}
+    impl Sealed for Pin<41, Alt0> {}
+    impl Sealed for Pin<45, Alt0> {}
+}
+
+/// One channel driven on pin `P`
+///
+/// The output is high for `duty` of every `range` ticks of the [PWM clock](set_clock()).
+/// Dropping it turns the channel off, [Pwm::release()] also hands the pin back.
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::drivers::gpio::Pin;
+/// use dyseos::drivers::pwm::{self, Pwm};
+///
+/// // 1MHz clock, 1kHz period, a quarter on
+/// pwm::set_clock(1_000_000).unwrap();
+/// let mut led = Pwm::new(Pin::<18, _>::take().unwrap().into_alt5(), 1000).unwrap();
+/// led.set_duty(250);
+/// ```
+pub struct Pwm<P: PwmPin> {
+    pin: Option<P>,
+    range: u32,
+    duty: u32,
+}
+
+impl<P: PwmPin> Pwm<P> {
+    /// Take `pin`'s channel and start it with a period of `range` ticks, off
+    pub fn new(pin: P, range: u32) -> Result<Pwm<P>, PwmError> {
+        if range == 0 {
+            return Err(PwmError::ZeroRange);
+        }
+
+        claim(P::CHANNEL)?;
+        PWM.set_range(P::CHANNEL, range);
+        PWM.set_data(P::CHANNEL, 0);
+        PWM.enable(P::CHANNEL, PwmBlock::CTL_PWEN | PwmBlock::CTL_MSEN);
+
+        Ok(Pwm {
+            pin: Some(pin),
+            range,
+            duty: 0,
+        })
+    }
+
+    /// The channel being driven
+    pub fn channel(&self) -> Channel {
+        P::CHANNEL
+    }
+
+    /// Ticks in a period
+    pub fn range(&self) -> u32 {
+        self.range
+    }
+
+    /// Change the period, the duty is clamped to it
+    pub fn set_range(&mut self, range: u32) -> Result<(), PwmError> {
+        if range == 0 {
+            return Err(PwmError::ZeroRange);
+        }
+
+        self.range = range;
+        PWM.set_range(P::CHANNEL, range);
+        self.set_duty(self.duty);
+        Ok(())
+    }
+
+    /// Ticks the output is high each period
+    pub fn duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Set the ticks the output is high each period, at most [Pwm::range()]
+    pub fn set_duty(&mut self, duty: u32) {
+        self.duty = duty.min(self.range);
+        PWM.set_data(P::CHANNEL, self.duty);
+    }
+
+    /// Output frequency in Hz, 0 before [set_clock()]
+    pub fn frequency(&self) -> u32 {
+        clock() / self.range
+    }
+
+    /// Turn the channel off and hand back the pin
+    pub fn release(mut self) -> P {
+        self.pin.take().unwrap()
+    }
+}
+
+impl<P: PwmPin> Drop for Pwm<P> {
+    fn drop(&mut self) {
+        PWM.disable(P::CHANNEL);
+        unclaim(P::CHANNEL);
+    }
+}
+
+impl<P: PwmPin> embedded_hal::pwm::ErrorType for Pwm<P> {
+    type Error = PwmError;
+}
+
+/// Duty cycles are 16 bits here, scaled to the range when it's larger
+impl<P: PwmPin> embedded_hal::pwm::SetDutyCycle for Pwm<P> {
+    fn max_duty_cycle(&self) -> u16 {
+        self.range.min(u16::MAX as u32) as u16
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), PwmError> {
+        let max = self.max_duty_cycle() as u64;
+        let duty = (duty as u64).min(max) * self.range as u64 / max;
+
+        self.set_duty(duty as u32);
+        Ok(())
+    }
+}
+
+/// Both channels fed from the FIFO, taken by [audio](super::audio)
+///
+/// Words written to [FifoOutput::FIFO] go to the channels in turn, One first. Dropping it
+/// turns both channels off.
+pub(super) struct FifoOutput;
+
+impl FifoOutput {
+    /// Physical address of the FIFO, where DMA writes
+    pub(super) const FIFO: usize = PwmBlock::FIF1;
+
+    /// Take both channels and start them in balanced mode with a period of `range` ticks,
+    /// pacing DMA from the FIFO
+    pub(super) fn start(range: u32) -> Result<FifoOutput, PwmError> {
+        if range == 0 {
+            return Err(PwmError::ZeroRange);
+        }
+
+        claim(Channel::One)?;
+        if let Err(err) = claim(Channel::Two) {
+            unclaim(Channel::One);
+            return Err(err);
+        }
+
+        PWM.set_range(Channel::One, range);
+        PWM.set_range(Channel::Two, range);
+        PWM.start_fifo();
+
+        Ok(FifoOutput)
+    }
+
+    /// Wait for the FIFO to run dry, at most `timeout`
+    pub(super) fn drain(&self, timeout: Duration) {
+        let deadline = timer::uptime() + timeout;
+
+        while !PWM.fifo_empty() && timer::uptime() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for FifoOutput {
+    fn drop(&mut self) {
+        PWM.stop_fifo();
+        unclaim(Channel::One);
+        unclaim(Channel::Two);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Run the PWM clock at `frequency` Hz or just above, returns the frequency it got
+///
+/// The clock is divided down from PLLD, or from the crystal below about 122kHz, by an
+/// integer. Both channels share it, changing it changes the frequency of anything running.
+pub fn set_clock(frequency: u32) -> Result<u32, PwmError> {
+    let frequency = frequency.max(1);
+
+    let (source, rate, divisor) = [
+        (Clocks::SRC_PLLD, PLLD_FREQUENCY),
+        (Clocks::SRC_OSCILLATOR, OSCILLATOR_FREQUENCY),
+    ]
+    .into_iter()
+    .map(|(source, rate)| (source, rate, rate / frequency))
+    .find(|&(_, _, divisor)| (Clocks::MIN_DIVISOR..=Clocks::MAX_DIVISOR).contains(&divisor))
+    .ok_or(PwmError::ClockOutOfRange)?;
+
+    CLOCK_MANAGER.set_pwm_clock(source, divisor);
+
+    let actual = rate / divisor;
+    CLOCK.store(actual, Ordering::Relaxed);
+    Ok(actual)
+}
+
+/// Frequency of the PWM clock in Hz, 0 before [set_clock()]
+pub fn clock() -> u32 {
+    CLOCK.load(Ordering::Relaxed)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// The clock manager's PWM clock
+struct ClockManager<const T: usize>;
+
+impl<const T: usize> ClockManager<T> {
+    const CM_PWMCTL: usize = T + 0xA0;
+    const CM_PWMDIV: usize = T + 0xA4;
+    const CTL_ENAB: u32 = 1 << 4;
+    const CTL_KILL: u32 = 1 << 5;
+    const CTL_BUSY: u32 = 1 << 7;
+    const DIV_SHIFT: u32 = 12;
+
+    const SRC_OSCILLATOR: u32 = 1;
+    const SRC_PLLD: u32 = 6;
+
+    /// Integer divisors only, no MASH noise shaping
+    const MIN_DIVISOR: u32 = 2;
+    const MAX_DIVISOR: u32 = 0xFFF;
+
+    /// Every write needs this in the top byte
+    const PASSWORD: u32 = 0x5A00_0000;
+
+    /// Stop the clock, wait for it, then restart it from `source` divided by `divisor`
+    ///
+    /// Changing either while the clock runs glitches it, or locks the clock manager up.
+    fn set_pwm_clock(&self, source: u32, divisor: u32) {
+        unsafe {
+            mmio::write32(Self::CM_PWMCTL, Self::PASSWORD | Self::CTL_KILL);
+            self.wait_idle();
+
+            mmio::write32(Self::CM_PWMDIV, Self::PASSWORD | divisor << Self::DIV_SHIFT);
+            mmio::write32(Self::CM_PWMCTL, Self::PASSWORD | source);
+            mmio::write32(Self::CM_PWMCTL, Self::PASSWORD | source | Self::CTL_ENAB);
+        }
+    }
+
+    fn wait_idle(&self) {
+        let deadline = timer::uptime() + CLOCK_TIMEOUT;
+
+        while unsafe { mmio::read32(Self::CM_PWMCTL) } & Self::CTL_BUSY != 0 && timer::uptime() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The clock manager
+type Clocks = ClockManager<0x3F10_1000>;
+
+static CLOCK_MANAGER: Clocks = ClockManager;
+
+/// BCM2835 PWM registers
+///
+/// CTL has a byte per channel, the same bits at 0 and 8.
+struct Controller<const T: usize>;
+
+impl<const T: usize> Controller<T> {
+    const CTL: usize = T;
+    const CTL_PWEN: u32 = 1 << 0;
+    /// Take data from the FIFO instead of DAT
+    const CTL_USEF: u32 = 1 << 5;
+    /// Clear the FIFO, only in channel one's byte
+    const CTL_CLRF: u32 = 1 << 6;
+    /// Mark-space mode instead of balanced
+    const CTL_MSEN: u32 = 1 << 7;
+    const CTL_CHANNEL_MASK: u32 = 0xFF;
+    const STA: usize = T + 0x04;
+    const STA_EMPT1: u32 = 1 << 1;
+    /// Write, read, gap and bus error flags, write 1 to clear
+    const STA_ERRORS: u32 = 0x1FC;
+    const DMAC: usize = T + 0x08;
+    const DMAC_ENAB: u32 = 1 << 31;
+    /// DREQ when the FIFO has room for this many words, PANIC at half that
+    const DMAC_THRESHOLD: u32 = 7;
+    const RNG1: usize = T + 0x10;
+    const DAT1: usize = T + 0x14;
+    const FIF1: usize = T + 0x18;
+    const RNG2: usize = T + 0x20;
+    const DAT2: usize = T + 0x24;
+
+    fn shift(channel: Channel) -> u32 {
+        channel as u32 * 8
+    }
+
+    fn set_range(&self, channel: Channel, range: u32) {
+        let reg = match channel {
+            Channel::One => Self::RNG1,
+            Channel::Two => Self::RNG2,
+        };
+
+        unsafe { mmio::write32(reg, range) };
+    }
+
+    fn set_data(&self, channel: Channel, data: u32) {
+        let reg = match channel {
+            Channel::One => Self::DAT1,
+            Channel::Two => Self::DAT2,
+        };
+
+        unsafe { mmio::write32(reg, data) };
+    }
+
+    /// Switch `channel` on with `bits` in its byte of CTL
+    ///
+    /// CTL is shared with the other channel, the [LOCK] keeps this read-modify-write whole.
+    fn enable(&self, channel: Channel, bits: u32) {
+        let shift = Self::shift(channel);
+
+        let _guard = LOCK.lock();
+        unsafe { mmio::modify32(Self::CTL, Self::CTL_CHANNEL_MASK << shift, bits << shift) };
+    }
+
+    fn disable(&self, channel: Channel) {
+        self.enable(channel, 0);
+    }
+
+    /// Both channels on the FIFO, DREQ from it, its data starts with channel one
+    fn start_fifo(&self) {
+        let fifo = Self::CTL_PWEN | Self::CTL_USEF;
+
+        let _guard = LOCK.lock();
+        unsafe {
+            mmio::write32(Self::CTL, 0);
+            mmio::write32(Self::STA, Self::STA_ERRORS);
+            mmio::write32(Self::CTL, Self::CTL_CLRF);
+            mmio::write32(
+                Self::DMAC,
+                Self::DMAC_ENAB | (Self::DMAC_THRESHOLD / 2) << 8 | Self::DMAC_THRESHOLD,
+            );
+            mmio::write32(Self::CTL, fifo | fifo << 8);
+        }
+    }
+
+    fn stop_fifo(&self) {
+        let _guard = LOCK.lock();
+        unsafe {
+            mmio::write32(Self::CTL, Self::CTL_CLRF);
+            mmio::write32(Self::DMAC, 0);
+            mmio::write32(Self::STA, Self::STA_ERRORS);
+        }
+    }
+
+    fn fifo_empty(&self) -> bool {
+        unsafe { mmio::read32(Self::STA) & Self::STA_EMPT1 != 0 }
+    }
+}
+
+/// The PWM block
+type PwmBlock = Controller<PWM_BASE>;
+
+static PWM: PwmBlock = Controller;
+
+/// Serializes changes to CTL
+static LOCK: TicketLock<()> = TicketLock::new(());
+
+/// Bit per [Channel] in use
+static TAKEN: AtomicU8 = AtomicU8::new(0);
+
+/// Frequency of the PWM clock
+static CLOCK: AtomicU32 = AtomicU32::new(0);
+
+fn claim(channel: Channel) -> Result<(), PwmError> {
+    let bit = 1 << channel as u8;
+
+    match TAKEN.fetch_or(bit, Ordering::AcqRel) & bit {
+        0 => Ok(()),
+        _ => Err(PwmError::Busy),
+    }
+}
+
+fn unclaim(channel: Channel) {
+    TAKEN.fetch_and(!(1 << channel as u8), Ordering::AcqRel);
+}
@@ -0,0 +1,102 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Hardware RNG
+//!
+//! The BCM2835 random number generator, a free running noise source that fills a small
+//! FIFO with 32 bit words. Its output isn't whitened or documented well enough to use
+//! directly, [crate::random] mixes it into a CSPRNG.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://github.com/torvalds/linux/blob/master/drivers/char/hw_random/bcm2835-rng.c>
+//!
+
+use super::mmio;
+use crate::cpu::timer;
+use core::time::Duration;
+
+/// Longest a word can take, the first one only comes after the warm up
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// BCM2835 RNG registers
+struct Rng<const T: usize>;
+
+impl<const T: usize> Rng<T> {
+    const CTRL: usize = T;
+    const CTRL_RBGEN: u32 = 1 << 0;
+    /// Words in the FIFO in the top byte, writing sets how many bits are thrown away first
+    const STATUS: usize = T + 0x04;
+    const STATUS_WARMUP: u32 = 0x4_0000;
+    const DATA: usize = T + 0x08;
+    const INT_MASK: usize = T + 0x10;
+    const INT_MASK_OFF: u32 = 1 << 0;
+
+    fn init(&self) {
+        unsafe {
+            if mmio::read32(Self::CTRL) & Self::CTRL_RBGEN != 0 {
+                return;
+            }
+
+            // Nothing waits on the interrupt, words are polled
+            mmio::modify32(Self::INT_MASK, Self::INT_MASK_OFF, Self::INT_MASK_OFF);
+            mmio::write32(Self::STATUS, Self::STATUS_WARMUP);
+            mmio::write32(Self::CTRL, Self::CTRL_RBGEN);
+        }
+    }
+
+    fn available(&self) -> u32 {
+        unsafe { mmio::read32(Self::STATUS) >> 24 }
+    }
+
+    fn data(&self) -> u32 {
+        unsafe { mmio::read32(Self::DATA) }
+    }
+}
+
+static RNG: Rng<0x3F10_4000> = Rng;
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Start the generator, words show up after a short warm up
+pub fn init() {
+    RNG.init();
+}
+
+/// A word, if one is ready
+pub fn try_read() -> Option<u32> {
+    match RNG.available() {
+        0 => None,
+        _ => Some(RNG.data()),
+    }
+}
+
+/// A word, waiting up to 100ms for one
+///
+/// None if the generator is off or broken.
+pub fn read() -> Option<u32> {
+    let end = timer::uptime() + TIMEOUT;
+
+    loop {
+        if let Some(word) = try_read() {
+            return Some(word);
+        }
+
+        if timer::uptime() > end {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
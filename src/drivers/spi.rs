@@ -0,0 +1,444 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS SPI
+//!
+//! The BCM2835 SPI0 master on the header's GPIO7-11, as an [embedded_hal::spi::SpiBus].
+//!
+//! A transfer keeps the FIFOs fed from the calling task. By polling the controller, or with
+//! [Config::interrupts] by sleeping until the controller is done with what's in the FIFO or
+//! needs its receive FIFO read, which frees the core on long transfers at slow clocks.
+//!
+//! The bus only moves bytes, chip select is usually a GPIO the device driver (or
+//! `embedded-hal-bus`) drives. [Config::chip_select] lets the controller drive CE0 or CE1
+//! instead, asserted for the length of each call.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 10)
+//!   - <https://docs.rs/embedded-hal/1.0.0/embedded_hal/spi/index.html>
+//!
+
+use super::gpio::{Alt0, Pin};
+use super::interrupt::{self, Irq};
+use super::mailbox::{self, ClockId};
+use super::mmio;
+use crate::cpu::timer;
+use crate::sync::futex::{futex_wait, futex_wake, FutexError};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use embedded_hal::spi::{ErrorKind, Mode, Phase, Polarity};
+
+/// Core clock if the firmware won't say, the Pi 3's default
+const CORE_CLOCK: u32 = 250_000_000;
+
+/// Bytes the FIFOs hold
+const FIFO_LEN: usize = 64;
+
+/// Longest a transfer may go without the controller making progress
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Peripheral interrupt of SPI0
+const IRQ: Irq = Irq::peripheral(54);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for the SPI driver
+pub enum SpiError {
+    /// Another driver holds the SPI pins
+    PinsTaken,
+    /// The controller stopped moving data
+    Timeout,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for SpiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            SpiError::PinsTaken => f.write_str("SPI pins are in use"),
+            SpiError::Timeout => f.write_str("SPI transfer timed out"),
+        }
+    }
+}
+
+impl embedded_hal::spi::Error for SpiError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// A chip enable line the controller can drive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChipSelect {
+    /// CE0, GPIO8
+    Ce0,
+    /// CE1, GPIO7
+    Ce1,
+}
+
+/// How to set up the bus
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Clock in Hz, rounded down to what the divider can make
+    pub frequency: u32,
+    /// Clock polarity and phase
+    pub mode: Mode,
+    /// The chip enable the controller drives, None leaves chip select to the caller
+    pub chip_select: Option<ChipSelect>,
+    /// Sleep on the interrupt instead of polling
+    pub interrupts: bool,
+}
+
+impl Default for Config {
+    /// 1MHz, mode 0, no chip select, polling
+    fn default() -> Config {
+        Config {
+            frequency: 1_000_000,
+            mode: embedded_hal::spi::MODE_0,
+            chip_select: None,
+            interrupts: false,
+        }
+    }
+}
+
+/// The SPI0 master
+///
+/// Holds its pins until dropped, so there is only ever one.
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::drivers::spi::{Config, Spi};
+/// use embedded_hal::spi::SpiBus;
+///
+/// let mut spi = Spi::new(Config::default()).unwrap();
+/// let mut id = [0x9F, 0, 0, 0];
+/// spi.transfer_in_place(&mut id).unwrap();
+/// ```
+pub struct Spi {
+    pins: Option<SpiPins>,
+    /// CS bits every transfer starts with
+    cs: u32,
+    interrupts: bool,
+}
+
+impl Spi {
+    /// Claim the pins and set up the controller
+    ///
+    /// Asks the firmware for the core clock, so it needs the heap. Falls back to polling if
+    /// the interrupt has another handler.
+    pub fn new(config: Config) -> Result<Spi, SpiError> {
+        let pins = SpiPins::take(config.chip_select).ok_or(SpiError::PinsTaken)?;
+        let clock = mailbox::get_clock_rate(ClockId::Core).unwrap_or(CORE_CLOCK);
+
+        let cs = SPI0.cs_bits(&config);
+
+        let interrupts = config.interrupts && interrupt::register(IRQ, handle_interrupt).is_ok();
+        if interrupts {
+            interrupt::enable(IRQ);
+        }
+
+        SPI0.setup(cs, divider(clock, config.frequency));
+
+        Ok(Spi {
+            pins: Some(pins),
+            cs,
+            interrupts,
+        })
+    }
+
+    /// Clock out `len` bytes, `tx` gives the byte to send at each index, `rx` takes the
+    /// byte received there
+    fn exchange(&mut self, len: usize, tx: impl Fn(usize) -> u8, mut rx: impl FnMut(usize, u8)) -> Result<(), SpiError> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        SPI0.start(self.cs);
+
+        let (mut sent, mut received) = (0, 0);
+        let mut deadline = timer::uptime() + TIMEOUT;
+
+        let result = loop {
+            let progress = (sent, received);
+
+            // Never more in flight than the receive FIFO holds
+            while sent < len && sent - received < FIFO_LEN && SPI0.can_write() {
+                SPI0.write(tx(sent));
+                sent += 1;
+            }
+            while received < sent && SPI0.can_read() {
+                rx(received, SPI0.read());
+                received += 1;
+            }
+
+            if received == len {
+                break Ok(());
+            }
+
+            let now = timer::uptime();
+            if (sent, received) != progress {
+                deadline = now + TIMEOUT;
+            } else if now >= deadline {
+                break Err(SpiError::Timeout);
+            }
+
+            if self.interrupts {
+                wait_for_interrupt(deadline - now);
+            } else {
+                core::hint::spin_loop();
+            }
+        };
+
+        SPI0.stop(self.cs);
+        result
+    }
+}
+
+impl Drop for Spi {
+    fn drop(&mut self) {
+        if self.interrupts {
+            interrupt::unregister(IRQ);
+        }
+
+        if let Some(pins) = self.pins.take() {
+            pins.release();
+        }
+    }
+}
+
+impl embedded_hal::spi::ErrorType for Spi {
+    type Error = SpiError;
+}
+
+/// Transfers are done when the call returns, [flush()](embedded_hal::spi::SpiBus::flush) has
+/// nothing to wait for
+impl embedded_hal::spi::SpiBus<u8> for Spi {
+    /// Sends zeros
+    fn read(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        self.exchange(words.len(), |_| 0, |i, byte| words[i] = byte)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        self.exchange(words.len(), |i| words[i], |_, _| ())
+    }
+
+    /// Sends zeros past the end of `write`, drops what's received past the end of `read`
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), SpiError> {
+        let len = read.len().max(write.len());
+
+        self.exchange(
+            len,
+            |i| write.get(i).copied().unwrap_or(0),
+            |i, byte| {
+                if let Some(word) = read.get_mut(i) {
+                    *word = byte;
+                }
+            },
+        )
+    }
+
+    /// The byte at an index is always sent before the one received there, so both can use
+    /// the same buffer
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        let words = core::cell::Cell::from_mut(words).as_slice_of_cells();
+        self.exchange(words.len(), |i| words[i].get(), |i, byte| words[i].set(byte))
+    }
+
+    fn flush(&mut self) -> Result<(), SpiError> {
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// MISO, MOSI and SCLK, and the chip enables the controller drives
+struct SpiPins {
+    miso: Pin<9, Alt0>,
+    mosi: Pin<10, Alt0>,
+    sclk: Pin<11, Alt0>,
+    ce0: Option<Pin<8, Alt0>>,
+    ce1: Option<Pin<7, Alt0>>,
+}
+
+impl SpiPins {
+    /// Claim the pins and switch them to SPI0, all or nothing
+    fn take(chip_select: Option<ChipSelect>) -> Option<SpiPins> {
+        let (miso, mosi, sclk) = (Pin::<9, _>::take(), Pin::<10, _>::take(), Pin::<11, _>::take());
+        let ce0 = match chip_select {
+            Some(ChipSelect::Ce0) => Pin::<8, _>::take().map(Some),
+            _ => Some(None),
+        };
+        let ce1 = match chip_select {
+            Some(ChipSelect::Ce1) => Pin::<7, _>::take().map(Some),
+            _ => Some(None),
+        };
+
+        match (miso, mosi, sclk, ce0, ce1) {
+            (Some(miso), Some(mosi), Some(sclk), Some(ce0), Some(ce1)) => Some(SpiPins {
+                miso: miso.into_alt0(),
+                mosi: mosi.into_alt0(),
+                sclk: sclk.into_alt0(),
+                ce0: ce0.map(Pin::into_alt0),
+                ce1: ce1.map(Pin::into_alt0),
+            }),
+            (miso, mosi, sclk, ce0, ce1) => {
+                miso.into_iter().for_each(Pin::release);
+                mosi.into_iter().for_each(Pin::release);
+                sclk.into_iter().for_each(Pin::release);
+                ce0.flatten().into_iter().for_each(Pin::release);
+                ce1.flatten().into_iter().for_each(Pin::release);
+                None
+            }
+        }
+    }
+
+    fn release(self) {
+        self.miso.release();
+        self.mosi.release();
+        self.sclk.release();
+        self.ce0.into_iter().for_each(Pin::release);
+        self.ce1.into_iter().for_each(Pin::release);
+    }
+}
+
+/// BCM2835 SPI0 registers
+struct Controller<const T: usize>;
+
+impl<const T: usize> Controller<T> {
+    const CS: usize = T;
+    const CS_CPHA: u32 = 1 << 2;
+    const CS_CPOL: u32 = 1 << 3;
+    const CS_CLEAR: u32 = 0b11 << 4;
+    /// Transfer active, chip select is asserted while it's set
+    const CS_TA: u32 = 1 << 7;
+    /// Interrupt on DONE
+    const CS_INTD: u32 = 1 << 9;
+    /// Interrupt on RXR
+    const CS_INTR: u32 = 1 << 10;
+    const CS_DONE: u32 = 1 << 16;
+    /// Receive FIFO has data
+    const CS_RXD: u32 = 1 << 17;
+    /// Transmit FIFO has room
+    const CS_TXD: u32 = 1 << 18;
+    /// Receive FIFO is 3/4 full
+    const CS_RXR: u32 = 1 << 19;
+    const FIFO: usize = T + 0x04;
+    const CLK: usize = T + 0x08;
+
+    /// The CS bits for `config`'s clock mode and chip select
+    fn cs_bits(&self, config: &Config) -> u32 {
+        let mut cs = match config.chip_select {
+            Some(ChipSelect::Ce0) | None => 0,
+            Some(ChipSelect::Ce1) => 1,
+        };
+
+        if config.mode.phase == Phase::CaptureOnSecondTransition {
+            cs |= Self::CS_CPHA;
+        }
+        if config.mode.polarity == Polarity::IdleHigh {
+            cs |= Self::CS_CPOL;
+        }
+
+        cs
+    }
+
+    /// Idle with the clock's polarity set, `cs` are the transfer's CS bits
+    fn setup(&self, cs: u32, divider: u32) {
+        unsafe {
+            mmio::write32(Self::CS, cs | Self::CS_CLEAR);
+            mmio::write32(Self::CLK, divider);
+        }
+    }
+
+    /// Empty the FIFOs and assert chip select
+    fn start(&self, cs: u32) {
+        unsafe { mmio::write32(Self::CS, cs | Self::CS_CLEAR | Self::CS_TA) };
+    }
+
+    /// Deassert chip select, dropping anything left in the FIFOs
+    fn stop(&self, cs: u32) {
+        unsafe { mmio::write32(Self::CS, cs | Self::CS_CLEAR) };
+    }
+
+    fn can_write(&self) -> bool {
+        unsafe { mmio::read32(Self::CS) & Self::CS_TXD != 0 }
+    }
+
+    fn can_read(&self) -> bool {
+        unsafe { mmio::read32(Self::CS) & Self::CS_RXD != 0 }
+    }
+
+    fn write(&self, byte: u8) {
+        unsafe { mmio::write32(Self::FIFO, byte as u32) };
+    }
+
+    fn read(&self) -> u8 {
+        unsafe { mmio::read32(Self::FIFO) as u8 }
+    }
+
+    /// Interrupt when the transmit FIFO runs dry or the receive FIFO fills up
+    fn enable_interrupts(&self) {
+        unsafe { mmio::modify32(Self::CS, Self::CS_INTD | Self::CS_INTR, Self::CS_INTD | Self::CS_INTR) };
+    }
+
+    /// The interrupt is level triggered on DONE and RXR, masking it is the only way to
+    /// quiet it before the FIFOs are serviced
+    fn disable_interrupts(&self) -> bool {
+        unsafe {
+            let cs = mmio::read32(Self::CS);
+            mmio::write32(Self::CS, cs & !(Self::CS_INTD | Self::CS_INTR));
+            cs & (Self::CS_DONE | Self::CS_RXR) != 0
+        }
+    }
+}
+
+static SPI0: Controller<0x3F20_4000> = Controller;
+
+/// Set by the interrupt, the futex a transfer sleeps on
+static WAKE: AtomicU32 = AtomicU32::new(0);
+
+/// Divider for the fastest clock not above `frequency`
+///
+/// It must be even, 0 divides by 65536.
+fn divider(clock: u32, frequency: u32) -> u32 {
+    let divider = clock.div_ceil(frequency.max(1)).next_multiple_of(2);
+
+    match divider {
+        0..=2 => 2,
+        65536.. => 0,
+        divider => divider,
+    }
+}
+
+/// Sleep until the controller wants attention, or at most `timeout`
+///
+/// Spins once if the caller can't sleep.
+fn wait_for_interrupt(timeout: Duration) {
+    WAKE.store(0, Ordering::Relaxed);
+    SPI0.enable_interrupts();
+
+    if let Err(FutexError::NotAllowed) = futex_wait(&WAKE, 0, Some(timeout)) {
+        core::hint::spin_loop();
+    }
+
+    SPI0.disable_interrupts();
+}
+
+fn handle_interrupt() {
+    if SPI0.disable_interrupts() {
+        WAKE.store(1, Ordering::Release);
+        futex_wake(&WAKE, usize::MAX);
+    }
+}
@@ -0,0 +1,271 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS System Timer
+//!
+//! The BCM2835 system timer: a free running 64 bit counter at 1MHz and four 32 bit compare
+//! registers matched against its low word. The VideoCore uses channels 0 and 2, channels
+//! 1 and 3 are free for the ARM and can call back through [set_alarm()] and
+//! [set_periodic()].
+//!
+//! It's clocked independently of the ARM generic timer ([crate::cpu::timer]), see
+//! [measure_generic_timer()].
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datasheets.raspberrypi.com/bcm2835/bcm2835-peripherals.pdf> (chapter 12)
+//!
+
+use super::interrupt::{self, Irq};
+use super::mmio;
+use crate::cpu::timer;
+use crate::sync::once::Once;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+/// Counter frequency in Hz
+pub const FREQUENCY: u64 = 1_000_000;
+
+/// Shortest delay programmed, a compare value this close is still ahead of the counter
+const MIN_DELAY: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for the system timer
+pub enum TimerError {
+    /// The channel already has a callback
+    Busy,
+    /// Compares are 32 bits, about 71 minutes
+    TooLong,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for TimerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            TimerError::Busy => f.write_str("Timer channel is in use"),
+            TimerError::TooLong => f.write_str("Delay doesn't fit the timer's compare register"),
+        }
+    }
+}
+
+/// A compare channel the ARM may use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Channel {
+    /// Compare 1
+    One = 1,
+    /// Compare 3
+    Three = 3,
+}
+
+impl Channel {
+    /// Index into the per channel tables
+    fn slot(self) -> usize {
+        match self {
+            Channel::One => 0,
+            Channel::Three => 1,
+        }
+    }
+
+    /// Peripheral interrupt of the channel, the interrupt number is the channel
+    fn irq(self) -> Irq {
+        Irq::peripheral(self as usize)
+    }
+}
+
+/// BCM2835 system timer registers
+struct SystemTimer<const T: usize>;
+
+impl<const T: usize> SystemTimer<T> {
+    /// Match bits, one per channel, write 1 to clear
+    const CS: usize = T;
+    const CLO: usize = T + 0x04;
+    const CHI: usize = T + 0x08;
+    const C0: usize = T + 0x0C;
+
+    /// The 64 bit counter, read as two halves
+    fn counter(&self) -> u64 {
+        unsafe {
+            loop {
+                let high = mmio::read32(Self::CHI);
+                let low = mmio::read32(Self::CLO);
+
+                // The low word wrapped between the reads
+                if mmio::read32(Self::CHI) == high {
+                    return ((high as u64) << 32) | low as u64;
+                }
+            }
+        }
+    }
+
+    /// Make `channel` match at `compare`, or a little later if that already passed
+    fn set_compare(&self, channel: Channel, mut compare: u32) {
+        let reg = Self::C0 + channel as usize * 4;
+
+        unsafe {
+            loop {
+                mmio::write32(reg, compare);
+
+                // Matched or still ahead, either way the interrupt comes
+                let now = mmio::read32(Self::CLO);
+                if self.matched(channel) || (compare.wrapping_sub(now) as i32) > 0 {
+                    return;
+                }
+
+                compare = now.wrapping_add(MIN_DELAY);
+            }
+        }
+    }
+
+    fn compare(&self, channel: Channel) -> u32 {
+        unsafe { mmio::read32(Self::C0 + channel as usize * 4) }
+    }
+
+    fn matched(&self, channel: Channel) -> bool {
+        unsafe { mmio::read32(Self::CS) & (1 << channel as usize) != 0 }
+    }
+
+    fn clear(&self, channel: Channel) {
+        unsafe { mmio::write32(Self::CS, 1 << channel as usize) };
+    }
+}
+
+static SYSTEM_TIMER: SystemTimer<0x3F00_3000> = SystemTimer;
+
+/// Callback of each channel, 0 means none, like [interrupt]'s own table
+static CALLBACKS: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+
+/// Period of each channel in ticks, 0 for an alarm that fires once
+static PERIODS: [AtomicU32; 2] = [const { AtomicU32::new(0) }; 2];
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Ticks of the 1MHz counter since power on
+pub fn counter() -> u64 {
+    SYSTEM_TIMER.counter()
+}
+
+/// Time since power on, by the system timer
+pub fn uptime() -> Duration {
+    Duration::from_micros(counter())
+}
+
+/// Call `callback` once, `after` from now
+///
+/// The callback runs from the channel's interrupt, with IRQs masked, on core 0.
+pub fn set_alarm(channel: Channel, after: Duration, callback: fn()) -> Result<(), TimerError> {
+    start(channel, ticks(after)?, 0, callback)
+}
+
+/// Call `callback` every `period`, starting one period from now
+///
+/// Periods are counted from one compare to the next, a late interrupt doesn't make the
+/// following ones late.
+pub fn set_periodic(channel: Channel, period: Duration, callback: fn()) -> Result<(), TimerError> {
+    let period = ticks(period)?;
+    start(channel, period, period, callback)
+}
+
+/// Drop the callback of `channel`, it won't be called again
+pub fn cancel(channel: Channel) {
+    interrupt::disable(channel.irq());
+    CALLBACKS[channel.slot()].store(0, Ordering::Release);
+    SYSTEM_TIMER.clear(channel);
+}
+
+/// Measure the frequency of the ARM generic timer against the system timer, over `window`
+///
+/// Spins for the whole window. What comes out should match
+/// [crate::cpu::timer::frequency()], a large difference means one of the clocks is off.
+pub fn measure_generic_timer(window: Duration) -> u64 {
+    let micros = (window.as_micros() as u64).max(1);
+
+    // Start on a tick edge, so the window isn't short by up to a tick
+    let edge = counter() + 1;
+    while counter() < edge {
+        core::hint::spin_loop();
+    }
+
+    let start = timer::counter();
+    while counter() < edge + micros {
+        core::hint::spin_loop();
+    }
+    let ticks = timer::counter() - start;
+
+    (ticks as u128 * FREQUENCY as u128 / micros as u128) as u64
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// Duration in counter ticks, if a compare can reach it
+fn ticks(duration: Duration) -> Result<u32, TimerError> {
+    let ticks = duration.as_micros().max(MIN_DELAY as u128);
+    u32::try_from(ticks).map_err(|_| TimerError::TooLong)
+}
+
+/// Install `callback` and program the first compare
+fn start(channel: Channel, delay: u32, period: u32, callback: fn()) -> Result<(), TimerError> {
+    init_irqs();
+
+    CALLBACKS[channel.slot()]
+        .compare_exchange(0, callback as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .map_err(|_| TimerError::Busy)?;
+
+    PERIODS[channel.slot()].store(period, Ordering::Relaxed);
+
+    SYSTEM_TIMER.clear(channel);
+    let now = SYSTEM_TIMER.counter() as u32;
+    SYSTEM_TIMER.set_compare(channel, now.wrapping_add(delay));
+    interrupt::enable(channel.irq());
+
+    Ok(())
+}
+
+/// Install the channel interrupt handlers, the first time a callback is set
+fn init_irqs() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        let _ = interrupt::register(Channel::One.irq(), || fire(Channel::One));
+        let _ = interrupt::register(Channel::Three.irq(), || fire(Channel::Three));
+    });
+}
+
+/// A compare matched, run the callback and rearm a periodic channel
+fn fire(channel: Channel) {
+    SYSTEM_TIMER.clear(channel);
+
+    let callback = CALLBACKS[channel.slot()].load(Ordering::Acquire);
+    if callback == 0 {
+        interrupt::disable(channel.irq());
+        return;
+    }
+
+    match PERIODS[channel.slot()].load(Ordering::Relaxed) {
+        0 => {
+            interrupt::disable(channel.irq());
+            CALLBACKS[channel.slot()].store(0, Ordering::Release);
+        }
+        period => {
+            let next = SYSTEM_TIMER.compare(channel).wrapping_add(period);
+            SYSTEM_TIMER.set_compare(channel, next);
+        }
+    }
+
+    let callback: fn() = unsafe { core::mem::transmute(callback) };
+    callback();
+}
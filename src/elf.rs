@@ -0,0 +1,408 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS ELF loader
+//!
+//! Loads statically linked ELF64 AArch64 executables into a fresh [AddressSpace] and starts
+//! them in EL0. Every `PT_LOAD` segment becomes a demand-zero region with the segment's
+//! permissions, the file part is copied in and the rest (bss) is left to fault in zeroed.
+//!
+//! The stack is set up the way the Linux aarch64 ABI expects it at `_start`, so a static
+//! musl binary runs unmodified:
+//!
+//! ```text
+//!   USER_STACK_TOP -> 16 random bytes (AT_RANDOM)
+//!                     argv and envp strings
+//!                     ...
+//!                     auxv (type, value) pairs, ending with AT_NULL
+//!                     envp pointers, NULL
+//!                     argv pointers, NULL
+//!               sp -> argc
+//! ```
+//!
+//! Segments have to be [PAGE_SIZE] aligned in the file and in memory, which is what aarch64
+//! linkers do by default (`max-page-size` is 64KiB).
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html>
+//!   - <https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html>
+//!   - <https://github.com/ARM-software/abi-aa/blob/main/aaelf64/aaelf64.rst>
+//!   - <https://lwn.net/Articles/631631/> (How programs get run: ELF binaries)
+//!
+
+use crate::memory::vm::{self, AddressSpace, VmError, USER_END, USER_STACK_SIZE, USER_STACK_TOP};
+use crate::memory::PAGE_SIZE;
+use crate::random;
+use crate::task::{self, JoinHandle};
+
+/// Size of the ELF64 file header
+const EHDR_SIZE: usize = 64;
+
+/// Size of an ELF64 program header
+const PHDR_SIZE: usize = 56;
+
+/// e_ident
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+/// e_type of an executable that isn't position independent
+const ET_EXEC: u16 = 2;
+
+/// e_machine
+const EM_AARCH64: u16 = 183;
+
+/// p_type
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+/// p_flags
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Auxiliary vector types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Random bytes at the top of the stack, libc seeds its stack protector with them
+const RANDOM_SIZE: usize = 16;
+
+/// Most of the stack argv and envp may take, the rest is for the program
+const MAX_ARGS_SIZE: usize = USER_STACK_SIZE / 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for loading programs
+pub enum ElfError {
+    /// The image ends before a header or segment does
+    Truncated,
+    /// Not an ELF64 little endian AArch64 file
+    BadHeader,
+    /// Not a static executable, shared objects and interpreters aren't supported
+    NotExecutable,
+    /// A segment is misaligned, overlaps another or leaves the user half
+    BadSegment,
+    /// argv and envp don't fit on the stack
+    ArgsTooLong,
+    /// Setting up the address space failed
+    Vm(VmError),
+    /// No task slot or stack for the program
+    Spawn,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            ElfError::Truncated => f.write_str("ELF image is truncated"),
+            ElfError::BadHeader => f.write_str("Not an ELF64 AArch64 image"),
+            ElfError::NotExecutable => f.write_str("Not a static executable"),
+            ElfError::BadSegment => f.write_str("Bad ELF segment"),
+            ElfError::ArgsTooLong => f.write_str("Arguments too long"),
+            ElfError::Vm(err) => write!(f, "Failed to map program: {}", err),
+            ElfError::Spawn => f.write_str("Failed to spawn program"),
+        }
+    }
+}
+
+impl From<VmError> for ElfError {
+    fn from(err: VmError) -> ElfError {
+        ElfError::Vm(err)
+    }
+}
+
+/// A program header
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    /// p_type
+    pub kind: u32,
+    /// p_flags, [PF_R], [PF_W] and [PF_X]
+    pub flags: u32,
+    /// Where the file part starts in the image
+    pub offset: usize,
+    /// Where the segment goes in the address space
+    pub vaddr: usize,
+    /// Bytes taken from the image
+    pub filesz: usize,
+    /// Bytes in memory, the rest after `filesz` is zeroed
+    pub memsz: usize,
+}
+
+impl Segment {
+    /// Protection of the segment's region
+    fn prot(&self) -> u32 {
+        [(PF_R, vm::PROT_READ), (PF_W, vm::PROT_WRITE), (PF_X, vm::PROT_EXEC)]
+            .iter()
+            .filter(|(flag, _)| self.flags & flag != 0)
+            .fold(0, |prot, (_, bit)| prot | bit)
+    }
+}
+
+/// A parsed ELF64 AArch64 executable
+///
+/// Only the headers are checked by [Elf::parse()], the segments when they're loaded.
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the file header of `image`
+    pub fn parse(image: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let ident = image.get(..16).ok_or(ElfError::Truncated)?;
+        if ident[..4] != ELF_MAGIC
+            || ident[4] != ELFCLASS64
+            || ident[5] != ELFDATA2LSB
+            || ident[6] != EV_CURRENT
+        {
+            return Err(ElfError::BadHeader);
+        }
+
+        if image.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+
+        if read_u16(image, 18) != EM_AARCH64 {
+            return Err(ElfError::BadHeader);
+        }
+
+        if read_u16(image, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+
+        if read_u16(image, 54) as usize != PHDR_SIZE {
+            return Err(ElfError::BadHeader);
+        }
+
+        let elf = Elf {
+            image,
+            entry: read_u64(image, 24) as usize,
+            phoff: read_u64(image, 32) as usize,
+            phnum: read_u16(image, 56) as usize,
+        };
+
+        let end = elf
+            .phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| size.checked_add(elf.phoff))
+            .ok_or(ElfError::Truncated)?;
+        if end > image.len() {
+            return Err(ElfError::Truncated);
+        }
+
+        Ok(elf)
+    }
+
+    /// Address of the first instruction
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Every program header
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.phnum).map(|i| {
+            let at = self.phoff + i * PHDR_SIZE;
+
+            Segment {
+                kind: read_u32(self.image, at),
+                flags: read_u32(self.image, at + 4),
+                offset: read_u64(self.image, at + 8) as usize,
+                vaddr: read_u64(self.image, at + 16) as usize,
+                filesz: read_u64(self.image, at + 32) as usize,
+                memsz: read_u64(self.image, at + 40) as usize,
+            }
+        })
+    }
+
+    /// Map every `PT_LOAD` segment into `space` and copy the file parts in
+    pub fn load(&self, space: &mut AddressSpace) -> Result<(), ElfError> {
+        if self.segments().any(|segment| segment.kind == PT_INTERP) {
+            return Err(ElfError::NotExecutable);
+        }
+
+        for segment in self.segments().filter(|segment| segment.kind == PT_LOAD) {
+            let end = segment.vaddr.checked_add(segment.memsz).ok_or(ElfError::BadSegment)?;
+            let file_end = segment.offset.checked_add(segment.filesz).ok_or(ElfError::Truncated)?;
+
+            if segment.filesz > segment.memsz
+                || segment.vaddr % PAGE_SIZE != segment.offset % PAGE_SIZE
+                || end > USER_END
+            {
+                return Err(ElfError::BadSegment);
+            }
+
+            let data = self.image.get(segment.offset..file_end).ok_or(ElfError::Truncated)?;
+            let start = segment.vaddr - segment.vaddr % PAGE_SIZE;
+
+            space
+                .map(Some(start), end - start, segment.prot())
+                .map_err(|err| match err {
+                    VmError::BadRange => ElfError::BadSegment,
+                    err => ElfError::Vm(err),
+                })?;
+            space.write(segment.vaddr, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Where the program headers end up in memory, if a segment loads them
+    fn phdr(&self) -> Option<usize> {
+        self.segments()
+            .filter(|segment| segment.kind == PT_LOAD)
+            .find(|segment| (segment.offset..segment.offset + segment.filesz).contains(&self.phoff))
+            .map(|segment| segment.vaddr + (self.phoff - segment.offset))
+    }
+
+    /// Auxiliary vector handed to the program, `random` is where the random bytes are
+    fn auxv(&self, random: usize) -> [(u64, u64); 7] {
+        // Without a loaded copy of the headers there is nothing to point at
+        let (phdr_type, phdr) = match self.phdr() {
+            Some(phdr) => (AT_PHDR, phdr as u64),
+            None => (AT_NULL, 0),
+        };
+
+        [
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_ENTRY, self.entry as u64),
+            (AT_PHENT, PHDR_SIZE as u64),
+            (AT_PHNUM, self.phnum as u64),
+            (AT_RANDOM, random as u64),
+            (phdr_type, phdr),
+            (AT_NULL, 0),
+        ]
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Build the address space of `image` with `argv` and `envp` on its stack
+///
+/// The space is ready for [task::spawn_in()], its start is the entry point and the initial
+/// stack.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<AddressSpace, ElfError> {
+    let elf = Elf::parse(image)?;
+    let mut space = AddressSpace::new()?;
+
+    elf.load(&mut space)?;
+
+    space.map(
+        Some(USER_STACK_TOP - USER_STACK_SIZE),
+        USER_STACK_SIZE,
+        vm::PROT_READ | vm::PROT_WRITE,
+    )?;
+
+    let mut bytes = [0; RANDOM_SIZE];
+    random::fill_bytes(&mut bytes);
+    let random = USER_STACK_TOP - RANDOM_SIZE;
+    space.write(random, &bytes)?;
+
+    let sp = push_args(&mut space, random, argv, envp, &elf.auxv(random))?;
+
+    space.set_start(elf.entry(), sp);
+    Ok(space)
+}
+
+/// Load `image` and run it in EL0
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::elf;
+///
+/// fn run_init(image: &[u8]) -> i32 {
+///     let handle = elf::spawn(image, &["init"], &["HOME=/"]).unwrap();
+///     handle.join()
+/// }
+/// ```
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<JoinHandle, ElfError> {
+    let space = load(image, argv, envp)?;
+    task::spawn_in(space).map_err(|_| ElfError::Spawn)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+fn read_u16(image: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([image[at], image[at + 1]])
+}
+
+fn read_u32(image: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&image[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(image: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&image[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Lay out argc, argv, envp and auxv below `top`, returns the sp for `_start`
+fn push_args(
+    space: &mut AddressSpace,
+    top: usize,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<usize, ElfError> {
+    let strings: usize = argv.iter().chain(envp).map(|arg| arg.len() + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * auxv.len();
+
+    if strings + words * 8 > MAX_ARGS_SIZE {
+        return Err(ElfError::ArgsTooLong);
+    }
+
+    let mut string = top - strings;
+    let sp = (string - words * 8) & !0xF;
+    let mut word = sp;
+
+    let mut push_word = |space: &mut AddressSpace, value: u64| {
+        let result = space.write(word, &value.to_le_bytes());
+        word += 8;
+        result
+    };
+
+    push_word(space, argv.len() as u64)?;
+
+    for list in [argv, envp] {
+        for arg in list {
+            space.write(string, arg.as_bytes())?;
+            space.write(string + arg.len(), &[0])?;
+
+            push_word(space, string as u64)?;
+            string += arg.len() + 1;
+        }
+        push_word(space, 0)?;
+    }
+
+    for &(kind, value) in auxv {
+        push_word(space, kind)?;
+        push_word(space, value)?;
+    }
+
+    Ok(sp)
+}
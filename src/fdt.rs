@@ -0,0 +1,281 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Device tree
+//!
+//! Just enough of a flattened device tree (DTB) reader to find properties by path, like
+//! `/chosen` `bootargs` or where the bootloader put the initrd. Nothing is copied, an [Fdt]
+//! borrows the blob and walks its structure block on every lookup.
+//!
+//! The blob the firmware booted with ([crate::cpu::boot_dtb()]) is checked and its frames
+//! reserved by [init()], after that it's available from [get()].
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html>
+//!   - <https://www.kernel.org/doc/Documentation/devicetree/bindings/chosen.txt>
+//!
+
+use crate::memory::{frame, phys_to_virt};
+use crate::sync::once::OnceCell;
+
+/// Magic number at the start of every blob
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+
+/// Size of the header
+const HEADER_SIZE: usize = 40;
+
+/// Oldest version with the layout this reader expects
+const FDT_COMPAT_VERSION: u32 = 16;
+
+/// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for reading a device tree
+pub enum FdtError {
+    /// Doesn't start with the magic number
+    BadMagic,
+    /// Older than version 16
+    BadVersion,
+    /// A block is outside the blob
+    Truncated,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for FdtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            FdtError::BadMagic => f.write_str("Not a device tree"),
+            FdtError::BadVersion => f.write_str("Unsupported device tree version"),
+            FdtError::Truncated => f.write_str("Device tree is truncated"),
+        }
+    }
+}
+
+/// A flattened device tree
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Check the header of `blob`
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Fdt<'a>, FdtError> {
+        if blob.len() < HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+
+        if be32(blob, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+
+        let header = |at| {
+            be32(blob, at)
+                .map(|value| value as usize)
+                .ok_or(FdtError::Truncated)
+        };
+
+        if be32(blob, 24).is_none_or(|compat| compat > FDT_COMPAT_VERSION)
+            || be32(blob, 20).is_none_or(|version| version < FDT_COMPAT_VERSION)
+        {
+            return Err(FdtError::BadVersion);
+        }
+
+        let blob = blob.get(..header(4)?).ok_or(FdtError::Truncated)?;
+        let block = |offset: usize, size: usize| {
+            offset
+                .checked_add(size)
+                .and_then(|end| blob.get(offset..end))
+                .ok_or(FdtError::Truncated)
+        };
+
+        Ok(Fdt {
+            blob,
+            structs: block(header(8)?, header(36)?)?,
+            strings: block(header(12)?, header(32)?)?,
+        })
+    }
+
+    /// Read the blob at physical address `pa`
+    ///
+    /// ## Safety
+    ///
+    /// `pa` has to point at RAM that holds the whole blob and isn't written for `'a`.
+    pub unsafe fn from_addr(pa: usize) -> Result<Fdt<'a>, FdtError> {
+        let header = core::slice::from_raw_parts(phys_to_virt(pa) as *const u8, HEADER_SIZE);
+        let size = be32(header, 4).unwrap_or(0) as usize;
+
+        match be32(header, 0) {
+            Some(FDT_MAGIC) if size >= HEADER_SIZE => Fdt::from_bytes(core::slice::from_raw_parts(
+                phys_to_virt(pa) as *const u8,
+                size,
+            )),
+            _ => Err(FdtError::BadMagic),
+        }
+    }
+
+    /// Size of the blob in bytes
+    pub fn size(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Value of property `name` of the node at `path`
+    ///
+    /// Path components without a unit address match any node with that name, `/memory`
+    /// finds `/memory@0`.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::fdt;
+    ///
+    /// if let Some(bootargs) = fdt::get().and_then(|fdt| fdt.property("/chosen", "bootargs")) {
+    ///     dyseos::println!("cmdline: {}", fdt::as_str(bootargs).unwrap_or(""));
+    /// }
+    /// ```
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let mut components = path
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .peekable();
+        let depth_wanted = components.clone().count();
+
+        // Open nodes, and how many of them are on the way to `path`
+        let mut depth = 0;
+        let mut matched = 0;
+        let mut offset = 0;
+
+        loop {
+            let token = be32(self.structs, offset)?;
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node = cstr(self.structs, offset)?;
+                    offset = align4(offset + node.len() + 1);
+                    depth += 1;
+
+                    // The root has no name and is always on the way
+                    if depth > 1
+                        && matched == depth - 2
+                        && components.next_if(|c| node_matches(node, c)).is_some()
+                    {
+                        matched += 1;
+                    }
+                }
+                FDT_END_NODE => {
+                    // Leaving the node `path` names, or one on the way, means it's not there
+                    if depth > 1 && matched == depth - 1 {
+                        return None;
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = be32(self.structs, offset)? as usize;
+                    let name_offset = be32(self.structs, offset + 4)? as usize;
+                    let value = self.structs.get(offset + 8..offset + 8 + len)?;
+                    offset = align4(offset + 8 + len);
+
+                    if matched == depth_wanted
+                        && depth == depth_wanted + 1
+                        && cstr(self.strings, name_offset)? == name.as_bytes()
+                    {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                // FDT_END, or a token this reader doesn't know
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Read a property holding one or two cells (`u32` or `u64`)
+pub fn as_u64(value: &[u8]) -> Option<u64> {
+    match value.len() {
+        4 => be32(value, 0).map(u64::from),
+        8 => Some((u64::from(be32(value, 0)?) << 32) | u64::from(be32(value, 4)?)),
+        _ => None,
+    }
+}
+
+/// Read a string property, without its terminating NUL
+pub fn as_str(value: &[u8]) -> Option<&str> {
+    core::str::from_utf8(value.strip_suffix(&[0]).unwrap_or(value)).ok()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The device tree the kernel booted with
+static BOOT_FDT: OnceCell<Fdt<'static>> = OnceCell::new();
+
+/// Check the device tree from the firmware and keep its frames from being allocated
+///
+/// Must be called once, by the boot core, after [crate::memory::init()]. Booting without a
+/// device tree isn't an error, [get()] just returns None.
+pub fn init() {
+    let Some(pa) = crate::cpu::boot_dtb() else {
+        return;
+    };
+
+    match unsafe { Fdt::from_addr(pa) } {
+        Ok(fdt) => {
+            frame::reserve(pa, pa + fdt.size());
+            let _ = BOOT_FDT.set(fdt);
+        }
+        Err(err) => crate::println!("Ignoring device tree at {:#x}: {}", pa, err),
+    }
+}
+
+/// The device tree the kernel booted with, see [init()]
+pub fn get() -> Option<&'static Fdt<'static>> {
+    BOOT_FDT.get()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+    let bytes = bytes.get(at..at.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// NUL terminated string at `at`, without the NUL
+fn cstr(bytes: &[u8], at: usize) -> Option<&[u8]> {
+    let rest = bytes.get(at..)?;
+    rest.iter().position(|&b| b == 0).map(|end| &rest[..end])
+}
+
+/// `component` without a unit address matches `node` with any
+fn node_matches(node: &[u8], component: &str) -> bool {
+    let component = component.as_bytes();
+
+    match component.contains(&b'@') {
+        true => node == component,
+        false => node.split(|&b| b == b'@').next() == Some(component),
+    }
+}
@@ -0,0 +1,451 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Initramfs
+//!
+//! Read-only file tree from a cpio archive in the `newc` format, for shipping programs and
+//! config files with the kernel. The archive comes from one of two places:
+//!
+//!   - The bootloader, through `linux,initrd-start` and `linux,initrd-end` in `/chosen` of
+//!     the device tree (qemu's `-initrd`). This one wins if both are there.
+//!   - The kernel image, built with the `initramfs` feature and the archive's path in the
+//!     `DYSEOS_INITRAMFS` environment variable. It ends up in the `.initramfs` section.
+//!
+//! Build an archive with `cd rootfs && find . | cpio -o -H newc > ../initramfs.cpio`.
+//!
+//! Nothing is copied or unpacked, the tree is the archive itself and lookups scan it.
+//! Hard links aren't resolved, only the last link of a file carries its data. [InitramfsFs]
+//! puts the tree in the [crate::vfs], it's mounted at `/` at boot.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.kernel.org/doc/html/latest/driver-api/early-userspace/buffer-format.html>
+//!   - <https://man7.org/linux/man-pages/man5/cpio.5.html>
+//!
+
+use crate::memory::{frame, phys_to_virt};
+use crate::sync::once::OnceCell;
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, Stat, VfsError, VfsResult};
+use alloc::{format, string::String, sync::Arc};
+
+/// Size of a newc header, the magic and 13 fields of 8 hex digits
+const HEADER_SIZE: usize = 110;
+
+/// newc magic, without and with checksums
+const MAGIC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+
+/// Name of the entry that ends the archive
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of the mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[cfg(feature = "initramfs")]
+#[link_section = ".initramfs"]
+#[used]
+static EMBEDDED: [u8; include_bytes!(env!("DYSEOS_INITRAMFS")).len()] =
+    *include_bytes!(env!("DYSEOS_INITRAMFS"));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for reading an archive
+pub enum CpioError {
+    /// A header doesn't start with the newc magic
+    BadMagic,
+    /// A header field isn't hex
+    BadHeader,
+    /// A name isn't UTF-8
+    BadName,
+    /// The archive ends inside an entry or before the trailer
+    Truncated,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for CpioError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            CpioError::BadMagic => f.write_str("Not a newc cpio archive"),
+            CpioError::BadHeader => f.write_str("Bad cpio header"),
+            CpioError::BadName => f.write_str("cpio name isn't UTF-8"),
+            CpioError::Truncated => f.write_str("cpio archive is truncated"),
+        }
+    }
+}
+
+/// What a [Node] is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Regular file, its data is the contents
+    File,
+    /// Directory, see [Initramfs::read_dir()]
+    Dir,
+    /// Symbolic link, its data is the target
+    Symlink,
+    /// Device node, fifo or socket
+    Other,
+}
+
+/// An entry of the tree
+#[derive(Clone, Copy, Debug)]
+pub struct Node<'a> {
+    path: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Node<'a> {
+    /// Path in the archive, relative to the root and without a leading `./`
+    pub fn path(&self) -> &'a str {
+        self.path
+    }
+
+    /// Last component of the path, empty for the root
+    pub fn name(&self) -> &'a str {
+        self.path.rsplit('/').next().unwrap_or("")
+    }
+
+    /// What the node is
+    pub fn kind(&self) -> Kind {
+        match self.mode & S_IFMT {
+            S_IFREG => Kind::File,
+            S_IFDIR => Kind::Dir,
+            S_IFLNK => Kind::Symlink,
+            _ => Kind::Other,
+        }
+    }
+
+    /// Permission bits
+    pub fn permissions(&self) -> u32 {
+        self.mode & !S_IFMT
+    }
+
+    /// Contents of a file or target of a symlink
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// A checked newc archive
+#[derive(Clone, Copy)]
+pub struct Initramfs<'a> {
+    archive: &'a [u8],
+}
+
+impl<'a> Initramfs<'a> {
+    /// Check every header of `archive`
+    pub fn parse(archive: &'a [u8]) -> Result<Initramfs<'a>, CpioError> {
+        let mut offset = 0;
+
+        while let Some((_, next)) = entry(archive, offset)? {
+            offset = next;
+        }
+
+        Ok(Initramfs { archive })
+    }
+
+    /// Every entry in archive order, without the root
+    pub fn entries(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let archive = self.archive;
+        let mut offset = 0;
+
+        // Checked by parse(), errors can't happen here
+        core::iter::from_fn(move || {
+            let (node, next) = entry(archive, offset).ok()??;
+            offset = next;
+            Some(node)
+        })
+        .filter(|node| !node.path.is_empty())
+    }
+
+    /// Find the node at `path`
+    ///
+    /// Paths are relative to the root of the archive, leading slashes and `.` components
+    /// are ignored. Directories only implied by the paths of their contents are found too.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::initramfs;
+    ///
+    /// if let Some(init) = initramfs::get().and_then(|fs| fs.lookup("/sbin/init")) {
+    ///     dyseos::elf::spawn(init.data(), &["/sbin/init"], &[]).unwrap();
+    /// }
+    /// ```
+    pub fn lookup(&self, path: &str) -> Option<Node<'a>> {
+        if components(path).next().is_none() {
+            return Some(Node { path: "", mode: S_IFDIR | 0o755, data: &[] });
+        }
+
+        if let Some(node) = self.entries().find(|node| components(node.path).eq(components(path))) {
+            return Some(node);
+        }
+
+        let depth = components(path).count();
+        self.entries()
+            .find(|node| strip_dir(node.path, path).is_some_and(|mut rest| rest.next().is_some()))
+            .map(|node| Node {
+                path: prefix(node.path, depth),
+                mode: S_IFDIR | 0o755,
+                data: &[],
+            })
+    }
+
+    /// Nodes directly inside the directory at `path`
+    ///
+    /// None if `path` isn't a directory. Implied directories show up once, where the first
+    /// path inside them does.
+    pub fn read_dir<'p>(&self, path: &'p str) -> Option<impl Iterator<Item = Node<'a>> + 'p>
+    where
+        'a: 'p,
+    {
+        if self.lookup(path)?.kind() != Kind::Dir {
+            return None;
+        }
+
+        let fs = *self;
+        let depth = components(path).count();
+        let child = move |node: Node<'a>| {
+            strip_dir(node.path, path)?.next()?;
+            Some(prefix(node.path, depth + 1))
+        };
+
+        Some(self.entries().enumerate().filter_map(move |(i, node)| {
+            let name = child(node)?;
+            let first = fs.entries().take(i).all(|earlier| child(earlier) != Some(name));
+
+            first.then(|| fs.lookup(name)).flatten()
+        }))
+    }
+}
+
+/// An [Initramfs] as a read-only [crate::vfs] filesystem
+pub struct InitramfsFs {
+    fs: Initramfs<'static>,
+}
+
+impl InitramfsFs {
+    /// Wrap `fs` for mounting
+    pub fn new(fs: Initramfs<'static>) -> InitramfsFs {
+        InitramfsFs { fs }
+    }
+}
+
+impl FileSystem for InitramfsFs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(NodeInode {
+            fs: self.fs,
+            node: Node { path: "", mode: S_IFDIR | 0o755, data: &[] },
+        })
+    }
+}
+
+/// A [Node] as an [Inode]
+struct NodeInode {
+    fs: Initramfs<'static>,
+    node: Node<'static>,
+}
+
+impl Inode for NodeInode {
+    fn stat(&self) -> VfsResult<Stat> {
+        Ok(Stat {
+            ino: inode_number(self.node.path),
+            kind: match self.node.kind() {
+                Kind::File => FileType::File,
+                Kind::Dir => FileType::Dir,
+                Kind::Symlink => FileType::Symlink,
+                Kind::Other => FileType::Other,
+            },
+            mode: self.node.permissions(),
+            nlink: 1,
+            size: self.node.data.len() as u64,
+            rdev: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        if self.node.kind() == Kind::Dir {
+            return Err(VfsError::IsDir);
+        }
+
+        let data = self.node.data.get(offset as usize..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+
+        Ok(len)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn Inode>> {
+        if self.node.kind() != Kind::Dir {
+            return Err(VfsError::NotDir);
+        }
+
+        let node = self.fs.lookup(&format!("{}/{}", self.node.path, name)).ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(NodeInode { fs: self.fs, node }))
+    }
+
+    fn readdir(&self, index: usize) -> VfsResult<Option<DirEntry>> {
+        let mut entries = self.fs.read_dir(self.node.path).ok_or(VfsError::NotDir)?;
+
+        Ok(entries.nth(index).map(|node| DirEntry {
+            name: String::from(node.name()),
+            ino: inode_number(node.path),
+            kind: NodeInode { fs: self.fs, node }.stat().map_or(FileType::Other, |stat| stat.kind),
+        }))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// The archive the kernel booted with
+static INITRAMFS: OnceCell<Initramfs<'static>> = OnceCell::new();
+
+/// Find the archive and keep its frames from being allocated
+///
+/// Must be called once, by the boot core, after [crate::fdt::init()]. Booting without an
+/// archive isn't an error, [get()] just returns None.
+pub fn init() {
+    let Some(archive) = initrd().or_else(embedded) else {
+        return;
+    };
+
+    match Initramfs::parse(archive) {
+        Ok(fs) => {
+            let _ = INITRAMFS.set(fs);
+        }
+        Err(err) => crate::println!("Ignoring initramfs: {}", err),
+    }
+}
+
+/// The archive the kernel booted with, see [init()]
+pub fn get() -> Option<&'static Initramfs<'static>> {
+    INITRAMFS.get()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// Archive the bootloader loaded, reserved so the frame allocator doesn't hand it out
+fn initrd() -> Option<&'static [u8]> {
+    let chosen = |name| crate::fdt::get()?.property("/chosen", name).and_then(crate::fdt::as_u64);
+
+    let start = chosen("linux,initrd-start")? as usize;
+    let end = chosen("linux,initrd-end")? as usize;
+    if end <= start {
+        return None;
+    }
+
+    frame::reserve(start, end);
+    Some(unsafe { core::slice::from_raw_parts(phys_to_virt(start) as *const u8, end - start) })
+}
+
+/// Archive linked into the `.initramfs` section, if there is one
+fn embedded() -> Option<&'static [u8]> {
+    extern "C" {
+        static _sinitramfs: u8;
+        static _einitramfs: u8;
+    }
+
+    let start = unsafe { &_sinitramfs as *const u8 };
+    let len = unsafe { &_einitramfs as *const u8 as usize } - start as usize;
+
+    match len {
+        0 => None,
+        len => Some(unsafe { core::slice::from_raw_parts(start, len) }),
+    }
+}
+
+/// The entry whose header is at `offset` and the offset of the next one
+///
+/// None at the trailer.
+fn entry(archive: &[u8], offset: usize) -> Result<Option<(Node<'_>, usize)>, CpioError> {
+    let header = archive.get(offset..offset + HEADER_SIZE).ok_or(CpioError::Truncated)?;
+    if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+        return Err(CpioError::BadMagic);
+    }
+
+    // Fields after the magic: ino, mode, uid, gid, nlink, mtime, filesize, ..., namesize
+    let field = |index: usize| {
+        let hex = core::str::from_utf8(&header[6 + index * 8..6 + index * 8 + 8]);
+        hex.ok().and_then(|hex| u32::from_str_radix(hex, 16).ok()).ok_or(CpioError::BadHeader)
+    };
+    let mode = field(1)?;
+    let file_size = field(6)? as usize;
+    let name_size = field(11)? as usize;
+
+    // The name is NUL terminated, name and data are padded to 4 bytes from the start
+    let name_start = offset + HEADER_SIZE;
+    let data_start = align4(name_start + name_size);
+    let data_end = data_start + file_size;
+
+    let name = archive.get(name_start..name_start + name_size).ok_or(CpioError::Truncated)?;
+    let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name)).map_err(|_| CpioError::BadName)?;
+    if name == TRAILER {
+        return Ok(None);
+    }
+
+    let data = archive.get(data_start..data_end).ok_or(CpioError::Truncated)?;
+    let path = name.trim_start_matches("./").trim_start_matches('/');
+    let path = match path {
+        "." => "",
+        path => path,
+    };
+
+    Ok(Some((Node { path, mode, data }, align4(data_end))))
+}
+
+/// Inode numbers are a hash of the path, implied directories don't have a header to
+/// number them by (FNV-1a)
+fn inode_number(path: &str) -> u64 {
+    path.bytes()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01B3))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Path components that name something, `a//./b/` is `a` then `b`
+fn components(path: &str) -> impl Iterator<Item = &str> + Clone {
+    path.split('/').filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// Components of `path` after directory `dir`, None if `path` isn't under it
+fn strip_dir<'a>(path: &'a str, dir: &str) -> Option<impl Iterator<Item = &'a str>> {
+    let mut rest = components(path);
+
+    for component in components(dir) {
+        if rest.next() != Some(component) {
+            return None;
+        }
+    }
+
+    Some(rest)
+}
+
+/// The first `depth` components of `path`
+fn prefix(path: &str, depth: usize) -> &str {
+    path.match_indices('/')
+        .map(|(at, _)| at)
+        .find(|&at| components(&path[..at]).count() == depth)
+        .map_or(path, |at| &path[..at])
+}
@@ -31,15 +31,44 @@
 #![warn(rustdoc::missing_crate_level_docs)]
 #![warn(rustdoc::missing_doc_code_examples)]
 
+extern crate alloc;
+
 
 /// Boot routines and specifics
 pub mod cpu;
 
+/// Physical memory
+pub mod memory;
+
 /// Panic
 pub mod panic;
 
 /// Hacky QEMU console
 pub mod drivers;
 
+/// Kernel command line
+pub mod cmdline;
+
+/// Loading user programs
+pub mod elf;
+
+/// Device tree from the firmware
+pub mod fdt;
+
+/// Files shipped with the kernel
+pub mod initramfs;
+
+/// Kernel random numbers
+pub mod random;
+
 /// Syncronization primatives
 pub mod sync;
+
+/// System calls from EL0
+pub mod syscall;
+
+/// Kernel threads and scheduling
+pub mod task;
+
+/// Files, filesystems and mounts
+pub mod vfs;
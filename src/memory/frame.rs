@@ -0,0 +1,187 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Page frames
+//!
+//! Bitmap allocator for physical page frames. Allocations are runs of contiguous frames
+//! (translation tables and DMA buffers need them) and come back zeroed.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use super::{DRAM_END, PAGE_SIZE};
+use crate::sync::ticket::TicketLock;
+
+/// Number of frames that can exist below [DRAM_END]
+const NUM_FRAMES: usize = DRAM_END / PAGE_SIZE;
+
+/// Set bits are frames in use (or not RAM we own)
+struct FrameBitmap {
+    used: [u64; NUM_FRAMES / 64],
+    free: usize,
+}
+
+impl FrameBitmap {
+    fn is_used(&self, frame: usize) -> bool {
+        self.used[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set(&mut self, frame: usize, used: bool) {
+        match used {
+            true => self.used[frame / 64] |= 1 << (frame % 64),
+            false => self.used[frame / 64] &= !(1 << (frame % 64)),
+        }
+    }
+
+    /// First fit search for `count` free frames in a row
+    fn find(&self, count: usize) -> Option<usize> {
+        let mut start = 0;
+
+        while start + count <= NUM_FRAMES {
+            match (start..start + count).find(|&frame| self.is_used(frame)) {
+                Some(used) => start = used + 1,
+                None => return Some(start),
+            }
+        }
+
+        None
+    }
+}
+
+/// Everything starts used, [init()] frees the RAM after the kernel
+static FRAMES: TicketLock<FrameBitmap> = TicketLock::new(FrameBitmap {
+    used: [u64::MAX; NUM_FRAMES / 64],
+    free: 0,
+});
+
+/// A run of contiguous physical frames
+///
+/// Not [Clone] or [Copy], there is only ever one owner of a frame. Give it back with [free()].
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrameRange {
+    first: usize,
+    count: usize,
+}
+
+impl FrameRange {
+    /// Physical address of the first frame
+    pub fn start(&self) -> usize {
+        self.first * PAGE_SIZE
+    }
+
+    /// One past the last byte of the range
+    pub fn end(&self) -> usize {
+        (self.first + self.count) * PAGE_SIZE
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        self.count * PAGE_SIZE
+    }
+
+    /// Number of frames
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The frames through the kernel mapping
+    pub fn as_ptr(&self) -> *mut u8 {
+        super::phys_to_virt(self.start()) as *mut u8
+    }
+
+    /// Give up ownership, the frames stay allocated
+    ///
+    /// For frames that get referenced by physical address, like pages in a translation
+    /// table. Get them back with [FrameRange::from_raw()].
+    pub fn into_raw(self) -> usize {
+        self.start()
+    }
+
+    /// Take back frames given up with [FrameRange::into_raw()]
+    ///
+    /// ## Safety
+    ///
+    /// `start` and `count` have to describe frames from [into_raw()](FrameRange::into_raw)
+    /// that nothing else owns.
+    pub unsafe fn from_raw(start: usize, count: usize) -> FrameRange {
+        FrameRange {
+            first: start / PAGE_SIZE,
+            count,
+        }
+    }
+}
+
+/// Mark the frames in `start..end` as free
+///
+/// Called by [super::init()], both addresses must be frame aligned.
+pub fn init(start: usize, end: usize) {
+    let mut frames = FRAMES.lock();
+
+    for frame in start / PAGE_SIZE..end / PAGE_SIZE {
+        frames.set(frame, false);
+        frames.free += 1;
+    }
+}
+
+/// Keep the frames overlapping `start..end` away from [alloc()] for good
+///
+/// For memory the boot firmware left data in, like the device tree or an initrd, found
+/// after [init()]. Frames that are already in use stay that way.
+pub fn reserve(start: usize, end: usize) {
+    let mut frames = FRAMES.lock();
+
+    for frame in start / PAGE_SIZE..super::page_align_up(end).min(DRAM_END) / PAGE_SIZE {
+        if !frames.is_used(frame) {
+            frames.set(frame, true);
+            frames.free -= 1;
+        }
+    }
+}
+
+/// Allocate `count` contiguous zeroed frames
+///
+/// Returns None if there is no run that long.
+pub fn alloc(count: usize) -> Option<FrameRange> {
+    if count == 0 {
+        return None;
+    }
+
+    let range = {
+        let mut frames = FRAMES.lock();
+        let first = frames.find(count)?;
+
+        for frame in first..first + count {
+            frames.set(frame, true);
+        }
+        frames.free -= count;
+
+        FrameRange { first, count }
+    };
+
+    // Zeroed outside the lock, the frames are ours now
+    unsafe { core::ptr::write_bytes(range.as_ptr(), 0, range.size()) };
+    Some(range)
+}
+
+/// Give frames back to the allocator
+pub fn free(range: FrameRange) {
+    let mut frames = FRAMES.lock();
+
+    for frame in range.first..range.first + range.count {
+        frames.set(frame, false);
+    }
+    frames.free += range.count;
+}
+
+/// Number of free frames
+pub fn available() -> usize {
+    FRAMES.lock().free
+}
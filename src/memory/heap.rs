@@ -0,0 +1,89 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Kernel heap
+//!
+//! Global allocator for `alloc` (Box, Vec, Arc, ...). A linked list allocator over
+//! [HEAP_SIZE] of contiguous frames taken from [super::frame] at boot. The heap doesn't
+//! grow, running out is an allocation error.
+//!
+//! The lock masks IRQs, so interrupt handlers may allocate too.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://os.phil-opp.com/allocator-designs/>
+//!   - <https://docs.rs/linked_list_allocator/0.10/>
+//!
+
+use super::{frame, PAGE_SIZE};
+use crate::sync::ticket::TicketLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+
+/// Size of the kernel heap in bytes
+pub const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+/// The allocator behind `alloc`
+struct KernelHeap {
+    heap: TicketLock<Heap>,
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.heap.lock().allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(()) => core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+/// Empty until [init()], allocating before that fails
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap {
+    heap: TicketLock::new(Heap::empty()),
+};
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Give the heap its memory
+///
+/// Must be called once, by the boot core, after [super::init()] and after anything the
+/// firmware left in RAM is [reserved](frame::reserve()).
+pub fn init() {
+    let Some(frames) = frame::alloc(HEAP_SIZE / PAGE_SIZE) else {
+        panic!("No memory for the kernel heap");
+    };
+
+    // The heap owns the frames for good
+    let start = frames.as_ptr();
+    frames.into_raw();
+
+    unsafe { KERNEL_HEAP.heap.lock().init(start, HEAP_SIZE) };
+}
+
+/// Bytes handed out by the heap
+pub fn used() -> usize {
+    KERNEL_HEAP.heap.lock().used()
+}
+
+/// Bytes left in the heap
+pub fn free() -> usize {
+    KERNEL_HEAP.heap.lock().free()
+}
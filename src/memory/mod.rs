@@ -0,0 +1,70 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Memory
+//!
+//! Physical memory management. RAM starts at 0, the kernel image sits at the bottom
+//! (see `raspberrypi.x`) and everything from `_end` up to the VideoCore's share at the
+//! top of RAM is handed out in [PAGE_SIZE] frames by [frame].
+//!
+//! The kernel runs in the upper half: physical address `pa` is mapped at
+//! [KERNEL_BASE] + `pa`, peripherals included. The lower half belongs to the running
+//! process, see [vm]. The kernel's own allocations come from [heap].
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://wiki.osdev.org/Page_Frame_Allocation>
+//!   - <https://github.com/raspberrypi/documentation/blob/develop/documentation/asciidoc/computers/config_txt/memory.adoc>
+//!
+
+pub mod frame;
+pub mod heap;
+pub mod vm;
+
+/// Where physical address 0 is mapped, the kernel is linked at `KERNEL_BASE + 0x80000`
+pub const KERNEL_BASE: usize = 0xFFFF_FC00_0000_0000;
+
+/// Size of a page frame, matches `PAGE_SIZE` in the linker script
+pub const PAGE_SIZE: usize = 64 * 1024;
+
+/// End of the RAM the ARM cores own, the VideoCore has the rest (qemu's default split)
+pub const DRAM_END: usize = 0x3C00_0000;
+
+/// Round `addr` up to a multiple of [PAGE_SIZE]
+pub const fn page_align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Kernel virtual address of physical address `pa`
+pub const fn phys_to_virt(pa: usize) -> usize {
+    pa | KERNEL_BASE
+}
+
+/// Physical address of kernel virtual address `va`
+pub const fn virt_to_phys(va: usize) -> usize {
+    va & !KERNEL_BASE
+}
+
+/// Hand everything after the kernel image to the frame allocator
+///
+/// The heap comes up separately with [heap::init()], after the firmware's blobs are
+/// reserved.
+/// Must be called once, by the boot core, before anything allocates frames.
+pub fn init() {
+    extern "C" {
+        static _end: u8;
+    }
+
+    let end = virt_to_phys(unsafe { &_end as *const u8 as usize });
+    frame::init(page_align_up(end), DRAM_END);
+}
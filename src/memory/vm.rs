@@ -0,0 +1,436 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Address spaces
+//!
+//! The lower half of every process. An [AddressSpace] owns a TTBR0 translation table,
+//! an ASID so its TLB entries don't have to be flushed on every switch, and a list of
+//! regions. Regions are demand-zero: nothing is allocated until the process touches a
+//! page, then the fault handler maps a zeroed frame there.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://man7.org/linux/man-pages/man2/mmap.2.html>
+//!   - <https://developer.arm.com/documentation/101811/latest/> (Address spaces and ASIDs)
+//!   - <https://wiki.osdev.org/Paging>
+//!
+
+use super::{frame, frame::FrameRange, phys_to_virt, PAGE_SIZE};
+use crate::cpu::mmu::{self, PageTable, ENTRIES, L2_SHIFT, L3_SHIFT, VA_BITS};
+use crate::sync::ticket::TicketLock;
+
+/// End of the user half, user addresses are below this
+pub const USER_END: usize = 1 << VA_BITS;
+
+/// Top of the stack of a user task, a guard page below [USER_END]
+pub const USER_STACK_TOP: usize = USER_END - PAGE_SIZE;
+
+/// Size of the stack of a user task
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// Where [AddressSpace::map()] puts regions that don't ask for an address
+const MMAP_BASE: usize = 0x10_0000_0000;
+
+/// Regions an address space can have
+pub const MAX_REGIONS: usize = 16;
+
+/// Region can be read
+pub const PROT_READ: u32 = 1;
+/// Region can be written
+pub const PROT_WRITE: u32 = 2;
+/// Region can be executed
+pub const PROT_EXEC: u32 = 4;
+
+/// Number of 8 bit ASIDs, 0 is never handed out
+const NUM_ASIDS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for address space changes
+pub enum VmError {
+    /// No frames left for pages or tables
+    OutOfMemory,
+    /// Every ASID is taken
+    OutOfAsids,
+    /// Unaligned, outside the user half or overlapping another region
+    BadRange,
+    /// The address space already has [MAX_REGIONS] regions
+    TooManyRegions,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for VmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VmError::OutOfMemory => f.write_str("Out of memory"),
+            VmError::OutOfAsids => f.write_str("Out of ASIDs"),
+            VmError::BadRange => f.write_str("Bad address range"),
+            VmError::TooManyRegions => f.write_str("Too many regions"),
+        }
+    }
+}
+
+/// ASIDs in use, 0 is reserved for tasks without an address space
+static ASIDS: TicketLock<[u64; NUM_ASIDS / 64]> = TicketLock::new([1, 0, 0, 0]);
+
+fn alloc_asid() -> Result<u16, VmError> {
+    let mut asids = ASIDS.lock();
+
+    for (i, word) in asids.iter_mut().enumerate() {
+        if *word != u64::MAX {
+            let bit = word.trailing_ones() as usize;
+            *word |= 1 << bit;
+            return Ok((i * 64 + bit) as u16);
+        }
+    }
+
+    Err(VmError::OutOfAsids)
+}
+
+/// Give an ASID back, its TLB entries have to be gone already
+fn free_asid(asid: u16) {
+    ASIDS.lock()[asid as usize / 64] &= !(1 << (asid % 64));
+}
+
+/// A range of user addresses and what the process may do with it
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    start: usize,
+    end: usize,
+    prot: u32,
+}
+
+impl Region {
+    fn contains(&self, va: usize) -> bool {
+        self.start <= va && va < self.end
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// A translation table through the kernel mapping
+///
+/// ## Safety
+///
+/// `pa` has to be a table owned by the caller.
+unsafe fn table<'a>(pa: usize) -> &'a mut PageTable {
+    &mut *(phys_to_virt(pa) as *mut PageTable)
+}
+
+fn l2_index(va: usize) -> usize {
+    (va >> L2_SHIFT) % ENTRIES
+}
+
+fn l3_index(va: usize) -> usize {
+    (va >> L3_SHIFT) % ENTRIES
+}
+
+/// `len` rounded up to whole pages
+///
+/// Lengths come straight from user space, 0 and ones that overflow are rejected.
+fn page_len(len: usize) -> Result<usize, VmError> {
+    match len.checked_add(PAGE_SIZE - 1) {
+        Some(end) if len != 0 => Ok(end & !(PAGE_SIZE - 1)),
+        _ => Err(VmError::BadRange),
+    }
+}
+
+/// User half of a process
+///
+/// Dropping it frees every page, the tables and the ASID. It must not be loaded in TTBR0 of
+/// any core by then.
+pub struct AddressSpace {
+    asid: u16,
+    /// Physical address of the L2 table
+    root: usize,
+    regions: [Option<Region>; MAX_REGIONS],
+    /// Next address for regions without a fixed address
+    mmap_next: usize,
+    /// pc and sp the program starts with in EL0
+    start: (usize, usize),
+}
+
+impl AddressSpace {
+    /// Creates a new empty address space
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::memory::vm::{AddressSpace, PROT_READ, PROT_WRITE};
+    ///
+    /// let mut space = AddressSpace::new().unwrap();
+    /// let heap = space.map(None, 0x20000, PROT_READ | PROT_WRITE).unwrap();
+    /// space.write(heap, b"hello").unwrap();
+    /// ```
+    pub fn new() -> Result<AddressSpace, VmError> {
+        let asid = alloc_asid()?;
+
+        let root = match frame::alloc(1) {
+            Some(root) => root.into_raw(),
+            None => {
+                free_asid(asid);
+                return Err(VmError::OutOfMemory);
+            }
+        };
+
+        Ok(AddressSpace {
+            asid,
+            root,
+            regions: [None; MAX_REGIONS],
+            mmap_next: MMAP_BASE,
+            start: (0, USER_STACK_TOP),
+        })
+    }
+
+    /// The ASID tagging this space's TLB entries
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Value to load in TTBR0_EL1 to switch to this space
+    pub fn ttbr0(&self) -> u64 {
+        mmu::ttbr0(self.root, self.asid)
+    }
+
+    /// pc and sp a task entering EL0 in this space starts with
+    pub fn start(&self) -> (usize, usize) {
+        self.start
+    }
+
+    /// Set where the program starts, for loaders
+    ///
+    /// Defaults to pc 0 and an sp of [USER_STACK_TOP].
+    pub fn set_start(&mut self, pc: usize, sp: usize) {
+        self.start = (pc, sp);
+    }
+
+    /// Add a demand-zero region of at least `len` bytes, returns its start
+    ///
+    /// `start` has to be page aligned, without one the region goes after the last one that
+    /// was placed this way.
+    pub fn map(&mut self, start: Option<usize>, len: usize, prot: u32) -> Result<usize, VmError> {
+        let len = page_len(len)?;
+        let placed = start.is_none();
+        let start = start.unwrap_or(self.mmap_next);
+        let end = start.checked_add(len).ok_or(VmError::BadRange)?;
+
+        if !start.is_multiple_of(PAGE_SIZE) || end > USER_END {
+            return Err(VmError::BadRange);
+        }
+
+        if self.regions.iter().flatten().any(|region| region.overlaps(start, end)) {
+            return Err(VmError::BadRange);
+        }
+
+        let free = self
+            .regions
+            .iter_mut()
+            .find(|region| region.is_none())
+            .ok_or(VmError::TooManyRegions)?;
+        *free = Some(Region { start, end, prot });
+
+        if placed {
+            self.mmap_next = end;
+        }
+
+        Ok(start)
+    }
+
+    /// Remove every page and region in `start..start + len`
+    ///
+    /// Regions that stick out on either side are trimmed.
+    pub fn unmap(&mut self, start: usize, len: usize) -> Result<(), VmError> {
+        let end = start.checked_add(page_len(len)?).ok_or(VmError::BadRange)?;
+        if !start.is_multiple_of(PAGE_SIZE) || end > USER_END {
+            return Err(VmError::BadRange);
+        }
+
+        // Splitting one region in two needs a spare slot, check before changing anything
+        let splits = self
+            .regions
+            .iter()
+            .flatten()
+            .any(|region| region.start < start && end < region.end);
+        if splits && self.regions.iter().all(Option::is_some) {
+            return Err(VmError::TooManyRegions);
+        }
+
+        for va in (start..end).step_by(PAGE_SIZE) {
+            self.unmap_page(va);
+        }
+
+        let mut tail = None;
+        for slot in self.regions.iter_mut() {
+            let Some(region) = slot else { continue };
+            if !region.overlaps(start, end) {
+                continue;
+            }
+
+            if region.start < start && end < region.end {
+                tail = Some(Region { start: end, ..*region });
+                region.end = start;
+            } else if region.start < start {
+                region.end = start;
+            } else if end < region.end {
+                region.start = end;
+            } else {
+                *slot = None;
+            }
+        }
+
+        if let Some(tail) = tail {
+            if let Some(free) = self.regions.iter_mut().find(|region| region.is_none()) {
+                *free = Some(tail);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a fault at `va`, returns true if the access can be retried
+    ///
+    /// Maps a zeroed page when `va` is in a region that allows the access and nothing is
+    /// mapped there yet. Anything else is a real fault.
+    pub fn handle_fault(&mut self, va: usize, write: bool, exec: bool) -> bool {
+        let Some(region) = self.region(va) else {
+            return false;
+        };
+
+        let allowed = match (write, exec) {
+            (true, _) => region.prot & PROT_WRITE != 0,
+            (_, true) => region.prot & PROT_EXEC != 0,
+            _ => region.prot != 0,
+        };
+
+        allowed && self.page(va).is_none() && self.fault_in(va, region.prot).is_ok()
+    }
+
+    /// Copy `bytes` into the space at `va`, mapping pages as needed
+    ///
+    /// Ignores the protection of the regions, for loading programs. The whole range has to
+    /// be inside regions.
+    pub fn write(&mut self, va: usize, bytes: &[u8]) -> Result<(), VmError> {
+        let mut done = 0;
+
+        while done < bytes.len() {
+            let addr = va + done;
+            let region = self.region(addr).ok_or(VmError::BadRange)?;
+
+            let pa = match self.page(addr) {
+                Some(pa) => pa,
+                None => self.fault_in(addr, region.prot)?,
+            };
+
+            let offset = addr % PAGE_SIZE;
+            let count = (PAGE_SIZE - offset).min(bytes.len() - done);
+            let kva = phys_to_virt(pa) + offset;
+
+            unsafe { core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), kva as *mut u8, count) };
+            if region.prot & PROT_EXEC != 0 {
+                mmu::sync_icache(kva, count);
+            }
+
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    fn region(&self, va: usize) -> Option<Region> {
+        self.regions.iter().flatten().find(|region| region.contains(va)).copied()
+    }
+
+    /// The L3 table covering `va`, allocated if `create` is set
+    fn l3(&mut self, va: usize, create: bool) -> Result<Option<&mut PageTable>, VmError> {
+        let l2 = unsafe { table(self.root) };
+        let entry = &mut l2.0[l2_index(va)];
+
+        if !mmu::is_valid(*entry) {
+            if !create {
+                return Ok(None);
+            }
+
+            let l3 = frame::alloc(1).ok_or(VmError::OutOfMemory)?;
+            *entry = mmu::table_descriptor(l3.into_raw());
+        }
+
+        Ok(Some(unsafe { table(mmu::descriptor_address(*entry)) }))
+    }
+
+    /// Physical address of the page mapped at `va`
+    fn page(&mut self, va: usize) -> Option<usize> {
+        let l3 = self.l3(va, false).ok()??;
+        let entry = l3.0[l3_index(va)];
+
+        match mmu::is_valid(entry) {
+            true => Some(mmu::descriptor_address(entry)),
+            false => None,
+        }
+    }
+
+    /// Map a zeroed page at `va`, returns its physical address
+    fn fault_in(&mut self, va: usize, prot: u32) -> Result<usize, VmError> {
+        let page = frame::alloc(1).ok_or(VmError::OutOfMemory)?;
+
+        let l3 = match self.l3(va, true) {
+            Ok(Some(l3)) => l3,
+            _ => {
+                frame::free(page);
+                return Err(VmError::OutOfMemory);
+            }
+        };
+
+        let pa = page.into_raw();
+        l3.0[l3_index(va)] = mmu::user_page_descriptor(pa, prot & PROT_WRITE != 0, prot & PROT_EXEC != 0);
+        mmu::sync_tables();
+
+        Ok(pa)
+    }
+
+    /// Unmap and free the page at `va`, if there is one
+    fn unmap_page(&mut self, va: usize) {
+        let Ok(Some(l3)) = self.l3(va, false) else {
+            return;
+        };
+
+        let entry = core::mem::take(&mut l3.0[l3_index(va)]);
+        if !mmu::is_valid(entry) {
+            return;
+        }
+
+        mmu::flush_page(self.asid, va);
+        frame::free(unsafe { FrameRange::from_raw(mmu::descriptor_address(entry), 1) });
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let l2 = unsafe { table(self.root) };
+
+        for &l2_entry in l2.0.iter().filter(|&&entry| mmu::is_valid(entry)) {
+            let l3_pa = mmu::descriptor_address(l2_entry);
+            let l3 = unsafe { table(l3_pa) };
+
+            for &entry in l3.0.iter().filter(|&&entry| mmu::is_valid(entry)) {
+                frame::free(unsafe { FrameRange::from_raw(mmu::descriptor_address(entry), 1) });
+            }
+
+            frame::free(unsafe { FrameRange::from_raw(l3_pa, 1) });
+        }
+
+        mmu::flush_asid(self.asid);
+        frame::free(unsafe { FrameRange::from_raw(self.root, 1) });
+        free_asid(self.asid);
+    }
+}
@@ -53,7 +53,8 @@ fn panic_prevent_reenter() {
 ///
 /// When [panic!()] is called information on the thread is packaged into
 /// [core::panic::PanicInfo]. This handler prints the panic location and
-/// and then parks the core.
+/// and then parks the core, or reboots after `panic=N` seconds if the command line has
+/// that option (like Linux, 0 means never).
 ///
 /// ## TODO:
 /// - Accessing the panic message is an unstable feature, the current workaround
@@ -71,5 +72,12 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 
     crate::println!("Kernel panicked at {}:{}\n{:?}", location, line, info,);
 
+    if let Some(secs) = crate::cmdline::value("panic").and_then(|secs| secs.parse().ok()) {
+        if secs > 0 {
+            crate::println!("Rebooting in {} seconds", secs);
+            crate::drivers::power::reboot_after(core::time::Duration::from_secs(secs));
+        }
+    }
+
     crate::cpu::_park();
 }
@@ -0,0 +1,240 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Random
+//!
+//! The kernel's random numbers, from a ChaCha20 based CSPRNG. Its key is the entropy pool:
+//! new entropy is xored into the key, which is then replaced by a ChaCha20 block of itself,
+//! so what went in before can't be recovered or cancelled out.
+//!
+//! [init()] seeds it from the [hardware RNG](crate::drivers::rng) and from jitter of the
+//! generic timer. Every [fill_bytes()] mixes in the counter and a hardware word if one is
+//! ready, then splits off a generator of its own for the output. Keys are replaced after
+//! every use (fast key erasure), output already handed out can't be reconstructed from
+//! the state.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://datatracker.ietf.org/doc/html/rfc8439>
+//!   - <https://blog.cr.yp.to/20170723-random.html> (fast key erasure)
+//!   - <https://man7.org/linux/man-pages/man7/random.7.html>
+//!
+
+use crate::cpu::timer;
+use crate::drivers::rng;
+use crate::sync::ticket::TicketLock;
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+/// Bytes in a ChaCha20 block
+const BLOCK_SIZE: usize = 64;
+
+/// Words in a key
+const KEY_WORDS: usize = 8;
+
+/// Nonces keep output and mixing apart, the same key never makes the same block for both
+const NONCE_OUTPUT: u64 = 0;
+const NONCE_MIX: u64 = 1;
+
+/// Hardware words read by [init()]
+const SEED_WORDS: usize = 16;
+
+/// Timer samples taken by [init()]
+const JITTER_SAMPLES: usize = 256;
+
+/// A ChaCha20 keystream
+struct Generator {
+    key: [u32; KEY_WORDS],
+}
+
+impl Generator {
+    const fn new() -> Generator {
+        Generator { key: [0; KEY_WORDS] }
+    }
+
+    /// Fill `buf` with keystream, then move to a new key
+    fn fill(&mut self, buf: &mut [u8]) {
+        // Block 0 is the next key, nobody sees it
+        let next = block(&self.key, 0, NONCE_OUTPUT);
+
+        for (counter, chunk) in (1..).zip(buf.chunks_mut(BLOCK_SIZE)) {
+            let words = block(&self.key, counter, NONCE_OUTPUT);
+            let bytes = words.iter().flat_map(|word| word.to_le_bytes());
+
+            for (out, byte) in chunk.iter_mut().zip(bytes) {
+                *out = byte;
+            }
+        }
+
+        self.key.copy_from_slice(&next[..KEY_WORDS]);
+    }
+
+    /// Fold `data` into the key
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(KEY_WORDS * 4) {
+            for (i, byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= (*byte as u32) << ((i % 4) * 8);
+            }
+
+            let next = block(&self.key, 0, NONCE_MIX);
+            self.key.copy_from_slice(&next[..KEY_WORDS]);
+        }
+    }
+
+    /// A generator with a key from this one's output
+    fn split(&mut self) -> Generator {
+        let mut bytes = [0; KEY_WORDS * 4];
+        self.fill(&mut bytes);
+
+        let mut child = Generator::new();
+        for (word, bytes) in child.key.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        child
+    }
+}
+
+/// The pool, locked just long enough to mix and split
+static POOL: TicketLock<Generator> = TicketLock::new(Generator::new());
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Start the hardware RNG and seed the pool
+///
+/// Called once by the boot core. Output before this is predictable.
+pub fn init() {
+    rng::init();
+
+    let mut pool = POOL.lock();
+
+    for _ in 0..SEED_WORDS {
+        match rng::read() {
+            Some(word) => pool.mix(&word.to_le_bytes()),
+            None => break,
+        }
+    }
+
+    pool.mix(&jitter());
+}
+
+/// Mix `data` into the pool
+///
+/// For anything hard to predict, like interrupt timings. Never lowers the quality of the
+/// output, even if an attacker knows `data`.
+pub fn add_entropy(data: &[u8]) {
+    POOL.lock().mix(data);
+}
+
+/// Fill `buf` with random bytes
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::random;
+///
+/// let mut canary = [0; 8];
+/// random::fill_bytes(&mut canary);
+/// ```
+pub fn fill_bytes(buf: &mut [u8]) {
+    let mut generator = {
+        let mut pool = POOL.lock();
+
+        // A little fresh entropy on every call, neither ever waits
+        pool.mix(&timer::counter().to_le_bytes());
+        if let Some(word) = rng::try_read() {
+            pool.mix(&word.to_le_bytes());
+        }
+
+        pool.split()
+    };
+
+    // Large requests don't hold the lock
+    generator.fill(buf);
+}
+
+/// A random u64
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// The ChaCha20 block function, 64 bit counter and nonce like the original
+fn block(key: &[u32; KEY_WORDS], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        // Columns
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        // Diagonals
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Timing noise of the generic timer
+///
+/// The time a small loop of memory accesses takes wobbles with cache and bus state, the
+/// low bits of each sample are kept.
+fn jitter() -> [u8; JITTER_SAMPLES] {
+    let mut samples = [0u8; JITTER_SAMPLES];
+    let mut scratch = [0u64; 32];
+
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let start = timer::counter();
+
+        for j in 0..(i % 7 + 1) * 8 {
+            let slot = (j * 13 + i) % scratch.len();
+            scratch[slot] = core::hint::black_box(scratch[slot].wrapping_mul(31).wrapping_add(start));
+        }
+
+        *sample = (timer::counter() - start) as u8 ^ (start as u8);
+    }
+
+    samples
+}
@@ -3,6 +3,10 @@
 _phys_dram_start = 0;
 _phys_bin_start = 0x80000;
 
+/* The kernel runs in the upper half, physical address pa is mapped at _kernel_virt_base + pa
+   (memory::KERNEL_BASE). Sections are linked there and loaded at their physical address. */
+_kernel_virt_base = 0xFFFFFC0000000000;
+
 PAGE_SIZE = 64K;
 PAGE_MASK = PAGE_SIZE - 1;
 
@@ -16,10 +20,10 @@ PHDRS {
 
 SECTIONS
 {
-	. = _phys_dram_start;
+	. = _kernel_virt_base + _phys_dram_start;
 
 	_sbcstack = .;
-	.boot_core_stack (NOLOAD) :
+	.boot_core_stack (NOLOAD) : AT(ADDR(.boot_core_stack) - _kernel_virt_base)
 	{
 												/*   ^             */
 												/*   | stack       */
@@ -35,7 +39,7 @@ SECTIONS
     ***********************************************************************************************/
     _scode = .;
 
-	.text :
+	.text : AT(ADDR(.text) - _kernel_virt_base)
 	{
 		KEEP(*(.text._start))
 		*(.text._init_mem)
@@ -44,7 +48,15 @@ SECTIONS
 		*(.text*)
 	}: segment_code
 
-	.rodata : ALIGN(8) { *(.rodata*) } :segment_code
+	.rodata : AT(ADDR(.rodata) - _kernel_virt_base) ALIGN(8) { *(.rodata*) } :segment_code
+
+	/* cpio archive built in with the initramfs feature, empty without it */
+	.initramfs : AT(ADDR(.initramfs) - _kernel_virt_base) ALIGN(4)
+	{
+		_sinitramfs = .;
+		KEEP(*(.initramfs))
+		_einitramfs = .;
+	} :segment_code
 
 	. = ALIGN(PAGE_SIZE);
 	_ecode = .;
@@ -52,7 +64,8 @@ SECTIONS
 	/***********************************************************************************************
 	* Data + BSS
 	***********************************************************************************************/
-	.data : {
+	.data : AT(ADDR(.data) - _kernel_virt_base)
+	{
 
 		_sdata = .;
 		*(.data*);
@@ -60,8 +73,19 @@ SECTIONS
 
 	} :segment_data
 
+	/* Per-core variables (see percpu!). Only a template, every core copies it into its own
+	   slot of .percpu_area at boot. Cache line aligned so cores don't share lines. */
+	.percpu : AT(ADDR(.percpu) - _kernel_virt_base) ALIGN(64)
+	{
+		_spercpu = .;
+		KEEP(*(.percpu*));
+		. = ALIGN(64);
+		_epercpu = .;
+
+	} :segment_data
+
 	/* Section is zeroed in pairs of u64. Align start and end to 16 bytes */
-	.bss (NOLOAD) : ALIGN(16)
+	.bss (NOLOAD) : AT(ADDR(.bss) - _kernel_virt_base) ALIGN(16)
 	{
 		_sbss = .;
 		*(.bss*);
@@ -70,4 +94,17 @@ SECTIONS
 
 	} :segment_data
 
-}
\ No newline at end of file
+	/* One copy of .percpu per core (NUM_CORES = 4) */
+	.percpu_area (NOLOAD) : AT(ADDR(.percpu_area) - _kernel_virt_base) ALIGN(64)
+	{
+		_spercpu_area = .;
+		. += (_epercpu - _spercpu) * 4;
+		_epercpu_area = .;
+
+	} :segment_data
+
+	/* Everything from here to the end of RAM is handed out by the frame allocator */
+	. = ALIGN(PAGE_SIZE);
+	_end = .;
+
+}
@@ -19,6 +19,8 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 use dyseos::*;
 
 #[no_mangle]
@@ -60,6 +62,60 @@ use dyseos::*;
 ///   800c8: 94000260     	bl	0x80a48 <core::panicking::panic_fmt::hc580a36ad1b33f2e>
 /// ```
 unsafe fn _kernel_init() -> ! {
+    cpu::percpu::init();
     println!("Kernel initializing: ...");
+    memory::init();
+    fdt::init();
+    initramfs::init();
+    memory::heap::init();
+    random::init();
+    drivers::console::init();
+    if let Err(err) = drivers::fbcon::init() {
+        println!("No screen: {}", err);
+    }
+    drivers::dma::init();
+    vfs::init();
+    mount_sd_card();
+    cpu::exception::init();
+    task::init();
+    cpu::smp::start_secondary_cores();
+
+    if let Some(init) = initramfs::get().and_then(|fs| fs.lookup("/init")) {
+        match elf::spawn(init.data(), &["/init"], &[]) {
+            Ok(handle) => println!("/init exited with {}", handle.join()),
+            Err(err) => println!("Failed to start /init: {}", err),
+        }
+    }
+
     panic!("Reached end of existing kernel... more coming soon!");
 }
+
+/// Bring up the SD card and mount its FAT32 filesystem on `/mnt`
+fn mount_sd_card() {
+    if let Err(err) = drivers::emmc::init() {
+        println!("No SD card: {}", err);
+        return;
+    }
+
+    let Some(card) = drivers::emmc::card() else {
+        return;
+    };
+
+    match vfs::fat::Fat32::find(card) {
+        Ok(fs) => {
+            let _ = vfs::mount("/mnt", alloc::sync::Arc::new(fs));
+        }
+        Err(err) => println!("SD card: {}", err),
+    }
+}
+
+#[no_mangle]
+/// Initialize a secondary core
+///
+/// Cores 1-3 land here after [cpu::smp::start_secondary_cores()], each one becomes the idle
+/// task of its core.
+unsafe fn _secondary_init() -> ! {
+    cpu::percpu::init();
+    cpu::exception::init();
+    task::init_secondary();
+}
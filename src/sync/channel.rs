@@ -0,0 +1,447 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Channels
+//!
+//! Bounded message queues between cores, tasks and interrupt handlers. Both flavours
+//! are lock-free on the sending side, so interrupt handlers can send, and the receiver
+//! can sleep on the futex until something arrives.
+//!
+//!   - [SpscChannel]: one sender, one receiver, a plain ring buffer.
+//!   - [MpscChannel]: any number of senders, one receiver (Vyukov's bounded queue).
+//!
+//! Capacities are const generics, like the base address of
+//! [crate::drivers::console]'s `SysConsole<const T: usize>`, and must be powers of two.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://doc.rust-lang.org/std/sync/mpsc/index.html>
+//!   - <https://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue>
+//!   - <https://docs.rs/heapless/latest/heapless/spsc/index.html>
+//!
+
+use super::futex::{futex_wait, futex_wake, FutexError};
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for receiving
+pub enum RecvError {
+    /// Nothing arrived before the timeout
+    TimedOut,
+    /// The channel is empty and the caller can't sleep, e.g. an interrupt handler
+    NotAllowed,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            RecvError::TimedOut => f.write_str("Receive timed out"),
+            RecvError::NotAllowed => f.write_str("Receive from a context that can't block"),
+        }
+    }
+}
+
+/// Wakes the receiver when something was sent
+///
+/// `count` is the futex, bumped by every send. The receiver only sets `waiting` before it
+/// goes to sleep, so senders don't touch the scheduler when nobody is listening.
+struct Signal {
+    count: AtomicU32,
+    waiting: AtomicBool,
+}
+
+impl Signal {
+    const fn new() -> Signal {
+        Signal {
+            count: AtomicU32::new(0),
+            waiting: AtomicBool::new(false),
+        }
+    }
+
+    /// Called by senders after publishing a message
+    fn notify(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        // Pairs with the fence in `wait`: either the receiver sees the message or we see it
+        // waiting.
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) {
+            futex_wake(&self.count, 1);
+        }
+    }
+
+    /// Sleep until `poll` returns something
+    fn wait<T>(&self, timeout: Option<Duration>, mut poll: impl FnMut() -> Option<T>) -> Result<T, RecvError> {
+        loop {
+            let seen = self.count.load(Ordering::Relaxed);
+            self.waiting.store(true, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+
+            let result = match poll() {
+                Some(value) => Ok(value),
+                None => match futex_wait(&self.count, seen, timeout) {
+                    Ok(()) | Err(FutexError::WouldBlock) => continue,
+                    Err(FutexError::TimedOut) => poll().ok_or(RecvError::TimedOut),
+                    Err(FutexError::NotAllowed) => poll().ok_or(RecvError::NotAllowed),
+                },
+            };
+
+            self.waiting.store(false, Ordering::Relaxed);
+            return result;
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// SPSC
+//--------------------------------------------------------------------------------------------------
+
+/// Single producer single consumer ring buffer
+///
+/// `head` is only written by the receiver and `tail` only by the sender, both count up
+/// forever and wrap, the slot is the count modulo `N`. The two ends are handed out once by
+/// [SpscChannel::split()].
+///
+pub struct SpscChannel<T, const N: usize> {
+    head: AtomicU32,
+    tail: AtomicU32,
+    split: AtomicBool,
+    signal: Signal,
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+// Only one sender and one receiver exist, a slot is owned by exactly one of them at a time.
+unsafe impl<T: Send, const N: usize> Sync for SpscChannel<T, N> {}
+
+/// Sending end of a [SpscChannel]
+///
+/// Sending takes `&mut self`, sharing the end by reference can't make a second sender.
+pub struct SpscSender<'a, T, const N: usize> {
+    channel: &'a SpscChannel<T, N>,
+}
+
+/// Receiving end of a [SpscChannel]
+///
+/// Receiving takes `&mut self`, sharing the end by reference can't make a second receiver.
+pub struct SpscReceiver<'a, T, const N: usize> {
+    channel: &'a SpscChannel<T, N>,
+}
+
+impl<T, const N: usize> SpscChannel<T, N> {
+    const POWER_OF_TWO: () = assert!(N.is_power_of_two() && N <= 1 << 31);
+
+    /// Creates a new empty channel with room for `N` messages
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::channel::SpscChannel;
+    ///
+    /// static UART_RX: SpscChannel<u8, 64> = SpscChannel::new();
+    ///
+    /// let (mut tx, mut rx) = UART_RX.split().unwrap();
+    ///
+    /// // interrupt handler
+    /// tx.try_send(b'a').unwrap();
+    ///
+    /// // task
+    /// assert_eq!(rx.recv(), Ok(b'a'));
+    /// ```
+    pub const fn new() -> SpscChannel<T, N> {
+        let () = Self::POWER_OF_TWO;
+
+        SpscChannel {
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+            split: AtomicBool::new(false),
+            signal: Signal::new(),
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+        }
+    }
+
+    /// Hand out the two ends, only the first call gets them
+    pub fn split(&self) -> Option<(SpscSender<'_, T, N>, SpscReceiver<'_, T, N>)> {
+        match self.split.swap(true, Ordering::AcqRel) {
+            false => Some((SpscSender { channel: self }, SpscReceiver { channel: self })),
+            true => None,
+        }
+    }
+
+    /// Number of queued messages
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire)) as usize
+    }
+
+    /// True when nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, index: u32) -> *mut MaybeUninit<T> {
+        self.buffer[index as usize % N].get()
+    }
+
+    fn try_pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*self.slot(head)).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for SpscChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscChannel<T, N> {
+    fn drop(&mut self) {
+        while self.try_pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> SpscSender<'_, T, N> {
+    /// Queue a message, or give it back if the channel is full
+    ///
+    /// Never blocks, safe to call from interrupt handlers.
+    pub fn try_send(&mut self, value: T) -> Result<(), T> {
+        let channel = self.channel;
+        let tail = channel.tail.load(Ordering::Relaxed);
+
+        if tail.wrapping_sub(channel.head.load(Ordering::Acquire)) as usize == N {
+            return Err(value);
+        }
+
+        unsafe { (*channel.slot(tail)).write(value) };
+        channel.tail.store(tail.wrapping_add(1), Ordering::Release);
+        channel.signal.notify();
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> SpscReceiver<'_, T, N> {
+    /// Take the oldest message, if there is one
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.channel.try_pop()
+    }
+
+    /// Take the oldest message, sleeping until one arrives
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.channel.signal.wait(None, || self.channel.try_pop())
+    }
+
+    /// Like [SpscReceiver::recv()] but gives up after `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvError> {
+        self.channel.signal.wait(Some(timeout), || self.channel.try_pop())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// MPSC
+//--------------------------------------------------------------------------------------------------
+
+/// Slot of a [MpscChannel]
+///
+/// `seq` says whose turn it is: equal to the send position when the slot is free for that
+/// send, one more when it holds the message for that position.
+struct Slot<T> {
+    seq: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Multi producer single consumer queue
+///
+/// Senders claim a position with a compare exchange on `send_pos` and publish through the
+/// slot's sequence number, so a slow sender only holds up the receiver, never other senders.
+///
+pub struct MpscChannel<T, const N: usize> {
+    send_pos: AtomicU32,
+    recv_pos: AtomicU32,
+    receiver: AtomicBool,
+    signal: Signal,
+    slots: [Slot<T>; N],
+}
+
+// Slots are handed between senders and the receiver through their sequence numbers.
+unsafe impl<T: Send, const N: usize> Sync for MpscChannel<T, N> {}
+
+/// Sending end of a [MpscChannel], cheap to copy
+pub struct MpscSender<'a, T, const N: usize> {
+    channel: &'a MpscChannel<T, N>,
+}
+
+/// Receiving end of a [MpscChannel]
+///
+/// Receiving takes `&mut self`, sharing the end by reference can't make a second receiver.
+pub struct MpscReceiver<'a, T, const N: usize> {
+    channel: &'a MpscChannel<T, N>,
+}
+
+impl<T, const N: usize> MpscChannel<T, N> {
+    const POWER_OF_TWO: () = assert!(N.is_power_of_two() && N <= 1 << 31);
+
+    /// Creates a new empty channel with room for `N` messages
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::channel::MpscChannel;
+    ///
+    /// static EVENTS: MpscChannel<u32, 16> = MpscChannel::new();
+    ///
+    /// let mut rx = EVENTS.receiver().unwrap();
+    /// EVENTS.sender().try_send(1).unwrap();
+    /// EVENTS.sender().try_send(2).unwrap();
+    ///
+    /// assert_eq!(rx.recv(), Ok(1));
+    /// assert_eq!(rx.try_recv(), Some(2));
+    /// ```
+    pub const fn new() -> MpscChannel<T, N> {
+        let () = Self::POWER_OF_TWO;
+
+        let mut slots = [const {
+            Slot {
+                seq: AtomicU32::new(0),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }; N];
+
+        let mut i = 0;
+        while i < N {
+            slots[i].seq = AtomicU32::new(i as u32);
+            i += 1;
+        }
+
+        MpscChannel {
+            send_pos: AtomicU32::new(0),
+            recv_pos: AtomicU32::new(0),
+            receiver: AtomicBool::new(false),
+            signal: Signal::new(),
+            slots,
+        }
+    }
+
+    /// A new sending end, there can be any number of them
+    pub fn sender(&self) -> MpscSender<'_, T, N> {
+        MpscSender { channel: self }
+    }
+
+    /// The receiving end, only the first call gets it
+    pub fn receiver(&self) -> Option<MpscReceiver<'_, T, N>> {
+        match self.receiver.swap(true, Ordering::AcqRel) {
+            false => Some(MpscReceiver { channel: self }),
+            true => None,
+        }
+    }
+
+    fn try_push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.send_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos as usize % N];
+            let seq = slot.seq.load(Ordering::Acquire);
+
+            match seq.wrapping_sub(pos) as i32 {
+                0 => match self.send_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // The receiver hasn't emptied this slot since the last lap, full
+                diff if diff < 0 => return Err(value),
+                // Another sender claimed it, catch up
+                _ => pos = self.send_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    fn try_pop(&self) -> Option<T> {
+        let pos = self.recv_pos.load(Ordering::Relaxed);
+        let slot = &self.slots[pos as usize % N];
+
+        if slot.seq.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return None;
+        }
+
+        let value = unsafe { (*slot.value.get()).assume_init_read() };
+        slot.seq.store(pos.wrapping_add(N as u32), Ordering::Release);
+        self.recv_pos.store(pos.wrapping_add(1), Ordering::Relaxed);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for MpscChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for MpscChannel<T, N> {
+    fn drop(&mut self) {
+        while self.try_pop().is_some() {}
+    }
+}
+
+impl<T, const N: usize> Clone for MpscSender<'_, T, N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, const N: usize> Copy for MpscSender<'_, T, N> {}
+
+impl<T, const N: usize> MpscSender<'_, T, N> {
+    /// Queue a message, or give it back if the channel is full
+    ///
+    /// Never blocks, safe to call from interrupt handlers and any core.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        self.channel.try_push(value)?;
+        self.channel.signal.notify();
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> MpscReceiver<'_, T, N> {
+    /// Take the oldest message, if there is one
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.channel.try_pop()
+    }
+
+    /// Take the oldest message, sleeping until one arrives
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.channel.signal.wait(None, || self.channel.try_pop())
+    }
+
+    /// Like [MpscReceiver::recv()] but gives up after `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvError> {
+        self.channel.signal.wait(Some(timeout), || self.channel.try_pop())
+    }
+}
@@ -0,0 +1,149 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Condition variable
+//!
+//! Block a task until some condition guarded by a [Mutex] changes. Same idea as
+//! std::sync::Condvar: the futex is a sequence number that every notify bumps, a waiter
+//! sleeps as long as it hasn't moved since it released the mutex.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://doc.rust-lang.org/std/sync/struct.Condvar.html>
+//!   - <https://stdrs.dev/nightly/x86_64-unknown-linux-gnu/src/std/sys/unix/locks/futex_condvar.rs.html>
+//!
+
+use super::futex::{futex_wait, futex_wake, FutexError};
+use super::mutex::{LockError, MutexGuard};
+use crate::task;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+/// Condition Variable
+///
+/// Used together with a [super::mutex::Mutex], [Condvar::wait()] takes the [MutexGuard],
+/// sleeps without holding the lock and hands the guard back once notified. Wake ups can be
+/// spurious, check the condition in a loop or use [Condvar::wait_while()].
+///
+pub struct Condvar {
+    futex: AtomicU32,
+}
+
+impl Condvar {
+    /// Creates a new condition variable with nobody waiting on it
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::condvar::Condvar;
+    ///
+    /// static READY: Condvar = Condvar::new();
+    /// ```
+    #[inline]
+    pub const fn new() -> Condvar {
+        Condvar {
+            futex: AtomicU32::new(0),
+        }
+    }
+
+    /// Sleep until notified, the guard is unlocked while sleeping
+    ///
+    /// Returns [LockError] (and releases the lock) when the caller can't sleep, like an
+    /// interrupt handler.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::{condvar::Condvar, mutex::Mutex};
+    ///
+    /// static QUEUED: Mutex<usize> = Mutex::new(0);
+    /// static NOT_EMPTY: Condvar = Condvar::new();
+    ///
+    /// // consumer
+    /// let mut queued = QUEUED.lock().unwrap();
+    /// while *queued == 0 {
+    ///     queued = NOT_EMPTY.wait(queued).unwrap();
+    /// }
+    /// *queued -= 1;
+    ///
+    /// // producer
+    /// *QUEUED.lock().unwrap() += 1;
+    /// NOT_EMPTY.notify_one();
+    /// ```
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> Result<MutexGuard<'a, T>, LockError> {
+        self.wait_optional_timeout(guard, None).map(|(guard, _)| guard)
+    }
+
+    /// Like [Condvar::wait()] but gives up after `timeout`
+    ///
+    /// The bool is true when the timeout ran out before a notify.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> Result<(MutexGuard<'a, T>, bool), LockError> {
+        self.wait_optional_timeout(guard, Some(timeout))
+    }
+
+    /// Sleep for as long as `condition` returns true
+    ///
+    /// The condition is checked with the lock held, before sleeping and after every wake up.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> Result<MutexGuard<'a, T>, LockError> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+
+        Ok(guard)
+    }
+
+    /// Wake one task waiting on this condition variable
+    pub fn notify_one(&self) {
+        self.futex.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.futex, 1);
+    }
+
+    /// Wake every task waiting on this condition variable
+    pub fn notify_all(&self) {
+        self.futex.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.futex, usize::MAX);
+    }
+
+    /// Shared body of the waits
+    ///
+    /// The sequence number is read before unlocking, a notify between unlocking and sleeping
+    /// changes it and [futex_wait()] returns right away.
+    fn wait_optional_timeout<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> Result<(MutexGuard<'a, T>, bool), LockError> {
+        if !task::can_block() {
+            return Err(LockError);
+        }
+
+        let seq = self.futex.load(Ordering::Relaxed);
+        let result = guard.unlocked(|| futex_wait(&self.futex, seq, timeout));
+
+        Ok((guard, result == Err(FutexError::TimedOut)))
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
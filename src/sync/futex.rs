@@ -0,0 +1,100 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Futex
+//!
+//! Fast userspace mutex style wait/wake. A task can sleep on the address of an
+//! [AtomicU32] as long as it holds an expected value, whoever changes the value
+//! wakes the sleepers. Every blocking primitive in [crate::sync] is built on this.
+//!
+//! The wait queues live in the scheduler ([crate::task::block_on()]), keyed by the
+//! address of the atomic.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://man7.org/linux/man-pages/man2/futex.2.html>
+//!   - <https://man7.org/linux/man-pages/man7/futex.7.html>
+//!   - <https://stdrs.dev/nightly/x86_64-unknown-linux-gnu/src/std/sys/unix/futex.rs.html>
+//!
+
+use crate::task::{self, BlockError};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for futex waits
+pub enum FutexError {
+    /// The futex didn't hold the expected value (EAGAIN)
+    WouldBlock,
+    /// The timeout ran out before a wake (ETIMEDOUT)
+    TimedOut,
+    /// The caller can't sleep, e.g. an interrupt handler (EDEADLK)
+    NotAllowed,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for FutexError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            FutexError::WouldBlock => f.write_str("Futex value changed"),
+            FutexError::TimedOut => f.write_str("Futex wait timed out"),
+            FutexError::NotAllowed => f.write_str("Futex wait from a context that can't block"),
+        }
+    }
+}
+
+impl From<BlockError> for FutexError {
+    fn from(err: BlockError) -> FutexError {
+        match err {
+            BlockError::Condition => FutexError::WouldBlock,
+            BlockError::TimedOut => FutexError::TimedOut,
+            BlockError::NotAllowed => FutexError::NotAllowed,
+        }
+    }
+}
+
+/// Sleep while `futex` holds `expected`
+///
+/// The comparison and going to sleep are atomic with respect to [futex_wake()]. Returns Ok
+/// when woken, which doesn't mean the value changed. Callers loop and re-check.
+///
+/// ## Examples
+///
+/// ```
+/// use core::sync::atomic::{AtomicU32, Ordering};
+/// use dyseos::sync::futex::{futex_wait, futex_wake};
+///
+/// static READY: AtomicU32 = AtomicU32::new(0);
+///
+/// // waiter
+/// while READY.load(Ordering::Acquire) == 0 {
+///     let _ = futex_wait(&READY, 0, None);
+/// }
+///
+/// // waker
+/// READY.store(1, Ordering::Release);
+/// futex_wake(&READY, usize::MAX);
+/// ```
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) -> Result<(), FutexError> {
+    let key = futex as *const AtomicU32 as usize;
+
+    task::block_on(key, timeout, || futex.load(Ordering::Relaxed) == expected)?;
+    Ok(())
+}
+
+/// Wake up to `n` tasks sleeping on `futex`
+///
+/// Returns the number of tasks woken. Safe to call from interrupt handlers.
+pub fn futex_wake(futex: &AtomicU32, n: usize) -> usize {
+    task::wake_on(futex as *const AtomicU32 as usize, n)
+}
@@ -23,4 +23,11 @@
 //!   - <https://doc.rust-lang.org/std/cell/index.html>
 //!   - <https://doc.rust-lang.org/std/sync/index.html>
 
+pub mod channel;
+pub mod condvar;
+pub mod futex;
 pub mod mutex;
+pub mod once;
+pub mod rwlock;
+pub mod semaphore;
+pub mod ticket;
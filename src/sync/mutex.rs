@@ -24,6 +24,19 @@
 //!   - <https://docs.rust-embedded.org/book/static-guarantees/typestate-programming.html>
 //!
 
+use super::futex::{futex_wait, futex_wake, FutexError};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Nobody holds the lock
+const UNLOCKED: u32 = 0;
+/// Held, nobody is waiting
+const LOCKED: u32 = 1;
+/// Held, someone might be sleeping on the futex
+const CONTENDED: u32 = 2;
+
+/// How many times [Mutex::lock()] polls before going to sleep
+const SPIN_LIMIT: usize = 100;
+
 #[derive(Debug)]
 /// An error type for the mutex
 ///
@@ -44,13 +57,11 @@ impl core::fmt::Display for LockError {
 /// aquired by a single thread at a time so it is safe to have a mutable borrow. 
 ///
 /// The futex is an atomic value that allows thread safe reading and writing of the lock value.
-/// 
-/// 
-/// ### TODO:
-/// - add syscall for futex_wait to improve lock aquisitions. 
+/// It is 0 when unlocked, 1 when locked and 2 when locked with (possibly) sleeping waiters, so
+/// unlocking only has to [futex_wake()] when someone is actually waiting.
 ///
 pub struct Mutex<T: ?Sized> {
-    futex: core::sync::atomic::AtomicBool,
+    futex: AtomicU32,
     data: core::cell::UnsafeCell<T>,
 }
 
@@ -78,20 +89,72 @@ unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
 /// see [crate::drivers::console]
 ///
 pub struct MutexGuard<'a, T: ?Sized + 'a> {
-    futex: &'a core::sync::atomic::AtomicBool,
+    futex: &'a AtomicU32,
     data: &'a core::cell::UnsafeCell<T>,
 }
 
 // impl<T: ?Sized> !Send for MutexGuard<'_, T> {}
 unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
 
+/// Poll the futex while it is locked without waiters
+///
+/// Returns early when it's unlocked or contended, there's no point spinning when others
+/// are already asleep.
+fn spin(futex: &AtomicU32) -> u32 {
+    let mut state = futex.load(Ordering::Relaxed);
+
+    for _ in 0..SPIN_LIMIT {
+        if state != LOCKED {
+            break;
+        }
+
+        core::hint::spin_loop();
+        state = futex.load(Ordering::Relaxed);
+    }
+
+    state
+}
+
+/// Slow path of [Mutex::lock()]
+///
+/// Mostly copied from the std futex mutex. Marks the futex contended and sleeps until
+/// the holder wakes us up.
+fn lock_contended(futex: &AtomicU32) -> Result<(), LockError> {
+    let mut state = spin(futex);
+
+    if state == UNLOCKED
+        && futex
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    {
+        return Ok(());
+    }
+
+    loop {
+        // Whoever unlocks after this sees CONTENDED and wakes someone.
+        if state != CONTENDED && futex.swap(CONTENDED, Ordering::Acquire) == UNLOCKED {
+            return Ok(());
+        }
+
+        match futex_wait(futex, CONTENDED, None) {
+            Ok(()) | Err(FutexError::WouldBlock) => {}
+            Err(_) => return Err(LockError),
+        }
+
+        state = spin(futex);
+    }
+}
+
+/// Release the lock, waking one sleeper if there are any
+fn unlock(futex: &AtomicU32) {
+    if futex.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+        futex_wake(futex, 1);
+    }
+}
+
 impl<T> Mutex<T> {
     /// Creates a new mutex in an unlocked state ready for use.
     ///
-    ///
-    /// ## TODO:
-    /// Write an actual futex call
-    ///
     /// ## Examples
     ///
     /// ```
@@ -102,7 +165,7 @@ impl<T> Mutex<T> {
     #[inline]
     pub const fn new(t: T) -> Mutex<T> {
         Mutex {
-            futex: core::sync::atomic::AtomicBool::new(false),
+            futex: AtomicU32::new(UNLOCKED),
             data: core::cell::UnsafeCell::new(t),
         }
     }
@@ -150,20 +213,17 @@ impl<T: ?Sized> Mutex<T> {
     ///
     /// ```
     pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
-        match self.futex.compare_exchange(
-            false,
-            true,
-            core::sync::atomic::Ordering::Acquire,
-            core::sync::atomic::Ordering::Relaxed,
-        ) {
+        match self.futex.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed) {
             Ok(_) => Some(self.as_guard()), // Locked!
             Err(_) => None,
         }
     }
-    
+
     /// Aquire the [MutexGuard]
     ///
-    /// Loops until the timeout is reached or the lock is aquired. Underneath this uses [Mutex::try_lock()].
+    /// Spins briefly, then puts the calling task to sleep on the futex until the lock is
+    /// released. Only fails when the caller can't sleep (an interrupt handler, or before the
+    /// scheduler runs on this core) and the lock is taken.
     ///
     /// ## Examples
     ///
@@ -189,17 +249,35 @@ impl<T: ?Sized> Mutex<T> {
     ///
     /// ```
     pub fn lock(&self) -> Result<MutexGuard<'_, T>, LockError> {
-        for _ in 0..100 {
-            // should timeout be a user input? if so no need for futex_wait()
-            match self.try_lock() {
-                Some(raii_guard) => return Ok(raii_guard),
-                None => {
-                    // delay until futex changes... need a system call ig
-                }
-            }
+        if let Some(raii_guard) = self.try_lock() {
+            return Ok(raii_guard);
+        }
+
+        lock_contended(&self.futex)?;
+        Ok(self.as_guard())
+    }
+}
+
+impl<T: ?Sized> MutexGuard<'_, T> {
+    /// Release the lock while `f` runs and take it back afterwards
+    ///
+    /// Used by [super::condvar::Condvar] to sleep without holding the mutex. Relocking spins
+    /// instead of failing if the caller turns out not to be able to sleep, the guard has to
+    /// hold the lock again when this returns.
+    pub(super) fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        unlock(self.futex);
+        let result = f();
+
+        while self
+            .futex
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+            && lock_contended(self.futex).is_err()
+        {
+            core::hint::spin_loop();
         }
 
-        Err(LockError)
+        result
     }
 }
 
@@ -221,6 +299,6 @@ impl<T: ?Sized> core::ops::DerefMut for MutexGuard<'_, T> {
 impl<T: ?Sized> Drop for MutexGuard<'_, T> {
     #[inline]
     fn drop(&mut self) {
-        self.futex.store(false, core::sync::atomic::Ordering::Release);
+        unlock(self.futex);
     }
 }
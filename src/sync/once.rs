@@ -0,0 +1,252 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS One-time initialization
+//!
+//! [Once] runs a closure exactly once, [OnceCell] stores the value it produced and [Lazy]
+//! does that on first dereference. Meant for driver init, e.g. a `static` that needs
+//! values from the mailbox before it can be built. Anyone racing the first caller sleeps
+//! on the futex until the value is ready.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://doc.rust-lang.org/std/sync/struct.Once.html>
+//!   - <https://doc.rust-lang.org/std/sync/struct.OnceLock.html>
+//!   - <https://doc.rust-lang.org/std/sync/struct.LazyLock.html>
+//!   - <https://stdrs.dev/nightly/x86_64-unknown-linux-gnu/src/std/sys_common/once/futex.rs.html>
+//!
+
+use super::futex::{futex_wait, futex_wake, FutexError};
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Nothing ran yet
+const INCOMPLETE: u32 = 0;
+/// Someone is running the closure
+const RUNNING: u32 = 1;
+/// The closure finished
+const COMPLETE: u32 = 2;
+
+/// Run a closure exactly once
+///
+/// The first caller of [Once::call_once()] runs the closure, everyone else waits for it to
+/// finish. There's no poisoning, a panic in the closure takes the kernel down anyway.
+///
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    /// Creates a new [Once] that hasn't run yet
+    #[inline]
+    pub const fn new() -> Once {
+        Once {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    /// True once a closure ran to completion
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Run `f` if no closure has run on this [Once] yet
+    ///
+    /// Returns when the closure finished, whoever ran it. Waiters sleep on the futex, or
+    /// spin when they can't sleep.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::once::Once;
+    ///
+    /// static INIT: Once = Once::new();
+    ///
+    /// INIT.call_once(|| println!("only printed once"));
+    /// INIT.call_once(|| println!("never printed"));
+    /// ```
+    pub fn call_once(&self, f: impl FnOnce()) {
+        if self.is_completed() {
+            return;
+        }
+
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                f();
+                self.state.store(COMPLETE, Ordering::Release);
+                futex_wake(&self.state, usize::MAX);
+            }
+            Err(_) => self.wait(),
+        }
+    }
+
+    /// Block until the running closure is done
+    fn wait(&self) {
+        while self.state.load(Ordering::Acquire) == RUNNING {
+            if let Err(FutexError::NotAllowed) = futex_wait(&self.state, RUNNING, None) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A cell that is written once
+///
+/// Reading through [OnceCell::get()] never blocks, [OnceCell::get_or_init()] waits for
+/// whoever is initializing the cell.
+///
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value is only written once, by the task that won the race in `once`, and is read
+// after that. Sharing the cell shares `T`, sending it can move `T` to another task.
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    /// Creates a new empty cell
+    #[inline]
+    pub const fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, if the cell has been initialized
+    pub fn get(&self) -> Option<&T> {
+        match self.once.is_completed() {
+            true => Some(unsafe { self.get_unchecked() }),
+            false => None,
+        }
+    }
+
+    /// Initialize the cell with `value`
+    ///
+    /// Gives the value back if the cell was already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// The value, initializing it with `f` if the cell is empty
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::once::OnceCell;
+    ///
+    /// static CLOCK_RATE: OnceCell<u32> = OnceCell::new();
+    ///
+    /// let rate = CLOCK_RATE.get_or_init(|| 48_000_000);
+    /// assert_eq!(CLOCK_RATE.get(), Some(rate));
+    /// ```
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        self.once.call_once(|| unsafe {
+            (*self.value.get()).write(f());
+        });
+
+        unsafe { self.get_unchecked() }
+    }
+
+    /// Mutable access to the value, no locking needed through `&mut`
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        match self.once.is_completed() {
+            true => Some(unsafe { (*self.value.get()).assume_init_mut() }),
+            false => None,
+        }
+    }
+
+    /// Only valid once `once` completed
+    unsafe fn get_unchecked(&self) -> &T {
+        (*self.value.get()).assume_init_ref()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { (*self.value.get()).assume_init_drop() };
+        }
+    }
+}
+
+/// A value that is initialized on first access
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::sync::once::Lazy;
+///
+/// static TABLE: Lazy<[u8; 256]> = Lazy::new(|| core::array::from_fn(|i| i as u8));
+///
+/// assert_eq!(TABLE[7], 7);
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+// `init` is only taken by the task that runs the initialization in `cell`.
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Creates a new lazy value that will be built by `init`
+    #[inline]
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Initialize the value if that hasn't happened yet and return it
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.cell.get_or_init(|| match unsafe { (*this.init.get()).take() } {
+            Some(init) => init(),
+            None => unreachable!("Lazy initialized twice"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> core::ops::Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
@@ -0,0 +1,251 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Reader-writer lock
+//!
+//! Any number of readers or a single writer. Same typestate guard style as
+//! [super::mutex], the guards hold references to the fields of the lock instead of the
+//! lock itself.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://doc.rust-lang.org/std/sync/struct.RwLock.html>
+//!   - <https://stdrs.dev/nightly/x86_64-unknown-linux-gnu/src/std/sys/unix/locks/futex_rwlock.rs.html>
+//!
+
+use super::futex::{futex_wait, futex_wake, FutexError};
+use super::mutex::LockError;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Low bits of the state count the readers, all ones means write locked
+const MASK: u32 = (1 << 30) - 1;
+/// Reader count of a write locked lock
+const WRITE_LOCKED: u32 = MASK;
+/// Most readers at once, one less than [WRITE_LOCKED]
+const MAX_READERS: u32 = MASK - 1;
+/// Set while a writer is (possibly) sleeping, new readers wait so writers don't starve
+const WRITERS_WAITING: u32 = 1 << 31;
+
+/// Reader-writer lock
+///
+/// The state is a single futex: the low 30 bits are the reader count ([WRITE_LOCKED] for a
+/// writer) and the top bit says writers are waiting. Every unlock that could let a sleeper
+/// in wakes all of them and they race again, simple over clever.
+///
+pub struct RwLock<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+/// TypeState read guard
+///
+/// Shared access to the data of a [RwLock], the read lock is released on drop.
+///
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    state: &'a AtomicU32,
+    data: &'a UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+/// TypeState write guard
+///
+/// Exclusive access to the data of a [RwLock], the write lock is released on drop.
+///
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    state: &'a AtomicU32,
+    data: &'a UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+/// A new reader can get in
+fn is_read_lockable(state: u32) -> bool {
+    state & MASK < MAX_READERS && state & WRITERS_WAITING == 0
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new unlocked lock
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::rwlock::RwLock;
+    ///
+    /// static CMDLINE: RwLock<[u8; 64]> = RwLock::new([0; 64]);
+    /// ```
+    #[inline]
+    pub const fn new(t: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicU32::new(0),
+            data: UnsafeCell::new(t),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Attempts to acquire a read guard without blocking
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+
+        match is_read_lockable(state)
+            && self
+                .state
+                .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            true => Some(RwLockReadGuard {
+                state: &self.state,
+                data: &self.data,
+            }),
+            false => None,
+        }
+    }
+
+    /// Attempts to acquire the write guard without blocking
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+
+        match state & MASK == 0
+            && self
+                .state
+                .compare_exchange(state, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            true => Some(RwLockWriteGuard {
+                state: &self.state,
+                data: &self.data,
+            }),
+            false => None,
+        }
+    }
+
+    /// Acquire a read guard
+    ///
+    /// Sleeps while a writer holds or waits for the lock. Only fails when the caller can't
+    /// sleep and the lock isn't available.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::rwlock::RwLock;
+    ///
+    /// let lock = RwLock::new(5);
+    /// {
+    ///     let a = lock.read().unwrap();
+    ///     let b = lock.read().unwrap();
+    ///     assert_eq!(*a + *b, 10);
+    /// }
+    /// *lock.write().unwrap() += 1;
+    /// ```
+    pub fn read(&self) -> Result<RwLockReadGuard<'_, T>, LockError> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return Ok(guard);
+            }
+
+            let state = self.state.load(Ordering::Relaxed);
+            if is_read_lockable(state) {
+                continue;
+            }
+
+            match futex_wait(&self.state, state, None) {
+                Ok(()) | Err(FutexError::WouldBlock) => {}
+                Err(_) => return Err(LockError),
+            }
+        }
+    }
+
+    /// Acquire the write guard
+    ///
+    /// Sleeps until every reader and writer is gone. Only fails when the caller can't sleep
+    /// and the lock isn't available.
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, T>, LockError> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return Ok(guard);
+            }
+
+            let state = self.state.load(Ordering::Relaxed);
+            if state & MASK == 0 {
+                continue;
+            }
+
+            // Keep new readers out, the last one to leave wakes us
+            if state & WRITERS_WAITING == 0
+                && self
+                    .state
+                    .compare_exchange(state, state | WRITERS_WAITING, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_err()
+            {
+                continue;
+            }
+
+            match futex_wait(&self.state, state | WRITERS_WAITING, None) {
+                Ok(()) | Err(FutexError::WouldBlock) => {}
+                Err(_) => {
+                    // Other waiting writers set the flag again when they wake up
+                    self.state.fetch_and(!WRITERS_WAITING, Ordering::Relaxed);
+                    futex_wake(&self.state, usize::MAX);
+                    return Err(LockError);
+                }
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> core::ops::Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        let state = self.state.fetch_sub(1, Ordering::Release) - 1;
+
+        // Readers only sleep behind a writer, so only a waiting writer needs the wake up
+        if state & MASK == 0 && state & WRITERS_WAITING != 0 {
+            futex_wake(self.state, usize::MAX);
+        }
+    }
+}
+
+impl<T: ?Sized> core::ops::Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
+
+impl<T: ?Sized> core::ops::DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.state.fetch_sub(WRITE_LOCKED, Ordering::Release);
+        futex_wake(self.state, usize::MAX);
+    }
+}
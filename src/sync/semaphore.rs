@@ -0,0 +1,157 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Counting semaphore
+//!
+//! A pool of permits. [Semaphore::acquire()] takes one, sleeping while there are none,
+//! and the returned guard gives it back when dropped. [Semaphore::release()] adds permits
+//! without a guard, so an interrupt handler can signal a task that
+//! [SemaphoreGuard::forget()]s the permits it takes.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://en.wikipedia.org/wiki/Semaphore_(programming)>
+//!   - <https://docs.rs/tokio/latest/tokio/sync/struct.Semaphore.html>
+//!
+
+use super::futex::{futex_wait, futex_wake, FutexError};
+use super::mutex::LockError;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+/// Counting semaphore
+///
+/// The futex is the number of free permits.
+///
+pub struct Semaphore {
+    permits: AtomicU32,
+}
+
+/// TypeState semaphore permit
+///
+/// Holding the guard means holding one permit of a [Semaphore], dropping it gives the
+/// permit back.
+///
+pub struct SemaphoreGuard<'a> {
+    permits: &'a AtomicU32,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with `permits` free permits
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::semaphore::Semaphore;
+    ///
+    /// // at most two tasks talk to the card at once
+    /// static SLOTS: Semaphore = Semaphore::new(2);
+    /// ```
+    #[inline]
+    pub const fn new(permits: u32) -> Semaphore {
+        Semaphore {
+            permits: AtomicU32::new(permits),
+        }
+    }
+
+    /// Number of free permits right now
+    pub fn available(&self) -> u32 {
+        self.permits.load(Ordering::Relaxed)
+    }
+
+    /// Take a permit if one is free
+    pub fn try_acquire(&self) -> Option<SemaphoreGuard<'_>> {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+
+        while permits > 0 {
+            match self.permits.compare_exchange_weak(
+                permits,
+                permits - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(SemaphoreGuard {
+                        permits: &self.permits,
+                    })
+                }
+                Err(current) => permits = current,
+            }
+        }
+
+        None
+    }
+
+    /// Take a permit, sleeping until one is free
+    ///
+    /// Only fails when the caller can't sleep and there are no permits.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::semaphore::Semaphore;
+    ///
+    /// static RX_READY: Semaphore = Semaphore::new(0);
+    ///
+    /// // interrupt handler
+    /// RX_READY.release(1);
+    ///
+    /// // task
+    /// RX_READY.acquire().unwrap().forget();
+    /// ```
+    pub fn acquire(&self) -> Result<SemaphoreGuard<'_>, LockError> {
+        self.acquire_optional_timeout(None)
+    }
+
+    /// Like [Semaphore::acquire()] but gives up after `timeout`
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<SemaphoreGuard<'_>, LockError> {
+        self.acquire_optional_timeout(Some(timeout))
+    }
+
+    /// Add `n` permits and wake as many sleepers
+    ///
+    /// Safe to call from interrupt handlers.
+    pub fn release(&self, n: u32) {
+        self.permits.fetch_add(n, Ordering::Release);
+        futex_wake(&self.permits, n as usize);
+    }
+
+    /// Shared body of the acquires, sleeps while the count is 0
+    fn acquire_optional_timeout(&self, timeout: Option<Duration>) -> Result<SemaphoreGuard<'_>, LockError> {
+        loop {
+            if let Some(guard) = self.try_acquire() {
+                return Ok(guard);
+            }
+
+            match futex_wait(&self.permits, 0, timeout) {
+                Ok(()) | Err(FutexError::WouldBlock) => {}
+                Err(_) => return self.try_acquire().ok_or(LockError),
+            }
+        }
+    }
+}
+
+impl SemaphoreGuard<'_> {
+    /// Keep the permit, it isn't given back to the [Semaphore]
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        futex_wake(self.permits, 1);
+    }
+}
@@ -0,0 +1,153 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Ticket lock
+//!
+//! Fair spinlock for short critical sections shared between cores, like the console.
+//! Lockers take a ticket and are served in the order they arrived, so one core can't
+//! keep winning the race the way it can with [super::mutex::Mutex::try_lock()].
+//! Waiters sleep in `wfe` and the unlock `sev`s them awake.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://en.wikipedia.org/wiki/Ticket_lock>
+//!   - <https://developer.arm.com/documentation/den0024/a/Multi-core-processors/Multi-processing-systems/Synchronization>
+//!   - <https://elixir.bootlin.com/linux/v4.0/source/arch/arm64/include/asm/spinlock.h>
+//!
+
+use crate::cpu::exception::IrqGuard;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// FIFO spinlock
+///
+/// TypeState lock like [super::mutex::Mutex], the data is only reachable through a
+/// [TicketLockGuard]. IRQs are masked on the calling core while the guard lives: the holder
+/// can't be preempted (the other cores would spin for a whole time slice) and an interrupt
+/// handler on the same core can't deadlock on it. Never sleeps, so it works everywhere.
+///
+pub struct TicketLock<T: ?Sized> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+/// TypeState ticket lock guard
+///
+/// Holds references to the fields of the [TicketLock], serves the next ticket and restores
+/// the IRQ mask when dropped.
+///
+pub struct TicketLockGuard<'a, T: ?Sized + 'a> {
+    now_serving: &'a AtomicU32,
+    data: &'a UnsafeCell<T>,
+    _irq: IrqGuard,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for TicketLockGuard<'_, T> {}
+
+impl<T> TicketLock<T> {
+    /// Creates a new unlocked ticket lock
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::ticket::TicketLock;
+    ///
+    /// static COUNTER: TicketLock<usize> = TicketLock::new(0);
+    /// ```
+    #[inline]
+    pub const fn new(t: T) -> TicketLock<T> {
+        TicketLock {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            data: UnsafeCell::new(t),
+        }
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    /// Builds a [TicketLockGuard], only once our ticket is being served
+    fn as_guard(&self, irq: IrqGuard) -> TicketLockGuard<'_, T> {
+        TicketLockGuard {
+            now_serving: &self.now_serving,
+            data: &self.data,
+            _irq: irq,
+        }
+    }
+
+    /// Take the lock if nobody holds or waits for it
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        let serving = self.now_serving.load(Ordering::Relaxed);
+
+        match self.next_ticket.compare_exchange(
+            serving,
+            serving.wrapping_add(1),
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Some(self.as_guard(irq)),
+            Err(_) => None,
+        }
+    }
+
+    /// Take a ticket and wait for it to be served
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::sync::ticket::TicketLock;
+    ///
+    /// let lock = TicketLock::new(0);
+    /// *lock.lock() += 1;
+    /// assert_eq!(*lock.lock(), 1);
+    /// ```
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let irq = IrqGuard::new();
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            aarch64_cpu::asm::wfe();
+        }
+
+        self.as_guard(irq)
+    }
+}
+
+impl<T: ?Sized> core::ops::Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data.get() }
+    }
+}
+
+impl<T: ?Sized> core::ops::DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.now_serving.fetch_add(1, Ordering::Release);
+
+        // The store has to be visible before the waiters wake up and look
+        aarch64_cpu::asm::barrier::dsb(aarch64_cpu::asm::barrier::ISHST);
+        aarch64_cpu::asm::sev();
+    }
+}
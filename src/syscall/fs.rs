@@ -0,0 +1,359 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS File system calls
+//!
+//! The calls on paths and file descriptors, on top of [crate::vfs] and the caller's
+//! [FdTable]. Data moves between user memory and files through a kernel buffer, so a
+//! fault on a user page never happens while a filesystem or device holds a lock.
+//!
+//! There are no working directories yet. Relative paths start at `/`, and a directory
+//! descriptor other than `AT_FDCWD` can't be used with them.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://man7.org/linux/man-pages/man2/openat.2.html>
+//!   - <https://man7.org/linux/man-pages/man2/getdents64.2.html>
+//!   - <https://man7.org/linux/man-pages/man2/unlink.2.html>
+//!   - <https://man7.org/linux/man-pages/man2/rename.2.html>
+//!   - <https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/stat.h>
+//!
+
+use super::{check_user, read_user_bytes, user_str, write_user, write_user_bytes, Errno, SysResult};
+use crate::task;
+use crate::vfs::{self, fd::FdTable, File, FileType, SeekFrom, Stat};
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+/// Directory descriptor meaning the working directory
+const AT_FDCWD: i64 = -100;
+
+/// newfstatat flags
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+/// unlinkat flag, remove a directory instead
+const AT_REMOVEDIR: u64 = 0x200;
+
+/// renameat2 flag, fail if the new path exists
+const RENAME_NOREPLACE: u64 = 1;
+
+/// lseek whence
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+/// Size of the kernel buffer reads and writes go through
+const IO_CHUNK: usize = 1024;
+
+/// File type bits of st_mode
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// d_type values of linux_dirent64
+const DT_UNKNOWN: u8 = 0;
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_BLK: u8 = 6;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// Header of a linux_dirent64, d_ino, d_off, d_reclen and d_type
+const DIRENT_HEADER: usize = 19;
+
+/// struct stat of asm-generic, what aarch64 uses
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LinuxStat {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    _pad1: u64,
+    size: i64,
+    blksize: i32,
+    _pad2: i32,
+    blocks: i64,
+    atime: i64,
+    atime_nsec: u64,
+    mtime: i64,
+    mtime_nsec: u64,
+    ctime: i64,
+    ctime_nsec: u64,
+    _unused: [u32; 2],
+}
+
+impl From<Stat> for LinuxStat {
+    fn from(stat: Stat) -> LinuxStat {
+        let kind = match stat.kind {
+            FileType::File => S_IFREG,
+            FileType::Dir => S_IFDIR,
+            FileType::Symlink => S_IFLNK,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+            FileType::Other => 0,
+        };
+
+        LinuxStat {
+            ino: stat.ino,
+            mode: kind | stat.mode,
+            nlink: stat.nlink,
+            rdev: stat.rdev,
+            size: stat.size as i64,
+            blksize: IO_CHUNK as i32,
+            blocks: stat.size.div_ceil(512) as i64,
+            ..LinuxStat::default()
+        }
+    }
+}
+
+fn d_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::File => DT_REG,
+        FileType::Dir => DT_DIR,
+        FileType::Symlink => DT_LNK,
+        FileType::CharDevice => DT_CHR,
+        FileType::BlockDevice => DT_BLK,
+        FileType::Other => DT_UNKNOWN,
+    }
+}
+
+fn files() -> Result<Arc<FdTable>, Errno> {
+    task::files().ok_or(Errno::EBADF)
+}
+
+fn file(fd: u64) -> Result<Arc<dyn File>, Errno> {
+    Ok(files()?.get(fd as usize)?)
+}
+
+/// Copy the path at `path`, relative to `dirfd`
+fn path_at(dirfd: u64, path: u64) -> Result<String, Errno> {
+    let path = user_str(path, vfs::PATH_MAX)?;
+
+    if !path.starts_with('/') && dirfd as i64 != AT_FDCWD {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(path)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Calls
+//--------------------------------------------------------------------------------------------------
+
+pub(super) fn sys_mkdirat(dirfd: u64, path: u64, mode: u64) -> SysResult {
+    let path = path_at(dirfd, path)?;
+    vfs::mkdir(&path, mode as u32 & 0o7777)?;
+
+    Ok(0)
+}
+
+pub(super) fn sys_unlinkat(dirfd: u64, path: u64, flags: u64) -> SysResult {
+    let path = path_at(dirfd, path)?;
+
+    match flags {
+        0 => vfs::unlink(&path)?,
+        AT_REMOVEDIR => vfs::rmdir(&path)?,
+        _ => return Err(Errno::EINVAL),
+    }
+
+    Ok(0)
+}
+
+pub(super) fn sys_symlinkat(target: u64, dirfd: u64, path: u64) -> SysResult {
+    let target = user_str(target, vfs::PATH_MAX)?;
+    if target.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+
+    vfs::symlink(&target, &path_at(dirfd, path)?)?;
+    Ok(0)
+}
+
+pub(super) fn sys_renameat2(olddirfd: u64, oldpath: u64, newdirfd: u64, newpath: u64, flags: u64) -> SysResult {
+    let from = path_at(olddirfd, oldpath)?;
+    let to = path_at(newdirfd, newpath)?;
+
+    match flags {
+        0 => {}
+        RENAME_NOREPLACE if vfs::lookup_link(&to).is_ok() => return Err(Errno::EEXIST),
+        RENAME_NOREPLACE => {}
+        _ => return Err(Errno::EINVAL),
+    }
+
+    vfs::rename(&from, &to)?;
+    Ok(0)
+}
+
+pub(super) fn sys_truncate(path: u64, length: u64) -> SysResult {
+    let path = user_str(path, vfs::PATH_MAX)?;
+    if (length as i64) < 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    vfs::truncate(&path, length)?;
+    Ok(0)
+}
+
+pub(super) fn sys_ftruncate(fd: u64, length: u64) -> SysResult {
+    if (length as i64) < 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    file(fd)?.truncate(length)?;
+    Ok(0)
+}
+
+pub(super) fn sys_openat(dirfd: u64, path: u64, flags: u64, mode: u64) -> SysResult {
+    let path = path_at(dirfd, path)?;
+    let file = vfs::open(&path, flags as u32, mode as u32 & 0o7777)?;
+
+    Ok(files()?.insert(file)? as u64)
+}
+
+pub(super) fn sys_close(fd: u64) -> SysResult {
+    let file = files()?.remove(fd as usize)?;
+    drop(file);
+
+    Ok(0)
+}
+
+pub(super) fn sys_read(fd: u64, buf: u64, count: u64) -> SysResult {
+    let file = file(fd)?;
+    let count = count as usize;
+    check_user(buf, count)?;
+
+    let mut chunk = [0; IO_CHUNK];
+    let mut done = 0;
+
+    while done < count {
+        let part = &mut chunk[..(count - done).min(IO_CHUNK)];
+        let read = match file.read(part) {
+            Ok(read) => read,
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        write_user_bytes(buf + done as u64, &part[..read])?;
+        done += read;
+
+        // Short read, the file or device has nothing more right now
+        if read < part.len() {
+            break;
+        }
+    }
+
+    Ok(done as u64)
+}
+
+pub(super) fn sys_write(fd: u64, buf: u64, count: u64) -> SysResult {
+    let file = file(fd)?;
+    let count = count as usize;
+    check_user(buf, count)?;
+
+    let mut chunk = [0; IO_CHUNK];
+    let mut done = 0;
+
+    while done < count {
+        let part = &mut chunk[..(count - done).min(IO_CHUNK)];
+        read_user_bytes(buf + done as u64, part)?;
+
+        let written = match file.write(part) {
+            Ok(written) => written,
+            Err(_) if done > 0 => break,
+            Err(err) => return Err(err.into()),
+        };
+        done += written;
+
+        if written < part.len() {
+            break;
+        }
+    }
+
+    Ok(done as u64)
+}
+
+pub(super) fn sys_lseek(fd: u64, offset: u64, whence: u64) -> SysResult {
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    Ok(file(fd)?.seek(pos)?)
+}
+
+pub(super) fn sys_getdents64(fd: u64, dirp: u64, count: u64) -> SysResult {
+    let file = file(fd)?;
+    let mut out = Vec::new();
+
+    loop {
+        let pos = file.seek(SeekFrom::Current(0))?;
+        let Some(entry) = file.readdir()? else {
+            break;
+        };
+
+        let reclen = (DIRENT_HEADER + entry.name.len() + 1).next_multiple_of(8);
+        if out.len() + reclen > count as usize {
+            // Handed out next time, unless not even one entry fits
+            file.seek(SeekFrom::Start(pos))?;
+            if out.is_empty() {
+                return Err(Errno::EINVAL);
+            }
+            break;
+        }
+
+        let start = out.len();
+        out.extend_from_slice(&entry.ino.to_le_bytes());
+        out.extend_from_slice(&(pos + 1).to_le_bytes());
+        out.extend_from_slice(&(reclen as u16).to_le_bytes());
+        out.push(d_type(entry.kind));
+        out.extend_from_slice(entry.name.as_bytes());
+        out.resize(start + reclen, 0);
+    }
+
+    write_user_bytes(dirp, &out)?;
+    Ok(out.len() as u64)
+}
+
+pub(super) fn sys_newfstatat(dirfd: u64, path: u64, statbuf: u64, flags: u64) -> SysResult {
+    let path = user_str(path, vfs::PATH_MAX)?;
+    if path.is_empty() && flags & AT_EMPTY_PATH != 0 {
+        return sys_fstat(dirfd, statbuf);
+    }
+
+    if !path.starts_with('/') && dirfd as i64 != AT_FDCWD {
+        return Err(Errno::EINVAL);
+    }
+
+    let inode = match flags & AT_SYMLINK_NOFOLLOW {
+        0 => vfs::lookup(&path)?,
+        _ => vfs::lookup_link(&path)?,
+    };
+
+    write_user(statbuf, LinuxStat::from(inode.stat()?))?;
+    Ok(0)
+}
+
+pub(super) fn sys_fstat(fd: u64, statbuf: u64) -> SysResult {
+    let stat = file(fd)?.stat()?;
+
+    write_user(statbuf, LinuxStat::from(stat))?;
+    Ok(0)
+}
@@ -0,0 +1,381 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS System calls
+//!
+//! EL0 programs ask the kernel for things with `svc #0`. The ABI follows Linux on
+//! aarch64: the call number goes in x8, arguments in x0-x5 and the result comes back
+//! in x0, negative values are an [Errno]. The numbers match Linux too, so a libc built
+//! for aarch64 Linux can talk to DyseOS for the calls that exist.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://man7.org/linux/man-pages/man2/syscall.2.html>
+//!   - <https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/unistd.h>
+//!   - <https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/errno-base.h>
+//!
+
+mod fs;
+
+use crate::cpu::exception::TrapFrame;
+use crate::cpu::uaccess;
+use crate::memory::vm::{self, VmError, USER_END};
+use crate::random;
+use crate::task;
+use crate::vfs::VfsError;
+use alloc::{string::String, vec::Vec};
+use core::time::Duration;
+
+/// mkdirat(dirfd, path, mode)
+pub const SYS_MKDIRAT: u64 = 34;
+/// unlinkat(dirfd, path, flags)
+pub const SYS_UNLINKAT: u64 = 35;
+/// symlinkat(target, newdirfd, linkpath)
+pub const SYS_SYMLINKAT: u64 = 36;
+/// renameat(olddirfd, oldpath, newdirfd, newpath)
+pub const SYS_RENAMEAT: u64 = 38;
+/// truncate(path, length)
+pub const SYS_TRUNCATE: u64 = 45;
+/// ftruncate(fd, length)
+pub const SYS_FTRUNCATE: u64 = 46;
+/// openat(dirfd, path, flags, mode)
+pub const SYS_OPENAT: u64 = 56;
+/// close(fd)
+pub const SYS_CLOSE: u64 = 57;
+/// getdents64(fd, dirp, count)
+pub const SYS_GETDENTS64: u64 = 61;
+/// lseek(fd, offset, whence)
+pub const SYS_LSEEK: u64 = 62;
+/// read(fd, buf, count)
+pub const SYS_READ: u64 = 63;
+/// write(fd, buf, count)
+pub const SYS_WRITE: u64 = 64;
+/// newfstatat(dirfd, path, statbuf, flags)
+pub const SYS_NEWFSTATAT: u64 = 79;
+/// fstat(fd, statbuf)
+pub const SYS_FSTAT: u64 = 80;
+/// exit(code)
+pub const SYS_EXIT: u64 = 93;
+/// nanosleep(req, rem)
+pub const SYS_NANOSLEEP: u64 = 101;
+/// sched_yield()
+pub const SYS_SCHED_YIELD: u64 = 124;
+/// getpid()
+pub const SYS_GETPID: u64 = 172;
+/// munmap(addr, length)
+pub const SYS_MUNMAP: u64 = 215;
+/// mmap(addr, length, prot, flags, fd, offset)
+pub const SYS_MMAP: u64 = 222;
+/// renameat2(olddirfd, oldpath, newdirfd, newpath, flags)
+pub const SYS_RENAMEAT2: u64 = 276;
+/// getrandom(buf, buflen, flags)
+pub const SYS_GETRANDOM: u64 = 278;
+
+/// mmap flags
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// getrandom flags, the pool is seeded at boot so none of them change anything
+const GRND_NONBLOCK: u64 = 0x1;
+const GRND_RANDOM: u64 = 0x2;
+const GRND_INSECURE: u64 = 0x4;
+
+/// Most bytes one getrandom returns, like Linux
+const GETRANDOM_MAX: usize = 0x01FF_FFFF;
+
+/// Error numbers handed back to EL0, negated in x0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// No such file or directory
+    ENOENT = 2,
+    /// I/O error
+    EIO = 5,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// Device or resource busy
+    EBUSY = 16,
+    /// File exists
+    EEXIST = 17,
+    /// Cross-device link
+    EXDEV = 18,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// No space left on device
+    ENOSPC = 28,
+    /// Illegal seek
+    ESPIPE = 29,
+    /// Read-only file system
+    EROFS = 30,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+    /// Directory not empty
+    ENOTEMPTY = 39,
+    /// Too many symbolic links
+    ELOOP = 40,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Errno::ENOENT => f.write_str("No such file or directory"),
+            Errno::EIO => f.write_str("I/O error"),
+            Errno::EBADF => f.write_str("Bad file descriptor"),
+            Errno::ENOMEM => f.write_str("Out of memory"),
+            Errno::EFAULT => f.write_str("Bad address"),
+            Errno::EBUSY => f.write_str("Device or resource busy"),
+            Errno::EEXIST => f.write_str("File exists"),
+            Errno::EXDEV => f.write_str("Cross-device link"),
+            Errno::ENOTDIR => f.write_str("Not a directory"),
+            Errno::EISDIR => f.write_str("Is a directory"),
+            Errno::EINVAL => f.write_str("Invalid argument"),
+            Errno::EMFILE => f.write_str("Too many open files"),
+            Errno::ENOSPC => f.write_str("No space left on device"),
+            Errno::ESPIPE => f.write_str("Illegal seek"),
+            Errno::EROFS => f.write_str("Read-only file system"),
+            Errno::ENAMETOOLONG => f.write_str("File name too long"),
+            Errno::ENOSYS => f.write_str("Function not implemented"),
+            Errno::ENOTEMPTY => f.write_str("Directory not empty"),
+            Errno::ELOOP => f.write_str("Too many symbolic links"),
+        }
+    }
+}
+
+impl From<VmError> for Errno {
+    fn from(err: VmError) -> Errno {
+        match err {
+            VmError::OutOfMemory | VmError::OutOfAsids | VmError::TooManyRegions => Errno::ENOMEM,
+            VmError::BadRange => Errno::EINVAL,
+        }
+    }
+}
+
+impl From<VfsError> for Errno {
+    fn from(err: VfsError) -> Errno {
+        match err {
+            VfsError::NotFound => Errno::ENOENT,
+            VfsError::NotDir => Errno::ENOTDIR,
+            VfsError::IsDir => Errno::EISDIR,
+            VfsError::Exists => Errno::EEXIST,
+            VfsError::NotEmpty => Errno::ENOTEMPTY,
+            VfsError::ReadOnly => Errno::EROFS,
+            VfsError::BadFd => Errno::EBADF,
+            VfsError::TooManyFiles => Errno::EMFILE,
+            VfsError::NoSpace => Errno::ENOSPC,
+            VfsError::NotSeekable => Errno::ESPIPE,
+            VfsError::Loop => Errno::ELOOP,
+            VfsError::NameTooLong => Errno::ENAMETOOLONG,
+            VfsError::Busy => Errno::EBUSY,
+            VfsError::CrossDevice => Errno::EXDEV,
+            VfsError::Invalid => Errno::EINVAL,
+            VfsError::Io => Errno::EIO,
+        }
+    }
+}
+
+/// Result of a system call, the value ends up in x0
+pub type SysResult = Result<u64, Errno>;
+
+/// struct timespec
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    sec: i64,
+    nsec: i64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// User memory
+//--------------------------------------------------------------------------------------------------
+
+/// Check that `len` bytes at `addr` are in the user half
+///
+/// The caller's address space is loaded in TTBR0, the copies below go through
+/// [uaccess](crate::cpu::uaccess) so a bad pointer fails the call with EFAULT. This only
+/// keeps pointers out of the kernel half.
+fn check_user(addr: u64, len: usize) -> Result<usize, Errno> {
+    let addr = addr as usize;
+    match addr.checked_add(len) {
+        _ if len == 0 => Ok(addr),
+        Some(end) if addr != 0 && end <= USER_END => Ok(addr),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Fill `buf` from user memory at `addr`
+fn read_user_bytes(addr: u64, buf: &mut [u8]) -> Result<(), Errno> {
+    let addr = check_user(addr, buf.len())?;
+    uaccess::copy_from_user(buf, addr).map_err(|_| Errno::EFAULT)
+}
+
+/// Copy `bytes` to user memory at `addr`
+fn write_user_bytes(addr: u64, bytes: &[u8]) -> Result<(), Errno> {
+    let addr = check_user(addr, bytes.len())?;
+    uaccess::copy_to_user(addr, bytes).map_err(|_| Errno::EFAULT)
+}
+
+/// Read a `T` from user memory, `T` has to be plain data that any bytes are valid for
+fn read_user<T: Copy>(addr: u64) -> Result<T, Errno> {
+    let mut value = core::mem::MaybeUninit::<T>::zeroed();
+    let bytes = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+
+    read_user_bytes(addr, bytes)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Write a `T` to user memory, `T` has to be plain data without padding
+fn write_user<T: Copy>(addr: u64, value: T) -> Result<(), Errno> {
+    let bytes = unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+    write_user_bytes(addr, bytes)
+}
+
+/// Copy the NUL terminated string at `addr`, at most `max` bytes without the NUL
+fn user_str(addr: u64, max: usize) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let at = addr.checked_add(bytes.len() as u64).ok_or(Errno::EFAULT)?;
+        match read_user::<u8>(at)? {
+            0 => break,
+            _ if bytes.len() == max => return Err(Errno::ENAMETOOLONG),
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Calls
+//--------------------------------------------------------------------------------------------------
+
+fn sys_nanosleep(req: u64, rem: u64) -> SysResult {
+    let req: Timespec = read_user(req)?;
+    if req.sec < 0 || !(0..1_000_000_000).contains(&req.nsec) {
+        return Err(Errno::EINVAL);
+    }
+
+    task::sleep(Duration::new(req.sec as u64, req.nsec as u32));
+
+    // Sleeps aren't interrupted, nothing remains
+    if rem != 0 {
+        write_user(rem, Timespec { sec: 0, nsec: 0 })?;
+    }
+
+    Ok(0)
+}
+
+fn sys_getrandom(buf: u64, len: u64, flags: u64) -> SysResult {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let len = (len as usize).min(GETRANDOM_MAX);
+    check_user(buf, len)?;
+
+    let mut chunk = [0; 256];
+    let mut done = 0;
+
+    while done < len {
+        let size = (len - done).min(chunk.len());
+        random::fill_bytes(&mut chunk[..size]);
+        write_user_bytes(buf + done as u64, &chunk[..size])?;
+        done += size;
+    }
+
+    Ok(len as u64)
+}
+
+fn sys_mmap(addr: u64, length: u64, prot: u64, flags: u64, _fd: u64, _offset: u64) -> SysResult {
+    // Only anonymous memory, files come later
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let prot = prot as u32 & (vm::PROT_READ | vm::PROT_WRITE | vm::PROT_EXEC);
+    let start = (flags & MAP_FIXED != 0).then_some(addr as usize);
+
+    let start = task::with_space(|space| space.map(start, length as usize, prot)).ok_or(Errno::EINVAL)??;
+    Ok(start as u64)
+}
+
+fn sys_munmap(addr: u64, length: u64) -> SysResult {
+    task::with_space(|space| space.unmap(addr as usize, length as usize)).ok_or(Errno::EINVAL)??;
+    Ok(0)
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Run the system call in `frame` and put the result in its x0
+///
+/// Called by the EL0 synchronous exception handler ([crate::cpu::exception]) with IRQs
+/// enabled, on the kernel stack of the calling task.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let [a0, a1, a2, a3, a4, a5] = [
+        frame.gpr[0],
+        frame.gpr[1],
+        frame.gpr[2],
+        frame.gpr[3],
+        frame.gpr[4],
+        frame.gpr[5],
+    ];
+
+    let result = match frame.gpr[8] {
+        SYS_MKDIRAT => fs::sys_mkdirat(a0, a1, a2),
+        SYS_UNLINKAT => fs::sys_unlinkat(a0, a1, a2),
+        SYS_SYMLINKAT => fs::sys_symlinkat(a0, a1, a2),
+        SYS_RENAMEAT => fs::sys_renameat2(a0, a1, a2, a3, 0),
+        SYS_TRUNCATE => fs::sys_truncate(a0, a1),
+        SYS_FTRUNCATE => fs::sys_ftruncate(a0, a1),
+        SYS_OPENAT => fs::sys_openat(a0, a1, a2, a3),
+        SYS_CLOSE => fs::sys_close(a0),
+        SYS_GETDENTS64 => fs::sys_getdents64(a0, a1, a2),
+        SYS_LSEEK => fs::sys_lseek(a0, a1, a2),
+        SYS_READ => fs::sys_read(a0, a1, a2),
+        SYS_WRITE => fs::sys_write(a0, a1, a2),
+        SYS_NEWFSTATAT => fs::sys_newfstatat(a0, a1, a2, a3),
+        SYS_FSTAT => fs::sys_fstat(a0, a1),
+        SYS_EXIT => task::exit(a0 as i32),
+        SYS_NANOSLEEP => sys_nanosleep(a0, a1),
+        SYS_SCHED_YIELD => {
+            task::yield_now();
+            Ok(0)
+        }
+        SYS_GETPID => Ok(task::current().0 as u64),
+        SYS_MUNMAP => sys_munmap(a0, a1),
+        SYS_MMAP => sys_mmap(a0, a1, a2, a3, a4, a5),
+        SYS_RENAMEAT2 => fs::sys_renameat2(a0, a1, a2, a3, a4),
+        SYS_GETRANDOM => sys_getrandom(a0, a1, a2),
+        _ => Err(Errno::ENOSYS),
+    };
+
+    frame.gpr[0] = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+}
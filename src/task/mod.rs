@@ -0,0 +1,529 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Tasks
+//!
+//! Preemptive kernel threads. Each task gets its own stack from [stack] and runs until
+//! it calls [yield_now()], [join()] or [exit()], or until the timer tick takes the core
+//! away (see [preempt()]). Runnable tasks are scheduled round-robin within a
+//! [Priority], higher priorities always go first.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://doc.rust-lang.org/std/thread/index.html>
+//!   - <https://wiki.osdev.org/Cooperative_Multitasking>
+//!   - <https://wiki.osdev.org/Scheduling_Algorithms>
+//!
+
+pub mod scheduler;
+pub mod stack;
+
+use crate::cpu::{
+    exception::{in_irq, IrqGuard},
+    mmu,
+    smp::core_id,
+    timer, Context,
+};
+use crate::drivers::interrupt::{self, Irq};
+use crate::memory::vm::{self, AddressSpace};
+use crate::vfs::fd::FdTable;
+use alloc::sync::Arc;
+use crate::sync::mutex::{Mutex, MutexGuard};
+use core::sync::atomic::{AtomicU32, Ordering};
+use scheduler::Scheduler;
+
+/// Maximum number of tasks alive at once, including the boot and idle tasks
+pub const MAX_TASKS: usize = 32;
+
+/// Scheduler tick frequency in Hz
+pub const TICK_HZ: u64 = 100;
+
+/// Number of [Priority] levels
+pub const NUM_PRIORITIES: usize = 4;
+
+/// Where [spawn_user()] loads the program image
+pub const USER_TEXT: usize = 0x40_0000;
+
+/// Why [block_on()] returned without being woken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    /// The condition said not to wait
+    Condition,
+    /// Nobody woke the task before the timeout
+    TimedOut,
+    /// The caller can't block, it's an interrupt handler, an idle task or the scheduler
+    /// isn't running on this core yet
+    NotAllowed,
+}
+
+#[derive(Debug)]
+/// An error type for spawning tasks
+///
+/// Returned when the task table or the stack pool is full.
+pub struct SpawnError;
+
+/// Allows printing the error
+impl core::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("Failed to spawn task")
+    }
+}
+
+/// Task identifier
+///
+/// Ids are never reused, the boot task is always 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tid(pub u32);
+
+/// Allows printing the id
+impl core::fmt::Display for Tid {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Scheduling priority
+///
+/// A ready task always runs before any task with a lower priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Only runs when the core has nothing else to do
+    Idle = 0,
+    /// Background work
+    Low = 1,
+    /// Default for [spawn()]
+    Normal = 2,
+    /// Latency sensitive work, like the console shell
+    High = 3,
+}
+
+/// Owned permission to join a task
+///
+/// Dropping the handle detaches the task, its slot gets released when it exits.
+pub struct JoinHandle {
+    slot: usize,
+    tid: Tid,
+}
+
+impl JoinHandle {
+    /// Id of the task this handle joins
+    pub fn tid(&self) -> Tid {
+        self.tid
+    }
+
+    /// Wait for the task to exit and return its exit code
+    ///
+    /// Blocks the caller until the task exits. From a context that can't block (an interrupt
+    /// handler, or before [init()]) this falls back to polling with [yield_now()].
+    pub fn join(self) -> i32 {
+        let handle = core::mem::ManuallyDrop::new(self);
+        let _irq = IrqGuard::new();
+        let core = core_id();
+
+        loop {
+            let switch = {
+                let mut sched = scheduler();
+                if let Some(code) = sched.reap(handle.slot, handle.tid) {
+                    return code;
+                }
+
+                if sched.can_block(core) && !in_irq() {
+                    let key = sched.join_key(handle.slot);
+                    sched.block_current(core, key, None);
+                }
+
+                sched.switch_next(core)
+            };
+
+            schedule(switch);
+        }
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        scheduler().detach(self.slot, self.tid);
+    }
+}
+
+/// The global task table
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+crate::percpu! {
+    /// Id of the task running on the core, so [current()] doesn't need the scheduler lock
+    static CURRENT: AtomicU32 = AtomicU32::new(0);
+}
+
+/// Locked scheduler with IRQs masked on this core
+///
+/// The tick handler takes the lock too, so it can't be held with IRQs enabled. The guard is
+/// released before the IRQ mask is restored.
+struct SchedulerGuard<'a> {
+    guard: MutexGuard<'a, Scheduler>,
+    _irq: IrqGuard,
+}
+
+impl core::ops::Deref for SchedulerGuard<'_> {
+    type Target = Scheduler;
+
+    fn deref(&self) -> &Scheduler {
+        &self.guard
+    }
+}
+
+impl core::ops::DerefMut for SchedulerGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Scheduler {
+        &mut self.guard
+    }
+}
+
+/// Return a reference to the scheduler.
+///
+/// Spins on [Mutex::try_lock()], the scheduler is only ever held for a few instructions and
+/// can't block (it's what blocking would be built on).
+fn scheduler<'a>() -> SchedulerGuard<'a> {
+    let irq = IrqGuard::new();
+
+    loop {
+        if let Some(guard) = SCHEDULER.try_lock() {
+            return SchedulerGuard { guard, _irq: irq };
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// Complete a switch and publish the new task as [CURRENT]
+///
+/// Runs on the stack of the task that was switched to, with IRQs masked. The new task's
+/// lower half is loaded before the old task is released, so a freed table is never live.
+fn finish_switch() {
+    let core = core_id();
+    let mut sched = scheduler();
+
+    mmu::set_ttbr0(sched.ttbr0(core));
+    sched.finish_switch(core);
+    CURRENT.local().store(sched.current(core).0, Ordering::Relaxed);
+}
+
+/// First code a new task runs
+///
+/// `_task_trampoline` jumps here with the task's entry function. The switch that got here
+/// happened with IRQs masked, new tasks start with them enabled.
+extern "C" fn _task_entry(entry: usize) -> ! {
+    finish_switch();
+    crate::cpu::exception::local_irq_enable();
+
+    let entry: fn() -> i32 = unsafe { core::mem::transmute(entry) };
+    exit(entry());
+}
+
+/// First code a new user task runs
+///
+/// Its address space is already loaded, drops to EL0 where the space says the program
+/// starts.
+extern "C" fn _user_entry(_: usize) -> ! {
+    finish_switch();
+
+    let Some((pc, sp)) = with_space(|space| space.start()) else {
+        unreachable!("User task without an address space");
+    };
+
+    unsafe { crate::cpu::exception::enter_el0(pc, sp, 0) }
+}
+
+/// Idle task body
+extern "C" fn _idle_entry(_: usize) -> ! {
+    finish_switch();
+    idle();
+}
+
+/// Wait for interrupts forever, the tick switches away when something is ready
+fn idle() -> ! {
+    crate::cpu::exception::local_irq_enable();
+
+    loop {
+        aarch64_cpu::asm::wfi();
+    }
+}
+
+/// Switch to whatever the scheduler picked, if anything
+///
+/// IRQs must be masked from picking until the switch is done, the tick could switch in
+/// between otherwise.
+fn schedule(switch: Option<(*mut Context, *const Context)>) {
+    if let Some((prev, next)) = switch {
+        unsafe { crate::cpu::_switch_context(prev, next) };
+        finish_switch();
+    }
+}
+
+/// Timer interrupt handler
+fn tick() {
+    timer::rearm();
+    scheduler().tick(core_id(), timer::counter());
+}
+
+/// Start the tick on the calling core
+fn start_tick() {
+    interrupt::enable(Irq::CNTPNS);
+    timer::start_tick(TICK_HZ);
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Turn the caller into task 0 and start preempting
+///
+/// Must be called once, from the boot core, after [crate::cpu::exception::init()] and before
+/// any other function in this module. Enables IRQs on the calling core.
+pub fn init() {
+    #[cfg(feature = "fpsimd")]
+    crate::cpu::enable_fpsimd();
+
+    let core = core_id();
+    {
+        let mut sched = scheduler();
+        sched.init(core);
+        if sched.spawn_idle(core, _idle_entry, 0).is_err() {
+            crate::cpu::_park();
+        }
+    }
+
+    if interrupt::register(Irq::CNTPNS, tick).is_err() {
+        crate::cpu::_park();
+    }
+
+    start_tick();
+    crate::cpu::exception::local_irq_enable();
+}
+
+/// Make a secondary core available to the scheduler
+///
+/// The caller becomes the idle task of its core. Never returns.
+pub fn init_secondary() -> ! {
+    #[cfg(feature = "fpsimd")]
+    crate::cpu::enable_fpsimd();
+
+    {
+        let core = core_id();
+        let mut sched = scheduler();
+        if sched.adopt_idle(core).is_err() {
+            crate::cpu::_park();
+        }
+        CURRENT.local().store(sched.current(core).0, Ordering::Relaxed);
+    }
+
+    start_tick();
+    idle();
+}
+
+/// Start a new kernel thread with [Priority::Normal]
+///
+/// The task is queued on the least busy core behind everything that is already runnable.
+/// Its return value becomes the exit code handed to [JoinHandle::join()].
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::task;
+///
+/// fn worker() -> i32 {
+///     task::yield_now();
+///     7
+/// }
+///
+/// let handle = task::spawn(worker).unwrap();
+/// assert_eq!(handle.join(), 7);
+/// ```
+pub fn spawn(entry: fn() -> i32) -> Result<JoinHandle, SpawnError> {
+    spawn_with_priority(entry, Priority::Normal)
+}
+
+/// Start a new kernel thread, see [spawn()]
+pub fn spawn_with_priority(entry: fn() -> i32, priority: Priority) -> Result<JoinHandle, SpawnError> {
+    let (slot, tid) = scheduler().spawn(priority, _task_entry, entry as usize, None, None)?;
+    Ok(JoinHandle { slot, tid })
+}
+
+/// Start a flat user program in an address space of its own
+///
+/// `image` is copied to [USER_TEXT] and entered there in EL0 with all registers zeroed,
+/// below [vm::USER_STACK_TOP] is a demand-zero stack of [vm::USER_STACK_SIZE]. The program
+/// leaves EL0 again through system calls ([crate::syscall]), exits with the exit syscall
+/// and is killed if it faults outside its regions.
+pub fn spawn_user(image: &[u8]) -> Result<JoinHandle, SpawnError> {
+    let mut space = AddressSpace::new().map_err(|_| SpawnError)?;
+
+    space
+        .map(Some(USER_TEXT), image.len().max(1), vm::PROT_READ | vm::PROT_EXEC)
+        .and_then(|text| space.write(text, image))
+        .and_then(|_| {
+            let stack = vm::USER_STACK_TOP - vm::USER_STACK_SIZE;
+            space.map(Some(stack), vm::USER_STACK_SIZE, vm::PROT_READ | vm::PROT_WRITE)
+        })
+        .map_err(|_| SpawnError)?;
+
+    space.set_start(USER_TEXT, vm::USER_STACK_TOP);
+    spawn_in(space)
+}
+
+/// Start a user task in `space`, for loaders that filled the space themselves
+///
+/// The task enters EL0 at [AddressSpace::start()] with stdin, stdout and stderr open on
+/// `/dev/console` (nothing open if there is no console).
+pub fn spawn_in(space: AddressSpace) -> Result<JoinHandle, SpawnError> {
+    let files = Arc::new(FdTable::with_console().unwrap_or_default());
+    let ttbr0 = space.ttbr0();
+    let space = Arc::new(Mutex::new(space));
+
+    let (slot, tid) = scheduler().spawn(Priority::Normal, _user_entry, 0, Some((space, ttbr0)), Some(files))?;
+    Ok(JoinHandle { slot, tid })
+}
+
+/// Let the next runnable task have the core
+///
+/// Returns immediately if nothing else with the same or higher priority is runnable.
+pub fn yield_now() {
+    let _irq = IrqGuard::new();
+    let switch = scheduler().switch_next(core_id());
+    schedule(switch);
+}
+
+/// Switch tasks if the tick (or a wake up) asked for it
+///
+/// Called on the way out of every IRQ, with IRQs still masked. The interrupted task resumes
+/// here when it gets the core back.
+pub fn preempt() {
+    let switch = scheduler().preempt(core_id());
+    schedule(switch);
+}
+
+/// Block the running task until [wake_on()] is called with the same `key`
+///
+/// `condition` runs under the scheduler lock right before blocking, the task only blocks if
+/// it returns true. Wakers take the same lock, so a wake can't slip in between checking and
+/// blocking. Keep it short, IRQs are masked.
+///
+/// Keys are addresses, like the futex word of [crate::sync::futex].
+pub fn block_on(
+    key: usize,
+    timeout: Option<core::time::Duration>,
+    condition: impl FnOnce() -> bool,
+) -> Result<(), BlockError> {
+    let _irq = IrqGuard::new();
+    let core = core_id();
+
+    if in_irq() {
+        return Err(BlockError::NotAllowed);
+    }
+
+    let switch = {
+        let mut sched = scheduler();
+        if !sched.can_block(core) {
+            return Err(BlockError::NotAllowed);
+        }
+
+        if !condition() {
+            return Err(BlockError::Condition);
+        }
+
+        let deadline = timeout.map(|t| timer::counter().saturating_add(timer::duration_to_ticks(t)));
+        sched.block_current(core, key, deadline);
+        sched.switch_next(core)
+    };
+    schedule(switch);
+
+    match scheduler().timed_out(core) {
+        true => Err(BlockError::TimedOut),
+        false => Ok(()),
+    }
+}
+
+/// Can the caller sleep in [block_on()]
+///
+/// False in interrupt handlers, idle tasks and before [init()] ran on this core.
+pub fn can_block() -> bool {
+    !in_irq() && scheduler().can_block(core_id())
+}
+
+/// Put the running task to sleep for at least `duration`
+///
+/// Spins instead when the caller can't block.
+pub fn sleep(duration: core::time::Duration) {
+    // Nobody knows this address, only the timeout wakes us
+    let key = 0u8;
+
+    if block_on(&key as *const u8 as usize, Some(duration), || true) == Err(BlockError::NotAllowed) {
+        timer::spin_for(duration);
+    }
+}
+
+/// Wake up to `n` tasks blocked on `key`, returns how many were woken
+///
+/// Safe to call from interrupt handlers.
+pub fn wake_on(key: usize, n: usize) -> usize {
+    scheduler().wake(key, n)
+}
+
+/// Wait for a task to exit, see [JoinHandle::join()]
+pub fn join(handle: JoinHandle) -> i32 {
+    handle.join()
+}
+
+/// Finish the running task
+///
+/// Parks the core if there is nothing left to run.
+pub fn exit(code: i32) -> ! {
+    // Closed here, outside the scheduler lock, files may block or take locks when dropped
+    let files = scheduler().take_files(core_id());
+    drop(files);
+
+    let _irq = IrqGuard::new();
+    let core = core_id();
+    let switch = {
+        let mut sched = scheduler();
+        sched.exit_current(core, code);
+        sched.switch_next(core)
+    };
+    schedule(switch);
+
+    crate::cpu::_park();
+}
+
+/// Run `f` on the address space of the running task
+///
+/// Returns None for kernel threads, they don't have one. The space has a lock of its own,
+/// taken after the scheduler's is released, so `f` can sleep and allocate. It must not touch
+/// user memory though, the fault would need the lock again.
+pub fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let space = scheduler().space(core_id())?;
+    let mut space = space.lock().ok()?;
+
+    Some(f(&mut space))
+}
+
+/// Open files of the running task
+///
+/// Returns None for kernel threads, they don't have any.
+pub fn files() -> Option<Arc<FdTable>> {
+    scheduler().files(core_id())
+}
+
+/// Id of the running task
+pub fn current() -> Tid {
+    // Masked so the read can't be split by a switch to another task
+    let _irq = IrqGuard::new();
+    Tid(CURRENT.local().load(Ordering::Relaxed))
+}
@@ -0,0 +1,593 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Scheduler
+//!
+//! Task table and per-core priority run queues. Nothing in here switches stacks, the
+//! scheduler only decides who runs next and hands back the contexts to switch
+//! between (see [crate::task::yield_now()]).
+//!
+//! Tasks are pinned to the core they were spawned on. Each core round-robins the
+//! highest non-empty priority and falls back to its idle task when nothing is ready.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use super::{stack, stack::TaskStack, Priority, SpawnError, Tid, MAX_TASKS, NUM_PRIORITIES};
+use crate::cpu::{smp::NUM_CORES, Context};
+use crate::cpu::mmu;
+use crate::memory::vm::AddressSpace;
+use crate::sync::mutex::Mutex;
+use crate::vfs::fd::FdTable;
+use alloc::sync::Arc;
+
+/// Timer ticks a task runs before it gets preempted
+pub const TIME_SLICE: u32 = 5;
+
+/// Lifecycle of a task slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Slot is unused
+    Free,
+    /// Waiting in a run queue
+    Ready,
+    /// Currently on a core
+    Running,
+    /// Waiting on a key, see [Scheduler::block_current()]
+    Blocked,
+    /// Called exit but is still on its stack, becomes [State::Exited] after the switch
+    Exiting,
+    /// Finished, waiting for a join to collect the exit code
+    Exited,
+}
+
+/// A kernel thread
+pub struct Task {
+    tid: Tid,
+    state: State,
+    priority: Priority,
+    core: usize,
+    slice: u32,
+    context: Context,
+    stack: Option<TaskStack>,
+    exit_code: i32,
+    detached: bool,
+    wait: Wait,
+    /// User half of a task running a program in EL0, dropped with the task
+    space: Option<Arc<Mutex<AddressSpace>>>,
+    /// TTBR0 value of `space`, kept here so a switch doesn't have to lock the space
+    ttbr0: Option<u64>,
+    /// Open files of a user task, closed by the task itself when it exits
+    files: Option<Arc<FdTable>>,
+}
+
+/// What a blocked task is waiting for
+#[derive(Clone, Copy)]
+struct Wait {
+    key: usize,
+    /// Counter value to wake up at, if any
+    deadline: Option<u64>,
+    /// Order the task blocked in, wakes are FIFO
+    seq: u64,
+    timed_out: bool,
+}
+
+impl Wait {
+    const NONE: Wait = Wait {
+        key: 0,
+        deadline: None,
+        seq: 0,
+        timed_out: false,
+    };
+}
+
+impl Task {
+    const EMPTY: Task = Task {
+        tid: Tid(0),
+        state: State::Free,
+        priority: Priority::Normal,
+        core: 0,
+        slice: TIME_SLICE,
+        context: Context::new(),
+        stack: None,
+        exit_code: 0,
+        detached: false,
+        wait: Wait::NONE,
+        space: None,
+        ttbr0: None,
+        files: None,
+    };
+}
+
+/// Fixed size FIFO of task slots
+pub struct RunQueue<const N: usize> {
+    slots: [usize; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RunQueue<N> {
+    /// An empty queue
+    pub const fn new() -> RunQueue<N> {
+        RunQueue {
+            slots: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a slot to the back of the queue, returns false when full
+    pub fn push(&mut self, slot: usize) -> bool {
+        if self.len == N {
+            return false;
+        }
+
+        self.slots[(self.head + self.len) % N] = slot;
+        self.len += 1;
+        true
+    }
+
+    /// Take the slot at the front of the queue
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let slot = self.slots[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(slot)
+    }
+
+    /// Number of queued slots
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// True when nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const N: usize> Default for RunQueue<N> {
+    fn default() -> RunQueue<N> {
+        RunQueue::new()
+    }
+}
+
+/// Scheduling state of a single core
+struct CoreQueue {
+    queues: [RunQueue<MAX_TASKS>; NUM_PRIORITIES],
+    current: usize,
+    idle: Option<usize>,
+    /// Task that was switched away from, cleaned up by [Scheduler::finish_switch()]
+    prev: Option<usize>,
+    need_resched: bool,
+    online: bool,
+}
+
+impl CoreQueue {
+    const EMPTY: CoreQueue = CoreQueue {
+        queues: [const { RunQueue::new() }; NUM_PRIORITIES],
+        current: 0,
+        idle: None,
+        prev: None,
+        need_resched: false,
+        online: false,
+    };
+
+    /// Highest priority with something queued
+    fn top_priority(&self) -> Option<usize> {
+        (0..NUM_PRIORITIES).rev().find(|&p| !self.queues[p].is_empty())
+    }
+
+    /// Queued tasks plus the running one, idle doesn't count
+    fn load(&self) -> usize {
+        let queued: usize = self.queues.iter().map(|q| q.len()).sum();
+        queued + (Some(self.current) != self.idle) as usize
+    }
+}
+
+/// The task table and run queues
+pub struct Scheduler {
+    tasks: [Task; MAX_TASKS],
+    cores: [CoreQueue; NUM_CORES],
+    next_tid: u32,
+    next_wait: u64,
+}
+
+impl Scheduler {
+    /// An empty scheduler, [Scheduler::init()] has to run before anything else
+    pub const fn new() -> Scheduler {
+        Scheduler {
+            tasks: [Task::EMPTY; MAX_TASKS],
+            cores: [CoreQueue::EMPTY; NUM_CORES],
+            next_tid: 1,
+            next_wait: 0,
+        }
+    }
+
+    /// Adopt the caller as task 0 on `core`
+    ///
+    /// The boot task runs on the boot core stack so it has no [TaskStack].
+    pub fn init(&mut self, core: usize) {
+        self.tasks[0] = Task {
+            state: State::Running,
+            core,
+            ..Task::EMPTY
+        };
+        self.cores[core].current = 0;
+        self.cores[core].online = true;
+    }
+
+    /// Adopt the caller as the idle task of `core`
+    ///
+    /// Used by secondary cores, their boot stack becomes the idle stack.
+    pub fn adopt_idle(&mut self, core: usize) -> Result<(), SpawnError> {
+        let slot = self.free_slot()?;
+        self.tasks[slot] = Task {
+            tid: self.alloc_tid(),
+            state: State::Running,
+            priority: Priority::Idle,
+            core,
+            ..Task::EMPTY
+        };
+
+        let cq = &mut self.cores[core];
+        cq.current = slot;
+        cq.idle = Some(slot);
+        cq.online = true;
+        Ok(())
+    }
+
+    /// Create the idle task of `core`, it only runs when nothing else can
+    pub fn spawn_idle(
+        &mut self,
+        core: usize,
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+    ) -> Result<(), SpawnError> {
+        let slot = self.new_task(core, Priority::Idle, entry, arg)?;
+        self.cores[core].idle = Some(slot);
+        Ok(())
+    }
+
+    /// Id of the task running on `core`
+    pub fn current(&self, core: usize) -> Tid {
+        self.tasks[self.cores[core].current].tid
+    }
+
+    /// Create a new task in the run queue of the least busy core
+    ///
+    /// Tasks that run a user program bring their `space`, with its TTBR0 value, and `files`.
+    /// Returns the slot and id of the task.
+    pub fn spawn(
+        &mut self,
+        priority: Priority,
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+        space: Option<(Arc<Mutex<AddressSpace>>, u64)>,
+        files: Option<Arc<FdTable>>,
+    ) -> Result<(usize, Tid), SpawnError> {
+        let core = (0..NUM_CORES)
+            .filter(|&core| self.cores[core].online)
+            .min_by_key(|&core| self.cores[core].load())
+            .ok_or(SpawnError)?;
+
+        let slot = self.new_task(core, priority, entry, arg)?;
+        (self.tasks[slot].space, self.tasks[slot].ttbr0) = match space {
+            Some((space, ttbr0)) => (Some(space), Some(ttbr0)),
+            None => (None, None),
+        };
+        self.tasks[slot].files = files;
+        self.enqueue(slot);
+
+        Ok((slot, self.tasks[slot].tid))
+    }
+
+    /// Pick the next task to run on `core`
+    ///
+    /// The current task goes to the back of its queue if it is still running. Returns the
+    /// contexts to pass to [crate::cpu::_switch_context()], or None when the current task should
+    /// keep running (or nothing at all can run).
+    pub fn switch_next(&mut self, core: usize) -> Option<(*mut Context, *const Context)> {
+        let prev = self.cores[core].current;
+
+        if self.tasks[prev].state == State::Running {
+            self.tasks[prev].state = State::Ready;
+
+            // idle is never queued, it's the fallback
+            if Some(prev) != self.cores[core].idle {
+                self.enqueue(prev);
+            }
+        }
+
+        let cq = &mut self.cores[core];
+        cq.need_resched = false;
+
+        let next = match cq.top_priority() {
+            Some(priority) => cq.queues[priority].pop()?,
+            None => cq.idle?,
+        };
+
+        cq.current = next;
+        self.tasks[next].state = State::Running;
+        self.tasks[next].slice = TIME_SLICE;
+
+        match next == prev {
+            true => None,
+            false => {
+                self.cores[core].prev = Some(prev);
+                Some((
+                    &mut self.tasks[prev].context as *mut Context,
+                    &self.tasks[next].context as *const Context,
+                ))
+            }
+        }
+    }
+
+    /// Clean up after a switch on `core`, called by the task that was switched to
+    ///
+    /// An exiting task is only off its stack once the switch is done, so this is the first
+    /// point it is safe to release.
+    pub fn finish_switch(&mut self, core: usize) {
+        let Some(prev) = self.cores[core].prev.take() else {
+            return;
+        };
+
+        if self.tasks[prev].state == State::Exiting {
+            self.tasks[prev].state = State::Exited;
+
+            match self.tasks[prev].detached {
+                true => self.release(prev),
+                false => {
+                    self.wake(self.join_key(prev), usize::MAX);
+                }
+            }
+        }
+    }
+
+    /// Account a timer tick on `core`
+    ///
+    /// Wakes tasks on `core` whose wait deadline passed `now` (counter ticks). Flags the core
+    /// for rescheduling when the running task used up its slice or something with a higher
+    /// priority is waiting.
+    pub fn tick(&mut self, core: usize, now: u64) {
+        for slot in 0..MAX_TASKS {
+            let task = &mut self.tasks[slot];
+            let expired = task.wait.deadline.is_some_and(|deadline| deadline <= now);
+
+            if task.state == State::Blocked && task.core == core && expired {
+                task.wait.timed_out = true;
+                self.unblock(slot);
+            }
+        }
+
+        let current = self.cores[core].current;
+        let task = &mut self.tasks[current];
+        task.slice = task.slice.saturating_sub(1);
+
+        let cq = &mut self.cores[core];
+        let preempted = cq
+            .top_priority()
+            .is_some_and(|p| p > task.priority as usize || (p == task.priority as usize && task.slice == 0));
+
+        cq.need_resched |= preempted;
+    }
+
+    /// Same as [Scheduler::switch_next()], but only when `core` was flagged for rescheduling
+    pub fn preempt(&mut self, core: usize) -> Option<(*mut Context, *const Context)> {
+        match self.cores[core].need_resched {
+            true => self.switch_next(core),
+            false => None,
+        }
+    }
+
+    /// True when the task running on `core` is allowed to block
+    ///
+    /// The idle task has to stay runnable and nothing can block before the core is online.
+    pub fn can_block(&self, core: usize) -> bool {
+        let cq = &self.cores[core];
+        cq.online && Some(cq.current) != cq.idle
+    }
+
+    /// Take the task running on `core` off the run queues until `key` is woken
+    ///
+    /// `deadline` is a counter value (see [crate::cpu::timer::counter()]) after which the tick
+    /// wakes the task anyway. Call [Scheduler::switch_next()] under the same lock, otherwise a
+    /// wake could be missed.
+    pub fn block_current(&mut self, core: usize, key: usize, deadline: Option<u64>) {
+        let seq = self.next_wait;
+        self.next_wait += 1;
+
+        let task = &mut self.tasks[self.cores[core].current];
+        task.state = State::Blocked;
+        task.wait = Wait {
+            key,
+            deadline,
+            seq,
+            timed_out: false,
+        };
+    }
+
+    /// True when the last wait of the task running on `core` ended in a timeout
+    pub fn timed_out(&self, core: usize) -> bool {
+        self.tasks[self.cores[core].current].wait.timed_out
+    }
+
+    /// Wake up to `n` tasks blocked on `key`, longest waiting first
+    ///
+    /// Returns the number of tasks woken.
+    pub fn wake(&mut self, key: usize, n: usize) -> usize {
+        let mut woken = 0;
+
+        while woken < n {
+            let oldest = (0..MAX_TASKS)
+                .filter(|&slot| self.tasks[slot].state == State::Blocked)
+                .filter(|&slot| self.tasks[slot].wait.key == key)
+                .min_by_key(|&slot| self.tasks[slot].wait.seq);
+
+            match oldest {
+                Some(slot) => self.unblock(slot),
+                None => break,
+            }
+
+            woken += 1;
+        }
+
+        woken
+    }
+
+    /// Key that joiners of `slot` block on, woken when the task exits
+    ///
+    /// The address of the slot, nothing else can use it as a key.
+    pub fn join_key(&self, slot: usize) -> usize {
+        &self.tasks[slot] as *const Task as usize
+    }
+
+    /// Address space of the task running on `core`, if it has one
+    pub fn space(&self, core: usize) -> Option<Arc<Mutex<AddressSpace>>> {
+        self.tasks[self.cores[core].current].space.clone()
+    }
+
+    /// Open files of the task running on `core`, if it has any
+    pub fn files(&self, core: usize) -> Option<Arc<FdTable>> {
+        self.tasks[self.cores[core].current].files.clone()
+    }
+
+    /// Take the open files away from the task running on `core`
+    pub fn take_files(&mut self, core: usize) -> Option<Arc<FdTable>> {
+        self.tasks[self.cores[core].current].files.take()
+    }
+
+    /// TTBR0 value of the task running on `core`
+    pub fn ttbr0(&self, core: usize) -> u64 {
+        match self.tasks[self.cores[core].current].ttbr0 {
+            Some(ttbr0) => ttbr0,
+            None => mmu::empty_ttbr0(),
+        }
+    }
+
+    /// Mark the task running on `core` as finished
+    pub fn exit_current(&mut self, core: usize, code: i32) {
+        let task = &mut self.tasks[self.cores[core].current];
+        task.state = State::Exiting;
+        task.exit_code = code;
+    }
+
+    /// Collect the exit code of a finished task and free its slot
+    ///
+    /// Returns None while the task is still alive.
+    pub fn reap(&mut self, slot: usize, tid: Tid) -> Option<i32> {
+        let task = &self.tasks[slot];
+        if task.tid != tid || task.state != State::Exited {
+            return None;
+        }
+
+        let code = task.exit_code;
+        self.release(slot);
+        Some(code)
+    }
+
+    /// Nobody will join this task, release it as soon as it exits
+    pub fn detach(&mut self, slot: usize, tid: Tid) {
+        if self.tasks[slot].tid != tid {
+            return;
+        }
+
+        match self.tasks[slot].state {
+            State::Exited => self.release(slot),
+            State::Free => {}
+            _ => self.tasks[slot].detached = true,
+        }
+    }
+
+    /// Put a blocked task back in its run queue
+    fn unblock(&mut self, slot: usize) {
+        self.tasks[slot].state = State::Ready;
+        self.tasks[slot].wait.deadline = None;
+        self.enqueue(slot);
+    }
+
+    /// Queue a ready task on its core, flagging the core if it should preempt
+    fn enqueue(&mut self, slot: usize) {
+        let task = &self.tasks[slot];
+        let cq = &mut self.cores[task.core];
+
+        cq.queues[task.priority as usize].push(slot);
+
+        if task.priority > self.tasks[cq.current].priority {
+            cq.need_resched = true;
+        }
+    }
+
+    fn new_task(
+        &mut self,
+        core: usize,
+        priority: Priority,
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+    ) -> Result<usize, SpawnError> {
+        let slot = self.free_slot()?;
+        let stack = stack::alloc().ok_or(SpawnError)?;
+
+        self.tasks[slot] = Task {
+            tid: self.alloc_tid(),
+            state: State::Ready,
+            priority,
+            core,
+            slice: TIME_SLICE,
+            context: Context::new_task(entry, arg, stack.top()),
+            stack: Some(stack),
+            exit_code: 0,
+            detached: false,
+            wait: Wait::NONE,
+            space: None,
+            ttbr0: None,
+            files: None,
+        };
+
+        Ok(slot)
+    }
+
+    fn free_slot(&self) -> Result<usize, SpawnError> {
+        self.tasks
+            .iter()
+            .position(|task| task.state == State::Free)
+            .ok_or(SpawnError)
+    }
+
+    fn alloc_tid(&mut self) -> Tid {
+        let tid = Tid(self.next_tid);
+        self.next_tid += 1;
+        tid
+    }
+
+    fn release(&mut self, slot: usize) {
+        if let Some(stack) = self.tasks[slot].stack.take() {
+            stack::free(stack);
+        }
+
+        // Not loaded on any core, the task switched away for the last time
+        self.tasks[slot].space = None;
+        self.tasks[slot].ttbr0 = None;
+
+        self.tasks[slot].state = State::Free;
+        self.tasks[slot].detached = false;
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
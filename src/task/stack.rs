@@ -0,0 +1,94 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Task stacks
+//!
+//! Fixed pool of kernel stacks handed out to tasks. There is no heap yet, so the pool
+//! is a static array in .bss and a bitmap of which stacks are in use.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Size of a single kernel stack in bytes
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// Number of stacks in the pool, the boot task uses the boot core stack so it doesn't need one
+pub const NUM_STACKS: usize = super::MAX_TASKS - 1;
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+/// The stack memory and a bitmap of stacks that are in use
+struct StackPool {
+    used: AtomicU32,
+    stacks: core::cell::UnsafeCell<[Stack; NUM_STACKS]>,
+}
+
+// The memory is never accessed through the pool, only handed out as addresses
+unsafe impl Sync for StackPool {}
+
+static STACK_POOL: StackPool = StackPool {
+    used: AtomicU32::new(0),
+    stacks: core::cell::UnsafeCell::new([const { Stack([0; STACK_SIZE]) }; NUM_STACKS]),
+};
+
+/// Handle to a stack from the pool
+///
+/// Not [Clone] or [Copy], there is only ever one owner of a stack. Give it back with [free()].
+#[derive(Debug)]
+pub struct TaskStack {
+    index: usize,
+}
+
+impl TaskStack {
+    /// Lowest address of the stack
+    pub fn bottom(&self) -> usize {
+        STACK_POOL.stacks.get() as usize + self.index * STACK_SIZE
+    }
+
+    /// One past the highest address of the stack, the initial sp
+    pub fn top(&self) -> usize {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+/// Take a free stack from the pool
+///
+/// Returns None when all stacks are in use.
+pub fn alloc() -> Option<TaskStack> {
+    let mut used = STACK_POOL.used.load(Ordering::Relaxed);
+
+    loop {
+        let index = (!used).trailing_zeros() as usize;
+        if index >= NUM_STACKS {
+            return None;
+        }
+
+        match STACK_POOL.used.compare_exchange_weak(
+            used,
+            used | (1 << index),
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return Some(TaskStack { index }),
+            Err(current) => used = current,
+        }
+    }
+}
+
+/// Return a stack to the pool
+///
+/// The stack must not be in use by anything, including the core calling this.
+pub fn free(stack: TaskStack) {
+    STACK_POOL.used.fetch_and(!(1 << stack.index), Ordering::Release);
+}
@@ -0,0 +1,275 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Device filesystem
+//!
+//! Flat directory of device inodes, mounted at `/dev`. Drivers [register()] their devices
+//! by name, [init()] adds the ones the kernel always has:
+//!
+//!   - `console` the system console, through [crate::drivers::console]
+//!   - `random` and `urandom` the kernel's [random numbers](crate::random)
+//!
+//! Storage drivers add their disks with [register_block()], as block device files that can
+//! be read and written at any offset.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat, VfsError, VfsResult};
+use crate::drivers::block::BlockDevice;
+use crate::drivers::console;
+use crate::sync::ticket::TicketLock;
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::time::Duration;
+
+/// Most bytes one read or write of a block device moves, larger ones come back short
+const BLOCK_IO_MAX: usize = 64 * 1024;
+
+/// How often a read of the console checks for input
+const READ_POLL: Duration = Duration::from_millis(10);
+
+/// Every registered device, an entry's inode number is its index + 2
+static DEVICES: TicketLock<Vec<(&'static str, Arc<dyn Inode>)>> = TicketLock::new(Vec::new());
+
+/// The device filesystem, every instance shows the same devices
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevDir)
+    }
+}
+
+/// The only directory of the filesystem
+struct DevDir;
+
+impl Inode for DevDir {
+    fn stat(&self) -> VfsResult<Stat> {
+        Ok(Stat {
+            ino: 1,
+            kind: FileType::Dir,
+            mode: 0o755,
+            nlink: 2,
+            size: 0,
+            rdev: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn Inode>> {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|(device, _)| *device == name)
+            .map(|(_, inode)| inode.clone())
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn readdir(&self, index: usize) -> VfsResult<Option<DirEntry>> {
+        let Some((name, inode)) = DEVICES.lock().get(index).cloned() else {
+            return Ok(None);
+        };
+
+        Ok(Some(DirEntry {
+            name: String::from(name),
+            ino: index as u64 + 2,
+            kind: inode.stat()?.kind,
+        }))
+    }
+}
+
+/// `/dev/console`
+struct ConsoleDevice;
+
+impl Inode for ConsoleDevice {
+    fn stat(&self) -> VfsResult<Stat> {
+        Ok(Stat {
+            // Registered first by init()
+            ino: 2,
+            kind: FileType::CharDevice,
+            mode: 0o600,
+            nlink: 1,
+            size: 0,
+            // Linux's /dev/console is 5:1
+            rdev: (5 << 8) | 1,
+        })
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // No receive interrupt yet, poll until at least one byte shows up
+        loop {
+            match console::_read(buf) {
+                0 => crate::task::sleep(READ_POLL),
+                n => return Ok(n),
+            }
+        }
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        console::_write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/random` and `/dev/urandom`, the same thing since the pool is seeded at boot
+struct RandomDevice {
+    ino: u64,
+    rdev: u64,
+}
+
+impl Inode for RandomDevice {
+    fn stat(&self) -> VfsResult<Stat> {
+        Ok(Stat {
+            ino: self.ino,
+            kind: FileType::CharDevice,
+            mode: 0o666,
+            nlink: 1,
+            size: 0,
+            rdev: self.rdev,
+        })
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        crate::random::fill_bytes(buf);
+        Ok(buf.len())
+    }
+
+    /// Written data is mixed into the pool, like on Linux
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        crate::random::add_entropy(buf);
+        Ok(buf.len())
+    }
+}
+
+/// A [BlockDevice] as a file
+struct BlockNode {
+    device: Arc<dyn BlockDevice>,
+    ino: u64,
+    rdev: u64,
+}
+
+impl BlockNode {
+    fn size(&self) -> u64 {
+        self.device.num_blocks() * self.device.block_size() as u64
+    }
+
+    /// The whole blocks covering `len` bytes at `offset`: first block and byte length
+    fn span(&self, offset: u64, len: usize) -> (u64, usize) {
+        let block_size = self.device.block_size() as u64;
+        let first = offset / block_size;
+        let last = (offset + len as u64).div_ceil(block_size);
+
+        (first, ((last - first) * block_size) as usize)
+    }
+}
+
+impl Inode for BlockNode {
+    fn stat(&self) -> VfsResult<Stat> {
+        Ok(Stat {
+            ino: self.ino,
+            kind: FileType::BlockDevice,
+            mode: 0o660,
+            nlink: 1,
+            size: self.size(),
+            rdev: self.rdev,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let len = self.size().saturating_sub(offset).min(buf.len().min(BLOCK_IO_MAX) as u64) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let (first, span) = self.span(offset, len);
+        let mut blocks = vec![0; span];
+        self.device.read_blocks(first, &mut blocks)?;
+
+        let start = (offset % self.device.block_size() as u64) as usize;
+        buf[..len].copy_from_slice(&blocks[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        let len = self.size().saturating_sub(offset).min(buf.len().min(BLOCK_IO_MAX) as u64) as usize;
+        if len == 0 {
+            return match buf.is_empty() {
+                true => Ok(0),
+                false => Err(VfsError::NoSpace),
+            };
+        }
+
+        let block_size = self.device.block_size();
+        let (first, span) = self.span(offset, len);
+        let start = (offset % block_size as u64) as usize;
+        let mut blocks = vec![0; span];
+
+        // Partial blocks at either end keep the rest of their bytes
+        if start != 0 || !len.is_multiple_of(block_size) {
+            self.device.read_blocks(first, &mut blocks)?;
+        }
+
+        blocks[start..start + len].copy_from_slice(&buf[..len]);
+        self.device.write_blocks(first, &blocks)?;
+        Ok(len)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Show `device` as `/dev/<name>`
+///
+/// Fails with [VfsError::Exists] if the name is taken.
+pub fn register(name: &'static str, device: Arc<dyn Inode>) -> VfsResult<()> {
+    let mut devices = DEVICES.lock();
+
+    if devices.iter().any(|(existing, _)| *existing == name) {
+        return Err(VfsError::Exists);
+    }
+
+    devices.push((name, device));
+    Ok(())
+}
+
+/// Show the block device `device` as `/dev/<name>`
+///
+/// `rdev` is its device number, `(major << 8) | minor`.
+pub fn register_block(name: &'static str, device: Arc<dyn BlockDevice>, rdev: u64) -> VfsResult<()> {
+    let mut devices = DEVICES.lock();
+
+    if devices.iter().any(|(existing, _)| *existing == name) {
+        return Err(VfsError::Exists);
+    }
+
+    let ino = devices.len() as u64 + 2;
+    devices.push((name, Arc::new(BlockNode { device, ino, rdev })));
+    Ok(())
+}
+
+/// Register the devices every kernel has, called by [super::init()]
+pub fn init() {
+    let _ = register("console", Arc::new(ConsoleDevice));
+
+    // Linux's are 1:8 and 1:9
+    for (name, minor) in [("random", 8), ("urandom", 9)] {
+        let ino = DEVICES.lock().len() as u64 + 2;
+        let _ = register(name, Arc::new(RandomDevice { ino, rdev: (1 << 8) | minor }));
+    }
+}
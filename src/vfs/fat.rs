@@ -0,0 +1,1331 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS FAT32
+//!
+//! The filesystem on SD cards, on top of any [BlockDevice]. [Fat32::find()] looks for it in
+//! the card's MBR partitions (or on the whole device), the result can be [super::mount]ed.
+//!
+//! Files and directories can be read, written, created, truncated, removed and renamed.
+//! Long file names are read and written, an 8.3 name is generated for names that need
+//! one. Names are matched without case, like everywhere else FAT is used.
+//!
+//! All access goes through one lock per volume. Clusters and FAT sectors are kept in
+//! small LRU caches, writes go through them straight to the device so nothing is lost
+//! when the card is pulled. There's no clock yet, so timestamps aren't kept.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf>
+//!   - <https://wiki.osdev.org/FAT>
+//!   - <https://en.wikipedia.org/wiki/Design_of_the_FAT_file_system>
+//!
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat, VfsError, VfsResult};
+use crate::drivers::block::{self, BlockDevice, BlockError};
+use crate::sync::mutex::{Mutex, MutexGuard};
+use crate::sync::ticket::TicketLock;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::any::Any;
+
+/// Size of a directory entry
+const DIR_ENTRY: usize = 32;
+
+/// Attribute bits of a directory entry
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes of a long name entry, no real entry has them all
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First byte of the entry after the last one
+const ENTRY_END: u8 = 0x00;
+/// First byte of a removed entry
+const ENTRY_FREE: u8 = 0xE5;
+
+/// Sequence number flag of the last (first on disk) long name entry
+const LONG_LAST: u8 = 0x40;
+/// UTF-16 units in a long name entry, and where they are
+const LONG_CHARS: usize = 13;
+const LONG_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Bits of byte 12 that Windows NT uses for all lower case 8.3 names
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// FAT entries are 28 bits, the top 4 are reserved
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+/// FAT entries from here up end a chain
+const FAT_EOC: u32 = 0x0FFF_FFF8;
+/// What the end of a chain is written as
+const FAT_EOC_MARK: u32 = 0x0FFF_FFFF;
+/// First cluster of the data area
+const FIRST_CLUSTER: u32 = 2;
+
+/// Bytes of clusters the cache keeps per volume
+const CLUSTER_CACHE: usize = 256 * 1024;
+/// FAT sectors the cache keeps per volume
+const FAT_CACHE: usize = 16;
+
+/// Longest name in UTF-16 units
+const NAME_MAX: usize = 255;
+
+/// MBR partition types of FAT32
+const PART_FAT32_CHS: u8 = 0x0B;
+const PART_FAT32_LBA: u8 = 0x0C;
+
+/// 1980-01-01, the earliest date FAT can store, used in place of a clock
+const DATE_EPOCH: u16 = (1 << 5) | 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for mounting a FAT32 volume
+pub enum FatError {
+    /// The boot sector isn't one of a FAT32 volume
+    NotFat32,
+    /// The volume uses something this driver can't handle, like a sector size that isn't
+    /// the device's block size
+    Unsupported,
+    /// Reading the device failed
+    Device(BlockError),
+}
+
+/// Allows printing the error
+impl core::fmt::Display for FatError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            FatError::NotFat32 => f.write_str("No FAT32 filesystem found"),
+            FatError::Unsupported => f.write_str("Unsupported FAT32 volume"),
+            FatError::Device(err) => write!(f, "Device error: {}", err),
+        }
+    }
+}
+
+impl From<BlockError> for FatError {
+    fn from(err: BlockError) -> FatError {
+        FatError::Device(err)
+    }
+}
+
+/// A mounted FAT32 volume
+pub struct Fat32 {
+    root: Arc<FatNode>,
+}
+
+impl Fat32 {
+    /// Use the FAT32 volume that fills `device`
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Fat32, FatError> {
+        let volume = Arc::new(Volume::new(device)?);
+
+        let root = Arc::new(FatNode {
+            ino: 1,
+            kind: FileType::Dir,
+            meta: TicketLock::new(Meta {
+                location: None,
+                cluster: volume.root_cluster,
+                size: 0,
+                removed: false,
+            }),
+            volume,
+        });
+
+        Ok(Fat32 { root })
+    }
+
+    /// Find a FAT32 volume on a disk
+    ///
+    /// Tries the FAT32 partitions of the MBR in order, then the whole device for disks
+    /// formatted without a partition table.
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::vfs::{self, fat::Fat32};
+    /// use alloc::sync::Arc;
+    ///
+    /// let fs = Fat32::find(card).unwrap();
+    /// vfs::mount("/mnt", Arc::new(fs)).unwrap();
+    /// ```
+    pub fn find(device: Arc<dyn BlockDevice>) -> Result<Fat32, FatError> {
+        for partition in block::partitions(&device)? {
+            if matches!(partition.kind(), PART_FAT32_CHS | PART_FAT32_LBA) {
+                if let Ok(fs) = Fat32::new(Arc::new(partition)) {
+                    return Ok(fs);
+                }
+            }
+        }
+
+        Fat32::new(device)
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Layout of the volume and everything behind its lock
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    sector_size: usize,
+    cluster_size: usize,
+    cluster_sectors: u64,
+    /// First sector of the first FAT
+    fat_start: u64,
+    fat_sectors: u64,
+    fats: u64,
+    /// First sector of cluster 2
+    data_start: u64,
+    /// One past the highest cluster
+    cluster_end: u32,
+    root_cluster: u32,
+    state: Mutex<State>,
+}
+
+struct State {
+    fat: Cache,
+    data: Cache,
+    /// Where the search for a free cluster starts
+    next_free: u32,
+    /// The nodes handed out and still alive, by the location of their entry
+    nodes: BTreeMap<(u32, u32), Weak<FatNode>>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Volume, FatError> {
+        let mut boot = vec![0; device.block_size()];
+        device.read_blocks(0, &mut boot)?;
+
+        if boot.len() < 512 || boot[510..512] != [0x55, 0xAA] {
+            return Err(FatError::NotFat32);
+        }
+
+        let sector_size = read_u16(&boot, 0x0B) as usize;
+        let cluster_sectors = boot[0x0D] as u64;
+        let reserved = read_u16(&boot, 0x0E) as u64;
+        let fats = boot[0x10] as u64;
+        let root_entries = read_u16(&boot, 0x11);
+        let fat16_sectors = read_u16(&boot, 0x16);
+        let total = match read_u16(&boot, 0x13) {
+            0 => read_u32(&boot, 0x20) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = read_u32(&boot, 0x24) as u64;
+        let root_cluster = read_u32(&boot, 0x2C);
+
+        // FAT12 and FAT16 have a fixed root directory and a FAT size in the old field
+        if root_entries != 0 || fat16_sectors != 0 || fat_sectors == 0 || fats == 0 {
+            return Err(FatError::NotFat32);
+        }
+
+        if !cluster_sectors.is_power_of_two() || !sector_size.is_power_of_two() || reserved == 0 {
+            return Err(FatError::NotFat32);
+        }
+
+        if sector_size != device.block_size() || total > device.num_blocks() {
+            return Err(FatError::Unsupported);
+        }
+
+        let data_start = reserved + fats * fat_sectors;
+        let clusters = total.checked_sub(data_start).ok_or(FatError::NotFat32)? / cluster_sectors;
+        // Clusters the FAT has entries for, a volume can't use more
+        let fat_entries = fat_sectors * sector_size as u64 / 4;
+        let cluster_end = (clusters + FIRST_CLUSTER as u64).min(fat_entries).min(FAT_EOC as u64) as u32;
+
+        if !(FIRST_CLUSTER..cluster_end).contains(&root_cluster) {
+            return Err(FatError::NotFat32);
+        }
+
+        let cluster_size = cluster_sectors as usize * sector_size;
+
+        Ok(Volume {
+            sector_size,
+            cluster_size,
+            cluster_sectors,
+            fat_start: reserved,
+            fat_sectors,
+            fats,
+            data_start,
+            cluster_end,
+            root_cluster,
+            state: Mutex::new(State {
+                fat: Cache::new(sector_size, 1, FAT_CACHE),
+                data: Cache::new(sector_size, cluster_sectors, (CLUSTER_CACHE / cluster_size).max(1)),
+                next_free: FIRST_CLUSTER,
+                nodes: BTreeMap::new(),
+            }),
+            device,
+        })
+    }
+
+    fn lock(&self) -> VfsResult<MutexGuard<'_, State>> {
+        self.state.lock().map_err(|_| VfsError::Io)
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_sectors
+    }
+
+    /// Sector of the first FAT holding the entry of `cluster`, and the entry's offset in it
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        (offset / self.sector_size as u64, (offset % self.sector_size as u64) as usize)
+    }
+
+    fn fat_get(&self, state: &mut State, cluster: u32) -> VfsResult<u32> {
+        let (sector, offset) = self.fat_position(cluster);
+        let data = state.fat.get(&*self.device, self.fat_start + sector)?;
+
+        Ok(read_u32(data, offset) & FAT_MASK)
+    }
+
+    /// Set the entry of `cluster` in every copy of the FAT
+    fn fat_set(&self, state: &mut State, cluster: u32, value: u32) -> VfsResult<()> {
+        let (sector, offset) = self.fat_position(cluster);
+
+        for fat in 0..self.fats {
+            let lba = self.fat_start + fat * self.fat_sectors + sector;
+            let old = read_u32(state.fat.get(&*self.device, lba)?, offset);
+            let new = (old & !FAT_MASK) | (value & FAT_MASK);
+
+            state.fat.write(&*self.device, lba, offset, &new.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Cluster after `cluster` in its chain, None at the end
+    fn next(&self, state: &mut State, cluster: u32) -> VfsResult<Option<u32>> {
+        match self.fat_get(state, cluster)? {
+            next if next >= FAT_EOC => Ok(None),
+            next if (FIRST_CLUSTER..self.cluster_end).contains(&next) => Ok(Some(next)),
+            // Free or bad clusters in a chain, the volume is damaged
+            _ => Err(VfsError::Io),
+        }
+    }
+
+    /// Every cluster of the chain starting at `first`
+    fn chain(&self, state: &mut State, first: u32) -> VfsResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = Some(first).filter(|&first| first != 0);
+
+        while let Some(current) = cluster {
+            // A chain longer than the volume loops
+            if chain.len() >= self.cluster_end as usize {
+                return Err(VfsError::Io);
+            }
+
+            chain.push(current);
+            cluster = self.next(state, current)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// The cluster `index` clusters into the chain at `first`
+    fn seek(&self, state: &mut State, first: u32, index: u64) -> VfsResult<u32> {
+        let mut cluster = first;
+
+        for _ in 0..index {
+            cluster = self.next(state, cluster)?.ok_or(VfsError::Io)?;
+        }
+
+        Ok(cluster)
+    }
+
+    /// Take a free cluster, zeroed and marked as the end of a chain
+    fn alloc_cluster(&self, state: &mut State) -> VfsResult<u32> {
+        let count = self.cluster_end - FIRST_CLUSTER;
+
+        for i in 0..count {
+            let cluster = FIRST_CLUSTER + (state.next_free - FIRST_CLUSTER + i) % count;
+
+            if self.fat_get(state, cluster)? == 0 {
+                self.fat_set(state, cluster, FAT_EOC_MARK)?;
+                state.data.fill(&*self.device, self.cluster_lba(cluster), &vec![0; self.cluster_size])?;
+                state.next_free = cluster;
+                return Ok(cluster);
+            }
+        }
+
+        Err(VfsError::NoSpace)
+    }
+
+    /// Make the chain at `first`, `have` clusters long, `want` clusters long
+    ///
+    /// Returns the first cluster, which is new if the chain was empty. Nothing changes if
+    /// there isn't room for all of it.
+    fn grow(&self, state: &mut State, first: u32, have: u64, want: u64) -> VfsResult<u32> {
+        let mut new = Vec::new();
+
+        for _ in have..want {
+            match self.alloc_cluster(state) {
+                Ok(cluster) => new.push(cluster),
+                Err(err) => {
+                    for cluster in new {
+                        self.fat_set(state, cluster, 0)?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        for pair in new.windows(2) {
+            self.fat_set(state, pair[0], pair[1])?;
+        }
+
+        match (have, new.first()) {
+            (_, None) => Ok(first),
+            (0, Some(&start)) => Ok(start),
+            (have, Some(&start)) => {
+                let last = self.seek(state, first, have - 1)?;
+                self.fat_set(state, last, start)?;
+                Ok(first)
+            }
+        }
+    }
+
+    /// Free every cluster of the chain at `first`
+    fn free_chain(&self, state: &mut State, first: u32) -> VfsResult<()> {
+        for cluster in self.chain(state, first)? {
+            self.fat_set(state, cluster, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read from the chain at `first`, which has to be long enough
+    fn read_chain(&self, state: &mut State, first: u32, offset: u64, buf: &mut [u8]) -> VfsResult<()> {
+        let size = self.cluster_size as u64;
+        let mut cluster = self.seek(state, first, offset / size)?;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let start = ((offset + done as u64) % size) as usize;
+            let count = (self.cluster_size - start).min(buf.len() - done);
+
+            let data = state.data.get(&*self.device, self.cluster_lba(cluster))?;
+            buf[done..done + count].copy_from_slice(&data[start..start + count]);
+            done += count;
+
+            if done < buf.len() {
+                cluster = self.next(state, cluster)?.ok_or(VfsError::Io)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write to the chain at `first`, which has to be long enough
+    fn write_chain(&self, state: &mut State, first: u32, offset: u64, buf: &[u8]) -> VfsResult<()> {
+        let size = self.cluster_size as u64;
+        let mut cluster = self.seek(state, first, offset / size)?;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let start = ((offset + done as u64) % size) as usize;
+            let count = (self.cluster_size - start).min(buf.len() - done);
+
+            state.data.write(&*self.device, self.cluster_lba(cluster), start, &buf[done..done + count])?;
+            done += count;
+
+            if done < buf.len() {
+                cluster = self.next(state, cluster)?.ok_or(VfsError::Io)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every entry of the directory at `dir`, without `.`, `..` and the volume label
+    fn read_dir(&self, state: &mut State, dir: u32) -> VfsResult<Vec<RawEntry>> {
+        let chain = self.chain(state, dir)?;
+        let mut entries = Vec::new();
+        let mut long = LongName::default();
+
+        for (index, &cluster) in chain.iter().enumerate() {
+            let data = state.data.get(&*self.device, self.cluster_lba(cluster))?;
+
+            for (i, raw) in data.chunks_exact(DIR_ENTRY).enumerate() {
+                let offset = (index * self.cluster_size + i * DIR_ENTRY) as u32;
+
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_FREE => long.clear(),
+                    _ if raw[11] & 0x3F == ATTR_LONG_NAME => long.push(raw, offset),
+                    // Short names can't have dots, only `.` and `..` start with one
+                    _ if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' => long.clear(),
+                    _ => {
+                        let raw: [u8; DIR_ENTRY] = raw.try_into().unwrap();
+                        let short = short_name(&raw);
+                        let (name, start) = long.take(checksum(&raw)).unwrap_or((short.clone(), offset));
+
+                        entries.push(RawEntry { name, short, raw, offset, start });
+                    }
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// The entry `name` of the directory at `dir`
+    fn find(&self, state: &mut State, dir: u32, name: &str) -> VfsResult<Option<RawEntry>> {
+        Ok(self.read_dir(state, dir)?.into_iter().find(|entry| entry.matches(name)))
+    }
+
+    /// Add an entry called `name` to the directory at `dir`, with the rest of its short
+    /// entry taken from `raw`
+    ///
+    /// Returns where the entry went, the directory grows if it has no room.
+    fn add_entry(&self, state: &mut State, dir: u32, name: &str, mut raw: [u8; DIR_ENTRY]) -> VfsResult<Location> {
+        let entries = self.read_dir(state, dir)?;
+        let (short, case, long) = match exact_short(name) {
+            Some((short, case)) => (short, case, false),
+            None => {
+                let taken: Vec<[u8; 11]> = entries.iter().map(|entry| entry.raw[..11].try_into().unwrap()).collect();
+                (generate_short(name, &taken)?, 0, true)
+            }
+        };
+
+        raw[..11].copy_from_slice(&short);
+        raw[12] = case;
+
+        let mut slots: Vec<[u8; DIR_ENTRY]> = match long {
+            true => long_entries(name, checksum(&raw)),
+            false => Vec::new(),
+        };
+        slots.push(raw);
+
+        let start = self.free_slots(state, dir, slots.len())?;
+        let bytes: Vec<u8> = slots.iter().flatten().copied().collect();
+        self.write_chain(state, dir, start as u64, &bytes)?;
+
+        Ok(Location {
+            dir,
+            offset: (start + bytes.len() - DIR_ENTRY) as u32,
+            start: start as u32,
+        })
+    }
+
+    /// Offset of `count` unused entries in a row in the directory at `dir`
+    fn free_slots(&self, state: &mut State, dir: u32, count: usize) -> VfsResult<usize> {
+        let chain = self.chain(state, dir)?;
+        let mut run = 0;
+
+        for (index, &cluster) in chain.iter().enumerate() {
+            let data = state.data.get(&*self.device, self.cluster_lba(cluster))?;
+
+            for (i, raw) in data.chunks_exact(DIR_ENTRY).enumerate() {
+                match raw[0] {
+                    ENTRY_END | ENTRY_FREE => run += 1,
+                    _ => run = 0,
+                }
+
+                if run == count {
+                    let slot = index * self.cluster_size / DIR_ENTRY + i;
+                    return Ok((slot + 1 - count) * DIR_ENTRY);
+                }
+            }
+        }
+
+        // Not enough room, the run at the end continues into new clusters
+        let length = chain.len() * self.cluster_size;
+        let start = length - run * DIR_ENTRY;
+        let want = (start + count * DIR_ENTRY).div_ceil(self.cluster_size);
+
+        self.grow(state, dir, chain.len() as u64, want as u64)?;
+        Ok(start)
+    }
+
+    /// Mark the entry at `location` and its long name entries as removed
+    fn remove_entry(&self, state: &mut State, location: Location) -> VfsResult<()> {
+        for offset in (location.start..=location.offset).step_by(DIR_ENTRY) {
+            self.write_chain(state, location.dir, offset as u64, &[ENTRY_FREE])?;
+        }
+
+        Ok(())
+    }
+
+    /// Store the first cluster and size of a node in its entry
+    fn update_entry(&self, state: &mut State, location: Location, cluster: u32, size: u32) -> VfsResult<()> {
+        let offset = location.offset as u64;
+
+        self.write_chain(state, location.dir, offset + 20, &((cluster >> 16) as u16).to_le_bytes())?;
+        self.write_chain(state, location.dir, offset + 26, &(cluster as u16).to_le_bytes())?;
+        self.write_chain(state, location.dir, offset + 28, &size.to_le_bytes())
+    }
+
+    /// The node for the short entry `raw` at `location`, the same one while it's alive
+    fn node(self: &Arc<Volume>, state: &mut State, location: Location, raw: &[u8; DIR_ENTRY]) -> Arc<FatNode> {
+        let key = (location.dir, location.offset);
+
+        if let Some(node) = state.nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+
+        let node = Arc::new(FatNode {
+            volume: self.clone(),
+            ino: inode_number(location.dir, location.offset),
+            kind: kind_of(raw),
+            meta: TicketLock::new(Meta {
+                location: Some(location),
+                cluster: cluster_of(raw),
+                size: read_u32(raw, 28),
+                removed: false,
+            }),
+        });
+
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+
+    /// Take `entry` out of the directory at `dir` and free its clusters
+    ///
+    /// A node that is still open stops working.
+    fn remove(&self, state: &mut State, dir: u32, entry: &RawEntry) -> VfsResult<()> {
+        self.remove_entry(state, entry.location(dir))?;
+        self.free_chain(state, entry.cluster())?;
+
+        if let Some(node) = state.nodes.remove(&(dir, entry.offset)).and_then(|node| node.upgrade()) {
+            node.meta.lock().removed = true;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the directory at `dir` has no entries
+    fn is_empty(&self, state: &mut State, dir: u32) -> VfsResult<bool> {
+        Ok(self.read_dir(state, dir)?.is_empty())
+    }
+
+    /// Whether the directory at `dir` is `ancestor` or below it
+    fn is_below(&self, state: &mut State, mut dir: u32, ancestor: u32) -> VfsResult<bool> {
+        for _ in 0..self.cluster_end {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            if dir == self.root_cluster {
+                return Ok(false);
+            }
+
+            // `..` is the second entry, cluster 0 means the root
+            let mut parent = [0; DIR_ENTRY];
+            self.read_chain(state, dir, DIR_ENTRY as u64, &mut parent)?;
+            dir = match cluster_of(&parent) {
+                0 => self.root_cluster,
+                cluster => cluster,
+            };
+        }
+
+        Err(VfsError::Io)
+    }
+
+    /// What a directory's `..` entry holds for `dir`
+    fn parent_ref(&self, dir: u32) -> u32 {
+        match dir == self.root_cluster {
+            true => 0,
+            false => dir,
+        }
+    }
+}
+
+/// Where a node's entry is
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    /// First cluster of the directory
+    dir: u32,
+    /// Byte offset of the short entry in the directory
+    offset: u32,
+    /// Byte offset of the first long name entry, `offset` without one
+    start: u32,
+}
+
+/// What a node knows about itself, kept in sync with its entry
+#[derive(Clone, Copy, Debug)]
+struct Meta {
+    /// None for the root directory
+    location: Option<Location>,
+    /// First cluster, 0 for an empty file
+    cluster: u32,
+    size: u32,
+    /// The entry was removed, the clusters may belong to something else by now
+    removed: bool,
+}
+
+/// A file or directory of a FAT32 volume
+struct FatNode {
+    volume: Arc<Volume>,
+    ino: u64,
+    kind: FileType,
+    meta: TicketLock<Meta>,
+}
+
+impl FatNode {
+    /// Lock the volume and read the node's metadata
+    fn begin(&self) -> VfsResult<(MutexGuard<'_, State>, Meta)> {
+        let state = self.volume.lock()?;
+        let meta = *self.meta.lock();
+
+        match meta.removed {
+            true => Err(VfsError::NotFound),
+            false => Ok((state, meta)),
+        }
+    }
+
+    fn dir(&self) -> VfsResult<(MutexGuard<'_, State>, Meta)> {
+        match self.kind {
+            FileType::Dir => self.begin(),
+            _ => Err(VfsError::NotDir),
+        }
+    }
+
+    fn file(&self) -> VfsResult<(MutexGuard<'_, State>, Meta)> {
+        match self.kind {
+            FileType::Dir => Err(VfsError::IsDir),
+            _ => self.begin(),
+        }
+    }
+
+    /// Change the size of the file to `size`, with the volume locked
+    ///
+    /// New bytes read as zeros: new clusters are zeroed when they're allocated and what
+    /// was past the end in the last cluster is cleared here.
+    fn resize(&self, state: &mut State, mut meta: Meta, size: u32) -> VfsResult<Meta> {
+        let volume = &self.volume;
+        let cluster_size = volume.cluster_size as u64;
+        let have = (meta.size as u64).div_ceil(cluster_size);
+        let want = (size as u64).div_ceil(cluster_size);
+
+        if size > meta.size {
+            let tail = meta.size as u64 % cluster_size;
+            if tail != 0 {
+                let len = (cluster_size - tail).min((size - meta.size) as u64) as usize;
+                volume.write_chain(state, meta.cluster, meta.size as u64, &vec![0; len])?;
+            }
+
+            meta.cluster = volume.grow(state, meta.cluster, have, want)?;
+        } else if want == 0 {
+            volume.free_chain(state, meta.cluster)?;
+            meta.cluster = 0;
+        } else if want < have {
+            let last = volume.seek(state, meta.cluster, want - 1)?;
+            let rest = volume.next(state, last)?;
+
+            volume.fat_set(state, last, FAT_EOC_MARK)?;
+            if let Some(rest) = rest {
+                volume.free_chain(state, rest)?;
+            }
+        }
+
+        meta.size = size;
+        self.save(state, meta)?;
+        Ok(meta)
+    }
+
+    /// Write `meta` back to the node and its entry
+    fn save(&self, state: &mut State, meta: Meta) -> VfsResult<()> {
+        if let Some(location) = meta.location {
+            self.volume.update_entry(state, location, meta.cluster, meta.size)?;
+        }
+
+        *self.meta.lock() = meta;
+        Ok(())
+    }
+}
+
+impl Inode for FatNode {
+    fn stat(&self) -> VfsResult<Stat> {
+        let meta = *self.meta.lock();
+
+        Ok(Stat {
+            ino: self.ino,
+            kind: self.kind,
+            // No owners or permissions on FAT, everyone gets everything like with vfat's
+            // default umask
+            mode: 0o755,
+            nlink: match self.kind {
+                FileType::Dir => 2,
+                _ => 1,
+            },
+            size: meta.size as u64,
+            rdev: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let (mut state, meta) = self.file()?;
+        let len = (meta.size as u64).saturating_sub(offset).min(buf.len() as u64) as usize;
+
+        // At or past the end, where the chain may already have run out
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.volume.read_chain(&mut state, meta.cluster, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        let (mut state, mut meta) = self.file()?;
+
+        // Nothing to write, so nothing to grow to either
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Sizes are 32 bits
+        let end = offset.checked_add(buf.len() as u64).filter(|&end| end <= u32::MAX as u64);
+        let end = end.ok_or(VfsError::NoSpace)? as u32;
+
+        if end > meta.size {
+            meta = self.resize(&mut state, meta, end)?;
+        }
+
+        self.volume.write_chain(&mut state, meta.cluster, offset, buf)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> VfsResult<()> {
+        let (mut state, meta) = self.file()?;
+        let size = u32::try_from(size).map_err(|_| VfsError::NoSpace)?;
+
+        self.resize(&mut state, meta, size)?;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn Inode>> {
+        let (mut state, meta) = self.dir()?;
+        let entry = self.volume.find(&mut state, meta.cluster, name)?.ok_or(VfsError::NotFound)?;
+
+        Ok(self.volume.node(&mut state, entry.location(meta.cluster), &entry.raw))
+    }
+
+    fn readdir(&self, index: usize) -> VfsResult<Option<DirEntry>> {
+        let (mut state, meta) = self.dir()?;
+        let entries = self.volume.read_dir(&mut state, meta.cluster)?;
+
+        Ok(entries.get(index).map(|entry| DirEntry {
+            name: entry.name.clone(),
+            ino: inode_number(meta.cluster, entry.offset),
+            kind: entry.kind(),
+        }))
+    }
+
+    fn create(&self, name: &str, kind: FileType, _mode: u32) -> VfsResult<Arc<dyn Inode>> {
+        check_name(name)?;
+        let (mut state, meta) = self.dir()?;
+        let volume = &self.volume;
+
+        if volume.find(&mut state, meta.cluster, name)?.is_some() {
+            return Err(VfsError::Exists);
+        }
+
+        let (attr, cluster) = match kind {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Dir => {
+                let cluster = volume.alloc_cluster(&mut state)?;
+
+                let mut dots = [new_entry(ATTR_DIRECTORY, cluster), new_entry(ATTR_DIRECTORY, volume.parent_ref(meta.cluster))];
+                dots[0][..11].copy_from_slice(b".          ");
+                dots[1][..11].copy_from_slice(b"..         ");
+
+                let bytes: Vec<u8> = dots.iter().flatten().copied().collect();
+                volume.write_chain(&mut state, cluster, 0, &bytes)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+            // No symlinks or device nodes on FAT
+            _ => return Err(VfsError::Invalid),
+        };
+
+        let raw = new_entry(attr, cluster);
+        let location = match volume.add_entry(&mut state, meta.cluster, name, raw) {
+            Ok(location) => location,
+            Err(err) => {
+                volume.free_chain(&mut state, cluster)?;
+                return Err(err);
+            }
+        };
+
+        Ok(volume.node(&mut state, location, &raw))
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let (mut state, meta) = self.dir()?;
+        let entry = self.volume.find(&mut state, meta.cluster, name)?.ok_or(VfsError::NotFound)?;
+
+        if entry.kind() == FileType::Dir {
+            return Err(VfsError::IsDir);
+        }
+
+        self.volume.remove(&mut state, meta.cluster, &entry)
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        let (mut state, meta) = self.dir()?;
+        let entry = self.volume.find(&mut state, meta.cluster, name)?.ok_or(VfsError::NotFound)?;
+
+        if entry.kind() != FileType::Dir {
+            return Err(VfsError::NotDir);
+        }
+        if !self.volume.is_empty(&mut state, entry.cluster())? {
+            return Err(VfsError::NotEmpty);
+        }
+
+        self.volume.remove(&mut state, meta.cluster, &entry)
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> VfsResult<()> {
+        check_name(new_name)?;
+
+        let new_dir = (&**new_dir as &dyn Any).downcast_ref::<FatNode>().ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &new_dir.volume) {
+            return Err(VfsError::CrossDevice);
+        }
+
+        let (mut state, meta) = self.dir()?;
+        let to = match new_dir.kind {
+            FileType::Dir => *new_dir.meta.lock(),
+            _ => return Err(VfsError::NotDir),
+        };
+        if to.removed {
+            return Err(VfsError::NotFound);
+        }
+
+        let volume = &self.volume;
+        let entry = volume.find(&mut state, meta.cluster, name)?.ok_or(VfsError::NotFound)?;
+        let is_dir = entry.kind() == FileType::Dir;
+
+        // A directory can't go inside itself
+        if is_dir && volume.is_below(&mut state, to.cluster, entry.cluster())? {
+            return Err(VfsError::Invalid);
+        }
+
+        let same = |existing: &RawEntry| to.cluster == meta.cluster && existing.offset == entry.offset;
+        let replaced = match volume.find(&mut state, to.cluster, new_name)? {
+            Some(existing) if same(&existing) && existing.name == new_name => return Ok(()),
+            // Same entry, only the case of the name changes
+            Some(existing) if same(&existing) => None,
+            Some(existing) => {
+                match (is_dir, existing.kind() == FileType::Dir) {
+                    (true, true) if !volume.is_empty(&mut state, existing.cluster())? => {
+                        return Err(VfsError::NotEmpty)
+                    }
+                    (true, false) => return Err(VfsError::NotDir),
+                    (false, true) => return Err(VfsError::IsDir),
+                    _ => {}
+                }
+
+                Some(existing)
+            }
+            None => None,
+        };
+
+        // The new entry goes in first, what it replaces and the old one are only removed
+        // once that worked, so a full directory loses neither file
+        let location = volume.add_entry(&mut state, to.cluster, new_name, entry.raw)?;
+        if let Some(existing) = replaced {
+            volume.remove(&mut state, to.cluster, &existing)?;
+        }
+        volume.remove_entry(&mut state, entry.location(meta.cluster))?;
+
+        if is_dir && to.cluster != meta.cluster {
+            let parent = volume.parent_ref(to.cluster);
+            volume.write_chain(&mut state, entry.cluster(), DIR_ENTRY as u64 + 20, &((parent >> 16) as u16).to_le_bytes())?;
+            volume.write_chain(&mut state, entry.cluster(), DIR_ENTRY as u64 + 26, &(parent as u16).to_le_bytes())?;
+        }
+
+        // An open node follows its entry
+        if let Some(node) = state.nodes.remove(&(meta.cluster, entry.offset)) {
+            if let Some(live) = node.upgrade() {
+                live.meta.lock().location = Some(location);
+            }
+            state.nodes.insert((location.dir, location.offset), node);
+        }
+
+        Ok(())
+    }
+}
+
+/// A directory entry as it is on disk
+struct RawEntry {
+    /// The long name, or the short one without it
+    name: String,
+    /// The 8.3 name
+    short: String,
+    raw: [u8; DIR_ENTRY],
+    /// Byte offset of the short entry in its directory
+    offset: u32,
+    /// Byte offset of the first long name entry, `offset` without one
+    start: u32,
+}
+
+impl RawEntry {
+    fn kind(&self) -> FileType {
+        kind_of(&self.raw)
+    }
+
+    fn cluster(&self) -> u32 {
+        cluster_of(&self.raw)
+    }
+
+    fn location(&self, dir: u32) -> Location {
+        Location { dir, offset: self.offset, start: self.start }
+    }
+
+    /// Names are compared without case, and either one matches
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Long name entries collected on the way to the short entry they belong to
+#[derive(Default)]
+struct LongName {
+    units: Vec<u16>,
+    /// Sequence number expected next, they count down to 1
+    next: u8,
+    checksum: u8,
+    start: u32,
+}
+
+impl LongName {
+    fn push(&mut self, raw: &[u8], offset: u32) {
+        let sequence = raw[0] & 0x1F;
+
+        if raw[0] & LONG_LAST != 0 {
+            self.units = vec![0xFFFF; sequence as usize * LONG_CHARS];
+            self.next = sequence;
+            self.checksum = raw[13];
+            self.start = offset;
+        }
+
+        if sequence == 0 || sequence != self.next || raw[13] != self.checksum {
+            self.clear();
+            return;
+        }
+
+        let base = (sequence as usize - 1) * LONG_CHARS;
+        for (i, &at) in LONG_OFFSETS.iter().enumerate() {
+            self.units[base + i] = read_u16(raw, at);
+        }
+        self.next -= 1;
+    }
+
+    /// The name and where it starts, if all of it was found for a short entry with
+    /// `checksum`
+    fn take(&mut self, checksum: u8) -> Option<(String, u32)> {
+        let complete = !self.units.is_empty() && self.next == 0 && self.checksum == checksum;
+        let units = core::mem::take(&mut self.units);
+
+        if !complete {
+            return None;
+        }
+
+        let len = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+        let name = char::decode_utf16(units[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        Some((name, self.start))
+    }
+
+    fn clear(&mut self) {
+        self.units.clear();
+        self.next = 0;
+    }
+}
+
+/// LRU cache of runs of sectors, writes go through to the device
+struct Cache {
+    sector_size: usize,
+    /// Sectors per cached unit
+    sectors: u64,
+    capacity: usize,
+    slots: Vec<Slot>,
+    clock: u64,
+}
+
+struct Slot {
+    lba: u64,
+    used: u64,
+    data: Box<[u8]>,
+}
+
+impl Cache {
+    fn new(sector_size: usize, sectors: u64, capacity: usize) -> Cache {
+        Cache {
+            sector_size,
+            sectors,
+            capacity,
+            slots: Vec::new(),
+            clock: 0,
+        }
+    }
+
+    /// The unit starting at `lba`, read from the device if it isn't cached
+    fn get(&mut self, device: &dyn BlockDevice, lba: u64) -> VfsResult<&mut [u8]> {
+        self.clock += 1;
+
+        let index = match self.slots.iter().position(|slot| slot.lba == lba) {
+            Some(index) => index,
+            None => {
+                let mut data = vec![0; self.sector_size * self.sectors as usize].into_boxed_slice();
+                device.read_blocks(lba, &mut data)?;
+                self.insert(lba, data)
+            }
+        };
+
+        let slot = &mut self.slots[index];
+        slot.used = self.clock;
+        Ok(&mut slot.data)
+    }
+
+    /// Write `bytes` at `offset` of the unit at `lba`, only the sectors touched go out
+    fn write(&mut self, device: &dyn BlockDevice, lba: u64, offset: usize, bytes: &[u8]) -> VfsResult<()> {
+        let sector_size = self.sector_size;
+        let data = self.get(device, lba)?;
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+        let first = offset / sector_size;
+        let last = (offset + bytes.len()).div_ceil(sector_size);
+        device.write_blocks(lba + first as u64, &data[first * sector_size..last * sector_size])?;
+
+        Ok(())
+    }
+
+    /// Replace the whole unit at `lba` without reading it first
+    fn fill(&mut self, device: &dyn BlockDevice, lba: u64, bytes: &[u8]) -> VfsResult<()> {
+        device.write_blocks(lba, bytes)?;
+
+        match self.slots.iter_mut().find(|slot| slot.lba == lba) {
+            Some(slot) => slot.data.copy_from_slice(bytes),
+            None => {
+                self.insert(lba, bytes.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add a unit, evicting the least recently used one if full
+    fn insert(&mut self, lba: u64, data: Box<[u8]>) -> usize {
+        let slot = Slot { lba, used: self.clock, data };
+
+        if self.slots.len() < self.capacity {
+            self.slots.push(slot);
+            return self.slots.len() - 1;
+        }
+
+        let (index, _) = self.slots.iter().enumerate().min_by_key(|(_, slot)| slot.used).unwrap();
+        self.slots[index] = slot;
+        index
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Entries don't move unless renamed, their place is as good a number as any
+fn inode_number(dir: u32, offset: u32) -> u64 {
+    ((dir as u64) << 32) | (offset / DIR_ENTRY as u32) as u64
+}
+
+fn kind_of(raw: &[u8]) -> FileType {
+    match raw[11] & ATTR_DIRECTORY {
+        0 => FileType::File,
+        _ => FileType::Dir,
+    }
+}
+
+/// First cluster stored in a short entry
+fn cluster_of(raw: &[u8]) -> u32 {
+    ((read_u16(raw, 20) as u32) << 16) | read_u16(raw, 26) as u32
+}
+
+/// A short entry with everything but the name filled in
+fn new_entry(attr: u8, cluster: u32) -> [u8; DIR_ENTRY] {
+    let mut raw = [0; DIR_ENTRY];
+
+    raw[..11].fill(b' ');
+    raw[11] = attr;
+    raw[16..18].copy_from_slice(&DATE_EPOCH.to_le_bytes());
+    raw[18..20].copy_from_slice(&DATE_EPOCH.to_le_bytes());
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[24..26].copy_from_slice(&DATE_EPOCH.to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    raw
+}
+
+/// Checksum of a short name, stored in its long name entries
+fn checksum(raw: &[u8]) -> u8 {
+    raw[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// `NAME.EXT` of a short entry, in lower case where the case bits say so
+fn short_name(raw: &[u8; DIR_ENTRY]) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes
+            .trim_ascii_end()
+            .iter()
+            .map(|&byte| match lower {
+                true => byte.to_ascii_lowercase() as char,
+                false => byte as char,
+            })
+            .collect()
+    };
+
+    let mut first = raw[..8].to_vec();
+    // 0xE5 is a valid first byte in some code pages, stored as 0x05
+    if first[0] == 0x05 {
+        first[0] = ENTRY_FREE;
+    }
+
+    let base = part(&first, raw[12] & CASE_LOWER_BASE != 0);
+    let ext = part(&raw[8..11], raw[12] & CASE_LOWER_EXT != 0);
+
+    match ext.is_empty() {
+        true => base,
+        false => format!("{}.{}", base, ext),
+    }
+}
+
+/// Characters allowed in a short name besides letters and digits
+fn short_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"$%'-_@~`!(){}^#&".contains(&byte)
+}
+
+/// The short name and case bits of `name`, if it is a valid 8.3 name
+fn exact_short(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut case = 0;
+    for (part, lower_bit) in [(base, CASE_LOWER_BASE), (ext, CASE_LOWER_EXT)] {
+        if !part.bytes().all(short_char) {
+            return None;
+        }
+
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        let upper = part.bytes().any(|byte| byte.is_ascii_uppercase());
+        match (lower, upper) {
+            // Mixed case needs a long name to keep it
+            (true, true) => return None,
+            (true, false) => case |= lower_bit,
+            _ => {}
+        }
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+
+    Some((short, case))
+}
+
+/// A `BASIS~N.EXT` short name for a long name, not in `taken`
+fn generate_short(name: &str, taken: &[[u8; 11]]) -> VfsResult<[u8; 11]> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.is_ascii() && short_char(c as u8) {
+                true => c.to_ascii_uppercase() as u8,
+                false => b'_',
+            })
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) => (clean(base), clean(ext)),
+        None => (clean(trimmed), Vec::new()),
+    };
+    let ext = &ext[..ext.len().min(3)];
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext);
+
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+
+    Err(VfsError::NoSpace)
+}
+
+/// The long name entries for `name`, in the order they go on disk
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_CHARS);
+
+    // Terminated with a 0 unless it fills the last entry, padded with 0xFFFF
+    if !units.len().is_multiple_of(LONG_CHARS) {
+        units.push(0);
+    }
+    units.resize(count * LONG_CHARS, 0xFFFF);
+
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut raw = [0; DIR_ENTRY];
+            raw[0] = (i + 1) as u8 | if i + 1 == count { LONG_LAST } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+
+            for (j, &at) in LONG_OFFSETS.iter().enumerate() {
+                raw[at..at + 2].copy_from_slice(&units[i * LONG_CHARS + j].to_le_bytes());
+            }
+            raw
+        })
+        .collect()
+}
+
+/// A name FAT can store
+fn check_name(name: &str) -> VfsResult<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::Invalid);
+    }
+
+    if name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) || name.ends_with(['.', ' ']) {
+        return Err(VfsError::Invalid);
+    }
+
+    match name.encode_utf16().count() > NAME_MAX {
+        true => Err(VfsError::NameTooLong),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,87 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS File descriptors
+//!
+//! Per-process table from small integers to open [File]s. New descriptors take the lowest
+//! free number, like POSIX says. User tasks start with 0, 1 and 2 on `/dev/console`.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use super::{File, VfsError, VfsResult, O_RDWR};
+use crate::sync::ticket::TicketLock;
+use alloc::{sync::Arc, vec::Vec};
+
+/// Most descriptors a process can have open
+pub const MAX_FDS: usize = 64;
+
+/// Open files of a process
+///
+/// The lock is only held to clone or swap an entry. Files are used and dropped outside of
+/// it, so closing one that blocks or takes locks of its own is fine.
+pub struct FdTable {
+    files: TicketLock<Vec<Option<Arc<dyn File>>>>,
+}
+
+impl FdTable {
+    /// A table with nothing open
+    pub const fn new() -> FdTable {
+        FdTable {
+            files: TicketLock::new(Vec::new()),
+        }
+    }
+
+    /// A table with stdin, stdout and stderr open on `/dev/console`
+    pub fn with_console() -> VfsResult<FdTable> {
+        let console = super::open("/dev/console", O_RDWR, 0)?;
+        let table = FdTable::new();
+
+        for _ in 0..3 {
+            table.insert(console.clone())?;
+        }
+
+        Ok(table)
+    }
+
+    /// Give `file` the lowest free descriptor
+    pub fn insert(&self, file: Arc<dyn File>) -> VfsResult<usize> {
+        let mut files = self.files.lock();
+
+        match files.iter().position(Option::is_none) {
+            Some(fd) => {
+                files[fd] = Some(file);
+                Ok(fd)
+            }
+            None if files.len() < MAX_FDS => {
+                files.push(Some(file));
+                Ok(files.len() - 1)
+            }
+            None => Err(VfsError::TooManyFiles),
+        }
+    }
+
+    /// The file open at `fd`
+    pub fn get(&self, fd: usize) -> VfsResult<Arc<dyn File>> {
+        self.files.lock().get(fd).cloned().flatten().ok_or(VfsError::BadFd)
+    }
+
+    /// Close `fd`, the file is handed back so it's dropped outside the lock
+    pub fn remove(&self, fd: usize) -> VfsResult<Arc<dyn File>> {
+        self.files.lock().get_mut(fd).and_then(Option::take).ok_or(VfsError::BadFd)
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> FdTable {
+        FdTable::new()
+    }
+}
@@ -0,0 +1,146 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Open files
+//!
+//! The [File] every inode gets when it's opened: an offset, the access mode and the inode.
+//! Regular files read and write at the offset, directories hand out their entries one by
+//! one (starting with `.` and `..`), character devices ignore the offset and can't seek.
+//!
+//! The offset is an atomic, not a lock. Two tasks reading through the same open file at
+//! once may read the same bytes, like they would on Linux for a device.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use super::{DirEntry, File, FileType, Inode, SeekFrom, Stat, VfsError, VfsResult};
+use alloc::{string::String, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// An opened [Inode]
+pub struct InodeFile {
+    inode: Arc<dyn Inode>,
+    offset: AtomicU64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl InodeFile {
+    /// Open `inode` with the given access
+    pub fn new(inode: Arc<dyn Inode>, readable: bool, writable: bool, append: bool) -> InodeFile {
+        InodeFile {
+            inode,
+            offset: AtomicU64::new(0),
+            readable,
+            writable,
+            append,
+        }
+    }
+
+    /// The inode behind the file
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    fn kind(&self) -> VfsResult<FileType> {
+        Ok(self.inode.stat()?.kind)
+    }
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> VfsResult<usize> {
+        if !self.readable {
+            return Err(VfsError::BadFd);
+        }
+
+        if self.kind()? == FileType::Dir {
+            return Err(VfsError::IsDir);
+        }
+
+        let offset = self.offset.load(Ordering::Relaxed);
+        let read = self.inode.read_at(offset, buf)?;
+        self.offset.fetch_add(read as u64, Ordering::Relaxed);
+
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> VfsResult<usize> {
+        if !self.writable {
+            return Err(VfsError::BadFd);
+        }
+
+        let offset = match self.append {
+            true => self.inode.stat()?.size,
+            false => self.offset.load(Ordering::Relaxed),
+        };
+
+        let written = self.inode.write_at(offset, buf)?;
+        self.offset.store(offset + written as u64, Ordering::Relaxed);
+
+        Ok(written)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> VfsResult<u64> {
+        let stat = self.inode.stat()?;
+        if stat.kind == FileType::CharDevice {
+            return Err(VfsError::NotSeekable);
+        }
+
+        let current = self.offset.load(Ordering::Relaxed);
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => current.checked_add_signed(delta),
+            SeekFrom::End(delta) => stat.size.checked_add_signed(delta),
+        };
+
+        let offset = offset.ok_or(VfsError::Invalid)?;
+        self.offset.store(offset, Ordering::Relaxed);
+        Ok(offset)
+    }
+
+    fn readdir(&self) -> VfsResult<Option<DirEntry>> {
+        let stat = self.inode.stat()?;
+        if stat.kind != FileType::Dir {
+            return Err(VfsError::NotDir);
+        }
+
+        let index = self.offset.load(Ordering::Relaxed) as usize;
+        let entry = match index {
+            0 => Some(DirEntry { name: String::from("."), ino: stat.ino, kind: FileType::Dir }),
+            // The parent isn't known without walking back up, its number stays 0
+            1 => Some(DirEntry { name: String::from(".."), ino: 0, kind: FileType::Dir }),
+            index => self.inode.readdir(index - 2)?,
+        };
+
+        if entry.is_some() {
+            self.offset.store(index as u64 + 1, Ordering::Relaxed);
+        }
+
+        Ok(entry)
+    }
+
+    fn stat(&self) -> VfsResult<Stat> {
+        self.inode.stat()
+    }
+
+    fn truncate(&self, size: u64) -> VfsResult<()> {
+        if !self.writable {
+            return Err(VfsError::BadFd);
+        }
+
+        match self.kind()? {
+            FileType::Dir => Err(VfsError::IsDir),
+            FileType::File => self.inode.truncate(size),
+            _ => Err(VfsError::Invalid),
+        }
+    }
+}
@@ -0,0 +1,560 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Virtual filesystem
+//!
+//! One tree of files over every filesystem. A [FileSystem] hands out [Inode]s, the nodes
+//! of its tree, and is [mount]ed at a path. Opening an inode gives a [File], the open file
+//! description with an offset, which processes reach through their [fd::FdTable].
+//!
+//! Paths are resolved lexically: `.` and `..` are removed first, then the mount with the
+//! longest matching prefix is walked from its root. Symbolic links restart the resolution
+//! with the link spliced in. There are no working directories yet, relative paths start
+//! at `/`.
+//!
+//! At boot [init()] mounts the initramfs at `/` ([tmpfs] if there is none), [devfs] at
+//! `/dev` and a [tmpfs] at `/tmp`.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.kernel.org/doc/html/latest/filesystems/vfs.html>
+//!   - <https://man7.org/linux/man-pages/man7/path_resolution.7.html>
+//!   - <https://man7.org/linux/man-pages/man2/open.2.html>
+//!
+
+pub mod devfs;
+pub mod fat;
+pub mod fd;
+pub mod file;
+pub mod tmpfs;
+
+use crate::drivers::block::BlockError;
+use crate::sync::ticket::TicketLock;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::any::Any;
+
+/// Open for reading only
+pub const O_RDONLY: u32 = 0;
+/// Open for writing only
+pub const O_WRONLY: u32 = 1;
+/// Open for reading and writing
+pub const O_RDWR: u32 = 2;
+/// Bits of the access mode
+pub const O_ACCMODE: u32 = 3;
+/// Create the file if it doesn't exist
+pub const O_CREAT: u32 = 0o100;
+/// With [O_CREAT], fail if the file exists
+pub const O_EXCL: u32 = 0o200;
+/// Cut the file to length 0
+pub const O_TRUNC: u32 = 0o1000;
+/// Every write goes to the end
+pub const O_APPEND: u32 = 0o2000;
+/// Fail unless the path is a directory (aarch64 value)
+pub const O_DIRECTORY: u32 = 0o40000;
+/// Don't follow a symlink in the last component (aarch64 value)
+pub const O_NOFOLLOW: u32 = 0o100000;
+
+/// Links followed while resolving one path before giving up
+const MAX_SYMLINKS: usize = 8;
+
+/// Longest path accepted
+pub const PATH_MAX: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// An error type for filesystem operations
+pub enum VfsError {
+    /// Nothing at that path
+    NotFound,
+    /// A component that has to be a directory isn't one
+    NotDir,
+    /// Directories can't be read or written like files
+    IsDir,
+    /// Something is already there
+    Exists,
+    /// The directory still has entries
+    NotEmpty,
+    /// The filesystem or file can't be changed
+    ReadOnly,
+    /// The file descriptor isn't open, or not for this kind of access
+    BadFd,
+    /// The file descriptor table is full
+    TooManyFiles,
+    /// The filesystem is full
+    NoSpace,
+    /// Seeking on a device that streams
+    NotSeekable,
+    /// Too many symlinks, probably a loop
+    Loop,
+    /// Path longer than [PATH_MAX]
+    NameTooLong,
+    /// Something else is mounted there
+    Busy,
+    /// The operation would cross filesystems
+    CrossDevice,
+    /// An argument makes no sense for the file
+    Invalid,
+    /// The device failed
+    Io,
+}
+
+/// Allows printing the error
+impl core::fmt::Display for VfsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VfsError::NotFound => f.write_str("No such file or directory"),
+            VfsError::NotDir => f.write_str("Not a directory"),
+            VfsError::IsDir => f.write_str("Is a directory"),
+            VfsError::Exists => f.write_str("File exists"),
+            VfsError::NotEmpty => f.write_str("Directory not empty"),
+            VfsError::ReadOnly => f.write_str("Read-only file system"),
+            VfsError::BadFd => f.write_str("Bad file descriptor"),
+            VfsError::TooManyFiles => f.write_str("Too many open files"),
+            VfsError::NoSpace => f.write_str("No space left on device"),
+            VfsError::NotSeekable => f.write_str("Illegal seek"),
+            VfsError::Loop => f.write_str("Too many levels of symbolic links"),
+            VfsError::NameTooLong => f.write_str("File name too long"),
+            VfsError::Busy => f.write_str("Device or resource busy"),
+            VfsError::CrossDevice => f.write_str("Invalid cross-device link"),
+            VfsError::Invalid => f.write_str("Invalid argument"),
+            VfsError::Io => f.write_str("Input/output error"),
+        }
+    }
+}
+
+impl From<BlockError> for VfsError {
+    fn from(err: BlockError) -> VfsError {
+        match err {
+            BlockError::ReadOnly => VfsError::ReadOnly,
+            BlockError::Busy => VfsError::Busy,
+            _ => VfsError::Io,
+        }
+    }
+}
+
+/// Result of a filesystem operation
+pub type VfsResult<T> = Result<T, VfsError>;
+
+/// What an inode is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    /// Regular file
+    File,
+    /// Directory
+    Dir,
+    /// Symbolic link, reading it gives the target
+    Symlink,
+    /// Character device, reads and writes stream and offsets mean nothing
+    CharDevice,
+    /// Block device
+    BlockDevice,
+    /// Fifo, socket or anything else
+    Other,
+}
+
+/// Metadata of an inode
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    /// Inode number, unique inside its filesystem
+    pub ino: u64,
+    /// What it is
+    pub kind: FileType,
+    /// Permission bits
+    pub mode: u32,
+    /// Number of hard links
+    pub nlink: u32,
+    /// Size in bytes
+    pub size: u64,
+    /// Device number of a device inode, `(major << 8) | minor`
+    pub rdev: u64,
+}
+
+/// An entry of a directory
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Name inside the directory
+    pub name: String,
+    /// Inode number of the entry
+    pub ino: u64,
+    /// What the entry is
+    pub kind: FileType,
+}
+
+/// Where [File::seek()] counts from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// From the start of the file
+    Start(u64),
+    /// From the current offset
+    Current(i64),
+    /// From the end of the file
+    End(i64),
+}
+
+/// A node of a filesystem's tree
+///
+/// Everything except [Inode::stat()] has a default that fails the way a read-only node of
+/// the wrong type would, filesystems implement what makes sense for each node. Inodes are
+/// [Any] so a filesystem can recognize its own, like the target directory of a rename.
+pub trait Inode: Any + Send + Sync {
+    /// Metadata of the node
+    fn stat(&self) -> VfsResult<Stat>;
+
+    /// Read at `offset`, returns how many bytes were read, 0 at the end
+    ///
+    /// Symlinks return their target.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> VfsResult<usize> {
+        Err(VfsError::Invalid)
+    }
+
+    /// Write at `offset`, returns how many bytes were written
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> VfsResult<usize> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Set the size of a file, cutting it or growing it with zeros
+    fn truncate(&self, _size: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Find the entry `name` of a directory
+    fn lookup(&self, _name: &str) -> VfsResult<Arc<dyn Inode>> {
+        Err(VfsError::NotDir)
+    }
+
+    /// The entry at `index` of a directory, None past the last one
+    ///
+    /// `.` and `..` aren't included.
+    fn readdir(&self, _index: usize) -> VfsResult<Option<DirEntry>> {
+        Err(VfsError::NotDir)
+    }
+
+    /// Make a new entry `name` in a directory
+    fn create(&self, _name: &str, _kind: FileType, _mode: u32) -> VfsResult<Arc<dyn Inode>> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Remove the entry `name` of a directory, it can't be a directory itself
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Remove the empty directory `name` of a directory
+    fn rmdir(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Move the entry `name` of a directory to `new_name` in `new_dir`
+    ///
+    /// Replaces what is at the new name, if it's the same kind of node (and an empty
+    /// directory for directories). `new_dir` has to be in the same filesystem.
+    fn rename(&self, _name: &str, _new_dir: &Arc<dyn Inode>, _new_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+/// A tree of inodes that can be [mount]ed
+pub trait FileSystem: Send + Sync {
+    /// Name of the filesystem type, like `tmpfs`
+    fn name(&self) -> &'static str;
+
+    /// The top directory
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// An open file, shared by every descriptor that refers to it
+pub trait File: Send + Sync {
+    /// Read at the current offset and move it forward
+    fn read(&self, buf: &mut [u8]) -> VfsResult<usize>;
+
+    /// Write at the current offset and move it forward
+    fn write(&self, buf: &[u8]) -> VfsResult<usize>;
+
+    /// Move the offset, returns the new one
+    fn seek(&self, pos: SeekFrom) -> VfsResult<u64>;
+
+    /// Next entry of an open directory, the offset counts entries
+    fn readdir(&self) -> VfsResult<Option<DirEntry>>;
+
+    /// Metadata of the file's inode
+    fn stat(&self) -> VfsResult<Stat>;
+
+    /// Set the size of the file, it has to be open for writing
+    fn truncate(&self, size: u64) -> VfsResult<()>;
+}
+
+/// A filesystem attached to the tree
+struct Mount {
+    /// Normalized absolute path
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// Every mount, looked up by longest prefix
+static MOUNTS: TicketLock<Vec<Mount>> = TicketLock::new(Vec::new());
+
+/// Result of walking a path without restarting
+enum Walk {
+    Found(Arc<dyn Inode>),
+    /// A symlink, resolve this path instead
+    Restart(String),
+}
+
+//--------------------------------------------------------------------------------------------------
+// Public Code
+//--------------------------------------------------------------------------------------------------
+
+/// Attach `fs` at `path`
+///
+/// The mount point doesn't have to exist in the filesystem below, mounts are matched by
+/// path before anything is walked.
+///
+/// ## Examples
+///
+/// ```
+/// use dyseos::vfs;
+/// use alloc::sync::Arc;
+///
+/// vfs::mount("/dev", Arc::new(vfs::devfs::DevFs)).unwrap();
+/// ```
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> VfsResult<()> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(VfsError::Busy);
+    }
+
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Find the inode at `path`, following symlinks
+pub fn lookup(path: &str) -> VfsResult<Arc<dyn Inode>> {
+    resolve(path, true)
+}
+
+/// Find the inode at `path`, a symlink in the last component isn't followed
+pub fn lookup_link(path: &str) -> VfsResult<Arc<dyn Inode>> {
+    resolve(path, false)
+}
+
+/// Open `path`
+///
+/// `flags` are the `O_*` flags, `mode` the permissions of a file made by [O_CREAT].
+pub fn open(path: &str, flags: u32, mode: u32) -> VfsResult<Arc<dyn File>> {
+    let inode = match resolve(path, flags & O_NOFOLLOW == 0) {
+        Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(VfsError::Exists),
+        Ok(inode) => inode,
+        Err(VfsError::NotFound) if flags & O_CREAT != 0 => {
+            let (dir, name) = split_last(path)?;
+            lookup(&dir)?.create(name, FileType::File, mode)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    let stat = inode.stat()?;
+    let access = flags & O_ACCMODE;
+    let writable = access == O_WRONLY || access == O_RDWR;
+
+    match stat.kind {
+        FileType::Dir if writable => return Err(VfsError::IsDir),
+        FileType::Dir => {}
+        _ if flags & O_DIRECTORY != 0 => return Err(VfsError::NotDir),
+        FileType::Symlink => return Err(VfsError::Loop),
+        _ => {}
+    }
+
+    if writable && flags & O_TRUNC != 0 && stat.kind == FileType::File {
+        inode.truncate(0)?;
+    }
+
+    Ok(Arc::new(file::InodeFile::new(
+        inode,
+        access != O_WRONLY,
+        writable,
+        flags & O_APPEND != 0,
+    )))
+}
+
+/// Make a directory at `path`
+pub fn mkdir(path: &str, mode: u32) -> VfsResult<()> {
+    let (dir, name) = split_last(path)?;
+    lookup(&dir)?.create(name, FileType::Dir, mode)?;
+
+    Ok(())
+}
+
+/// Make a symbolic link at `path` pointing at `target`
+pub fn symlink(target: &str, path: &str) -> VfsResult<()> {
+    let (dir, name) = split_last(path)?;
+    let link = lookup(&dir)?.create(name, FileType::Symlink, 0o777)?;
+
+    link.write_at(0, target.as_bytes())?;
+    Ok(())
+}
+
+/// Remove the file, link or device at `path`
+pub fn unlink(path: &str) -> VfsResult<()> {
+    let (dir, name) = split_last(path)?;
+    lookup(&dir)?.unlink(name)
+}
+
+/// Remove the empty directory at `path`
+pub fn rmdir(path: &str) -> VfsResult<()> {
+    let (dir, name) = split_last(path)?;
+    lookup(&dir)?.rmdir(name)
+}
+
+/// Move `from` to `to`, both have to be in the same filesystem
+pub fn rename(from: &str, to: &str) -> VfsResult<()> {
+    let (from, to) = (normalize(from)?, normalize(to)?);
+
+    // Mounts are matched by path, a mount point can't move with the tree under it
+    if MOUNTS.lock().iter().any(|mount| mount.path == from || mount.path == to) {
+        return Err(VfsError::Busy);
+    }
+
+    let (from_dir, from_name) = split_last(&from)?;
+    let (to_dir, to_name) = split_last(&to)?;
+    lookup(&from_dir)?.rename(from_name, &lookup(&to_dir)?, to_name)
+}
+
+/// Set the size of the file at `path`
+pub fn truncate(path: &str, size: u64) -> VfsResult<()> {
+    let inode = lookup(path)?;
+
+    match inode.stat()?.kind {
+        FileType::Dir => Err(VfsError::IsDir),
+        FileType::File => inode.truncate(size),
+        _ => Err(VfsError::Invalid),
+    }
+}
+
+/// Mount the boot filesystems
+///
+/// The initramfs becomes `/` if there is one, a [tmpfs] otherwise. [devfs] goes on `/dev`
+/// and a [tmpfs] on `/tmp`. Call once, after the heap is up.
+pub fn init() {
+    let _ = match crate::initramfs::get() {
+        Some(initramfs) => mount("/", Arc::new(crate::initramfs::InitramfsFs::new(*initramfs))),
+        None => mount("/", Arc::new(tmpfs::TmpFs::new(tmpfs::DEFAULT_SIZE))),
+    };
+
+    devfs::init();
+    let _ = mount("/dev", Arc::new(devfs::DevFs));
+    let _ = mount("/tmp", Arc::new(tmpfs::TmpFs::new(tmpfs::DEFAULT_SIZE)));
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// Absolute path without empty, `.` and `..` components
+///
+/// `..` at the root stays at the root.
+fn normalize(path: &str) -> VfsResult<String> {
+    if path.len() > PATH_MAX {
+        return Err(VfsError::NameTooLong);
+    }
+
+    let mut parts: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            component => parts.push(component),
+        }
+    }
+
+    Ok(format!("/{}", parts.join("/")))
+}
+
+/// Directory and last component of `path`
+fn split_last(path: &str) -> VfsResult<(String, &str)> {
+    let name = path.rsplit('/').find(|c| !c.is_empty()).ok_or(VfsError::Exists)?;
+    if name == "." || name == ".." {
+        return Err(VfsError::Exists);
+    }
+
+    let dir = &path[..path.rfind(name).unwrap_or(0)];
+    Ok((normalize(dir)?, name))
+}
+
+/// The mount `path` is under and the rest of the path inside it
+fn find_mount(path: &str) -> VfsResult<(Arc<dyn FileSystem>, String, usize)> {
+    let mounts = MOUNTS.lock();
+
+    let under = |mount: &&Mount| {
+        mount.path == "/"
+            || path == mount.path
+            || path.strip_prefix(mount.path.as_str()).is_some_and(|rest| rest.starts_with('/'))
+    };
+
+    mounts
+        .iter()
+        .filter(under)
+        .max_by_key(|mount| mount.path.len())
+        .map(|mount| (mount.fs.clone(), mount.path.clone(), mount.path.len()))
+        .ok_or(VfsError::NotFound)
+}
+
+fn resolve(path: &str, follow_last: bool) -> VfsResult<Arc<dyn Inode>> {
+    let mut path = normalize(path)?;
+
+    for _ in 0..=MAX_SYMLINKS {
+        match walk(&path, follow_last)? {
+            Walk::Found(inode) => return Ok(inode),
+            Walk::Restart(next) => path = normalize(&next)?,
+        }
+    }
+
+    Err(VfsError::Loop)
+}
+
+/// Walk the normalized `path` down from its mount
+fn walk(path: &str, follow_last: bool) -> VfsResult<Walk> {
+    let (fs, mount_path, skip) = find_mount(path)?;
+    let components: Vec<&str> = path[skip..].split('/').filter(|c| !c.is_empty()).collect();
+    let mut inode = fs.root();
+
+    for (i, name) in components.iter().enumerate() {
+        let next = inode.lookup(name)?;
+        let last = i + 1 == components.len();
+
+        if next.stat()?.kind == FileType::Symlink && (follow_last || !last) {
+            let target = read_link(&*next)?;
+            let rest = components[i + 1..].join("/");
+
+            let restart = match target.starts_with('/') {
+                true => format!("{}/{}", target, rest),
+                false => format!("{}/{}/{}/{}", mount_path, components[..i].join("/"), target, rest),
+            };
+            return Ok(Walk::Restart(restart));
+        }
+
+        inode = next;
+    }
+
+    Ok(Walk::Found(inode))
+}
+
+/// Target of a symlink inode
+fn read_link(inode: &dyn Inode) -> VfsResult<String> {
+    let mut target = alloc::vec![0; inode.stat()?.size as usize];
+    let len = inode.read_at(0, &mut target)?;
+    target.truncate(len);
+
+    String::from_utf8(target).map_err(|_| VfsError::Invalid)
+}
@@ -0,0 +1,468 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Tmpfs
+//!
+//! Writable filesystem that lives on the kernel heap, for somewhere to put files without
+//! a block device. Mounted at `/tmp`, and at `/` when there's no initramfs.
+//!
+//! File data is kept in [BLOCK_SIZE] blocks indexed by their position in the file. Blocks
+//! are only allocated when written, so holes left by seeking past the end or by
+//! [Inode::truncate()] take no memory and read as zeros. Every filesystem has a size
+//! limit, writes that would go over it fail with [VfsError::NoSpace].
+//!
+//! There are no hard links, every node has exactly one parent.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+//! ## Resources
+//!
+//!   - <https://www.kernel.org/doc/html/latest/filesystems/tmpfs.html>
+//!   - <https://man7.org/linux/man-pages/man2/rename.2.html>
+//!
+
+use super::{DirEntry, FileSystem, FileType, Inode, Stat, VfsError, VfsResult};
+use crate::sync::ticket::TicketLock;
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Size limit of the tmpfs mounts made at boot
+pub const DEFAULT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Unit file data is allocated in
+const BLOCK_SIZE: usize = 4096;
+
+/// Longest name of a directory entry
+const NAME_MAX: usize = 255;
+
+/// A tmpfs, every [FileSystem::root()] call returns the same tree
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+}
+
+impl TmpFs {
+    /// Make an empty filesystem that holds at most `max_size` bytes of file data
+    ///
+    /// ## Examples
+    ///
+    /// ```
+    /// use dyseos::vfs::{self, tmpfs::TmpFs};
+    /// use alloc::sync::Arc;
+    ///
+    /// vfs::mount("/run", Arc::new(TmpFs::new(1024 * 1024))).unwrap();
+    /// ```
+    pub fn new(max_size: usize) -> TmpFs {
+        let shared = Arc::new(Shared {
+            next_ino: AtomicU64::new(1),
+            blocks: AtomicUsize::new(0),
+            max_blocks: max_size / BLOCK_SIZE,
+            tree: TicketLock::new(()),
+        });
+
+        TmpFs {
+            root: TmpNode::new(&shared, FileType::Dir, 0o1777),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// State of a whole filesystem, every node points back at it
+struct Shared {
+    next_ino: AtomicU64,
+    /// Data blocks allocated by all files
+    blocks: AtomicUsize,
+    max_blocks: usize,
+    /// Held by everything that locks more than one node at once, renames and rmdir
+    tree: TicketLock<()>,
+}
+
+impl Shared {
+    /// Account for `count` more blocks, if there's room
+    fn charge(&self, count: usize) -> VfsResult<()> {
+        self.blocks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |blocks| {
+                blocks.checked_add(count).filter(|&blocks| blocks <= self.max_blocks)
+            })
+            .map(|_| ())
+            .map_err(|_| VfsError::NoSpace)
+    }
+
+    /// Give back `count` blocks
+    fn refund(&self, count: usize) {
+        self.blocks.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+/// What a node holds
+enum Data {
+    File {
+        size: u64,
+        /// Allocated blocks by index, missing ones are holes
+        blocks: BTreeMap<u64, Box<[u8]>>,
+    },
+    Dir(BTreeMap<String, Arc<TmpNode>>),
+    Symlink(String),
+}
+
+/// A file, directory or symlink of a tmpfs
+struct TmpNode {
+    ino: u64,
+    kind: FileType,
+    mode: u32,
+    shared: Arc<Shared>,
+    data: TicketLock<Data>,
+}
+
+impl TmpNode {
+    fn new(shared: &Arc<Shared>, kind: FileType, mode: u32) -> Arc<TmpNode> {
+        let data = match kind {
+            FileType::Dir => Data::Dir(BTreeMap::new()),
+            FileType::Symlink => Data::Symlink(String::new()),
+            _ => Data::File { size: 0, blocks: BTreeMap::new() },
+        };
+
+        Arc::new(TmpNode {
+            ino: shared.next_ino.fetch_add(1, Ordering::Relaxed),
+            kind,
+            mode: mode & 0o7777,
+            shared: shared.clone(),
+            data: TicketLock::new(data),
+        })
+    }
+
+    fn is_empty_dir(&self) -> bool {
+        matches!(&*self.data.lock(), Data::Dir(entries) if entries.is_empty())
+    }
+
+    /// Whether `node` is this directory or somewhere below it
+    fn contains(&self, node: &TmpNode) -> bool {
+        if core::ptr::eq(self, node) {
+            return true;
+        }
+
+        let children: Vec<Arc<TmpNode>> = match &*self.data.lock() {
+            Data::Dir(entries) => entries.values().filter(|child| child.kind == FileType::Dir).cloned().collect(),
+            _ => return false,
+        };
+
+        children.iter().any(|child| child.contains(node))
+    }
+}
+
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        if let Data::File { blocks, .. } = &*self.data.lock() {
+            self.shared.refund(blocks.len());
+        }
+    }
+}
+
+impl Inode for TmpNode {
+    fn stat(&self) -> VfsResult<Stat> {
+        let data = self.data.lock();
+
+        let (size, nlink) = match &*data {
+            Data::File { size, .. } => (*size, 1),
+            Data::Symlink(target) => (target.len() as u64, 1),
+            Data::Dir(entries) => {
+                let subdirs = entries.values().filter(|child| child.kind == FileType::Dir).count();
+                (0, 2 + subdirs as u32)
+            }
+        };
+
+        Ok(Stat {
+            ino: self.ino,
+            kind: self.kind,
+            mode: self.mode,
+            nlink,
+            size,
+            rdev: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let data = self.data.lock();
+
+        let (size, blocks) = match &*data {
+            Data::File { size, blocks } => (*size, blocks),
+            Data::Symlink(target) => {
+                let target = target.as_bytes().get(offset as usize..).unwrap_or(&[]);
+                let len = target.len().min(buf.len());
+                buf[..len].copy_from_slice(&target[..len]);
+                return Ok(len);
+            }
+            Data::Dir(_) => return Err(VfsError::IsDir),
+        };
+
+        let len = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done as u64;
+            let start = (pos % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - start).min(len - done);
+            let dest = &mut buf[done..done + count];
+
+            match blocks.get(&(pos / BLOCK_SIZE as u64)) {
+                Some(block) => dest.copy_from_slice(&block[start..start + count]),
+                None => dest.fill(0),
+            }
+            done += count;
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        let mut data = self.data.lock();
+
+        let (size, blocks) = match &mut *data {
+            Data::File { size, blocks } => (size, blocks),
+            // Only done by vfs::symlink(), right after the link is created
+            Data::Symlink(target) if offset == 0 && target.is_empty() => {
+                *target = String::from(core::str::from_utf8(buf).map_err(|_| VfsError::Invalid)?);
+                return Ok(buf.len());
+            }
+            Data::Symlink(_) => return Err(VfsError::Invalid),
+            Data::Dir(_) => return Err(VfsError::IsDir),
+        };
+
+        offset.checked_add(buf.len() as u64).ok_or(VfsError::Invalid)?;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - start).min(buf.len() - done);
+
+            let block = match blocks.entry(pos / BLOCK_SIZE as u64) {
+                Entry::Occupied(block) => block.into_mut(),
+                Entry::Vacant(hole) => {
+                    match self.shared.charge(1) {
+                        Ok(()) => hole.insert(vec![0; BLOCK_SIZE].into_boxed_slice()),
+                        // Short write if some of it made it
+                        Err(_) if done > 0 => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+            };
+
+            block[start..start + count].copy_from_slice(&buf[done..done + count]);
+            done += count;
+        }
+
+        *size = (*size).max(offset + done as u64);
+        Ok(done)
+    }
+
+    fn truncate(&self, new_size: u64) -> VfsResult<()> {
+        let mut data = self.data.lock();
+
+        let Data::File { size, blocks } = &mut *data else {
+            return Err(VfsError::Invalid);
+        };
+
+        if new_size < *size {
+            // Drop whole blocks past the end, zero the tail of the last one so growing
+            // the file again reads zeros
+            let cut = blocks.split_off(&new_size.div_ceil(BLOCK_SIZE as u64));
+            self.shared.refund(cut.len());
+
+            let tail = (new_size % BLOCK_SIZE as u64) as usize;
+            if let Some(block) = blocks.get_mut(&(new_size / BLOCK_SIZE as u64)) {
+                block[tail..].fill(0);
+            }
+        }
+
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<Arc<dyn Inode>> {
+        match &*self.data.lock() {
+            Data::Dir(entries) => Ok(entries.get(name).ok_or(VfsError::NotFound)?.clone()),
+            _ => Err(VfsError::NotDir),
+        }
+    }
+
+    fn readdir(&self, index: usize) -> VfsResult<Option<DirEntry>> {
+        let data = self.data.lock();
+        let Data::Dir(entries) = &*data else {
+            return Err(VfsError::NotDir);
+        };
+
+        Ok(entries.iter().nth(index).map(|(name, node)| DirEntry {
+            name: name.clone(),
+            ino: node.ino,
+            kind: node.kind,
+        }))
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> VfsResult<Arc<dyn Inode>> {
+        check_name(name)?;
+        if !matches!(kind, FileType::File | FileType::Dir | FileType::Symlink) {
+            return Err(VfsError::Invalid);
+        }
+
+        let mut data = self.data.lock();
+        let Data::Dir(entries) = &mut *data else {
+            return Err(VfsError::NotDir);
+        };
+
+        if entries.contains_key(name) {
+            return Err(VfsError::Exists);
+        }
+
+        let node = TmpNode::new(&self.shared, kind, mode);
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let removed = {
+            let mut data = self.data.lock();
+            let Data::Dir(entries) = &mut *data else {
+                return Err(VfsError::NotDir);
+            };
+
+            match entries.get(name) {
+                None => return Err(VfsError::NotFound),
+                Some(node) if node.kind == FileType::Dir => return Err(VfsError::IsDir),
+                Some(_) => entries.remove(name),
+            }
+        };
+
+        // Open files keep the node alive, its blocks go when the last one closes
+        drop(removed);
+        Ok(())
+    }
+
+    fn rmdir(&self, name: &str) -> VfsResult<()> {
+        let _tree = self.shared.tree.lock();
+        let mut data = self.data.lock();
+        let Data::Dir(entries) = &mut *data else {
+            return Err(VfsError::NotDir);
+        };
+
+        // Entries are never the directory itself, there's no `.`, so this locks another node
+        match entries.get(name) {
+            None => Err(VfsError::NotFound),
+            Some(node) if node.kind != FileType::Dir => Err(VfsError::NotDir),
+            Some(node) if !node.is_empty_dir() => Err(VfsError::NotEmpty),
+            Some(_) => {
+                entries.remove(name);
+                Ok(())
+            }
+        }
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> VfsResult<()> {
+        check_name(new_name)?;
+
+        let new_dir = (&**new_dir as &dyn Any).downcast_ref::<TmpNode>().ok_or(VfsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.shared, &new_dir.shared) {
+            return Err(VfsError::CrossDevice);
+        }
+        if new_dir.kind != FileType::Dir {
+            return Err(VfsError::NotDir);
+        }
+
+        // Nothing else moves directories while this is held, so the checks stay true
+        let _tree = self.shared.tree.lock();
+
+        let node = match &*self.data.lock() {
+            Data::Dir(entries) => entries.get(name).ok_or(VfsError::NotFound)?.clone(),
+            _ => return Err(VfsError::NotDir),
+        };
+
+        // A directory can't go inside itself
+        if node.kind == FileType::Dir && node.contains(new_dir) {
+            return Err(VfsError::Invalid);
+        }
+
+        let same_dir = core::ptr::eq(self, new_dir);
+
+        // The target can be this directory, `a/x` over `a`. It holds `name` so it isn't
+        // empty, and checking that below would lock it a second time
+        if !same_dir {
+            let target = match &*new_dir.data.lock() {
+                Data::Dir(entries) => entries.get(new_name).cloned(),
+                _ => return Err(VfsError::NotDir),
+            };
+
+            if target.is_some_and(|target| core::ptr::eq(&*target, self)) {
+                return Err(VfsError::NotEmpty);
+            }
+        }
+
+        let mut from = self.data.lock();
+        let mut to = match same_dir {
+            true => None,
+            false => Some(new_dir.data.lock()),
+        };
+
+        let replaced = {
+            let Some(Data::Dir(targets)) = to.as_deref_mut().or(Some(&mut *from)) else {
+                return Err(VfsError::NotDir);
+            };
+
+            match targets.get(new_name) {
+                Some(existing) if Arc::ptr_eq(existing, &node) => return Ok(()),
+                Some(existing) => match (node.kind, existing.kind) {
+                    (FileType::Dir, FileType::Dir) if !existing.is_empty_dir() => {
+                        return Err(VfsError::NotEmpty)
+                    }
+                    (FileType::Dir, FileType::Dir) => {}
+                    (FileType::Dir, _) => return Err(VfsError::NotDir),
+                    (_, FileType::Dir) => return Err(VfsError::IsDir),
+                    _ => {}
+                },
+                None => {}
+            }
+
+            targets.insert(String::from(new_name), node)
+        };
+
+        if let Data::Dir(entries) = &mut *from {
+            // Same directory and same name is handled above, this can't remove the new entry
+            entries.remove(name);
+        }
+
+        drop((from, to));
+        drop(replaced);
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Private api
+//--------------------------------------------------------------------------------------------------
+
+/// A name that can be a directory entry
+fn check_name(name: &str) -> VfsResult<()> {
+    match name {
+        "" | "." | ".." => Err(VfsError::Invalid),
+        name if name.contains('/') => Err(VfsError::Invalid),
+        name if name.len() > NAME_MAX => Err(VfsError::NameTooLong),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,28 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Drivers, for the host
+//!
+//! The kernel's block device layer and a console that is always empty.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+#[path = "../../../../src/drivers/block.rs"]
+pub mod block;
+
+pub mod console {
+    pub fn _read(_buf: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn _write_bytes(_bytes: &[u8]) {}
+}
@@ -0,0 +1,74 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS FAT32 host tests
+//!
+//! The kernel's [vfs] and block device code built for the host, so the FAT32 driver can be
+//! tested against images made by `mkfs.vfat` with a plain `cargo test`. The sources are
+//! the kernel's own, only the parts of the crate they reach that need the hardware (IRQ
+//! masking, the scheduler, the console) are stubbed out here.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+extern crate alloc;
+
+#[path = "../../../src/vfs/mod.rs"]
+pub mod vfs;
+
+pub mod drivers;
+pub mod sync;
+
+pub mod task {
+    pub fn sleep(duration: core::time::Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+pub mod random {
+    pub fn fill_bytes(buf: &mut [u8]) {
+        buf.fill(0);
+    }
+
+    pub fn add_entropy(_bytes: &[u8]) {}
+}
+
+/// There's never an initramfs, [vfs::init()] falls back to a tmpfs
+pub mod initramfs {
+    use crate::vfs::{FileSystem, Inode};
+    use alloc::sync::Arc;
+    use core::marker::PhantomData;
+
+    #[derive(Clone, Copy)]
+    pub struct Initramfs<'a>(PhantomData<&'a [u8]>);
+
+    pub struct InitramfsFs;
+
+    impl InitramfsFs {
+        pub fn new(_fs: Initramfs<'static>) -> InitramfsFs {
+            InitramfsFs
+        }
+    }
+
+    impl FileSystem for InitramfsFs {
+        fn name(&self) -> &'static str {
+            "initramfs"
+        }
+
+        fn root(&self) -> Arc<dyn Inode> {
+            unreachable!()
+        }
+    }
+
+    pub fn get() -> Option<&'static Initramfs<'static>> {
+        None
+    }
+}
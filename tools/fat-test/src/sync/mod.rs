@@ -0,0 +1,68 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS Sync, for the host
+//!
+//! The kernel's mutex, on a futex that yields the thread instead of sleeping, and a ticket
+//! lock on top of std's.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+// Some of it is only for the condvar, which isn't built here
+#[allow(dead_code)]
+#[path = "../../../../src/sync/mutex.rs"]
+pub mod mutex;
+
+/// Spinning with IRQs masked means nothing on the host, a std mutex does the same job
+pub mod ticket {
+    pub type TicketLockGuard<'a, T> = std::sync::MutexGuard<'a, T>;
+
+    pub struct TicketLock<T: ?Sized>(std::sync::Mutex<T>);
+
+    impl<T> TicketLock<T> {
+        pub const fn new(data: T) -> TicketLock<T> {
+            TicketLock(std::sync::Mutex::new(data))
+        }
+    }
+
+    impl<T: ?Sized> TicketLock<T> {
+        pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+            self.0.try_lock().ok()
+        }
+
+        pub fn lock(&self) -> TicketLockGuard<'_, T> {
+            self.0.lock().unwrap()
+        }
+    }
+}
+
+/// No scheduler to sleep in, waiting is yielding the thread
+pub mod futex {
+    use core::sync::atomic::AtomicU32;
+    use core::time::Duration;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum FutexError {
+        WouldBlock,
+        TimedOut,
+        NotAllowed,
+    }
+
+    pub fn futex_wait(_futex: &AtomicU32, _expected: u32, _timeout: Option<Duration>) -> Result<(), FutexError> {
+        std::thread::yield_now();
+        Ok(())
+    }
+
+    pub fn futex_wake(_futex: &AtomicU32, _n: usize) -> usize {
+        0
+    }
+}
@@ -0,0 +1,261 @@
+/********************************************************************************
+ *
+ *      ____                     ____          __           __       _
+ *     / __ \__  __________     /  _/___  ____/ /_  _______/ /______(_)__  _____
+ *    / / / / / / / ___/ _ \    / // __ \/ __  / / / / ___/ __/ ___/ / _ \/ ___/
+ *   / /_/ / /_/ (__  )  __/  _/ // / / / /_/ / /_/ (__  ) /_/ /  / /  __(__  )
+ *  /_____/\__, /____/\___/  /___/_/ /_/\__,_/\__,_/____/\__/_/  /_/\___/____/
+ *        /____/
+ *
+ *
+ ********************************************************************************/
+//! # DyseOS FAT32 tests
+//!
+//! The driver against fresh images from `mkfs.vfat` (dosfstools), kept in memory. Every
+//! test mounts the image a second time to check that what it did made it to the disk and
+//! not just into the driver's caches.
+//!
+//! Author: Mitchell Scott <scott.mitchell913@gmail.com>
+//!
+
+use fat_test::drivers::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use fat_test::vfs::fat::Fat32;
+use fat_test::vfs::{FileSystem, FileType, Inode, VfsError};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Size of the images, just over the smallest FAT32 with 512 byte clusters
+const IMAGE_KIB: usize = 40 * 1024;
+
+/// A disk image in memory
+struct Image(Mutex<Vec<u8>>);
+
+impl BlockDevice for Image {
+    fn num_blocks(&self) -> u64 {
+        (self.0.lock().unwrap().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = lba as usize * SECTOR_SIZE;
+        let image = self.0.lock().unwrap();
+
+        buf.copy_from_slice(image.get(start..start + buf.len()).ok_or(BlockError::OutOfRange)?);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let start = lba as usize * SECTOR_SIZE;
+        let mut image = self.0.lock().unwrap();
+
+        image.get_mut(start..start + buf.len()).ok_or(BlockError::OutOfRange)?.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// A new FAT32 image with one sector clusters, so directories fill up quickly
+fn mkfs() -> Arc<Image> {
+    static IMAGES: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!("dyseos-fat-{}-{}.img", std::process::id(), IMAGES.fetch_add(1, Ordering::Relaxed));
+    let path = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_file(&path);
+
+    let status = Command::new("mkfs.vfat")
+        .args(["-F", "32", "-s", "1", "-C"])
+        .arg(&path)
+        .arg(IMAGE_KIB.to_string())
+        .stdout(Stdio::null())
+        .status()
+        .expect("mkfs.vfat from dosfstools is needed for these tests");
+    assert!(status.success(), "mkfs.vfat failed");
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    Arc::new(Image(Mutex::new(bytes)))
+}
+
+/// Root directory of a fresh mount of `image`
+fn mount(image: &Arc<Image>) -> Arc<dyn Inode> {
+    Fat32::new(image.clone()).unwrap().root()
+}
+
+fn create(dir: &Arc<dyn Inode>, name: &str, data: &[u8]) {
+    let file = dir.create(name, FileType::File, 0o644).unwrap();
+    assert_eq!(file.write_at(0, data).unwrap(), data.len());
+}
+
+fn contents(dir: &Arc<dyn Inode>, name: &str) -> Vec<u8> {
+    let file = dir.lookup(name).unwrap();
+    let mut buf = vec![0; file.stat().unwrap().size as usize];
+
+    assert_eq!(file.read_at(0, &mut buf).unwrap(), buf.len());
+    buf
+}
+
+fn names(dir: &Arc<dyn Inode>) -> Vec<String> {
+    (0..).map_while(|index| dir.readdir(index).unwrap()).map(|entry| entry.name).collect()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn long_names_round_trip() {
+    let image = mkfs();
+    let root = mount(&image);
+    let created = ["A long file name.txt", "Long name one.txt", "Long name two.txt", "MixedCase.Ext", "lower.txt", "UPPER.TXT"];
+
+    for name in created {
+        create(&root, name, name.as_bytes());
+    }
+
+    let root = mount(&image);
+    assert_eq!(names(&root), created);
+
+    for name in created {
+        assert_eq!(contents(&root, name), name.as_bytes());
+    }
+
+    // Matched without case, like everywhere else
+    assert_eq!(contents(&root, "a LONG file NAME.TXT"), b"A long file name.txt");
+    assert_eq!(root.create("long NAME one.txt", FileType::File, 0o644).err(), Some(VfsError::Exists));
+}
+
+#[test]
+fn directory_grows_past_a_cluster() {
+    let image = mkfs();
+    let root = mount(&image);
+    let dir = root.create("many", FileType::Dir, 0o755).unwrap();
+
+    // Three entries each, a cluster only holds 16
+    let created: Vec<String> = (0..100).map(|i| format!("file number {i}.dat")).collect();
+    for name in &created {
+        create(&dir, name, name.as_bytes());
+    }
+
+    let root = mount(&image);
+    let dir = root.lookup("many").unwrap();
+
+    assert_eq!(names(&root), ["many"]);
+    assert_eq!(names(&dir), created);
+    for name in &created {
+        assert_eq!(contents(&dir, name), name.as_bytes());
+    }
+}
+
+#[test]
+fn rename_over_a_file() {
+    let image = mkfs();
+    let root = mount(&image);
+
+    create(&root, "source.txt", b"new");
+    // Several clusters, all of them have to be freed
+    create(&root, "Target file.txt", &[b'o'; 2000]);
+    root.rename("source.txt", &root, "target FILE.txt").unwrap();
+
+    for root in [root, mount(&image)] {
+        assert_eq!(names(&root), ["target FILE.txt"]);
+        assert_eq!(contents(&root, "Target file.txt"), b"new");
+        assert_eq!(root.lookup("source.txt").err(), Some(VfsError::NotFound));
+    }
+}
+
+#[test]
+fn rename_over_on_a_full_volume() {
+    let image = mkfs();
+    let root = mount(&image);
+
+    // Fill the root's first cluster, the long name below needs a new one
+    create(&root, "source.txt", b"source");
+    create(&root, "TARGET.TXT", b"");
+    let fill = root.create("fill", FileType::File, 0o644).unwrap();
+    for i in 0..13 {
+        create(&root, &format!("f{i}"), b"");
+    }
+
+    // Big writes first, then down to a cluster at a time for the rest
+    let mut size = 0;
+    for chunk in [1024 * 1024, 64 * 1024, 512] {
+        while let Ok(written) = fill.write_at(size, &vec![0; chunk]) {
+            size += written as u64;
+        }
+    }
+
+    // Nothing is lost when there's no room for the new entry
+    assert_eq!(root.rename("source.txt", &root, "Target.txt").err(), Some(VfsError::NoSpace));
+
+    let root = mount(&image);
+    assert_eq!(contents(&root, "source.txt"), b"source");
+    assert_eq!(names(&root).iter().filter(|name| name.eq_ignore_ascii_case("target.txt")).count(), 1);
+}
+
+#[test]
+fn rename_over_a_directory() {
+    let image = mkfs();
+    let root = mount(&image);
+    let from = root.create("from", FileType::Dir, 0o755).unwrap();
+    let to = root.create("to", FileType::Dir, 0o755).unwrap();
+
+    let moved = from.create("Moved directory", FileType::Dir, 0o755).unwrap();
+    create(&moved, "inside.txt", b"inside");
+    let full = to.create("full", FileType::Dir, 0o755).unwrap();
+    create(&full, "keep.txt", b"keep");
+    to.create("empty", FileType::Dir, 0o755).unwrap();
+
+    assert_eq!(from.rename("Moved directory", &to, "full").err(), Some(VfsError::NotEmpty));
+    from.rename("Moved directory", &to, "empty").unwrap();
+
+    let root = mount(&image);
+    let to = root.lookup("to").unwrap();
+
+    assert!(names(&root.lookup("from").unwrap()).is_empty());
+    assert_eq!(names(&to), ["full", "empty"]);
+    assert_eq!(contents(&to.lookup("empty").unwrap(), "inside.txt"), b"inside");
+    assert_eq!(contents(&to.lookup("full").unwrap(), "keep.txt"), b"keep");
+
+    // `..` of the moved directory was pointed at its new parent
+    let moved = to.lookup("empty").unwrap();
+    assert!(moved.rename("inside.txt", &root, "out.txt").is_ok());
+    assert_eq!(contents(&root, "out.txt"), b"inside");
+}
+
+#[test]
+fn reads_at_and_past_the_end() {
+    let image = mkfs();
+    let root = mount(&image);
+
+    // A whole cluster, so the chain ends right where the file does
+    create(&root, "cluster.bin", &[0xAB; 512]);
+    create(&root, "odd.bin", &[0xCD; 700]);
+
+    let root = mount(&image);
+    let mut buf = [0; 64];
+
+    for (name, size) in [("cluster.bin", 512), ("odd.bin", 700)] {
+        let file = root.lookup(name).unwrap();
+
+        assert_eq!(file.read_at(size - 10, &mut buf).unwrap(), 10);
+        assert_eq!(file.read_at(size, &mut buf).unwrap(), 0);
+        assert_eq!(file.read_at(2000, &mut buf).unwrap(), 0);
+        assert_eq!(file.read_at(u64::MAX, &mut buf).unwrap(), 0);
+    }
+
+    // Nothing allocated at all
+    create(&root, "empty.bin", b"");
+    assert_eq!(root.lookup("empty.bin").unwrap().read_at(0, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn empty_write_past_the_end() {
+    let image = mkfs();
+    let root = mount(&image);
+
+    create(&root, "short.txt", b"short");
+    let file = root.lookup("short.txt").unwrap();
+
+    assert_eq!(file.write_at(100_000, &[]).unwrap(), 0);
+    assert_eq!(file.stat().unwrap().size, 5);
+    assert_eq!(contents(&mount(&image), "short.txt"), b"short");
+}